@@ -57,6 +57,9 @@ async fn main() -> std::io::Result<()> {
     }
 
     let file = File::open(&args[1])?;
+    // The agent is started in the project's source directory, which the
+    // conditions of the steps are evaluated against
+    let project_dir = std::env::current_dir()?;
 
     let work_dir = Path::new("agent-work");
     let cache_dir = work_dir.join("caches");
@@ -78,10 +81,13 @@ async fn main() -> std::io::Result<()> {
             panic!("Failed to parse parameters file: {:#?}", e);
         }
         Ok(invoke) => {
-            let pipeline_uuid = invoke.pipeline.clone();
+            // The control socket is named after the context when one is given
+            // so that orchestrators can control agents for sibling contexts
+            let control_uuid = invoke.context.unwrap_or(invoke.pipeline);
+            let socket = control::agent_socket(&control_uuid);
             async_std::task::spawn(async move {
                 // TODO better error handling and behavior
-                control::run(pipeline_uuid, sender)
+                control::run(control_uuid, sender)
                     .await
                     .expect("Failed to bind control?");
             });
@@ -95,9 +101,34 @@ async fn main() -> std::io::Result<()> {
             std::env::set_current_dir(pipeline_dir)?;
 
             set_common_env_vars();
-
-            let status = run(&steps_dir, &invoke.steps, invoke.pipeline, Some(receiver))
-                .expect("Failed to run pipeline");
+            // The steps are in process groups of their own, so they would not
+            // otherwise see the signals sent to the agent
+            forward_signals();
+
+            let facts = when::Facts::gather(invoke.environment.as_ref(), &project_dir);
+            if !when::evaluate_all(&invoke.when, &facts) {
+                println!("Agent skipping the steps, their conditions were not met");
+                std::process::exit(otto_models::Status::Skipped as i32);
+            }
+
+            let values = interpolate::Values {
+                properties: invoke.properties.clone(),
+                parameters: invoke.parameters.clone(),
+            };
+            let status = run(
+                &steps_dir,
+                &invoke.steps,
+                invoke.pipeline,
+                RunOptions {
+                    post: invoke.post.as_ref(),
+                    environment: invoke.environment.as_ref(),
+                    values: Some(&values),
+                    controller: Some(receiver),
+                    socket: Some(socket),
+                    ..Default::default()
+                },
+            )
+            .expect("Failed to run pipeline");
 
             println!("Agent exiting {:?}", status);
 
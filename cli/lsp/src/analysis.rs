@@ -0,0 +1,851 @@
+/*
+ * The analysis module answers the questions an editor asks about an Ottofile,
+ * such as what could be typed at the cursor, independently of the protocol
+ * which the questions arrive by
+ *
+ * Everything works from byte offsets into the buffer, which the server
+ * converts to and from the positions of the protocol.
+ */
+
+use lsp_types::{
+    CompletionItem, CompletionItemKind, Diagnostic, DiagnosticSeverity, Documentation,
+    MarkupContent, MarkupKind, NumberOrString, Position, Range,
+};
+use otto_models::osp::{Manifest, Parameter, ParameterType};
+use otto_parser::syntax::lex;
+use otto_parser::{
+    parse_pipeline_with_syntax, validate, Parsed, Severity, Syntax, SyntaxElement, SyntaxKind,
+    SyntaxNode, SyntaxToken, SyntaxTree,
+};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// The statements which may start a file
+const TOP_LEVEL: &[&str] = &["pipeline", "import", "template", "macro"];
+const PIPELINE: &[&str] = &[
+    "stage",
+    "parallel",
+    "fanout",
+    "matrix",
+    "environment",
+    "parameters",
+    "triggers",
+    "post",
+    "use",
+];
+const STAGE: &[&str] = &[
+    "name",
+    "agent",
+    "environment",
+    "needs",
+    "when",
+    "steps",
+    "post",
+];
+const POST: &[&str] = &["always", "success", "failure", "unstable", "aborted"];
+const CONDITIONS: &[&str] = &[
+    "branch",
+    "environment",
+    "changeset",
+    "not",
+    "anyOf",
+    "allOf",
+];
+const PARALLEL: &[&str] = &["stage", "use"];
+const MATRIX: &[&str] = &["axes", "excludes", "stage"];
+const AGENT: &[&str] = &["label"];
+const TRIGGERS: &[&str] = &["cron", "push", "upstream"];
+const PARAMETERS: &[&str] = &["string", "boolean", "choice"];
+/// Blocks whose statements are named by the user, such as environment
+/// variables, where nothing can be suggested
+const NAMED: &[&str] = &["environment", "axes", "excludes", "exclude"];
+
+/**
+ * What may be written in a block
+ */
+#[derive(Debug, PartialEq)]
+enum Context {
+    /// Steps, such as those in a `steps` block or the block of a step
+    Steps,
+    /// Only the given keywords
+    Keywords(&'static [&'static str]),
+}
+
+/**
+ * Choose the context of the innermost block, given the first words of the
+ * statements which opened each of the enclosing blocks
+ */
+fn context(blocks: &[String]) -> Context {
+    let block = match blocks.last() {
+        Some(block) => block.as_str(),
+        None => return Context::Keywords(TOP_LEVEL),
+    };
+
+    match block {
+        "pipeline" => Context::Keywords(PIPELINE),
+        "stage" | "template" => Context::Keywords(STAGE),
+        "post" => Context::Keywords(POST),
+        "when" | "not" | "anyOf" | "allOf" => Context::Keywords(CONDITIONS),
+        "parallel" | "fanout" => Context::Keywords(PARALLEL),
+        "matrix" => Context::Keywords(MATRIX),
+        "agent" => Context::Keywords(AGENT),
+        "triggers" => Context::Keywords(TRIGGERS),
+        "parameters" => Context::Keywords(PARAMETERS),
+        named if NAMED.contains(&named) => Context::Keywords(&[]),
+        // Everything else is `steps`, a post condition, a macro or a step
+        // which takes a block, all of which contain steps
+        _ => Context::Steps,
+    }
+}
+
+/**
+ * What surrounds an offset in the buffer
+ */
+#[derive(Debug, Default, PartialEq)]
+struct Surroundings {
+    /// The first words of the statements which opened each of the blocks
+    /// enclosing the offset, outermost first
+    blocks: Vec<String>,
+    /// The offset at which the statement containing the offset starts
+    statement: usize,
+    /// Whether the offset is within a string or a comment
+    quoted: bool,
+}
+
+/**
+ * Find what surrounds the offset from the syntax tree of the buffer
+ *
+ * The buffer is rarely valid while it is being typed, so the text of the
+ * invalid statement the offset is in, which may be the whole of the buffer,
+ * is scanned token by token instead
+ */
+fn surroundings(buffer: &str, offset: usize) -> Surroundings {
+    let tree = SyntaxTree::parse(buffer);
+    let mut found = Surroundings::default();
+    let mut node = tree.root();
+
+    loop {
+        let child = node
+            .children()
+            .iter()
+            .find(|child| child.start() < offset && offset <= child.end());
+
+        match child {
+            Some(SyntaxElement::Node(child)) => {
+                // The block of a step belongs to the statement of the step
+                let block = child
+                    .children()
+                    .iter()
+                    .find_map(|c| c.as_node().filter(|c| SyntaxKind::Block == c.kind()))
+                    .unwrap_or(child);
+                if within_braces(block, offset) {
+                    found.blocks.push(first_word(child.text()).to_string());
+                    node = block;
+                    continue;
+                }
+
+                found.statement = child.start();
+                found.quoted = child
+                    .tokens()
+                    .iter()
+                    .any(|token| is_within_text(token, offset));
+                return found;
+            }
+            Some(SyntaxElement::Token(token)) if SyntaxKind::Invalid == token.kind() => {
+                found.statement = token.start();
+                scan(
+                    buffer,
+                    &lex(token.text(), token.start()),
+                    offset,
+                    &mut found,
+                );
+                return found;
+            }
+            Some(SyntaxElement::Token(token)) if is_within_text(token, offset) => {
+                found.quoted = true;
+                return found;
+            }
+            _ => {
+                found.statement = statement_before(buffer, node, offset);
+                return found;
+            }
+        }
+    }
+}
+
+/**
+ * Returns true if the offset is between the braces of the node, or after the
+ * opening brace if it is never closed
+ */
+fn within_braces(node: &SyntaxNode, offset: usize) -> bool {
+    let mut tokens = node.children().iter().filter_map(SyntaxElement::as_token);
+    let open = tokens.clone().find(|t| SyntaxKind::LBrace == t.kind());
+    let close = tokens.rfind(|t| SyntaxKind::RBrace == t.kind());
+
+    match (open, close) {
+        (Some(open), Some(close)) => open.end() <= offset && offset <= close.start(),
+        (Some(open), None) => open.end() <= offset,
+        _ => false,
+    }
+}
+
+/**
+ * Returns true if the offset is within the text of a string or a comment,
+ * including the end of those which are never closed
+ */
+fn is_within_text(token: &SyntaxToken, offset: usize) -> bool {
+    let text = token.text();
+    let unclosed = match token.kind() {
+        SyntaxKind::Str => false,
+        SyntaxKind::Comment => text.starts_with("//"),
+        SyntaxKind::Invalid => text.starts_with(['\'', '"']) || text.starts_with("/*"),
+        _ => return false,
+    };
+    token.start() < offset && (offset < token.end() || (unclosed && offset == token.end()))
+}
+
+/**
+ * Where the statement at the offset starts, when the offset is between the
+ * statements of the block
+ *
+ * The offset continues the statement before it on the same line, otherwise it
+ * starts a statement of its own
+ */
+fn statement_before(buffer: &str, block: &SyntaxNode, offset: usize) -> usize {
+    block
+        .children()
+        .iter()
+        .rfind(|child| child.end() <= offset && !child.kind().is_trivia())
+        .filter(|child| !matches!(child.kind(), SyntaxKind::LBrace | SyntaxKind::RBrace))
+        .filter(|child| !buffer[child.end()..offset].contains('\n'))
+        .map(|child| child.start())
+        .unwrap_or(offset)
+}
+
+/**
+ * Follow the tokens of text which could not be parsed up to the offset, opening
+ * and closing blocks at each brace
+ */
+fn scan(buffer: &str, tokens: &[SyntaxToken], offset: usize, found: &mut Surroundings) {
+    // Statements continue over newlines within parenthesis and brackets
+    let mut nesting = 0;
+
+    for token in tokens.iter().take_while(|token| token.start() < offset) {
+        if is_within_text(token, offset) {
+            found.quoted = true;
+            return;
+        }
+
+        match token.kind() {
+            SyntaxKind::LParen | SyntaxKind::LBracket => nesting += 1,
+            SyntaxKind::RParen | SyntaxKind::RBracket => nesting = std::cmp::max(nesting, 1) - 1,
+            SyntaxKind::LBrace => {
+                found
+                    .blocks
+                    .push(first_word(&buffer[found.statement..token.start()]).to_string());
+                found.statement = token.end();
+                nesting = 0;
+            }
+            SyntaxKind::RBrace => {
+                found.blocks.pop();
+                found.statement = token.end();
+                nesting = 0;
+            }
+            SyntaxKind::Whitespace if nesting == 0 => {
+                let before = &token.text()[..std::cmp::min(offset, token.end()) - token.start()];
+                if let Some(newline) = before.rfind('\n') {
+                    found.statement = token.start() + newline + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn first_word(statement: &str) -> &str {
+    let statement = statement.trim_start();
+    let end = statement.find(|c| !is_ident(c)).unwrap_or(statement.len());
+    &statement[..end]
+}
+
+/**
+ * The byte range of the word at the offset, if there is one
+ */
+fn word_at(buffer: &str, offset: usize) -> Option<(usize, usize)> {
+    let start = buffer[..offset]
+        .rfind(|c| !is_ident(c))
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let end = buffer[offset..]
+        .find(|c| !is_ident(c))
+        .map(|i| offset + i)
+        .unwrap_or(buffer.len());
+
+    if start < end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/**
+ * Convert a byte offset into a position, the characters of which are counted
+ * in UTF-16 code units as the protocol requires
+ */
+pub fn position_of(buffer: &str, offset: usize) -> Position {
+    let mut offset = offset.min(buffer.len());
+    while !buffer.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &buffer[..offset];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+
+    Position::new(
+        before.matches('\n').count() as u32,
+        before[line_start..].encode_utf16().count() as u32,
+    )
+}
+
+/**
+ * Convert a position into a byte offset, positions past the end of a line are
+ * taken to be the end of it
+ */
+pub fn offset_of(buffer: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match buffer[line_start..].find('\n') {
+            Some(end) => line_start += end + 1,
+            None => return buffer.len(),
+        }
+    }
+    let line_end = buffer[line_start..]
+        .find('\n')
+        .map(|end| line_start + end)
+        .unwrap_or(buffer.len());
+
+    let mut units = 0;
+    for (i, c) in buffer[line_start..line_end].char_indices() {
+        if units >= position.character {
+            return line_start + i;
+        }
+        units += c.len_utf16() as u32;
+    }
+    line_end
+}
+
+fn range_of(buffer: &str, start: usize, end: usize) -> Range {
+    Range::new(position_of(buffer, start), position_of(buffer, end))
+}
+
+/**
+ * Parse the buffer, which may import libraries from within the directory of
+ * its file since the editor only has the files of the user's own projects
+ */
+fn parse(file: Option<&str>, buffer: &str) -> Parsed {
+    let root = file.and_then(|file| Path::new(file).parent()).map(|dir| {
+        match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
+        }
+    });
+    let syntax = file.map(Syntax::from_path).unwrap_or(Syntax::Otto);
+    parse_pipeline_with_syntax(file, buffer, syntax, root)
+}
+
+/**
+ * Parse the buffer, and validate its steps against the manifests, converting
+ * every problem found into a diagnostic for the editor
+ *
+ * The file is used to find the libraries which the pipeline imports, and to
+ * choose its syntax
+ */
+pub fn diagnostics(
+    file: Option<&str>,
+    buffer: &str,
+    manifests: &HashMap<String, Manifest>,
+) -> Vec<Diagnostic> {
+    let parsed = parse(file, buffer);
+    let mut found = parsed.diagnostics;
+    // The steps which could be parsed are worth checking while the rest of
+    // the pipeline is still being written
+    if !manifests.is_empty() {
+        found.extend(validate(&parsed.pipeline, manifests));
+    }
+
+    found
+        .into_iter()
+        .map(|diagnostic| {
+            let mut message = diagnostic.message.clone();
+            if !diagnostic.expected.is_empty() {
+                message.push_str(&format!("\nexpected {}", diagnostic.expected.join(", ")));
+            }
+            if let Some(hint) = &diagnostic.hint {
+                message.push_str(&format!("\nhint: {}", hint));
+            }
+
+            let range = match &diagnostic.file {
+                // Problems in imported libraries are shown on their imports
+                Some(library) => {
+                    message = format!("{}: {}", library, message);
+                    import_of(buffer, library)
+                        .map(|(start, end)| range_of(buffer, start, end))
+                        .unwrap_or_default()
+                }
+                None if diagnostic.start.line == 0 => Range::default(),
+                None => range_of(buffer, diagnostic.start.offset, diagnostic.end.offset),
+            };
+
+            Diagnostic {
+                range,
+                severity: Some(match diagnostic.severity {
+                    Severity::Error => DiagnosticSeverity::ERROR,
+                    Severity::Warning => DiagnosticSeverity::WARNING,
+                }),
+                code: Some(NumberOrString::String(diagnostic.code.to_string())),
+                source: Some("otto".to_string()),
+                message,
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+/**
+ * Find the import statement of the library, by the path it was imported with
+ */
+fn import_of(buffer: &str, library: &str) -> Option<(usize, usize)> {
+    let mut start = 0;
+    for line in buffer.split('\n') {
+        let statement = line.trim();
+        if let Some(path) = statement.strip_prefix("import") {
+            let path = path.trim().trim_matches(|c| c == '\'' || c == '"');
+            if !path.is_empty() && library.ends_with(path) {
+                let indent = line.len() - line.trim_start().len();
+                return Some((start + indent, start + indent + statement.len()));
+            }
+        }
+        start += line.len() + 1;
+    }
+    None
+}
+
+/**
+ * The first paragraph of a description on a single line, for showing next to
+ * a completion or in a list of parameters
+ */
+fn summary(description: &str) -> String {
+    let paragraph = description.trim().split("\n\n").next().unwrap_or("");
+    paragraph.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn type_name(parameter: &Parameter) -> &'static str {
+    match parameter.p_type {
+        ParameterType::StringParameter => "string",
+        ParameterType::BoolParameter => "boolean",
+        ParameterType::NumberParameter => "number",
+        ParameterType::BlockParameter => "block",
+    }
+}
+
+fn markdown(value: String) -> MarkupContent {
+    MarkupContent {
+        kind: MarkupKind::Markdown,
+        value,
+    }
+}
+
+/**
+ * Document the step with its description and parameters
+ */
+fn step_docs(manifest: &Manifest) -> String {
+    let mut docs = format!(
+        "**{}**\n\n{}\n",
+        manifest.symbol,
+        manifest.description.trim()
+    );
+    if !manifest.parameters.is_empty() {
+        docs.push_str("\nParameters:\n\n");
+        for parameter in manifest.parameters.iter() {
+            docs.push_str(&format!(
+                "* `{}` ({}{}): {}\n",
+                parameter.name,
+                type_name(parameter),
+                if parameter.required { ", required" } else { "" },
+                summary(&parameter.description)
+            ));
+        }
+    }
+    docs
+}
+
+fn parameter_docs(manifest: &Manifest, parameter: &Parameter) -> String {
+    format!(
+        "**{}** `{}` ({}{})\n\n{}\n",
+        manifest.symbol,
+        parameter.name,
+        type_name(parameter),
+        if parameter.required { ", required" } else { "" },
+        parameter.description.trim()
+    )
+}
+
+/**
+ * Suggest what could be written at the offset: the steps or keywords which
+ * may start a statement, or the keyword parameters of a step
+ */
+pub fn completions(
+    buffer: &str,
+    offset: usize,
+    manifests: &HashMap<String, Manifest>,
+) -> Vec<CompletionItem> {
+    let found = surroundings(buffer, offset);
+    if found.quoted {
+        return vec![];
+    }
+    let context = context(&found.blocks);
+    let statement = buffer[found.statement..offset].trim_start();
+
+    // The first word of a statement is being typed
+    if statement.chars().all(is_ident) {
+        return match context {
+            Context::Keywords(keywords) => keywords
+                .iter()
+                .map(|keyword| CompletionItem {
+                    label: keyword.to_string(),
+                    kind: Some(CompletionItemKind::KEYWORD),
+                    ..Default::default()
+                })
+                .collect(),
+            Context::Steps => {
+                let mut symbols: Vec<&Manifest> = manifests.values().collect();
+                symbols.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+                symbols
+                    .into_iter()
+                    .map(|manifest| CompletionItem {
+                        label: manifest.symbol.clone(),
+                        kind: Some(CompletionItemKind::FUNCTION),
+                        detail: Some(summary(&manifest.description)),
+                        documentation: Some(Documentation::MarkupContent(markdown(step_docs(
+                            manifest,
+                        )))),
+                        ..Default::default()
+                    })
+                    .collect()
+            }
+        };
+    }
+
+    if context != Context::Steps {
+        return vec![];
+    }
+    let symbol = first_word(statement);
+    let manifest = match manifests.get(symbol) {
+        Some(manifest) => manifest,
+        None => return vec![],
+    };
+
+    // Keyword parameters may be given after the symbol, an opening parenthesis
+    // or a comma
+    let arguments = &statement[symbol.len()..];
+    let typed = arguments.len() - arguments.trim_end_matches(|c: char| is_ident(c)).len();
+    let before = arguments[..arguments.len() - typed].trim_end();
+    if !(before.is_empty() || before.ends_with('(') || before.ends_with(',')) {
+        return vec![];
+    }
+
+    manifest
+        .parameters
+        .iter()
+        .filter(|parameter| !matches!(parameter.p_type, ParameterType::BlockParameter))
+        .filter(|parameter| !is_given(arguments, &parameter.name))
+        .map(|parameter| CompletionItem {
+            label: parameter.name.clone(),
+            kind: Some(CompletionItemKind::PROPERTY),
+            detail: Some(type_name(parameter).to_string()),
+            documentation: Some(Documentation::MarkupContent(markdown(
+                parameter.description.trim().to_string(),
+            ))),
+            insert_text: Some(format!("{}: ", parameter.name)),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/**
+ * Returns true if the keyword argument is already given in the arguments
+ */
+fn is_given(arguments: &str, name: &str) -> bool {
+    arguments.match_indices(name).any(|(i, _)| {
+        let before = arguments[..i].chars().next_back();
+        let after = arguments[i + name.len()..].trim_start();
+        !before.map(is_ident).unwrap_or(false) && after.starts_with(':')
+    })
+}
+
+/**
+ * Describe the step, or the keyword parameter of a step, at the offset
+ */
+pub fn hover(
+    buffer: &str,
+    offset: usize,
+    manifests: &HashMap<String, Manifest>,
+) -> Option<(MarkupContent, Range)> {
+    let (start, end) = word_at(buffer, offset)?;
+    let found = surroundings(buffer, start);
+    if found.quoted || context(&found.blocks) != Context::Steps {
+        return None;
+    }
+
+    let word = &buffer[start..end];
+    let range = range_of(buffer, start, end);
+    if buffer[found.statement..start].trim().is_empty() {
+        let manifest = manifests.get(word)?;
+        return Some((markdown(step_docs(manifest)), range));
+    }
+
+    if buffer[end..].trim_start().starts_with(':') {
+        let manifest = manifests.get(first_word(&buffer[found.statement..]))?;
+        let parameter = manifest.parameters.iter().find(|p| p.name == word)?;
+        return Some((markdown(parameter_docs(manifest, parameter)), range));
+    }
+    None
+}
+
+/**
+ * Find the stage named by the `needs` at the offset, returning the file it was
+ * declared in, if it was not declared in the buffer, and its byte range
+ */
+pub fn definition(
+    file: Option<&str>,
+    buffer: &str,
+    offset: usize,
+) -> Option<(Option<String>, usize, usize)> {
+    let found = surroundings(buffer, offset);
+    if !found.quoted || first_word(&buffer[found.statement..]) != "needs" {
+        return None;
+    }
+
+    // The string at the offset, which cannot span lines within needs
+    let line_start = buffer[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let open = buffer[line_start..offset].rfind(['\'', '"'])? + line_start;
+    let quote = &buffer[open..open + 1];
+    let close = buffer[offset..].find(quote)? + offset;
+    let name = &buffer[open + 1..close];
+
+    let parsed = parse(file, buffer);
+    let stage = parsed
+        .pipeline
+        .contexts()
+        .find(|ctx| ctx.properties.get("name").map(String::as_str) == Some(name))?;
+    let source = stage.source.as_ref()?;
+    let declared = source
+        .file
+        .clone()
+        .filter(|declared| Some(declared.as_str()) != file);
+    Some((declared, source.start, source.end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn manifests() -> HashMap<String, Manifest> {
+        otto_models::osp::load_manifests(Path::new("../../stdlib"))
+            .expect("Failed to load the stdlib")
+    }
+
+    /**
+     * Split the buffer at the `|` which marks the cursor
+     */
+    fn cursor(buffer: &str) -> (String, usize) {
+        let offset = buffer.find('|').expect("Missing the cursor");
+        (buffer.replacen('|', "", 1), offset)
+    }
+
+    fn labels(items: &[CompletionItem]) -> Vec<&str> {
+        items.iter().map(|item| item.label.as_str()).collect()
+    }
+
+    #[test]
+    fn positions_round_trip() {
+        let buffer = "pipeline {\n    sh 'héllo 🦀'\n}";
+        let offset = buffer.find('🦀').unwrap();
+        let position = position_of(buffer, offset);
+        assert_eq!(position, Position::new(1, 14));
+        assert_eq!(offset_of(buffer, position), offset);
+        assert_eq!(
+            offset_of(buffer, Position::new(1, 100)),
+            buffer.rfind('\n').unwrap()
+        );
+        assert_eq!(offset_of(buffer, Position::new(9, 0)), buffer.len());
+    }
+
+    #[test]
+    fn surroundings_of_offsets() {
+        let (buffer, offset) = cursor(
+            "pipeline {\n  stage {\n    steps {\n      dir('a') { /* { */ sh '}' }\n      |",
+        );
+        let found = surroundings(&buffer, offset);
+        assert_eq!(found.blocks, vec!["pipeline", "stage", "steps"]);
+        assert_eq!(found.statement, buffer.rfind('\n').unwrap() + 1);
+        assert!(!found.quoted);
+
+        let (buffer, offset) = cursor("pipeline {\n  stage { steps { sh 'make |");
+        assert!(surroundings(&buffer, offset).quoted);
+    }
+
+    #[test]
+    fn surroundings_of_valid_buffers() {
+        let (buffer, offset) =
+            cursor("pipeline {\n  stage {\n    steps {\n      dir('a') { s| }\n    }\n  }\n}");
+        let found = surroundings(&buffer, offset);
+        assert_eq!(found.blocks, vec!["pipeline", "stage", "steps", "dir"]);
+        assert_eq!(found.statement, offset - 1);
+        assert!(!found.quoted);
+
+        let (buffer, offset) = cursor("pipeline {\n  steps {\n    sh 'ma|ke' // done\n  }\n}");
+        assert!(surroundings(&buffer, offset).quoted);
+
+        // The statement being typed is invalid, but the rest of the tree is not
+        let (buffer, offset) =
+            cursor("pipeline {\n  stage {\n    steps {\n      sh(script: 'make', |\n    }\n  }\n}");
+        let found = surroundings(&buffer, offset);
+        assert_eq!(found.blocks, vec!["pipeline", "stage", "steps"]);
+        assert_eq!(&buffer[found.statement..offset], "sh(script: 'make', ");
+        assert!(!found.quoted);
+    }
+
+    #[test]
+    fn complete_steps() {
+        let (buffer, offset) = cursor("pipeline {\n  stage {\n    steps {\n      s|");
+        let items = completions(&buffer, offset, &manifests());
+        assert!(labels(&items).contains(&"sh"));
+        assert!(labels(&items).contains(&"dir"));
+        let sh = items.iter().find(|item| item.label == "sh").unwrap();
+        assert_eq!(sh.kind, Some(CompletionItemKind::FUNCTION));
+
+        // Steps are also completed within the blocks of steps
+        let (buffer, offset) = cursor("macro lint {\n  dir('a') {\n    |");
+        assert!(labels(&completions(&buffer, offset, &manifests())).contains(&"sh"));
+    }
+
+    #[test]
+    fn complete_keywords() {
+        let (buffer, offset) = cursor("pipeline {\n  stage {\n    |\n  }\n}");
+        assert_eq!(
+            labels(&completions(&buffer, offset, &manifests())),
+            STAGE.to_vec()
+        );
+
+        let (buffer, offset) = cursor("pipeline {\n  stage {\n    post { |");
+        assert_eq!(
+            labels(&completions(&buffer, offset, &manifests())),
+            POST.to_vec()
+        );
+
+        let (buffer, offset) = cursor("pipeline {\n  environment {\n    |");
+        assert!(completions(&buffer, offset, &manifests()).is_empty());
+    }
+
+    #[test]
+    fn complete_parameters() {
+        let (buffer, offset) = cursor("pipeline { stage { steps {\n  sh script: 'make', |");
+        let items = completions(&buffer, offset, &manifests());
+        assert!(!labels(&items).contains(&"script"));
+        assert!(labels(&items).contains(&"returnStdout"));
+        let item = items.iter().find(|i| i.label == "returnStdout").unwrap();
+        assert_eq!(item.insert_text.as_deref(), Some("returnStdout: "));
+
+        // Block parameters are given as blocks rather than by name
+        let (buffer, offset) = cursor("pipeline { stage { steps {\n  dir(dir|");
+        assert_eq!(
+            labels(&completions(&buffer, offset, &manifests())),
+            vec!["directory"]
+        );
+
+        // Nothing is suggested for the values of parameters
+        let (buffer, offset) = cursor("pipeline { stage { steps {\n  sh script: |");
+        assert!(completions(&buffer, offset, &manifests()).is_empty());
+    }
+
+    #[test]
+    fn hover_steps_and_parameters() {
+        let buffer = "pipeline { stage { steps {\n  sh script: 'make', returnStdout: true\n} } }";
+        let manifests = manifests();
+
+        let (docs, range) = hover(buffer, buffer.find("sh").unwrap() + 1, &manifests)
+            .expect("Missing the docs of the step");
+        assert!(docs.value.starts_with("**sh**"));
+        assert!(docs.value.contains("* `script` (string, required)"));
+        assert_eq!(range, Range::new(Position::new(1, 2), Position::new(1, 4)));
+
+        let (docs, _) = hover(buffer, buffer.find("returnStdout").unwrap(), &manifests)
+            .expect("Missing the docs of the parameter");
+        assert!(docs.value.starts_with("**sh** `returnStdout` (boolean)"));
+
+        assert!(hover(buffer, buffer.find("stage").unwrap(), &manifests).is_none());
+        assert!(hover(buffer, buffer.find("make").unwrap(), &manifests).is_none());
+    }
+
+    #[test]
+    fn definition_of_needs() {
+        let buffer = r#"pipeline {
+    stage {
+        name = 'Build'
+        steps { sh 'make' }
+    }
+    stage {
+        name = 'Test'
+        needs = ['Build']
+        steps { sh 'make check' }
+    }
+}"#;
+        let offset = buffer.find("'Build']").unwrap() + 2;
+        let (file, start, end) = definition(None, buffer, offset).expect("Missing the definition");
+        assert!(file.is_none());
+        assert_eq!(start, buffer.find("stage").unwrap());
+        assert!(buffer[start..end].contains("name = 'Build'"));
+
+        // Only the strings of needs refer to stages
+        assert!(definition(None, buffer, buffer.find("Build").unwrap() + 1).is_none());
+    }
+
+    #[test]
+    fn diagnostics_while_typing() {
+        let buffer = "pipeline {\n  stage {\n    steps {\n      sh 'make'\n      shh 'typo'\n    }\n  }\n  stage {\n    name = \n  }\n}";
+        let found = diagnostics(None, buffer, &manifests());
+        let codes: Vec<_> = found
+            .iter()
+            .map(|d| match &d.code {
+                Some(NumberOrString::String(code)) => code.as_str(),
+                _ => "",
+            })
+            .collect();
+        // The steps which could be parsed are validated despite the error
+        assert_eq!(codes, vec!["E0001", "E0005", "E0100"]);
+        assert_eq!(found[2].range.start, Position::new(4, 6));
+        assert_eq!(found[2].severity, Some(DiagnosticSeverity::ERROR));
+        assert!(found[0]
+            .message
+            .ends_with("hint: property values must be quoted strings"));
+    }
+
+    #[test]
+    fn diagnostics_of_imports() {
+        let buffer = "import 'missing.otto'\npipeline { stage { steps { sh 'make' } } }";
+        let found = diagnostics(Some("Ottofile"), buffer, &HashMap::new());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].range.start.line, 0);
+        assert_eq!(
+            found[0].code,
+            Some(NumberOrString::String(
+                otto_parser::codes::IMPORT_FAILED.into()
+            ))
+        );
+    }
+}
@@ -0,0 +1,329 @@
+/*
+ * The otto-lsp binary is a language server for Ottofiles, speaking the
+ * Language Server Protocol over standard input and output so that editors can
+ * show the problems in a pipeline as it is typed, complete steps and their
+ * parameters, show the documentation of steps and jump to the stages which
+ * are needed.
+ *
+ * Steps are described by the manifests in the directory named by STEPS_DIR.
+ */
+use log::*;
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument,
+    Notification as _, PublishDiagnostics,
+};
+use lsp_types::request::{Completion, GotoDefinition, HoverRequest, Request as _};
+use lsp_types::*;
+use otto_models::osp::{load_manifests, Manifest};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+mod analysis;
+
+/**
+ * The Server keeps the contents of the documents open in the editor, which
+ * differ from those on disk until they are saved
+ */
+struct Server {
+    connection: Connection,
+    manifests: HashMap<String, Manifest>,
+    documents: HashMap<Url, String>,
+}
+
+impl Server {
+    fn run(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        while let Ok(message) = self.connection.receiver.recv() {
+            match message {
+                Message::Request(request) => {
+                    if self.connection.handle_shutdown(&request)? {
+                        return Ok(());
+                    }
+                    let response = self.request(request);
+                    self.connection.sender.send(response.into())?;
+                }
+                Message::Notification(notification) => self.notification(notification)?,
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&self, request: Request) -> Response {
+        let id = request.id.clone();
+        let result = match request.method.as_str() {
+            Completion::METHOD => parse::<CompletionParams>(request.params)
+                .map(|params| serde_json::to_value(self.completion(params))),
+            HoverRequest::METHOD => parse::<HoverParams>(request.params)
+                .map(|params| serde_json::to_value(self.hover(params))),
+            GotoDefinition::METHOD => parse::<GotoDefinitionParams>(request.params)
+                .map(|params| serde_json::to_value(self.definition(params))),
+            method => {
+                return Response::new_err(
+                    id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("Unsupported request: {}", method),
+                )
+            }
+        };
+
+        match result {
+            Ok(Ok(value)) => Response::new_ok(id, value),
+            Ok(Err(err)) => Response::new_err(id, ErrorCode::InternalError as i32, err.to_string()),
+            Err(err) => Response::new_err(id, ErrorCode::InvalidParams as i32, err.to_string()),
+        }
+    }
+
+    /**
+     * Keep the documents in step with the editor, publishing the problems of
+     * the document which changed
+     *
+     * A notification cannot be answered, so one whose params are invalid is
+     * logged and otherwise ignored
+     */
+    fn notification(
+        &mut self,
+        notification: Notification,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let method = notification.method.clone();
+        let uri = match self.update(notification) {
+            Ok(Some(uri)) => uri,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                error!("Ignoring the {} notification: {}", method, e);
+                return Ok(());
+            }
+        };
+
+        let diagnostics = match self.documents.get(&uri) {
+            Some(buffer) => {
+                analysis::diagnostics(file_of(&uri).as_deref(), buffer, &self.manifests)
+            }
+            // The problems of a closed document are no longer shown
+            None => vec![],
+        };
+        self.publish(uri, diagnostics)
+    }
+
+    /**
+     * Update the documents for the notification, returning the document which
+     * changed, if any
+     */
+    fn update(&mut self, notification: Notification) -> Result<Option<Url>, serde_json::Error> {
+        let uri = match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams = parse(notification.params)?;
+                let uri = params.text_document.uri;
+                self.documents
+                    .insert(uri.clone(), params.text_document.text);
+                uri
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: DidChangeTextDocumentParams = parse(notification.params)?;
+                let uri = params.text_document.uri;
+                // Documents are synchronized in full, so the last change has
+                // the whole of the text
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    self.documents.insert(uri.clone(), change.text);
+                }
+                uri
+            }
+            DidSaveTextDocument::METHOD => {
+                let params: DidSaveTextDocumentParams = parse(notification.params)?;
+                if let Some(text) = params.text {
+                    self.documents
+                        .insert(params.text_document.uri.clone(), text);
+                }
+                params.text_document.uri
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams = parse(notification.params)?;
+                self.documents.remove(&params.text_document.uri);
+                params.text_document.uri
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(uri))
+    }
+
+    fn publish(
+        &self,
+        uri: Url,
+        diagnostics: Vec<Diagnostic>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+        let notification = Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+        self.connection.sender.send(notification.into())?;
+        Ok(())
+    }
+
+    /**
+     * Find the document and the offset of the position within it
+     */
+    fn locate(&self, position: &TextDocumentPositionParams) -> Option<(&str, usize)> {
+        let buffer = self.documents.get(&position.text_document.uri)?;
+        Some((buffer, analysis::offset_of(buffer, position.position)))
+    }
+
+    fn completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let (buffer, offset) = self.locate(&params.text_document_position)?;
+        Some(CompletionResponse::Array(analysis::completions(
+            buffer,
+            offset,
+            &self.manifests,
+        )))
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let (buffer, offset) = self.locate(&params.text_document_position_params)?;
+        let (docs, range) = analysis::hover(buffer, offset, &self.manifests)?;
+        Some(Hover {
+            contents: HoverContents::Markup(docs),
+            range: Some(range),
+        })
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let position = &params.text_document_position_params;
+        let (buffer, offset) = self.locate(position)?;
+        let uri = &position.text_document.uri;
+        let file = file_of(uri);
+
+        let (declared, start, end) = analysis::definition(file.as_deref(), buffer, offset)?;
+        let location = match declared {
+            // Stages may come from the templates of imported libraries
+            Some(library) => {
+                let uri = Url::from_file_path(&library).ok()?;
+                let buffer = match self.documents.get(&uri) {
+                    Some(buffer) => buffer.clone(),
+                    None => std::fs::read_to_string(&library).ok()?,
+                };
+                Location::new(uri, range(&buffer, start, end))
+            }
+            None => Location::new(uri.clone(), range(buffer, start, end)),
+        };
+        Some(GotoDefinitionResponse::Scalar(location))
+    }
+}
+
+fn range(buffer: &str, start: usize, end: usize) -> Range {
+    Range::new(
+        analysis::position_of(buffer, start),
+        analysis::position_of(buffer, end),
+    )
+}
+
+/**
+ * The path of the document, for finding the libraries it imports and choosing
+ * its syntax
+ */
+fn file_of(uri: &Url) -> Option<String> {
+    uri.to_file_path()
+        .ok()
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+fn parse<P: serde::de::DeserializeOwned>(
+    params: serde_json::Value,
+) -> Result<P, serde_json::Error> {
+    serde_json::from_value(params)
+}
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec!["(".to_string(), ",".to_string(), " ".to_string()]),
+            ..Default::default()
+        }),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Standard output belongs to the protocol, so logs are written to standard
+    // error, as pretty_env_logger does
+    pretty_env_logger::init();
+
+    let manifests = match std::env::var("STEPS_DIR") {
+        Ok(dir) => {
+            let manifests = load_manifests(Path::new(&dir))?;
+            info!("Loaded {} steps from {}", manifests.len(), dir);
+            manifests
+        }
+        Err(_) => {
+            warn!("STEPS_DIR is not defined, steps will not be completed or validated");
+            HashMap::new()
+        }
+    };
+
+    let (connection, threads) = Connection::stdio();
+    connection.initialize(serde_json::to_value(capabilities())?)?;
+    info!("Initialized otto-lsp");
+
+    let mut server = Server {
+        connection,
+        manifests,
+        documents: HashMap::new(),
+    };
+    server.run()?;
+    // The connection must be dropped for the threads to finish
+    drop(server);
+    threads.join()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serve_after_invalid_notification() {
+        let (connection, client) = Connection::memory();
+        let server = std::thread::spawn(move || {
+            Server {
+                connection,
+                manifests: HashMap::new(),
+                documents: HashMap::new(),
+            }
+            .run()
+            .is_ok()
+        });
+
+        let invalid = Notification::new(
+            DidOpenTextDocument::METHOD.to_string(),
+            serde_json::json!({ "textDocument": 42 }),
+        );
+        client.sender.send(invalid.into()).unwrap();
+
+        let uri = Url::parse("file:///tmp/Ottofile").unwrap();
+        let open = Notification::new(
+            DidOpenTextDocument::METHOD.to_string(),
+            DidOpenTextDocumentParams {
+                text_document: TextDocumentItem::new(
+                    uri.clone(),
+                    "otto".to_string(),
+                    1,
+                    "pipeline { steps { sh 'make' } }".to_string(),
+                ),
+            },
+        );
+        client.sender.send(open.into()).unwrap();
+
+        match client.receiver.recv().unwrap() {
+            Message::Notification(published) => {
+                assert_eq!(published.method, PublishDiagnostics::METHOD);
+                let params: PublishDiagnosticsParams = parse(published.params).unwrap();
+                assert_eq!(params.uri, uri);
+                assert!(params.diagnostics.is_empty());
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        drop(client);
+        assert!(server.join().unwrap());
+    }
+}
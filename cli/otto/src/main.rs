@@ -0,0 +1,276 @@
+/*
+ * The otto command line tool contains the commands for working with Ottofiles
+ * locally, such as formatting them or converting Jenkinsfiles into them, and
+ * for managing the local credentials
+ */
+use gumdrop::Options;
+use otto_agent::credentials::Store;
+use otto_parser::*;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+#[derive(Debug, Options)]
+struct OttoOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(command)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Options)]
+enum Command {
+    #[options(help = "format Ottofiles in the canonical style")]
+    Fmt(FmtOptions),
+    #[options(help = "convert a declarative Jenkinsfile into an Ottofile")]
+    Convert(ConvertOptions),
+    #[options(help = "manage the secrets in the credentials store")]
+    Credentials(CredentialsOptions),
+}
+
+#[derive(Debug, Options)]
+struct FmtOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(help = "check that the files are formatted without changing them")]
+    check: bool,
+    #[options(
+        free,
+        help = "the Ottofiles to format, standard input is used when none are given"
+    )]
+    files: Vec<String>,
+}
+
+#[derive(Debug, Options)]
+struct ConvertOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(
+        help = "the file to write the Ottofile to, standard output is used when not given",
+        meta = "FILE"
+    )]
+    output: Option<PathBuf>,
+    #[options(
+        free,
+        help = "the Jenkinsfile to convert, standard input is used when not given"
+    )]
+    file: Option<String>,
+}
+
+#[derive(Debug, Options)]
+struct CredentialsOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(
+        help = "the directory of the credentials store, defaults to CREDENTIALS_DIR",
+        meta = "DIR"
+    )]
+    dir: Option<PathBuf>,
+    #[options(command)]
+    command: Option<CredentialsCommand>,
+}
+
+#[derive(Debug, Options)]
+enum CredentialsCommand {
+    #[options(help = "add a secret, which is read from standard input")]
+    Add(CredentialOptions),
+    #[options(help = "remove a secret")]
+    Remove(CredentialOptions),
+    #[options(help = "list the ids of the secrets")]
+    List(ListOptions),
+}
+
+#[derive(Debug, Options)]
+struct CredentialOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(free, required, help = "the id of the secret")]
+    id: String,
+}
+
+#[derive(Debug, Options)]
+struct ListOptions {
+    #[options(help = "print help message")]
+    help: bool,
+}
+
+/**
+ * Print every diagnostic for the buffer to stderr
+ */
+fn report(name: &str, buffer: &str, diagnostics: &[Diagnostic]) {
+    eprintln!("{} could not be parsed:", name);
+    for diagnostic in diagnostics.iter() {
+        eprintln!("{}", diagnostic.render(buffer));
+    }
+}
+
+/**
+ * Format the files in place, or standard input to standard output
+ *
+ * Returns false if any of the files could not be formatted, or when checking,
+ * if any of the files are not already formatted
+ */
+fn fmt(opts: &FmtOptions) -> std::io::Result<bool> {
+    if opts.files.is_empty() {
+        let mut buffer = String::new();
+        std::io::stdin().read_to_string(&mut buffer)?;
+
+        return match format_pipeline_string(&buffer) {
+            Ok(formatted) => {
+                if opts.check {
+                    return Ok(formatted == buffer);
+                }
+                std::io::stdout().write_all(formatted.as_bytes())?;
+                Ok(true)
+            }
+            Err(diagnostics) => {
+                report("<stdin>", &buffer, &diagnostics);
+                Ok(false)
+            }
+        };
+    }
+
+    let mut success = true;
+    for file in opts.files.iter() {
+        let buffer = std::fs::read_to_string(file)?;
+
+        match format_pipeline_string(&buffer) {
+            Ok(formatted) => {
+                if formatted == buffer {
+                    continue;
+                }
+                if opts.check {
+                    println!("{} is not formatted", file);
+                    success = false;
+                } else {
+                    std::fs::write(file, formatted)?;
+                }
+            }
+            Err(diagnostics) => {
+                report(file, &buffer, &diagnostics);
+                success = false;
+            }
+        }
+    }
+    Ok(success)
+}
+
+/**
+ * Convert the Jenkinsfile, or standard input, into an Ottofile
+ *
+ * Everything which could not be converted is reported on stderr, and false is
+ * returned if the Jenkinsfile could not be converted at all
+ */
+fn convert(opts: &ConvertOptions) -> std::io::Result<bool> {
+    let (name, buffer) = match &opts.file {
+        Some(file) => (file.as_str(), std::fs::read_to_string(file)?),
+        None => {
+            let mut buffer = String::new();
+            std::io::stdin().read_to_string(&mut buffer)?;
+            ("<stdin>", buffer)
+        }
+    };
+
+    let converted = convert_jenkinsfile(&buffer);
+    if !converted.diagnostics.is_empty() {
+        eprintln!("{} could not be fully converted:", name);
+        for diagnostic in converted.diagnostics.iter() {
+            eprintln!("{}", diagnostic.render(&buffer));
+        }
+    }
+    if converted.has_errors() {
+        return Ok(false);
+    }
+
+    match &opts.output {
+        Some(output) => std::fs::write(output, &converted.ottofile)?,
+        None => std::io::stdout().write_all(converted.ottofile.as_bytes())?,
+    }
+    Ok(true)
+}
+
+/**
+ * Change the secrets in the credentials store, or list them
+ *
+ * Returns false if the command could not be carried out
+ */
+fn credentials(opts: &CredentialsOptions) -> std::io::Result<bool> {
+    let dir = match opts
+        .dir
+        .clone()
+        .or_else(|| std::env::var("CREDENTIALS_DIR").ok().map(PathBuf::from))
+    {
+        Some(dir) => dir,
+        None => {
+            eprintln!("The credentials store must be given with --dir or CREDENTIALS_DIR");
+            return Ok(false);
+        }
+    };
+    // Only adding a credential creates the store, looking at or removing the
+    // credentials of a store which does not exist writes nothing
+    let existing = Store::open_existing(&dir)?;
+
+    match &opts.command {
+        Some(CredentialsCommand::Add(opts)) => {
+            let mut secret = String::new();
+            std::io::stdin().read_to_string(&mut secret)?;
+            // The newline which ends the secret typed in is not part of it
+            if secret.ends_with('\n') {
+                secret.pop();
+            }
+            let mut store = match existing {
+                Some(store) => store,
+                None => Store::open(&dir)?,
+            };
+            store.insert(&opts.id, &secret)?;
+            store.save()?;
+        }
+        Some(CredentialsCommand::Remove(opts)) => match existing {
+            Some(mut store) if store.ids().contains(&opts.id.as_str()) => {
+                store.remove(&opts.id);
+                store.save()?;
+            }
+            _ => {
+                eprintln!("There is no credential `{}`", opts.id);
+                return Ok(false);
+            }
+        },
+        Some(CredentialsCommand::List(_)) => match existing {
+            Some(store) if !store.ids().is_empty() => {
+                for id in store.ids() {
+                    println!("{}", id);
+                }
+            }
+            _ => eprintln!("There are no credentials in {:?}", dir),
+        },
+        None => {
+            eprintln!("{}", CredentialsOptions::usage());
+            if let Some(commands) = CredentialsOptions::command_list() {
+                eprintln!("\nAvailable commands:\n{}", commands);
+            }
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn main() -> std::io::Result<()> {
+    let opts = OttoOptions::parse_args_default_or_exit();
+
+    let success = match opts.command {
+        Some(Command::Fmt(ref fmt_opts)) => fmt(fmt_opts)?,
+        Some(Command::Convert(ref convert_opts)) => convert(convert_opts)?,
+        Some(Command::Credentials(ref credentials_opts)) => credentials(credentials_opts)?,
+        None => {
+            eprintln!("{}", OttoOptions::usage());
+            if let Some(commands) = OttoOptions::command_list() {
+                eprintln!("\nAvailable commands:\n{}", commands);
+            }
+            false
+        }
+    };
+
+    if !success {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -5,6 +5,9 @@
 use async_std::channel::Sender;
 use log::*;
 use serde::{Deserialize, Serialize};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
 use uuid::Uuid;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -24,7 +27,9 @@ struct State {
 async fn handle_request(mut req: tide::Request<State>) -> tide::Result {
     let message: Request = req.body_json().await?;
     debug!("Receiving control request on agent sock: {:#?}", message);
-    req.state().sender.send(message).await;
+    if let Err(e) = req.state().sender.send(message).await {
+        error!("Failed to pass the control request to the runloop: {:?}", e);
+    }
     Ok("{}".into())
 }
 
@@ -51,6 +56,81 @@ pub async fn run(pipeline: Uuid, sender: Sender<Request>) -> tide::Result<()> {
     Ok(())
 }
 
+/**
+ * How long to wait for an agent to accept and answer a control request
+ */
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/**
+ * Ask the agent listening on the given control socket to terminate
+ *
+ * The request is written by hand rather than with an HTTP client, since the
+ * control socket is a unix socket
+ */
+pub fn terminate(sock: &Path) -> std::io::Result<()> {
+    use std::io::{Error, Read, Write};
+
+    let body = serde_json::to_string(&Request::Terminate)?;
+    let mut stream = connect(sock)?;
+    stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONTROL_TIMEOUT))?;
+    write!(
+        stream,
+        "POST /control HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    if response.starts_with("HTTP/1.1 200") {
+        Ok(())
+    } else {
+        Err(Error::other(format!(
+            "The agent refused to terminate: {}",
+            response
+        )))
+    }
+}
+
+/**
+ * Connect to the control socket, giving up if the agent has not accepted the
+ * connection in time
+ *
+ * Unix sockets have no connect timeout of their own, so the connection is made
+ * on a thread which is left behind if it never completes
+ */
+fn connect(sock: &Path) -> std::io::Result<UnixStream> {
+    use std::io::{Error, ErrorKind};
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let path = sock.to_path_buf();
+    std::thread::spawn(move || {
+        // Nobody is left to receive the stream if it took too long
+        let _ = sender.send(UnixStream::connect(path));
+    });
+
+    receiver.recv_timeout(CONTROL_TIMEOUT).unwrap_or_else(|_| {
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("Timed out connecting to {:?}", sock),
+        ))
+    })
+}
+
+/**
+ * Ask the agent with the given process id to terminate by sending it SIGTERM,
+ * for agents which may not have bound their control socket yet
+ */
+pub fn signal_terminate(pid: u32) -> std::io::Result<()> {
+    // Safety: kill(2) has no memory safety concerns, at worst it fails
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 /**
  * Return a string representing the absolute path of this agent's control socket
  */
@@ -65,6 +145,57 @@ pub fn agent_socket(pipeline: &Uuid) -> std::path::PathBuf {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_terminate() {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::os::unix::net::UnixListener;
+
+        let dir = tempfile::tempdir().expect("Failed to create a temp dir");
+        let sock = dir.path().join("agent.sock");
+        let listener = UnixListener::bind(&sock).expect("Failed to bind the socket");
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("Failed to accept");
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).expect("Failed to read");
+                if let Some(value) = line.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().expect("Invalid length");
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).expect("Failed to read body");
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}")
+                .expect("Failed to respond");
+            serde_json::from_slice::<Request>(&body).expect("Invalid request")
+        });
+
+        terminate(&sock).expect("Failed to terminate");
+        let request = server.join().expect("The server failed");
+        assert!(matches!(request, Request::Terminate));
+    }
+
+    #[test]
+    fn test_terminate_unresponsive() {
+        use std::os::unix::net::UnixListener;
+
+        let dir = tempfile::tempdir().expect("Failed to create a temp dir");
+        let sock = dir.path().join("agent.sock");
+        // The connection is accepted by the backlog, but never answered
+        let _listener = UnixListener::bind(&sock).expect("Failed to bind the socket");
+
+        let started = std::time::Instant::now();
+        assert!(terminate(&sock).is_err());
+        assert!(started.elapsed() < CONTROL_TIMEOUT * 3);
+    }
+
     #[test]
     fn test_agent_sock() {
         let uuid = uuid::Uuid::new_v4();
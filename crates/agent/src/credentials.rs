@@ -0,0 +1,336 @@
+/*
+ * The credentials module contains the store of the secrets which steps can be
+ * given, along with the masking of those secrets in the output of steps
+ *
+ * The store is a pair of files in a directory, typically PREFIX/etc/otto: the
+ * key which the secrets are encrypted with, and the encrypted secrets.
+ */
+
+use aes_gcm::aead::{generic_array::GenericArray, Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use log::*;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// The environment variable listing the names of the environment variables
+/// whose values are secrets, separated by colons
+pub const SECRETS_VAR: &str = "OTTO_SECRETS";
+/// The environment variable listing the names of the environment variables
+/// whose values are the paths of files containing secrets, separated by colons
+pub const SECRET_FILES_VAR: &str = "OTTO_SECRET_FILES";
+
+/// What secrets are replaced with in the output of steps
+const MASK: &str = "****";
+
+const KEY_FILE: &str = "credentials.key";
+const SECRETS_FILE: &str = "credentials.yml";
+
+/**
+ * A secret as it is stored, encrypted with the store's key
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Sealed {
+    /// The base64 encoded nonce the secret was encrypted with
+    nonce: String,
+    /// The base64 encoded encrypted secret
+    secret: String,
+}
+
+/**
+ * The Store holds the secrets which can be given to steps, keyed by their ids
+ */
+pub struct Store {
+    dir: PathBuf,
+    cipher: Aes256Gcm,
+    secrets: HashMap<String, Sealed>,
+}
+
+impl Store {
+    /**
+     * Open the store in the directory, creating the key for a new store if it
+     * does not already exist
+     *
+     * Nothing is written besides the key until the store is saved
+     */
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        if let Some(store) = Self::open_existing(dir)? {
+            return Ok(store);
+        }
+
+        info!("Creating a new credentials key in {:?}", dir);
+        let mut key = vec![0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        std::fs::create_dir_all(dir)?;
+        write_private(&dir.join(KEY_FILE), &key)?;
+        Self::with_key(dir, &key)
+    }
+
+    /**
+     * Open the store in the directory without writing anything, returning
+     * None if there is no store there yet
+     */
+    pub fn open_existing(dir: &Path) -> std::io::Result<Option<Self>> {
+        let key_file = dir.join(KEY_FILE);
+        if !key_file.is_file() {
+            return Ok(None);
+        }
+        Self::with_key(dir, &std::fs::read(&key_file)?).map(Some)
+    }
+
+    fn with_key(dir: &Path, key: &[u8]) -> std::io::Result<Self> {
+        if key.len() != 32 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{:?} is not a valid credentials key", dir.join(KEY_FILE)),
+            ));
+        }
+
+        let secrets_file = dir.join(SECRETS_FILE);
+        let secrets = if secrets_file.is_file() {
+            let file = std::fs::File::open(&secrets_file)?;
+            serde_yaml::from_reader(file).map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            cipher: Aes256Gcm::new(GenericArray::from_slice(key)),
+            secrets,
+        })
+    }
+
+    /**
+     * Decrypt the secret with the given id, returning None if there is none
+     */
+    pub fn get(&self, id: &str) -> std::io::Result<Option<String>> {
+        let sealed = match self.secrets.get(id) {
+            Some(sealed) => sealed,
+            None => return Ok(None),
+        };
+
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("The credential `{}` could not be decrypted", id),
+            )
+        };
+        let nonce = base64::decode(&sealed.nonce).map_err(|_| invalid())?;
+        let secret = base64::decode(&sealed.secret).map_err(|_| invalid())?;
+        if nonce.len() != 12 {
+            return Err(invalid());
+        }
+
+        let secret = self
+            .cipher
+            .decrypt(GenericArray::from_slice(&nonce), secret.as_ref())
+            .map_err(|_| invalid())?;
+        String::from_utf8(secret).map(Some).map_err(|_| invalid())
+    }
+
+    /**
+     * Encrypt the secret under the given id, replacing any existing secret
+     */
+    pub fn insert(&mut self, id: &str, secret: &str) -> std::io::Result<()> {
+        let mut nonce = [0; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let sealed = self
+            .cipher
+            .encrypt(GenericArray::from_slice(&nonce), secret.as_bytes())
+            .map_err(|_| Error::other("Failed to encrypt the credential"))?;
+        self.secrets.insert(
+            id.to_string(),
+            Sealed {
+                nonce: base64::encode(nonce),
+                secret: base64::encode(sealed),
+            },
+        );
+        Ok(())
+    }
+
+    /**
+     * Remove the secret with the given id, returning false if there was none
+     */
+    pub fn remove(&mut self, id: &str) -> bool {
+        self.secrets.remove(id).is_some()
+    }
+
+    /**
+     * The ids of every secret in the store, in order
+     */
+    pub fn ids(&self) -> Vec<&str> {
+        let mut ids: Vec<&str> = self.secrets.keys().map(|id| id.as_str()).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /**
+     * Write the encrypted secrets out to the store's directory
+     */
+    pub fn save(&self) -> std::io::Result<()> {
+        let yaml = serde_yaml::to_string(&self.secrets)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        write_private(&self.dir.join(SECRETS_FILE), yaml.as_bytes())
+    }
+}
+
+/**
+ * Write the file so that only its owner can read it
+ */
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+
+/**
+ * Mark the environment variable as holding a secret, or the path of a file
+ * holding a secret, so that agents will mask the secret in the output of any
+ * steps they run
+ *
+ * The variable must be exported into this process for its value to be found
+ */
+pub fn mark_secret(variable: &str, file: bool) {
+    let list = if file { SECRET_FILES_VAR } else { SECRETS_VAR };
+    let names = match std::env::var(list) {
+        Ok(names) if !names.is_empty() => format!("{}:{}", names, variable),
+        _ => variable.to_string(),
+    };
+    std::env::set_var(list, names);
+}
+
+/**
+ * Find the secrets which have been marked in this process's environment
+ *
+ * Secrets which span lines, such as keys in files, are masked line by line
+ * since the output of steps is masked a line at a time
+ */
+pub fn secrets_from_env() -> Vec<String> {
+    let names = |list: &str| -> Vec<String> {
+        std::env::var(list)
+            .unwrap_or_default()
+            .split(':')
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_string())
+            .collect()
+    };
+
+    let mut values: Vec<String> = names(SECRETS_VAR)
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .collect();
+    values.extend(names(SECRET_FILES_VAR).iter().filter_map(|name| {
+        std::env::var(name)
+            .ok()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+    }));
+
+    let mut secrets: Vec<String> = values
+        .iter()
+        .flat_map(|value| value.lines())
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect();
+    // Longer secrets are masked first, in case they contain shorter ones
+    secrets.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    secrets.dedup();
+    secrets
+}
+
+/**
+ * Replace every occurrence of the secrets in the buffer
+ */
+pub fn mask(buffer: &str, secrets: &[String]) -> String {
+    let mut masked = buffer.to_string();
+    for secret in secrets.iter() {
+        if masked.contains(secret.as_str()) {
+            masked = masked.replace(secret.as_str(), MASK);
+        }
+    }
+    masked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_round_trip() {
+        let dir = tempfile::tempdir().expect("Failed to create a temporary directory");
+        let mut store = Store::open(dir.path()).expect("Failed to open the store");
+        store.insert("github", "hunter2").expect("Failed to insert");
+        store.save().expect("Failed to save");
+
+        let raw = std::fs::read_to_string(dir.path().join(SECRETS_FILE)).unwrap();
+        assert!(!raw.contains("hunter2"));
+
+        let store = Store::open(dir.path()).expect("Failed to reopen the store");
+        assert_eq!(store.ids(), vec!["github"]);
+        assert_eq!(store.get("github").unwrap(), Some("hunter2".to_string()));
+        assert_eq!(store.get("gitlab").unwrap(), None);
+    }
+
+    #[test]
+    fn store_with_another_key() {
+        let dir = tempfile::tempdir().expect("Failed to create a temporary directory");
+        let mut store = Store::open(dir.path()).expect("Failed to open the store");
+        store.insert("github", "hunter2").unwrap();
+        store.save().unwrap();
+
+        std::fs::remove_file(dir.path().join(KEY_FILE)).unwrap();
+        let store = Store::open(dir.path()).expect("Failed to reopen the store");
+        assert!(store.get("github").is_err());
+    }
+
+    #[test]
+    fn store_open_existing() {
+        let dir = tempfile::tempdir().expect("Failed to create a temporary directory");
+        assert!(Store::open_existing(dir.path()).unwrap().is_none());
+        assert!(!dir.path().join(KEY_FILE).exists());
+
+        let mut store = Store::open(dir.path()).expect("Failed to open the store");
+        store.insert("github", "hunter2").unwrap();
+        store.save().unwrap();
+
+        let store = Store::open_existing(dir.path())
+            .unwrap()
+            .expect("Missing the store");
+        assert_eq!(store.get("github").unwrap(), Some("hunter2".to_string()));
+    }
+
+    #[test]
+    fn secrets_from_marked_variables() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"-----BEGIN KEY-----\nc2VjcmV0\n").unwrap();
+        std::env::set_var("OTTO_TEST_SECRET", "hunter2");
+        std::env::set_var("OTTO_TEST_SECRET_FILE", file.path());
+        mark_secret("OTTO_TEST_SECRET", false);
+        mark_secret("OTTO_TEST_SECRET_FILE", true);
+
+        let secrets = secrets_from_env();
+        for secret in ["hunter2", "-----BEGIN KEY-----", "c2VjcmV0"].iter() {
+            assert!(secrets.contains(&secret.to_string()));
+        }
+    }
+
+    #[test]
+    fn mask_secrets() {
+        let secrets = vec!["hunter22".to_string(), "hunter2".to_string()];
+        assert_eq!(
+            mask("login hunter22 then hunter2", &secrets),
+            "login **** then ****"
+        );
+        assert_eq!(mask("nothing to see", &secrets), "nothing to see");
+    }
+}
@@ -0,0 +1,265 @@
+/*
+ * The interpolate module resolves the references in the arguments of steps,
+ * e.g. `"cargo build --target ${env.TARGET}"`, just before the step runs
+ */
+
+use crate::step::Configuration;
+use otto_models::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/**
+ * The values of the context, and of the pipeline run, which references can
+ * resolve to besides the environment and configuration
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Values {
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+    #[serde(default)]
+    pub parameters: HashMap<String, String>,
+}
+
+/**
+ * The values which the arguments of a step can refer to
+ */
+pub struct Scope<'a> {
+    pub environment: Option<&'a HashMap<String, String>>,
+    pub values: Option<&'a Values>,
+    pub configuration: &'a Configuration,
+}
+
+impl Scope<'_> {
+    /**
+     * Look up the value of the reference, returning None if it is not defined
+     *
+     * Environment variables which the context does not declare fall back to
+     * the agent's own environment, which the step will inherit
+     */
+    pub fn lookup(&self, reference: &Reference) -> Option<String> {
+        match reference {
+            Reference::Env(name) => self
+                .environment
+                .and_then(|env| env.get(name).cloned())
+                .or_else(|| std::env::var(name).ok()),
+            Reference::Property(name) => self.values.and_then(|v| v.properties.get(name).cloned()),
+            Reference::Parameter(name) => self.values.and_then(|v| v.parameters.get(name).cloned()),
+            Reference::Config(field) => match field.as_str() {
+                "pipeline" => Some(self.configuration.pipeline.to_string()),
+                "uuid" => Some(self.configuration.uuid.to_string()),
+                "cache" => self
+                    .configuration
+                    .cache
+                    .as_ref()
+                    .map(|cache| cache.to_string_lossy().to_string()),
+                "ipc" => Some(self.configuration.ipc.to_string_lossy().to_string()),
+                _ => None,
+            },
+        }
+    }
+}
+
+/**
+ * Resolve every interpolation in the value, including those nested in lists
+ * and maps, returning the first reference which is not defined on failure
+ */
+pub fn resolve(value: &Value, scope: &Scope) -> Result<Value, Reference> {
+    if let Some(interpolation) = Interpolation::from_value(value) {
+        let mut resolved = String::new();
+        for segment in interpolation.segments.iter() {
+            match segment {
+                Segment::Literal(text) => resolved.push_str(text),
+                Segment::Reference(reference) => match scope.lookup(reference) {
+                    Some(value) => resolved.push_str(&value),
+                    None => return Err(reference.clone()),
+                },
+            }
+        }
+        return Ok(Value::String(resolved));
+    }
+
+    match value {
+        Value::Array(values) => Ok(Value::Array(
+            values
+                .iter()
+                .map(|v| resolve(v, scope))
+                .collect::<Result<_, _>>()?,
+        )),
+        Value::Object(map) => {
+            let mut resolved = serde_json::Map::new();
+            // The keys were escaped so that the map could not be mistaken for
+            // an interpolation, which it no longer can be once resolved
+            for (key, value) in map.iter() {
+                resolved.insert(unescape_key(key).to_string(), resolve(value, scope)?);
+            }
+            Ok(Value::Object(resolved))
+        }
+        other => Ok(other.clone()),
+    }
+}
+
+/**
+ * Resolve the arguments of the step
+ *
+ * The steps nested in its block are left as they are, since the agent which
+ * runs them resolves them just before they run. That way a step such as
+ * `withCredentials` can provide values to the steps in its block, and their
+ * arguments are never resolved before those values exist
+ */
+pub fn resolve_step(step: &Step, scope: &Scope) -> Result<Step, Reference> {
+    let mut resolved = step.clone();
+
+    resolved.parameters = match &step.parameters {
+        StepParameters::Positional(args) => StepParameters::Positional(
+            args.iter()
+                .map(|arg| resolve(arg, scope))
+                .collect::<Result<_, _>>()?,
+        ),
+        StepParameters::Keyword(kwargs) => {
+            let mut resolved = HashMap::new();
+            for (key, value) in kwargs.iter() {
+                resolved.insert(key.clone(), resolve(value, scope)?);
+            }
+            StepParameters::Keyword(resolved)
+        }
+    };
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn configuration() -> Configuration {
+        Configuration {
+            pipeline: generate_uuid(),
+            uuid: generate_uuid(),
+            cache: None,
+            ipc: PathBuf::from("/tmp/agent.sock"),
+            endpoints: HashMap::new(),
+        }
+    }
+
+    fn interpolate(segments: Vec<Segment>) -> Value {
+        Interpolation { segments }.to_value()
+    }
+
+    fn env(name: &str) -> Segment {
+        Segment::Reference(Reference::Env(name.to_string()))
+    }
+
+    #[test]
+    fn resolve_references() {
+        let configuration = configuration();
+        let mut environment = HashMap::new();
+        environment.insert("TARGET".to_string(), "wasm32".to_string());
+        let mut values = Values::default();
+        values
+            .properties
+            .insert("name".to_string(), "Build".to_string());
+        values
+            .parameters
+            .insert("VERSION".to_string(), "1.0".to_string());
+        let scope = Scope {
+            environment: Some(&environment),
+            values: Some(&values),
+            configuration: &configuration,
+        };
+
+        let value = interpolate(vec![
+            Segment::Literal("--target ".to_string()),
+            env("TARGET"),
+            Segment::Literal(" ".to_string()),
+            Segment::Reference(Reference::Property("name".to_string())),
+            Segment::Literal(" ".to_string()),
+            Segment::Reference(Reference::Parameter("VERSION".to_string())),
+            Segment::Literal(" ".to_string()),
+            Segment::Reference(Reference::Config("pipeline".to_string())),
+        ]);
+        assert_eq!(
+            resolve(&value, &scope).expect("Failed to resolve"),
+            Value::String(format!(
+                "--target wasm32 Build 1.0 {}",
+                configuration.pipeline
+            ))
+        );
+
+        // Interpolations nested in lists are resolved too
+        let list = Value::Array(vec![value, Value::Bool(true)]);
+        assert!(matches!(
+            resolve(&list, &scope).expect("Failed to resolve"),
+            Value::Array(values) if values[0].is_string()
+        ));
+    }
+
+    #[test]
+    fn resolve_escaped_keys() {
+        let configuration = configuration();
+        let scope = Scope {
+            environment: None,
+            values: None,
+            configuration: &configuration,
+        };
+
+        let value = serde_json::json!({ "$$interpolate": [], "name": "a" });
+        assert_eq!(
+            resolve(&value, &scope).expect("Failed to resolve"),
+            serde_json::json!({ "$interpolate": [], "name": "a" })
+        );
+    }
+
+    #[test]
+    fn resolve_undefined() {
+        let configuration = configuration();
+        let scope = Scope {
+            environment: None,
+            values: None,
+            configuration: &configuration,
+        };
+
+        let value = interpolate(vec![env("OTTO_SURELY_UNDEFINED")]);
+        assert_eq!(
+            resolve(&value, &scope),
+            Err(Reference::Env("OTTO_SURELY_UNDEFINED".to_string()))
+        );
+
+        let value = interpolate(vec![Segment::Reference(Reference::Config(
+            "cache".to_string(),
+        ))]);
+        assert!(resolve(&value, &scope).is_err());
+    }
+
+    #[test]
+    fn resolve_nested_steps() {
+        let configuration = configuration();
+        let scope = Scope {
+            environment: None,
+            values: None,
+            configuration: &configuration,
+        };
+
+        let inner = Step::new(
+            generate_uuid(),
+            "echo".to_string(),
+            StepParameters::Positional(vec![interpolate(vec![Segment::Reference(
+                Reference::Config("uuid".to_string()),
+            )])]),
+        );
+        let mut step = Step::new(
+            inner.context,
+            "dir".to_string(),
+            StepParameters::Positional(vec![Value::String("src".to_string())]),
+        );
+        step.block = Some(vec![inner]);
+
+        // The nested steps are resolved by the agent which runs them
+        let resolved = resolve_step(&step, &scope).expect("Failed to resolve");
+        match &resolved.block.unwrap()[0].parameters {
+            StepParameters::Positional(args) => {
+                assert!(Interpolation::from_value(&args[0]).is_some());
+            }
+            _ => panic!("Expected positional arguments"),
+        }
+    }
+}
@@ -5,12 +5,18 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::File;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tempfile::NamedTempFile;
 use uuid::Uuid;
 
 pub mod control;
+pub mod credentials;
+pub mod interpolate;
 pub mod step;
+pub mod when;
 
 /**
  * The format of the invocation file for the agent
@@ -19,6 +25,26 @@ pub mod step;
 pub struct Invocation {
     pub pipeline: Uuid,
     pub steps: Vec<otto_models::Step>,
+    /// Environment variables to export into every step's process
+    #[serde(default)]
+    pub environment: Option<HashMap<String, String>>,
+    /// Properties of the context which the arguments of steps can refer to
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+    /// Values of the pipeline's parameters which the arguments of steps can
+    /// refer to
+    #[serde(default)]
+    pub parameters: HashMap<String, String>,
+    /// Conditions which must all be met for the steps to run
+    #[serde(default)]
+    pub when: Vec<Condition>,
+    /// Steps to run once the steps have finished, regardless of how
+    #[serde(default)]
+    pub post: Option<Post>,
+    /// The context being run, the agent's control socket is named after it
+    /// when given so that the agents for sibling contexts can be told apart
+    #[serde(default)]
+    pub context: Option<Uuid>,
 }
 
 /**
@@ -29,6 +55,9 @@ pub enum Log {
     StepStart {
         symbol: String,
         uuid: Uuid,
+        /// Where in the pipeline's source the step was declared
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<SourceLocation>,
     },
     StepOutput {
         symbol: String,
@@ -39,6 +68,8 @@ pub enum Log {
     StepEnd {
         symbol: String,
         uuid: Uuid,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<SourceLocation>,
     },
 }
 
@@ -92,11 +123,45 @@ fn load_manifests_for_symbols(
     Ok(manifests)
 }
 
+/**
+ * Load the manifests for the steps, including any steps nested in their blocks
+ */
 fn load_manifests_for(
     steps_dir: &str,
     steps: &Vec<Step>,
 ) -> std::io::Result<HashMap<String, LoadedManifest>> {
-    load_manifests_for_symbols(steps_dir, steps.iter().map(|s| s.symbol.clone()).collect())
+    fn collect_symbols(steps: &[Step], symbols: &mut Vec<String>) {
+        for step in steps.iter() {
+            if !symbols.contains(&step.symbol) {
+                symbols.push(step.symbol.clone());
+            }
+            if let Some(block) = &step.block {
+                collect_symbols(block, symbols);
+            }
+        }
+    }
+
+    let mut symbols = vec![];
+    collect_symbols(steps, &mut symbols);
+    load_manifests_for_symbols(steps_dir, symbols)
+}
+
+/**
+ * Validate the steps against their manifests, logging every problem found
+ *
+ * Returns false if the steps should not be executed
+ */
+fn validate(steps: &[Step], manifests: &HashMap<String, LoadedManifest>) -> bool {
+    let manifests: HashMap<String, osp::Manifest> = manifests
+        .iter()
+        .map(|(symbol, loaded)| (symbol.clone(), loaded.manifest.clone()))
+        .collect();
+
+    let problems = osp::validate_steps(steps, &manifests);
+    for problem in problems.iter() {
+        error!("{}", problem);
+    }
+    problems.is_empty()
 }
 
 /**
@@ -136,19 +201,337 @@ fn positional_to_keyword(args: &StepParameters, manifest: &osp::Manifest) -> Ste
     }
 }
 
+/**
+ * Convert the step's parameters into keyword parameters for invoking it
+ *
+ * If the step has a block of nested steps, they will be passed under the
+ * parameter which the manifest declares with the block type
+ */
+fn parameters_for(step: &Step, manifest: &osp::Manifest) -> StepParameters {
+    let mut parameters = positional_to_keyword(&step.parameters, manifest);
+
+    if let Some(block) = &step.block {
+        let block_param = manifest
+            .parameters
+            .iter()
+            .find(|p| matches!(p.p_type, osp::ParameterType::BlockParameter));
+
+        match (block_param, &mut parameters) {
+            (Some(param), StepParameters::Keyword(kwargs)) => {
+                kwargs.insert(
+                    param.name.clone(),
+                    serde_json::to_value(block).expect("Failed to serialize the block"),
+                );
+            }
+            _ => {
+                error!(
+                    "The step does not accept a block, discarding nested steps ({})",
+                    manifest.symbol
+                );
+            }
+        }
+    }
+    parameters
+}
+
+/**
+ * Determine whether the given manifest takes a block of nested steps
+ */
+fn has_block_parameter(manifest: &osp::Manifest) -> bool {
+    manifest
+        .parameters
+        .iter()
+        .any(|p| matches!(p.p_type, osp::ParameterType::BlockParameter))
+}
+
+/**
+ * The reasons for which a running step is killed
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Killed {
+    /// The agent was asked to terminate
+    Terminated,
+    /// The step was still running at the deadline
+    TimedOut,
+}
+
+/**
+ * Watch the control messages while a step is running, killing the step if
+ * the agent is asked to terminate or if it is still running at the deadline
+ *
+ * Returns the reason the step was killed, if it was
+ */
+fn watch_step(
+    controller: Option<Receiver<control::Request>>,
+    deadline: Option<Instant>,
+    handle: Arc<Mutex<Child>>,
+    finished: Arc<AtomicBool>,
+) -> Option<Killed> {
+    use async_std::future::timeout;
+
+    while !finished.load(Ordering::SeqCst) {
+        // The signal has already been forwarded to the step, but it may well
+        // have been ignored
+        if SIGNALLED.swap(false, Ordering::SeqCst) {
+            info!("Agent has received a signal to terminate, killing the running step");
+            kill_step(&handle.lock().expect("Failed to lock the step"));
+            return Some(Killed::Terminated);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            info!("The step is still running at its deadline, killing it");
+            kill_step(&handle.lock().expect("Failed to lock the step"));
+            return Some(Killed::TimedOut);
+        }
+
+        let received = match &controller {
+            Some(controller) => {
+                async_std::task::block_on(timeout(Duration::from_millis(100), controller.recv()))
+                    .ok()
+                    .and_then(Result::ok)
+            }
+            None => {
+                std::thread::sleep(Duration::from_millis(100));
+                None
+            }
+        };
+
+        if let Some(msg) = received {
+            debug!(
+                "Processing control message while a step is running: {:#?}",
+                msg
+            );
+            match msg {
+                control::Request::Terminate => {
+                    info!("Agent has been asked to terminate, killing the running step");
+                    kill_step(&handle.lock().expect("Failed to lock the step"));
+                    return Some(Killed::Terminated);
+                }
+            }
+        }
+    }
+    None
+}
+
+/**
+ * Kill the step along with every process it has spawned
+ *
+ * The steps of the top-level agent each have a process group of their own
+ * which can be killed as a whole, whereas the steps of nested agents share the
+ * group of the step which started them, so only their own processes are killed
+ */
+fn kill_step(child: &Child) {
+    if is_nested() {
+        kill_tree(child.id() as libc::pid_t);
+        return;
+    }
+
+    let pgid = child.id() as libc::pid_t;
+    // Safety: kill(2) has no memory safety concerns, at worst it fails
+    if unsafe { libc::kill(-pgid, libc::SIGKILL) } != 0 {
+        error!(
+            "Failed to kill the step: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/**
+ * Kill the process along with every process descended from it
+ *
+ * Each process is stopped before its children are looked up, so that it
+ * cannot spawn any more while they are being killed
+ */
+fn kill_tree(pid: libc::pid_t) {
+    // Safety: kill(2) has no memory safety concerns, at worst it fails
+    unsafe { libc::kill(pid, libc::SIGSTOP) };
+
+    match Command::new("pgrep")
+        .arg("-P")
+        .arg(pid.to_string())
+        .output()
+    {
+        Ok(output) => {
+            for child in String::from_utf8_lossy(&output.stdout)
+                .split_whitespace()
+                .filter_map(|child| child.parse().ok())
+            {
+                kill_tree(child);
+            }
+        }
+        Err(e) => error!("Failed to find the processes spawned by {}: {}", pid, e),
+    }
+
+    if unsafe { libc::kill(pid, libc::SIGKILL) } != 0 {
+        error!(
+            "Failed to kill the process {}: {}",
+            pid,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/**
+ * The process group of the step the top-level agent is running, if any, to
+ * which the signals the agent receives are forwarded
+ */
+static STEP_GROUP: AtomicI32 = AtomicI32::new(0);
+
+/**
+ * Set when the agent receives a signal to terminate, until the runloop has
+ * aborted the steps because of it
+ */
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    SIGNALLED.store(true, Ordering::SeqCst);
+    let pgid = STEP_GROUP.load(Ordering::SeqCst);
+    if pgid > 0 {
+        // Safety: kill(2) is async-signal-safe
+        unsafe { libc::kill(-pgid, signal) };
+    }
+}
+
+/**
+ * Forward SIGTERM and SIGINT to the process group of the running step,
+ * aborting the steps rather than leaving them running once the agent exits
+ *
+ * Only the top-level agent should forward signals, the agents for nested
+ * steps are in the process group of the step which started them, so they
+ * receive the forwarded signals along with their own steps
+ */
+pub fn forward_signals() {
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        // Safety: the handler only touches atomics and calls kill(2), both of
+        // which are async-signal-safe
+        unsafe {
+            libc::signal(
+                signal,
+                forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            )
+        };
+    }
+}
+
+/**
+ * Determine whether this is an agent for the nested steps of a step, rather
+ * than the top-level agent, by whether it was given a control socket
+ */
+fn is_nested() -> bool {
+    std::env::var_os("AGENT_SOCKET").is_some()
+}
+
+/**
+ * Wait for the step to exit without holding onto the lock, so that the step
+ * can still be killed while waiting
+ */
+fn wait_for(handle: &Mutex<Child>) -> std::io::Result<ExitStatus> {
+    loop {
+        if let Some(status) = handle.lock().expect("Failed to lock the step").try_wait()? {
+            return Ok(status);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/**
+ * The options for running steps with `run()`, every one of which is optional
+ */
+#[derive(Default)]
+pub struct RunOptions<'a> {
+    /// The post steps to run once the steps have finished
+    pub post: Option<&'a Post>,
+    /// Environment variables to export into every step's process
+    pub environment: Option<&'a HashMap<String, String>>,
+    /// The values which the arguments of steps can refer to
+    pub values: Option<&'a interpolate::Values>,
+    /// The time by which the steps must have finished
+    pub deadline: Option<Instant>,
+    /// The control messages sent to the agent
+    pub controller: Option<Receiver<control::Request>>,
+    /// The control socket the agent is listening on, which is given to the
+    /// steps. Agents for nested steps use the socket of the agent which started
+    /// them instead
+    pub socket: Option<PathBuf>,
+}
+
 /**
  * The run method is the "core" of the agent which will run a series of steps
  * passed in.
  *
+ * Once the steps have finished, the post steps for the status they finished
+ * with are run, even if the steps failed or the agent was asked to terminate.
+ *
+ * Steps still running at the deadline are killed, and the steps are aborted.
+ * The post steps are not held to the deadline, since they are typically
+ * needed all the more when the steps have run out of time.
+ *
  * Currently it is very simple and primitive
  */
 pub fn run(
     steps_dir: &str,
     steps: &Vec<Step>,
     pipeline: Uuid,
-    controller: Option<Receiver<control::Request>>,
+    options: RunOptions,
 ) -> std::io::Result<Status> {
-    let manifests = load_manifests_for(steps_dir, steps)?;
+    let post = options.post;
+    let mut all_steps = steps.to_vec();
+    if let Some(post) = post {
+        all_steps.extend(post.steps().cloned());
+    }
+    let manifests = load_manifests_for(steps_dir, &all_steps)?;
+
+    if !validate(&all_steps, &manifests) {
+        info!("The steps are not valid, refusing to execute them");
+        return Ok(Status::Failed);
+    }
+
+    let status = run_steps(steps, &manifests, pipeline, &options, options.deadline)?;
+
+    let post_steps = post.map(|post| post.steps_for(&status)).unwrap_or_default();
+    if post_steps.is_empty() {
+        return Ok(status);
+    }
+
+    info!("Running the post steps for {:?}", status);
+    let post_status = run_steps(&post_steps, &manifests, pipeline, &options, None)?;
+
+    // Failing post steps fail an otherwise successful run, but never hide
+    // the status the steps themselves finished with
+    if post_status != Status::Successful && matches!(status, Status::Successful | Status::Unstable)
+    {
+        return Ok(post_status);
+    }
+    Ok(status)
+}
+
+/**
+ * Run the steps in order, stopping at the first step which fails
+ *
+ * Steps which exit as unstable mark the steps as unstable, but the remaining
+ * steps are still run
+ */
+fn run_steps(
+    steps: &[Step],
+    manifests: &HashMap<String, LoadedManifest>,
+    pipeline: Uuid,
+    options: &RunOptions,
+    deadline: Option<Instant>,
+) -> std::io::Result<Status> {
+    let environment = options.environment;
+    let values = options.values;
+    let controller = options.controller.as_ref();
+    let nested = is_nested();
+    // Agents for nested steps use the control socket of the agent which
+    // started them, which every step is given
+    let sock = std::env::var("AGENT_SOCKET")
+        .map(PathBuf::from)
+        .ok()
+        .or_else(|| options.socket.clone())
+        .unwrap_or_else(|| control::agent_socket(&pipeline));
+
+    let mut result = Status::Successful;
+    // Secrets given to the steps by a `withCredentials` step around them
+    let secrets = credentials::secrets_from_env();
 
     // XXX: hacks
     let mut endpoints = HashMap::new();
@@ -156,7 +539,7 @@ pub fn run(
 
     // Now that things are valid and collected, let's executed
     for step in steps.iter() {
-        if let Some(ref ctl) = controller {
+        if let Some(ctl) = controller {
             while !ctl.is_empty() {
                 if let Ok(msg) = ctl.try_recv() {
                     debug!("Processing control message in runloop: {:#?}", msg);
@@ -165,16 +548,37 @@ pub fn run(
                             // TODO: this needs to halt the entire pipeline, not just what is
                             // executing on this agent
                             info!("Runloop has been asked to terminate, exiting");
-                            return Ok(Status::Failed);
+                            return Ok(Status::Aborted);
                         }
                     }
                 }
             }
         }
+        if SIGNALLED.swap(false, Ordering::SeqCst) {
+            info!("Agent has received a signal to terminate, exiting the runloop");
+            return Ok(Status::Aborted);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            error!(
+                "The deadline has passed before step `{}` could start, exiting the runloop",
+                step.symbol
+            );
+            return Ok(Status::Aborted);
+        }
         if let Some(runner) = manifests.get(&step.symbol) {
             let entrypoint = runner.path.join(&runner.manifest.entrypoint.path);
 
-            let mut file = NamedTempFile::new()?;
+            // Every step has a scratch directory of its own, which is removed
+            // once the step has exited even if it was killed. Agents for nested
+            // steps make theirs within the directory of the step which started
+            // them, so nothing is left behind when that step is killed
+            let scratch = match std::env::var_os("SCRATCH_DIR") {
+                Some(dir) => tempfile::tempdir_in(dir)?,
+                None => tempfile::tempdir()?,
+            };
+            // The invocation holds the environment, which may have secrets in
+            // it, so it is kept in the scratch directory to be removed with it
+            let mut file = NamedTempFile::new_in(scratch.path())?;
 
             let cache = match runner.manifest.cache {
                 true => {
@@ -187,18 +591,41 @@ pub fn run(
                 false => None,
             };
 
-            // TODO: This is going to be wrong on nested steps
-            let sock = control::agent_socket(&pipeline);
             let configuration = step::Configuration {
-                pipeline: pipeline,
+                pipeline,
                 uuid: step.uuid,
-                cache: cache,
-                ipc: sock,
+                cache,
+                ipc: sock.clone(),
                 endpoints: endpoints.clone(),
             };
+
+            let scope = interpolate::Scope {
+                environment,
+                values,
+                configuration: &configuration,
+            };
+            let resolved = match interpolate::resolve_step(step, &scope) {
+                Ok(resolved) => resolved,
+                Err(reference) => {
+                    match &step.source {
+                        Some(source) => error!(
+                            "Step `{}` at {} refers to `{}` which is not defined",
+                            step.symbol, source, reference
+                        ),
+                        None => error!(
+                            "Step `{}` refers to `{}` which is not defined",
+                            step.symbol, reference
+                        ),
+                    }
+                    return Ok(Status::Failed);
+                }
+            };
+
             let invocation: step::Invocation<StepParameters> = step::Invocation {
                 configuration,
-                parameters: positional_to_keyword(&step.parameters, &runner.manifest),
+                parameters: parameters_for(&resolved, &runner.manifest),
+                values: values.cloned().unwrap_or_default(),
+                environment: environment.cloned().unwrap_or_default(),
             };
 
             serde_json::to_writer(&mut file, &invocation)
@@ -206,8 +633,21 @@ pub fn run(
 
             use os_pipe::pipe;
             use std::io::{BufRead, BufReader};
+            use std::os::unix::process::CommandExt;
             let mut cmd = Command::new(entrypoint);
             cmd.arg(file.path());
+            // The top-level agent runs every step in a process group of its own
+            // so that it can be terminated along with any processes it has
+            // spawned, including the steps of nested agents, which stay in the
+            // group of the step which started them
+            if !nested {
+                cmd.process_group(0);
+            }
+            cmd.env("AGENT_SOCKET", &sock);
+            cmd.env("SCRATCH_DIR", scratch.path());
+            if let Some(environment) = environment {
+                cmd.envs(environment);
+            }
             let (reader, writer) = pipe().unwrap();
             let writer_clone = writer.try_clone().unwrap();
             cmd.stdout(writer);
@@ -216,41 +656,97 @@ pub fn run(
             let log = Log::StepStart {
                 symbol: step.symbol.clone(),
                 uuid: step.uuid,
+                source: step.source.clone(),
             };
             println!("{:?}", log);
 
-            let mut handle = cmd.spawn()?;
+            let handle = Arc::new(Mutex::new(cmd.spawn()?));
             drop(cmd);
+            if !nested {
+                let pgid = handle.lock().expect("Failed to lock the step").id();
+                STEP_GROUP.store(pgid as i32, Ordering::SeqCst);
+            }
+
+            let finished = Arc::new(AtomicBool::new(false));
+            let watcher = if controller.is_some() || deadline.is_some() || !nested {
+                let controller = controller.cloned();
+                let handle = handle.clone();
+                let finished = finished.clone();
+                Some(std::thread::spawn(move || {
+                    watch_step(controller, deadline, handle, finished)
+                }))
+            } else {
+                None
+            };
+
+            // Steps with blocks run their nested steps through an agent of their
+            // own, which will already have formatted the log lines
+            let passthrough = has_block_parameter(&runner.manifest);
 
             let bufr = BufReader::new(reader);
-            for line in bufr.lines() {
-                if let Ok(buffer) = line {
-                    if "dir" == step.symbol {
-                        println!("{}", buffer);
-                    } else {
-                        let log = Log::StepOutput {
-                            // TODO: Remove this allocation
-                            symbol: step.symbol.clone(),
-                            uuid: step.uuid,
-                            stream: LogStream::Stdout,
-                            buffer,
-                        };
-                        // TODO: send this to a log service
-                        println!("{:?}", log);
-                    }
+            for buffer in bufr.lines().map_while(Result::ok) {
+                let buffer = credentials::mask(&buffer, &secrets);
+                if passthrough {
+                    println!("{}", buffer);
+                } else {
+                    let log = Log::StepOutput {
+                        // TODO: Remove this allocation
+                        symbol: step.symbol.clone(),
+                        uuid: step.uuid,
+                        stream: LogStream::Stdout,
+                        buffer,
+                    };
+                    // TODO: send this to a log service
+                    println!("{:?}", log);
                 }
             }
 
-            let status = handle.wait()?;
+            let status = wait_for(&handle)?;
+            STEP_GROUP.store(0, Ordering::SeqCst);
+            finished.store(true, Ordering::SeqCst);
+            let killed = watcher.and_then(|watcher| watcher.join().unwrap_or(None));
 
             let log = Log::StepEnd {
                 symbol: step.symbol.clone(),
                 uuid: step.uuid,
+                source: step.source.clone(),
             };
 
             println!("{:?}", log);
 
+            match killed {
+                Some(Killed::Terminated) => {
+                    info!("Step was terminated, exiting the runloop");
+                    return Ok(Status::Aborted);
+                }
+                Some(Killed::TimedOut) => {
+                    let log = Log::StepOutput {
+                        symbol: step.symbol.clone(),
+                        uuid: step.uuid,
+                        buffer: "Killed the step, it was still running at its deadline".to_string(),
+                        stream: LogStream::Stderr,
+                    };
+                    println!("{:?}", log);
+                    match &step.source {
+                        Some(source) => error!("Step `{}` timed out at {}", step.symbol, source),
+                        None => error!("Step `{}` timed out", step.symbol),
+                    }
+                    return Ok(Status::Aborted);
+                }
+                None => {}
+            }
+
+            if status.code() == Some(Status::Unstable as i32) {
+                info!("Step `{}` was unstable, continuing", step.symbol);
+                result = Status::Unstable;
+                continue;
+            }
+
             if !status.success() {
+                match &step.source {
+                    Some(source) => error!("Step `{}` failed at {}", step.symbol, source),
+                    None => error!("Step `{}` failed", step.symbol),
+                }
                 info!("Step was not successful, exiting the runloop");
                 // TODO: this needs to halt the entire pipeline, not just what is executing on this
                 // agent
@@ -259,7 +755,83 @@ pub fn run(
         }
     }
 
-    Ok(Status::Successful)
+    Ok(result)
+}
+
+/**
+ * The longest to wait between attempts, however many have failed
+ */
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/**
+ * The backoff to wait after the next failed attempt
+ */
+fn next_backoff(backoff: Duration) -> Duration {
+    backoff
+        .checked_mul(2)
+        .map_or(MAX_BACKOFF, |doubled| doubled.min(MAX_BACKOFF))
+}
+
+/**
+ * Make attempts until one does not fail, up to the given number of attempts,
+ * waiting for the backoff between them which doubles after each failure, up
+ * to a minute
+ *
+ * Every attempt is logged as output of the step making them. Attempts which
+ * are aborted are not retried, since the agent has either been asked to
+ * terminate or the attempt ran out of time.
+ */
+pub fn retry<F>(
+    symbol: &str,
+    uuid: Uuid,
+    attempts: u32,
+    backoff: Duration,
+    mut attempt: F,
+) -> std::io::Result<Status>
+where
+    F: FnMut() -> std::io::Result<Status>,
+{
+    let log = |buffer: String, stream: LogStream| {
+        let log = Log::StepOutput {
+            symbol: symbol.to_string(),
+            uuid,
+            buffer,
+            stream,
+        };
+        println!("{:?}", log);
+    };
+
+    let attempts = attempts.max(1);
+    let mut backoff = backoff.min(MAX_BACKOFF);
+    for number in 1..=attempts {
+        log(
+            format!("Attempt {} of {}", number, attempts),
+            LogStream::Stdout,
+        );
+        let status = attempt()?;
+        if status != Status::Failed {
+            return Ok(status);
+        }
+
+        if number < attempts {
+            log(
+                format!(
+                    "Attempt {} of {} failed, retrying in {}s",
+                    number,
+                    attempts,
+                    backoff.as_secs_f32()
+                ),
+                LogStream::Stderr,
+            );
+            std::thread::sleep(backoff);
+            backoff = next_backoff(backoff);
+        }
+    }
+    log(
+        format!("All {} attempts failed", attempts),
+        LogStream::Stderr,
+    );
+    Ok(Status::Failed)
 }
 
 #[cfg(test)]
@@ -286,12 +858,200 @@ mod tests {
             uuid: otto_models::generate_uuid(),
             context: otto_models::generate_uuid(),
             parameters: StepParameters::Positional(vec![params]),
+            block: None,
+            source: None,
         };
         let manifests =
             load_manifests_for("../../stdlib", &vec![step]).expect("Failed to look into stdlib?");
         assert!(manifests.len() > 0);
     }
 
+    #[test]
+    fn load_manifests_nested() {
+        let mut step = Step::new(
+            otto_models::generate_uuid(),
+            "dir".to_string(),
+            StepParameters::Positional(vec![]),
+        );
+        step.block = Some(vec![Step::new(
+            step.context,
+            "echo".to_string(),
+            StepParameters::Positional(vec![]),
+        )]);
+        let manifests =
+            load_manifests_for("../../stdlib", &vec![step]).expect("Failed to look into stdlib?");
+        assert!(manifests.contains_key("dir"));
+        assert!(manifests.contains_key("echo"));
+    }
+
+    #[test]
+    fn run_terminates_running_step() {
+        let steps_dir = tempfile::tempdir().expect("Failed to create a steps dir");
+        // The sleep is a separate process which must be killed too, otherwise
+        // it would keep the output pipe open
+        write_step(steps_dir.path(), "sleep", "sleep 30");
+
+        let (sender, receiver) = async_std::channel::bounded(1);
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            async_std::task::block_on(sender.send(control::Request::Terminate))
+                .expect("Failed to send");
+        });
+
+        let started = std::time::Instant::now();
+        let status = run(
+            &steps_dir.path().to_string_lossy(),
+            &vec![step("sleep")],
+            otto_models::generate_uuid(),
+            RunOptions {
+                controller: Some(receiver),
+                ..Default::default()
+            },
+        )
+        .expect("Failed to run");
+
+        assert!(matches!(status, Status::Aborted));
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn run_kills_step_at_deadline() {
+        let steps_dir = tempfile::tempdir().expect("Failed to create a steps dir");
+        write_step(steps_dir.path(), "sleep", "sleep 30");
+
+        let started = Instant::now();
+        let status = run(
+            &steps_dir.path().to_string_lossy(),
+            &vec![step("sleep"), step("sleep")],
+            otto_models::generate_uuid(),
+            RunOptions {
+                deadline: Some(started + Duration::from_millis(200)),
+                ..Default::default()
+            },
+        )
+        .expect("Failed to run");
+
+        assert!(matches!(status, Status::Aborted));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn kill_tree_kills_descendants() {
+        use std::io::{BufRead, BufReader};
+        use std::process::Stdio;
+
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg("sleep 30 & echo $!; wait")
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Failed to spawn");
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .expect("Failed to read the pid");
+        let sleep: libc::pid_t = line.trim().parse().expect("Invalid pid");
+
+        kill_tree(child.id() as libc::pid_t);
+        assert!(!child.wait().expect("Failed to wait").success());
+
+        // The orphaned sleep is reaped by whichever process adopts it
+        let started = Instant::now();
+        let alive = || {
+            let stat = Command::new("ps")
+                .args(["-o", "stat=", "-p", &sleep.to_string()])
+                .output()
+                .expect("Failed to run ps");
+            let stat = String::from_utf8_lossy(&stat.stdout);
+            !stat.trim().is_empty() && !stat.starts_with('Z')
+        };
+        while alive() && started.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(!alive());
+    }
+
+    #[test]
+    fn retry_until_success() {
+        let mut statuses = vec![Status::Successful, Status::Failed, Status::Failed];
+        let status = retry("retry", Uuid::nil(), 3, Duration::from_millis(1), || {
+            Ok(statuses.pop().unwrap())
+        })
+        .expect("Failed to retry");
+        assert_eq!(status, Status::Successful);
+        assert!(statuses.is_empty());
+    }
+
+    #[test]
+    fn retry_backoff_is_capped() {
+        assert_eq!(next_backoff(Duration::from_secs(1)), Duration::from_secs(2));
+        assert_eq!(next_backoff(Duration::from_secs(40)), MAX_BACKOFF);
+        assert_eq!(next_backoff(MAX_BACKOFF), MAX_BACKOFF);
+        assert_eq!(next_backoff(Duration::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn retry_gives_up() {
+        let mut attempts = 0;
+        let status = retry("retry", Uuid::nil(), 2, Duration::from_millis(1), || {
+            attempts += 1;
+            Ok(Status::Failed)
+        })
+        .expect("Failed to retry");
+        assert_eq!(status, Status::Failed);
+        assert_eq!(attempts, 2);
+
+        // Aborted attempts, e.g. those which ran out of time, are not retried
+        let mut attempts = 0;
+        let status = retry("retry", Uuid::nil(), 3, Duration::from_millis(1), || {
+            attempts += 1;
+            Ok(Status::Aborted)
+        })
+        .expect("Failed to retry");
+        assert_eq!(status, Status::Aborted);
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn run_refuses_invalid_steps() {
+        let steps_dir = envdump_steps_dir();
+        let out = NamedTempFile::new().expect("Failed to create output file");
+        let steps = vec![
+            Step::new(
+                otto_models::generate_uuid(),
+                "envdump".to_string(),
+                StepParameters::Positional(vec![]),
+            ),
+            Step::new(
+                otto_models::generate_uuid(),
+                "missing".to_string(),
+                StepParameters::Positional(vec![]),
+            ),
+        ];
+
+        let mut environment = HashMap::new();
+        environment.insert("FOO".to_string(), "bar".to_string());
+        environment.insert("OUT".to_string(), out.path().to_string_lossy().to_string());
+
+        let status = run(
+            &steps_dir.path().to_string_lossy(),
+            &steps,
+            otto_models::generate_uuid(),
+            RunOptions {
+                environment: Some(&environment),
+                ..Default::default()
+            },
+        )
+        .expect("Failed to run");
+
+        assert!(matches!(status, Status::Failed));
+        // Nothing should have been executed, not even the valid step
+        assert_eq!(
+            std::fs::read_to_string(out.path()).expect("Failed to read output"),
+            ""
+        );
+    }
+
     #[test]
     fn pos_to_keyword() {
         use serde_json::Value;
@@ -327,4 +1087,340 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn block_parameters_for() {
+        use serde_json::Value;
+        let inner = Step::new(
+            otto_models::generate_uuid(),
+            "sh".to_string(),
+            StepParameters::Positional(vec![Value::String("make".to_string())]),
+        );
+        let mut step = Step::new(
+            inner.context,
+            "dir".to_string(),
+            StepParameters::Positional(vec![Value::String("subproject".to_string())]),
+        );
+        step.block = Some(vec![inner]);
+
+        let manifests = load_manifests_for_symbols("../../stdlib", vec!["dir".to_string()])
+            .expect("Failed to look into stdlib?");
+        let loaded = manifests.get("dir").expect("Must have a `dir` manifest");
+
+        match parameters_for(&step, &loaded.manifest) {
+            StepParameters::Positional(_) => panic!("Expected keyword parameters"),
+            StepParameters::Keyword(kw) => {
+                assert_eq!(
+                    kw.get("directory"),
+                    Some(&Value::String("subproject".to_string()))
+                );
+                let block: Vec<Step> =
+                    serde_json::from_value(kw.get("block").expect("Missing block").clone())
+                        .expect("Failed to deserialize the block");
+                assert_eq!(block.len(), 1);
+                assert_eq!(block[0].symbol, "sh");
+            }
+        }
+    }
+
+    #[test]
+    fn block_parameters_for_blockless_step() {
+        use serde_json::Value;
+        let mut step = Step::new(
+            otto_models::generate_uuid(),
+            "sh".to_string(),
+            StepParameters::Positional(vec![Value::String("ls".to_string())]),
+        );
+        step.block = Some(vec![]);
+
+        let manifests = load_manifests_for_symbols("../../stdlib", vec!["sh".to_string()])
+            .expect("Failed to look into stdlib?");
+        let loaded = manifests.get("sh").expect("Must have a `sh` manifest");
+
+        match parameters_for(&step, &loaded.manifest) {
+            StepParameters::Positional(_) => panic!("Expected keyword parameters"),
+            StepParameters::Keyword(kw) => {
+                assert_eq!(kw.len(), 1);
+                assert!(!has_block_parameter(&loaded.manifest));
+            }
+        }
+    }
+
+    /**
+     * Create a steps directory containing a single `envdump` step which writes
+     * the value of $FOO into the file at $OUT
+     */
+    fn envdump_steps_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().expect("Failed to create a steps dir");
+        write_step(dir.path(), "envdump", "printf '%s' \"$FOO\" > \"$OUT\"");
+        dir
+    }
+
+    /**
+     * Write a step with the given symbol into the steps directory, which will
+     * run the shell script when invoked
+     */
+    fn write_step(steps_dir: &Path, symbol: &str, script: &str) {
+        use std::io::Write;
+        use std::os::unix::fs::PermissionsExt;
+
+        let step_dir = steps_dir.join(symbol);
+        std::fs::create_dir(&step_dir).expect("Failed to create the step dir");
+
+        let mut manifest =
+            File::create(step_dir.join("manifest.yml")).expect("Failed to create manifest");
+        write!(
+            manifest,
+            r#"---
+symbol: {}
+description: A test step
+includes: []
+entrypoint:
+  path: {}-step
+parameters:
+  - name: message
+    required: false
+    type: string
+    description: An optional message
+"#,
+            symbol, symbol
+        )
+        .expect("Failed to write manifest");
+
+        let entrypoint = step_dir.join(format!("{}-step", symbol));
+        let mut file = File::create(&entrypoint).expect("Failed to create entrypoint");
+        writeln!(file, "#!/bin/sh\n{}", script).expect("Failed to write entrypoint");
+        drop(file);
+        std::fs::set_permissions(&entrypoint, std::fs::Permissions::from_mode(0o755))
+            .expect("Failed to make the entrypoint executable");
+    }
+
+    /**
+     * Create a steps directory with the given steps, which append their
+     * symbol to the file at $OUT before exiting with the given code
+     */
+    fn recording_steps_dir(steps: &[(&str, i32)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().expect("Failed to create a steps dir");
+        for (symbol, code) in steps.iter() {
+            let script = format!("echo {} >> \"$OUT\"\nexit {}", symbol, code);
+            write_step(dir.path(), symbol, &script);
+        }
+        dir
+    }
+
+    fn step(symbol: &str) -> Step {
+        Step::new(
+            otto_models::generate_uuid(),
+            symbol.to_string(),
+            StepParameters::Positional(vec![]),
+        )
+    }
+
+    fn run_recorded(
+        steps_dir: &tempfile::TempDir,
+        steps: &[Step],
+        post: &Post,
+        controller: Option<Receiver<control::Request>>,
+    ) -> (Status, String) {
+        let out = NamedTempFile::new().expect("Failed to create output file");
+        let mut environment = HashMap::new();
+        environment.insert("OUT".to_string(), out.path().to_string_lossy().to_string());
+
+        let status = run(
+            &steps_dir.path().to_string_lossy(),
+            &steps.to_vec(),
+            otto_models::generate_uuid(),
+            RunOptions {
+                post: Some(post),
+                environment: Some(&environment),
+                controller,
+                ..Default::default()
+            },
+        )
+        .expect("Failed to run");
+        let recorded = std::fs::read_to_string(out.path()).expect("Failed to read output");
+        (status, recorded)
+    }
+
+    #[test]
+    fn run_removes_scratch_dir() {
+        let steps_dir = tempfile::tempdir().expect("Failed to create a steps dir");
+        write_step(
+            steps_dir.path(),
+            "scratch",
+            "touch \"$SCRATCH_DIR/secret\" && echo \"$SCRATCH_DIR\" \"$1\" > \"$OUT\"",
+        );
+
+        let (status, recorded) =
+            run_recorded(&steps_dir, &[step("scratch")], &Post::default(), None);
+        assert_eq!(status, Status::Successful);
+        let (scratch, invocation) = recorded.trim().split_once(' ').expect("Missing paths");
+        assert!(!Path::new(scratch).exists());
+        // The invocation has the environment, secrets and all
+        assert!(Path::new(invocation).starts_with(scratch));
+    }
+
+    #[test]
+    fn run_post_after_failure() {
+        let steps_dir = recording_steps_dir(&[
+            ("fail", 1),
+            ("never", 0),
+            ("cleanup", 0),
+            ("notify", 0),
+            ("celebrate", 0),
+        ]);
+        let post = Post {
+            always: vec![step("cleanup")],
+            failure: vec![step("notify")],
+            success: vec![step("celebrate")],
+            ..Default::default()
+        };
+
+        let (status, recorded) =
+            run_recorded(&steps_dir, &[step("fail"), step("never")], &post, None);
+        assert_eq!(status, Status::Failed);
+        assert_eq!(recorded, "fail\ncleanup\nnotify\n");
+    }
+
+    #[test]
+    fn run_post_unstable() {
+        let steps_dir = recording_steps_dir(&[("flaky", 3), ("after", 0), ("notify", 0)]);
+        let post = Post {
+            unstable: vec![step("notify")],
+            ..Default::default()
+        };
+
+        let (status, recorded) =
+            run_recorded(&steps_dir, &[step("flaky"), step("after")], &post, None);
+        assert_eq!(status, Status::Unstable);
+        assert_eq!(recorded, "flaky\nafter\nnotify\n");
+    }
+
+    #[test]
+    fn run_failing_post_fails() {
+        let steps_dir = recording_steps_dir(&[("build", 0), ("fail", 1)]);
+        let post = Post {
+            success: vec![step("fail")],
+            ..Default::default()
+        };
+
+        let (status, recorded) = run_recorded(&steps_dir, &[step("build")], &post, None);
+        assert_eq!(status, Status::Failed);
+        assert_eq!(recorded, "build\nfail\n");
+    }
+
+    #[test]
+    fn run_post_after_termination() {
+        let steps_dir = recording_steps_dir(&[("cleanup", 0)]);
+        write_step(steps_dir.path(), "sleep", "sleep 30");
+        let post = Post {
+            aborted: vec![step("cleanup")],
+            ..Default::default()
+        };
+
+        let (sender, receiver) = async_std::channel::bounded(1);
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            async_std::task::block_on(sender.send(control::Request::Terminate))
+                .expect("Failed to send");
+        });
+
+        let (status, recorded) = run_recorded(&steps_dir, &[step("sleep")], &post, Some(receiver));
+        assert_eq!(status, Status::Aborted);
+        assert_eq!(recorded, "cleanup\n");
+    }
+
+    #[test]
+    fn run_exports_environment() {
+        let steps_dir = envdump_steps_dir();
+        let out = NamedTempFile::new().expect("Failed to create output file");
+        let step = Step::new(
+            otto_models::generate_uuid(),
+            "envdump".to_string(),
+            StepParameters::Positional(vec![]),
+        );
+
+        let mut environment = HashMap::new();
+        environment.insert("FOO".to_string(), "bar".to_string());
+        environment.insert("OUT".to_string(), out.path().to_string_lossy().to_string());
+
+        let status = run(
+            &steps_dir.path().to_string_lossy(),
+            &vec![step],
+            otto_models::generate_uuid(),
+            RunOptions {
+                environment: Some(&environment),
+                ..Default::default()
+            },
+        )
+        .expect("Failed to run");
+
+        assert!(matches!(status, Status::Successful));
+        assert_eq!(
+            std::fs::read_to_string(out.path()).expect("Failed to read output"),
+            "bar"
+        );
+    }
+
+    #[test]
+    fn run_interpolates_arguments() {
+        let steps_dir = tempfile::tempdir().expect("Failed to create a steps dir");
+        // The step records the invocation file it was given
+        write_step(steps_dir.path(), "record", "cat \"$1\" > \"$OUT\"");
+        let out = NamedTempFile::new().expect("Failed to create output file");
+
+        let mut environment = HashMap::new();
+        environment.insert("TARGET".to_string(), "wasm32".to_string());
+        environment.insert("OUT".to_string(), out.path().to_string_lossy().to_string());
+        let mut values = interpolate::Values::default();
+        values
+            .properties
+            .insert("name".to_string(), "Build".to_string());
+
+        let step = Step::new(
+            otto_models::generate_uuid(),
+            "record".to_string(),
+            StepParameters::Positional(vec![Interpolation {
+                segments: vec![
+                    Segment::Reference(Reference::Property("name".to_string())),
+                    Segment::Literal(" for ".to_string()),
+                    Segment::Reference(Reference::Env("TARGET".to_string())),
+                ],
+            }
+            .to_value()]),
+        );
+
+        let status = run(
+            &steps_dir.path().to_string_lossy(),
+            &vec![step],
+            otto_models::generate_uuid(),
+            RunOptions {
+                environment: Some(&environment),
+                values: Some(&values),
+                ..Default::default()
+            },
+        )
+        .expect("Failed to run");
+
+        assert_eq!(status, Status::Successful);
+        let recorded = std::fs::read_to_string(out.path()).expect("Failed to read output");
+        assert!(recorded.contains("\"Build for wasm32\""));
+    }
+
+    #[test]
+    fn run_fails_on_undefined_reference() {
+        let steps_dir = recording_steps_dir(&[("build", 0), ("never", 0)]);
+        let mut build = step("build");
+        build.parameters = StepParameters::Positional(vec![Interpolation {
+            segments: vec![Segment::Reference(Reference::Property(
+                "undefined".to_string(),
+            ))],
+        }
+        .to_value()]);
+
+        let (status, recorded) =
+            run_recorded(&steps_dir, &[build, step("never")], &Post::default(), None);
+        assert_eq!(status, Status::Failed);
+        assert_eq!(recorded, "");
+    }
 }
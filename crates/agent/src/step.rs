@@ -23,6 +23,14 @@ pub struct Invocation<P> {
     pub configuration: Configuration,
     /// Parameters are to be a step-defined type
     pub parameters: P,
+    /// The values which the arguments of the steps in the step's block can
+    /// refer to, for steps which run their block with an agent of their own
+    #[serde(default)]
+    pub values: crate::interpolate::Values,
+    /// The environment variables declared by the context, which the step's
+    /// process has already been given
+    #[serde(default)]
+    pub environment: HashMap<String, String>,
 }
 
 /**
@@ -46,6 +54,48 @@ pub struct Endpoint {
     pub url: Url,
 }
 
+/**
+ * A number given to a step, which is a string rather than a number when it
+ * was written as one or interpolated, e.g. `"${params.ATTEMPTS}"`
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Number {
+    Number(serde_json::Number),
+    String(String),
+}
+
+impl Number {
+    /**
+     * The number as a float, if it is one
+     */
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Number::Number(number) => number.as_f64(),
+            Number::String(number) => number.trim().parse().ok(),
+        }
+    }
+
+    /**
+     * The number, if it is a whole number which is not negative
+     */
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Number::Number(number) => number.as_u64(),
+            Number::String(number) => number.trim().parse().ok(),
+        }
+    }
+}
+
+impl std::fmt::Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Number::Number(number) => write!(f, "{}", number),
+            Number::String(number) => write!(f, "{}", number),
+        }
+    }
+}
+
 /**
  * This function will handle parsing the command line arguments passed to the step
  * and return the desired Invocation struct
@@ -0,0 +1,217 @@
+/*
+ * The when module evaluates the conditions of a context, deciding whether its
+ * steps should run or be skipped
+ */
+
+use glob::Pattern;
+use log::*;
+use otto_models::Condition;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+/**
+ * Facts are what is known about the pipeline run which the conditions are
+ * evaluated against
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Facts {
+    /// The branch being built, if it is known
+    pub branch: Option<String>,
+    pub environment: HashMap<String, String>,
+    /// The paths of the files changed by the revision being built
+    pub changes: Vec<String>,
+}
+
+impl Facts {
+    /**
+     * Gather the facts from the environment of the agent, along with the
+     * environment which will be exported for the steps
+     *
+     * The branch and changed files are taken from the `BRANCH_NAME` and
+     * `CHANGED_FILES` (newline separated) variables when they are set, and
+     * otherwise from the git repository of the project in the given directory
+     */
+    pub fn gather(environment: Option<&HashMap<String, String>>, project: &Path) -> Self {
+        let mut env: HashMap<String, String> = std::env::vars().collect();
+        if let Some(environment) = environment {
+            env.extend(environment.clone());
+        }
+
+        let branch = env
+            .get("BRANCH_NAME")
+            .cloned()
+            .or_else(|| git(project, &["rev-parse", "--abbrev-ref", "HEAD"]))
+            .map(|branch| branch.trim().to_string());
+
+        let changes = env
+            .get("CHANGED_FILES")
+            .cloned()
+            .or_else(|| git(project, &["diff", "--name-only", "HEAD~1", "HEAD"]))
+            .map(|changes| {
+                changes
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(|line| line.trim().to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            branch,
+            environment: env,
+            changes,
+        }
+    }
+}
+
+/**
+ * Run git with the arguments in the directory, returning its output if it
+ * succeeded
+ */
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .ok()?;
+    if output.status.success() {
+        String::from_utf8(output.stdout).ok()
+    } else {
+        None
+    }
+}
+
+/**
+ * Returns true if every one of the conditions is met
+ */
+pub fn evaluate_all(conditions: &[Condition], facts: &Facts) -> bool {
+    conditions
+        .iter()
+        .all(|condition| evaluate(condition, facts))
+}
+
+/**
+ * Returns true if the condition is met
+ */
+pub fn evaluate(condition: &Condition, facts: &Facts) -> bool {
+    match condition {
+        Condition::Branch(pattern) => match &facts.branch {
+            Some(branch) => matches(pattern, branch),
+            None => false,
+        },
+        Condition::Environment { name, value } => match (facts.environment.get(name), value) {
+            (Some(actual), Some(expected)) => actual == expected,
+            (Some(_), None) => true,
+            (None, _) => false,
+        },
+        Condition::Changeset(pattern) => facts.changes.iter().any(|path| matches(pattern, path)),
+        Condition::Not(condition) => !evaluate(condition, facts),
+        Condition::AnyOf(conditions) => conditions.iter().any(|c| evaluate(c, facts)),
+        Condition::AllOf(conditions) => evaluate_all(conditions, facts),
+    }
+}
+
+/**
+ * Match the value against the glob pattern, an invalid pattern never matches
+ */
+fn matches(pattern: &str, value: &str) -> bool {
+    match Pattern::new(pattern) {
+        Ok(pattern) => pattern.matches(value),
+        Err(e) => {
+            error!("Invalid pattern `{}`: {}", pattern, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facts() -> Facts {
+        let mut environment = HashMap::new();
+        environment.insert("DEPLOY".to_string(), "true".to_string());
+        Facts {
+            branch: Some("release/1.0".to_string()),
+            environment,
+            changes: vec!["src/lib.rs".to_string(), "README.adoc".to_string()],
+        }
+    }
+
+    #[test]
+    fn evaluate_branch() {
+        assert!(evaluate(&Condition::Branch("release/*".into()), &facts()));
+        assert!(!evaluate(&Condition::Branch("main".into()), &facts()));
+        assert!(!evaluate(
+            &Condition::Branch("main".into()),
+            &Facts::default()
+        ));
+    }
+
+    #[test]
+    fn evaluate_environment() {
+        let set = |name: &str, value: Option<&str>| Condition::Environment {
+            name: name.to_string(),
+            value: value.map(|v| v.to_string()),
+        };
+        assert!(evaluate(&set("DEPLOY", None), &facts()));
+        assert!(evaluate(&set("DEPLOY", Some("true")), &facts()));
+        assert!(!evaluate(&set("DEPLOY", Some("false")), &facts()));
+        assert!(!evaluate(&set("MISSING", None), &facts()));
+    }
+
+    #[test]
+    fn evaluate_changeset() {
+        assert!(evaluate(&Condition::Changeset("src/**".into()), &facts()));
+        assert!(!evaluate(&Condition::Changeset("docs/**".into()), &facts()));
+        assert!(!evaluate(&Condition::Changeset("[".into()), &facts()));
+    }
+
+    #[test]
+    fn evaluate_combinations() {
+        let main = Condition::Branch("main".into());
+        let release = Condition::Branch("release/*".into());
+
+        assert!(evaluate(&Condition::Not(Box::new(main.clone())), &facts()));
+        assert!(evaluate(
+            &Condition::AnyOf(vec![main.clone(), release.clone()]),
+            &facts()
+        ));
+        assert!(!evaluate(
+            &Condition::AllOf(vec![main, release.clone()]),
+            &facts()
+        ));
+        assert!(evaluate_all(&[], &facts()));
+        assert!(evaluate_all(&[release], &facts()));
+    }
+
+    #[test]
+    fn gather_from_project() {
+        let project = tempfile::tempdir().expect("Failed to create a project dir");
+        for args in [
+            &["init", "--quiet"][..],
+            &["checkout", "--quiet", "-b", "feature"],
+            &[
+                "-c",
+                "user.name=otto",
+                "-c",
+                "user.email=otto@example.com",
+                "commit",
+                "--quiet",
+                "--allow-empty",
+                "-m",
+                "Initial",
+            ],
+        ] {
+            assert!(git(project.path(), args).is_some());
+        }
+
+        let facts = Facts::gather(None, project.path());
+        // An explicit branch wins over the project's
+        match std::env::var("BRANCH_NAME") {
+            Ok(branch) => assert_eq!(facts.branch, Some(branch)),
+            Err(_) => assert_eq!(facts.branch, Some("feature".to_string())),
+        }
+    }
+}
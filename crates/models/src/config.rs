@@ -5,7 +5,7 @@
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /**
  * Struct representing the main configuration file for Otto, e.g. PREFIX/etc/otto/otto.yml
@@ -52,6 +52,40 @@ pub struct Project {
     pub source: SourceMap,
     #[serde(default = "default_pipeline")]
     pub pipeline: Pipeline,
+    /// What should cause the project's pipeline to be run, besides any triggers
+    /// declared by the pipeline itself
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub triggers: Vec<crate::triggers::Trigger>,
+}
+
+/**
+ * Load every project in the directory, typically PREFIX/etc/otto/projects.d,
+ * from the files ending in .yml, in the order of their file names
+ */
+pub fn load_projects(dir: &Path) -> std::io::Result<Vec<Project>> {
+    use std::io::{Error, ErrorKind};
+
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "yml") {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    let mut projects = vec![];
+    for project_file in files.iter() {
+        let file = std::fs::File::open(project_file)?;
+        let project: Project = serde_yaml::from_reader(file).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to load {:?}: {}", project_file, e),
+            )
+        })?;
+        projects.push(project);
+    }
+    Ok(projects)
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -118,6 +152,13 @@ source:
         assert_eq!(project.source.refspec, "*");
     }
 
+    #[test]
+    fn load_demo_projects() {
+        let projects = load_projects(Path::new("../../demo/config/projects.d"))
+            .expect("Failed to load projects");
+        assert!(projects.iter().any(|p| p.title == "Hello World"));
+    }
+
     #[test]
     fn test_default_refspec() {
         assert_eq!(default_refspec(), "*");
@@ -164,4 +205,25 @@ pipeline:
         let project: Project = serde_yaml::from_str(&yaml).expect("Failed to deser project");
         assert!(project.pipeline.inline.is_some());
     }
+
+    #[test]
+    fn deser_project_w_triggers() {
+        let yaml = r#"
+---
+title: 'Hello World'
+description: |
+  The Hello World project exists as a simple demonstrate loading a project into Otto
+source:
+  url: 'https://github.com/rtyler/hello-gem.git'
+triggers:
+  - cron: '@daily'
+  - upstream: 'otto-models'
+"#;
+        let project: Project = serde_yaml::from_str(yaml).expect("Failed to deser project");
+        assert_eq!(project.triggers.len(), 2);
+        assert_eq!(
+            project.triggers[1],
+            crate::triggers::Trigger::Upstream("otto-models".to_string())
+        );
+    }
 }
@@ -0,0 +1,323 @@
+/*
+ * The labels module contains the expressions which contexts use to select the
+ * agents they may run on, by the labels which those agents advertise
+ */
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// How deeply an expression may nest, which keeps both parsing it and
+/// evaluating it from exhausting the stack
+const MAX_DEPTH: usize = 64;
+
+/**
+ * An expression over the labels of an agent, e.g. `linux && (rust || go)`
+ *
+ * Expressions are serialized as their text, so that they read the same in the
+ * pipeline as they do in its machine readable form
+ *
+ * ```rust
+ * use otto_models::labels::Expression;
+ * let expression = Expression::parse("linux && !arm").expect("Failed to parse");
+ * assert!(expression.matches(&["linux".to_string(), "x86_64".to_string()]));
+ * assert!(!expression.matches(&["linux".to_string(), "arm".to_string()]));
+ * ```
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Expression {
+    /// The agent has the label
+    Label(String),
+    /// The expression does not match the agent
+    Not(Box<Expression>),
+    /// Both expressions match the agent
+    And(Box<Expression>, Box<Expression>),
+    /// Either expression matches the agent
+    Or(Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    /**
+     * Parse the text of an expression, returning a description of the problem
+     * if it is not valid
+     *
+     * `!` binds tightest, followed by `&&` and then `||`, and parenthesis can
+     * group expressions
+     */
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        if tokens.is_empty() {
+            return Err("the label expression is empty".to_string());
+        }
+
+        let mut parser = Parser {
+            tokens,
+            next: 0,
+            depth: 0,
+        };
+        let expression = parser.or()?;
+        match parser.tokens.get(parser.next) {
+            None => Ok(expression),
+            Some(token) => Err(format!("unexpected `{}` in the label expression", token)),
+        }
+    }
+
+    /**
+     * Returns true if an agent with the given labels satisfies the expression
+     */
+    pub fn matches(&self, labels: &[String]) -> bool {
+        match self {
+            Expression::Label(label) => labels.contains(label),
+            Expression::Not(expression) => !expression.matches(labels),
+            Expression::And(left, right) => left.matches(labels) && right.matches(labels),
+            Expression::Or(left, right) => left.matches(labels) || right.matches(labels),
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expression::Or(_, _) => 0,
+            Expression::And(_, _) => 1,
+            _ => 2,
+        }
+    }
+
+    /**
+     * Write out the operand, wrapped in parenthesis if it binds more loosely
+     * than the operator it belongs to
+     */
+    fn fmt_operand(
+        &self,
+        operand: &Expression,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        if operand.precedence() < self.precedence() {
+            write!(f, "({})", operand)
+        } else {
+            write!(f, "{}", operand)
+        }
+    }
+}
+
+impl std::fmt::Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expression::Label(label) => write!(f, "{}", label),
+            Expression::Not(expression) => {
+                write!(f, "!")?;
+                self.fmt_operand(expression, f)
+            }
+            Expression::And(left, right) | Expression::Or(left, right) => {
+                self.fmt_operand(left, f)?;
+                match self {
+                    Expression::And(_, _) => write!(f, " && ")?,
+                    _ => write!(f, " || ")?,
+                }
+                self.fmt_operand(right, f)
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        Self::parse(&text)
+    }
+}
+
+impl From<Expression> for String {
+    fn from(expression: Expression) -> String {
+        expression.to_string()
+    }
+}
+
+/**
+ * The characters which may make up a label, besides alphanumerics
+ */
+fn is_label_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':' | '+')
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' | ')' | '!' => tokens.push(c.to_string()),
+            '&' | '|' => {
+                if chars.next() != Some(c) {
+                    return Err(format!("`{}` should be `{}{}`", c, c, c));
+                }
+                tokens.push(format!("{}{}", c, c));
+            }
+            c if is_label_char(c) => {
+                let mut label = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if !is_label_char(c) {
+                        break;
+                    }
+                    label.push(c);
+                    chars.next();
+                }
+                tokens.push(label);
+            }
+            other => return Err(format!("`{}` cannot be part of a label", other)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    next: usize,
+    /// How deeply the expression being parsed is nested, counting every
+    /// operator and parenthesis it is within
+    depth: usize,
+}
+
+impl Parser {
+    fn take(&mut self, token: &str) -> bool {
+        if self.tokens.get(self.next).map(|t| t.as_str()) == Some(token) {
+            self.next += 1;
+            return true;
+        }
+        false
+    }
+
+    /**
+     * Nest the expression being parsed the given number of levels deeper,
+     * unless that would nest it too deeply
+     */
+    fn enter(&mut self, levels: usize) -> Result<(), String> {
+        self.depth += levels;
+        if self.depth > MAX_DEPTH {
+            return Err(format!(
+                "the label expression is nested more than {} levels deep",
+                MAX_DEPTH
+            ));
+        }
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Expression, String> {
+        let depth = self.depth;
+        let mut expression = self.and()?;
+        while self.take("||") {
+            self.enter(1)?;
+            expression = Expression::Or(Box::new(expression), Box::new(self.and()?));
+        }
+        self.depth = depth;
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        let depth = self.depth;
+        let mut expression = self.unary()?;
+        while self.take("&&") {
+            self.enter(1)?;
+            expression = Expression::And(Box::new(expression), Box::new(self.unary()?));
+        }
+        self.depth = depth;
+        Ok(expression)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        // Negations are counted rather than parsed recursively
+        let mut negations = 0;
+        while self.take("!") {
+            negations += 1;
+        }
+        self.enter(negations)?;
+
+        let mut expression = self.operand()?;
+        for _ in 0..negations {
+            expression = Expression::Not(Box::new(expression));
+        }
+        self.depth -= negations;
+        Ok(expression)
+    }
+
+    fn operand(&mut self) -> Result<Expression, String> {
+        if self.take("(") {
+            self.enter(1)?;
+            let expression = self.or()?;
+            if !self.take(")") {
+                return Err("unclosed `(` in the label expression".to_string());
+            }
+            self.depth -= 1;
+            return Ok(expression);
+        }
+
+        match self.tokens.get(self.next) {
+            Some(token) if token.chars().all(is_label_char) => {
+                self.next += 1;
+                Ok(Expression::Label(token.clone()))
+            }
+            Some(token) => Err(format!(
+                "expected a label but found `{}` in the label expression",
+                token
+            )),
+            None => Err("the label expression ends where a label was expected".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(labels: &[&str]) -> Vec<String> {
+        labels.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_precedence() {
+        let expression = Expression::parse("linux && rust || !windows && go").unwrap();
+        assert_eq!(expression.to_string(), "linux && rust || !windows && go");
+        assert!(expression.matches(&labels(&["linux", "rust"])));
+        assert!(expression.matches(&labels(&["macos", "go"])));
+        assert!(!expression.matches(&labels(&["windows", "go"])));
+
+        let expression = Expression::parse("linux&&(rust||go)").unwrap();
+        assert_eq!(expression.to_string(), "linux && (rust || go)");
+        assert!(!expression.matches(&labels(&["linux"])));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Expression::parse("").is_err());
+        assert!(Expression::parse("linux &").is_err());
+        assert!(Expression::parse("linux &&").is_err());
+        assert!(Expression::parse("(linux").is_err());
+        assert!(Expression::parse("linux rust").is_err());
+        assert!(Expression::parse("linux, rust").is_err());
+    }
+
+    #[test]
+    fn parse_nested_too_deeply() {
+        let expression = Expression::parse(&format!("{}linux", "!".repeat(64))).unwrap();
+        assert!(expression.matches(&labels(&["linux"])));
+
+        let nested = "the label expression is nested more than 64 levels deep";
+        let negated = format!("{}linux", "!".repeat(20_000));
+        assert_eq!(Expression::parse(&negated).unwrap_err(), nested);
+        let grouped = format!("{}linux{}", "(".repeat(20_000), ")".repeat(20_000));
+        assert_eq!(Expression::parse(&grouped).unwrap_err(), nested);
+        let chained = vec!["linux"; 20_000].join(" && ");
+        assert_eq!(Expression::parse(&chained).unwrap_err(), nested);
+    }
+
+    #[test]
+    fn serialize_as_text() {
+        let expression: Expression = serde_json::from_str("\"linux && rust\"").unwrap();
+        assert_eq!(
+            serde_json::to_string(&expression).unwrap(),
+            "\"linux && rust\""
+        );
+        assert!(serde_json::from_str::<Expression>("\"linux &&\"").is_err());
+    }
+}
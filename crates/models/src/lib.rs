@@ -4,7 +4,9 @@ use uuid::Uuid;
 
 pub use serde_json::Value;
 pub mod config;
+pub mod labels;
 pub mod osp;
+pub mod triggers;
 
 /**
  * A Pipeline contains the total configuration and steps for a single pipeline run
@@ -14,6 +16,32 @@ pub struct Pipeline {
     #[serde(default = "generate_uuid")]
     pub uuid: Uuid,
     pub batches: Vec<Batch>,
+    /// Steps to run once every batch has finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post: Option<Post>,
+    /// Inputs which are given values when the pipeline is run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parameters: Vec<Parameter>,
+    /// What should cause the pipeline to be run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub triggers: Vec<triggers::Trigger>,
+}
+
+impl Pipeline {
+    /**
+     * Returns true if any of the contexts declare what they need, in which
+     * case every context does and the pipeline is scheduled as a graph
+     */
+    pub fn is_graph(&self) -> bool {
+        self.contexts().any(|ctx| ctx.needs.is_some())
+    }
+
+    /**
+     * Iterate over the contexts of every batch, in order
+     */
+    pub fn contexts(&self) -> impl Iterator<Item = &Context> {
+        self.batches.iter().flat_map(|batch| batch.contexts.iter())
+    }
 }
 
 impl Default for Pipeline {
@@ -21,37 +49,136 @@ impl Default for Pipeline {
         Self {
             uuid: generate_uuid(),
             batches: vec![],
+            post: None,
+            parameters: vec![],
+            triggers: vec![],
+        }
+    }
+}
+
+/**
+ * A typed input to the pipeline, such as the version to release, whose value
+ * is supplied by whoever runs the pipeline
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Parameter {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: ParameterType,
+    /// The value to use when none is supplied, parameters without a default
+    /// must always be given a value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+    /// The values which a `choice` parameter may take
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub choices: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParameterType {
+    String,
+    Boolean,
+    Choice,
+}
+
+impl Parameter {
+    /**
+     * Check that the value can be given to the parameter, returning a
+     * description of the problem if it cannot
+     */
+    pub fn check(&self, value: &Value) -> Result<(), String> {
+        match (self.kind, value) {
+            (ParameterType::String, Value::String(_)) => Ok(()),
+            (ParameterType::Boolean, Value::Bool(_)) => Ok(()),
+            (ParameterType::Choice, Value::String(choice)) => {
+                if self.choices.contains(choice) {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "`{}` is not one of the choices for `{}`: {}",
+                        choice,
+                        self.name,
+                        self.choices.join(", ")
+                    ))
+                }
+            }
+            (ParameterType::Boolean, _) => Err(format!("`{}` must be a boolean", self.name)),
+            _ => Err(format!("`{}` must be a string", self.name)),
         }
     }
 }
 
+/**
+ * Check the supplied values against the declared parameters, filling in the
+ * defaults for those which were not supplied
+ *
+ * The values are returned as the strings which steps will see, otherwise every
+ * problem with the supplied values is returned
+ */
+pub fn resolve_parameters(
+    declared: &[Parameter],
+    values: &HashMap<String, Value>,
+) -> Result<HashMap<String, String>, Vec<String>> {
+    let mut resolved = HashMap::new();
+    let mut errors = vec![];
+
+    for name in values.keys() {
+        if !declared.iter().any(|p| &p.name == name) {
+            errors.push(format!("`{}` is not a parameter of the pipeline", name));
+        }
+    }
+
+    for parameter in declared.iter() {
+        let value = match values.get(&parameter.name).or(parameter.default.as_ref()) {
+            Some(value) => value,
+            None => {
+                errors.push(format!("`{}` must be given a value", parameter.name));
+                continue;
+            }
+        };
+
+        match parameter.check(value) {
+            Ok(()) => {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                resolved.insert(parameter.name.clone(), value);
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(resolved)
+    } else {
+        errors.sort();
+        Err(errors)
+    }
+}
+
 /**
  * A batch is a collection of contexts that should be executed in a given mode.
  *
  * This structure basically allows for Otto to execute batches of contexts in parallel, or in various
  * other flows.
  */
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Batch {
     pub mode: BatchMode,
     pub contexts: Vec<Context>,
 }
 
-impl Default for Batch {
-    fn default() -> Self {
-        Self {
-            mode: BatchMode::Linear,
-            contexts: vec![],
-        }
-    }
-}
-
 /**
  * The mode in which an orchestrator should execute the batch
  */
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub enum BatchMode {
     /// Each context should be executed in order
+    #[default]
     Linear,
     /// Each context should be executed in parallel completely independent of each other
     Parallel,
@@ -59,6 +186,69 @@ pub enum BatchMode {
     Fanout,
 }
 
+/**
+ * The request which an orchestrator's `/v1/run` endpoint expects
+ *
+ * The workload is either the contexts of a single batch along with its mode,
+ * or the batches of a pipeline which are run one after another.
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RunWorkload {
+    pub pipeline: Uuid,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub contexts: Vec<Context>,
+    /// How the contexts should be run, in sequence by default
+    #[serde(default)]
+    pub mode: BatchMode,
+    /// Batches to run in order once the contexts have finished, each only
+    /// once the one before it has succeeded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub batches: Vec<Batch>,
+    /// The pipeline's post steps, which run once everything else has finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post: Option<Post>,
+    /// The parameters declared by the pipeline
+    #[serde(default)]
+    pub parameters: Vec<Parameter>,
+    /// The values supplied for the parameters, which are checked against
+    /// their declarations before anything is run
+    #[serde(default)]
+    pub values: HashMap<String, Value>,
+}
+
+impl RunWorkload {
+    /**
+     * Create a workload which runs every batch of the pipeline, without any
+     * values so that the parameters are given their defaults
+     */
+    pub fn new(pipeline: &Pipeline) -> Self {
+        Self {
+            // Every run of the pipeline is distinct
+            pipeline: Uuid::new_v4(),
+            batches: pipeline.batches.clone(),
+            post: pipeline.post.clone(),
+            parameters: pipeline.parameters.clone(),
+            ..Default::default()
+        }
+    }
+
+    /**
+     * The batches to run in order, starting with the one made of the
+     * workload's own contexts if it has any
+     */
+    pub fn batches(&self) -> Vec<Batch> {
+        let mut batches = vec![];
+        if !self.contexts.is_empty() {
+            batches.push(Batch {
+                mode: self.mode.clone(),
+                contexts: self.contexts.clone(),
+            });
+        }
+        batches.extend(self.batches.iter().cloned());
+        batches
+    }
+}
+
 /**
  * Possible statuses that a Pipeline can have
  *
@@ -68,12 +258,114 @@ pub enum BatchMode {
  * For example, if a step's invocation returns a 3 exit code, then the agent should automatically
  * know to the set the pipeline status to Unstable
  */
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
 pub enum Status {
     Successful = 0,
     Failed = 1,
     Aborted = 2,
     Unstable = 3,
+    /// The conditions for running were not met, so nothing was run
+    Skipped = 4,
+}
+
+impl Status {
+    /**
+     * Map the exit code of a step or agent back into its status, any code
+     * which is not a status is a failure
+     */
+    pub fn from_code(code: i32) -> Self {
+        match code {
+            0 => Status::Successful,
+            2 => Status::Aborted,
+            3 => Status::Unstable,
+            4 => Status::Skipped,
+            _ => Status::Failed,
+        }
+    }
+}
+
+/**
+ * The steps to run after a context or pipeline has finished, depending on the
+ * status it finished with
+ *
+ * These steps run even when the context or pipeline failed or was aborted,
+ * which makes them suitable for cleaning up or sending notifications
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Post {
+    /// Run regardless of the status, before any of the other conditions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub always: Vec<Step>,
+    /// Run when the status is `Successful`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub success: Vec<Step>,
+    /// Run when the status is `Failed`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failure: Vec<Step>,
+    /// Run when the status is `Unstable`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unstable: Vec<Step>,
+    /// Run when the status is `Aborted`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aborted: Vec<Step>,
+    /// The environment of the pipeline's post steps, the post steps of a
+    /// context run with the environment of the context instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<HashMap<String, String>>,
+}
+
+impl Post {
+    /**
+     * Return the steps which should run after finishing with the given status
+     *
+     * Nothing ran when skipped, so there is nothing to run afterwards either
+     */
+    pub fn steps_for(&self, status: &Status) -> Vec<Step> {
+        let conditional = match status {
+            Status::Successful => &self.success,
+            Status::Failed => &self.failure,
+            Status::Unstable => &self.unstable,
+            Status::Aborted => &self.aborted,
+            Status::Skipped => return vec![],
+        };
+        self.always
+            .iter()
+            .chain(conditional.iter())
+            .cloned()
+            .collect()
+    }
+
+    /**
+     * Iterate over every step, regardless of the condition it runs under
+     */
+    pub fn steps(&self) -> impl Iterator<Item = &Step> {
+        self.always
+            .iter()
+            .chain(self.success.iter())
+            .chain(self.failure.iter())
+            .chain(self.unstable.iter())
+            .chain(self.aborted.iter())
+    }
+}
+
+/**
+ * A condition which must be met for a context to run, otherwise the context is
+ * skipped
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Condition {
+    /// The branch being built matches the glob pattern
+    Branch(String),
+    /// The environment variable is set, and has the value if one is given
+    Environment { name: String, value: Option<String> },
+    /// Any of the changed files matches the glob pattern
+    Changeset(String),
+    /// The condition is not met
+    Not(Box<Condition>),
+    /// At least one of the conditions is met
+    AnyOf(Vec<Condition>),
+    /// Every one of the conditions is met
+    AllOf(Vec<Condition>),
 }
 
 /**
@@ -87,6 +379,23 @@ pub struct Context {
     pub properties: HashMap<String, String>,
     pub environment: Option<HashMap<String, String>>,
     pub steps: Vec<Step>,
+    /// Conditions which must all be met for the steps to run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub when: Vec<Condition>,
+    /// Steps to run once the steps of this context have finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post: Option<Post>,
+    /// The contexts which must succeed before this one may start, when given
+    /// the contexts are scheduled as a graph rather than batch by batch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub needs: Option<Vec<Uuid>>,
+    /// What the agent running this context must provide, any agent will do
+    /// when there are no requirements
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<AgentRequirement>,
+    /// Where in the pipeline's source this context was declared
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<SourceLocation>,
 }
 
 impl Default for Context {
@@ -96,10 +405,33 @@ impl Default for Context {
             properties: HashMap::default(),
             environment: None,
             steps: vec![],
+            when: vec![],
+            post: None,
+            needs: None,
+            agent: None,
+            source: None,
         }
     }
 }
 
+/**
+ * The requirements a context places on the agent which runs it
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct AgentRequirement {
+    /// The expression which the labels advertised by the agent must match
+    pub label: labels::Expression,
+}
+
+impl AgentRequirement {
+    /**
+     * Returns true if an agent advertising the labels meets the requirements
+     */
+    pub fn matches(&self, labels: &[String]) -> bool {
+        self.label.matches(labels)
+    }
+}
+
 /**
  * A step is the smallest unit of execution for the pipeline
  */
@@ -111,6 +443,15 @@ pub struct Step {
     pub context: Uuid,
     pub symbol: String,
     pub parameters: StepParameters,
+    /// The steps nested inside of this step's block, e.g. `dir('src') { sh 'make' }`
+    ///
+    /// The agent will pass these into the step as the parameter which the step's
+    /// manifest declares with the block type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<Vec<Step>>,
+    /// Where in the pipeline's source this step was declared
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<SourceLocation>,
 }
 
 impl Step {
@@ -120,10 +461,52 @@ impl Step {
             context,
             symbol,
             parameters,
+            block: None,
+            source: None,
         }
     }
 }
 
+/**
+ * A SourceLocation links a parsed structure back to the pipeline source it
+ * came from, allowing errors at runtime to be reported against the right line
+ *
+ * ```rust
+ * # use otto_models::SourceLocation;
+ * let location = SourceLocation {
+ *     file: Some("Ottofile".to_string()),
+ *     line: 3,
+ *     column: 5,
+ *     start: 40,
+ *     end: 47,
+ * };
+ * assert_eq!(location.to_string(), "Ottofile:3:5");
+ * ```
+ */
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct SourceLocation {
+    /// The name of the file which was parsed, if it was parsed from a file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// The line the span starts on, starting at 1
+    pub line: usize,
+    /// The column the span starts on, starting at 1
+    pub column: usize,
+    /// The byte offset of the start of the span
+    pub start: usize,
+    /// The byte offset of the end of the span
+    pub end: usize,
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file)?;
+        }
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
 /**
  * The StepParameters enum helps map map both positional and keyword
  * parameters for a given step.
@@ -143,6 +526,90 @@ pub enum StepParameters {
     Keyword(HashMap<String, Value>),
 }
 
+/**
+ * An interpolated string, such as `"cargo build --target ${env.TARGET}"`,
+ * whose references can only be resolved by the agent when the step runs
+ *
+ * Interpolations are passed amongst the step's parameters as a map with the
+ * single key `$interpolate`, see `to_value()` and `from_value()`. The keys of
+ * the maps written by users are escaped with `escape_key()` so that they can
+ * never be mistaken for an interpolation
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Interpolation {
+    #[serde(rename = "$interpolate")]
+    pub segments: Vec<Segment>,
+}
+
+impl Interpolation {
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).expect("Failed to serialize an interpolation")
+    }
+
+    /**
+     * Return the interpolation if the value is one
+     */
+    pub fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Object(map) if map.len() == 1 && map.contains_key("$interpolate") => {
+                serde_json::from_value(value.clone()).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Escape the key of a map written by a user by doubling a leading `$`, which
+ * only the keys of interpolations otherwise start with
+ */
+pub fn escape_key(key: String) -> String {
+    if key.starts_with('$') {
+        format!("${}", key)
+    } else {
+        key
+    }
+}
+
+/**
+ * Reverse `escape_key()` for a key of a map which is not an interpolation
+ */
+pub fn unescape_key(key: &str) -> &str {
+    key.strip_prefix('$').unwrap_or(key)
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Segment {
+    Literal(String),
+    Reference(Reference),
+}
+
+/**
+ * A reference to a value which is only known when the step runs
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Reference {
+    /// An environment variable, e.g. `${env.TARGET}`
+    Env(String),
+    /// A property of the context, e.g. `${properties.name}`
+    Property(String),
+    /// A field of the step's configuration, e.g. `${config.pipeline}`
+    Config(String),
+    /// A parameter of the pipeline, e.g. `${params.VERSION}`
+    Parameter(String),
+}
+
+impl std::fmt::Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reference::Env(name) => write!(f, "${{env.{}}}", name),
+            Reference::Property(name) => write!(f, "${{properties.{}}}", name),
+            Reference::Config(name) => write!(f, "${{config.{}}}", name),
+            Reference::Parameter(name) => write!(f, "${{params.{}}}", name),
+        }
+    }
+}
+
 /**
  * Generate a UUID v4 for use in structs, etc
  *
@@ -203,4 +670,226 @@ mod tests {
 
         assert_eq!(step.symbol, "sh");
     }
+
+    #[test]
+    fn deserialize_block() {
+        let buf = r#"
+        {"symbol":"dir",
+        "uuid":"5599cffb-f23a-4e0f-a0b9-f74654641b2b",
+        "context":"3ce1f6fb-79ca-4564-a47e-98265f53ef7f",
+        "parameters" : ["subproject"],
+        "block" : [
+            {"symbol":"sh",
+            "uuid":"f619073f-4129-4d30-a94f-f61af164a6d8",
+            "context":"3ce1f6fb-79ca-4564-a47e-98265f53ef7f",
+            "parameters" : ["make"]}
+        ]}"#;
+        let step = serde_json::from_str::<Step>(buf).expect("Failed to deserialize");
+
+        let block = step.block.expect("Failed to deserialize the block");
+        assert_eq!(block.len(), 1);
+        assert_eq!(block[0].symbol, "sh");
+        assert!(block[0].block.is_none());
+    }
+
+    #[test]
+    fn deserialize_without_source() {
+        let buf = r#"
+        {"uuid":"3ce1f6fb-79ca-4564-a47e-98265f53ef7f",
+        "properties" : {},
+        "environment" : null,
+        "steps" : []}"#;
+        let context = serde_json::from_str::<Context>(buf).expect("Failed to deserialize");
+        assert!(context.source.is_none());
+    }
+
+    #[test]
+    fn run_workload_batches() {
+        let buf = r#"
+        {"pipeline":"fdbebdcf-ad5c-49e5-890f-aef294b476c5",
+        "contexts":[{"uuid":"3ce1f6fb-79ca-4564-a47e-98265f53ef7f","properties":{},"steps":[]}],
+        "mode":"Parallel",
+        "batches":[{"mode":"Fanout","contexts":[]}],
+        "values":{"version":"1.0"}}"#;
+        let run = serde_json::from_str::<RunWorkload>(buf).expect("Failed to deserialize");
+
+        let batches = run.batches();
+        assert_eq!(batches.len(), 2);
+        assert!(matches!(batches[0].mode, BatchMode::Parallel));
+        assert_eq!(batches[0].contexts.len(), 1);
+        assert!(matches!(batches[1].mode, BatchMode::Fanout));
+        assert_eq!(run.values["version"], "1.0");
+    }
+
+    #[test]
+    fn run_workload_keeps_every_batch() {
+        let pipeline = Pipeline {
+            batches: vec![
+                Batch {
+                    mode: BatchMode::Parallel,
+                    contexts: vec![Context::default(), Context::default()],
+                },
+                Batch::default(),
+            ],
+            ..Default::default()
+        };
+        let run = RunWorkload::new(&pipeline);
+
+        assert!(run.contexts.is_empty());
+        let batches = run.batches();
+        assert_eq!(batches.len(), 2);
+        assert!(matches!(batches[0].mode, BatchMode::Parallel));
+        assert!(matches!(batches[1].mode, BatchMode::Linear));
+        assert_ne!(run.pipeline, pipeline.uuid);
+    }
+
+    #[test]
+    fn serialize_source() {
+        let mut step = Step::new(
+            generate_uuid(),
+            "sh".to_string(),
+            StepParameters::Positional(vec![]),
+        );
+        let value = serde_json::to_value(&step).expect("Failed to serialize");
+        assert!(value.get("source").is_none());
+
+        step.source = Some(SourceLocation {
+            file: None,
+            line: 1,
+            column: 2,
+            start: 1,
+            end: 8,
+        });
+        let value = serde_json::to_value(&step).expect("Failed to serialize");
+        assert_eq!(value["source"]["line"], 1);
+        assert!(value["source"].get("file").is_none());
+    }
+
+    #[test]
+    fn deserialize_post() {
+        let buf = r#"
+        {"always" : [
+            {"symbol":"echo",
+            "context":"3ce1f6fb-79ca-4564-a47e-98265f53ef7f",
+            "parameters" : ["done"]}
+        ],
+        "failure" : [
+            {"symbol":"sh",
+            "context":"3ce1f6fb-79ca-4564-a47e-98265f53ef7f",
+            "parameters" : ["make clean"]}
+        ]}"#;
+        let post = serde_json::from_str::<Post>(buf).expect("Failed to deserialize");
+        assert_eq!(post.steps().count(), 2);
+
+        let symbols =
+            |steps: Vec<Step>| -> Vec<String> { steps.into_iter().map(|s| s.symbol).collect() };
+        assert_eq!(symbols(post.steps_for(&Status::Failed)), vec!["echo", "sh"]);
+        assert_eq!(symbols(post.steps_for(&Status::Successful)), vec!["echo"]);
+        assert!(post.steps_for(&Status::Skipped).is_empty());
+    }
+
+    #[test]
+    fn deserialize_when() {
+        let buf = r#"
+        {"properties" : {},
+        "environment" : null,
+        "steps" : [],
+        "when" : [
+            {"Branch" : "main"},
+            {"Not" : {"Environment" : {"name" : "SKIP", "value" : null}}}
+        ]}"#;
+        let context = serde_json::from_str::<Context>(buf).expect("Failed to deserialize");
+        assert_eq!(context.when[0], Condition::Branch("main".to_string()));
+        assert!(matches!(context.when[1], Condition::Not(_)));
+    }
+
+    #[test]
+    fn interpolation_value() {
+        let interpolation = Interpolation {
+            segments: vec![
+                Segment::Literal("--target ".to_string()),
+                Segment::Reference(Reference::Env("TARGET".to_string())),
+            ],
+        };
+        let value = interpolation.to_value();
+        assert_eq!(
+            value,
+            serde_json::json!({"$interpolate": [
+                {"Literal": "--target "},
+                {"Reference": {"Env": "TARGET"}},
+            ]})
+        );
+        assert_eq!(Interpolation::from_value(&value), Some(interpolation));
+        assert_eq!(Interpolation::from_value(&serde_json::json!({})), None);
+        assert_eq!(
+            Reference::Config("pipeline".to_string()).to_string(),
+            "${config.pipeline}"
+        );
+    }
+
+    #[test]
+    fn status_from_code() {
+        assert_eq!(Status::from_code(Status::Unstable as i32), Status::Unstable);
+        assert_eq!(Status::from_code(0), Status::Successful);
+        assert_eq!(Status::from_code(127), Status::Failed);
+    }
+
+    fn parameter(kind: ParameterType, default: Option<Value>) -> Parameter {
+        Parameter {
+            name: format!("{:?}", kind).to_uppercase(),
+            kind,
+            default,
+            choices: vec!["staging".to_string(), "production".to_string()],
+            description: None,
+        }
+    }
+
+    #[test]
+    fn deserialize_parameter() {
+        let buf = r#"{"name":"VERSION","type":"string","default":"1.0"}"#;
+        let parameter = serde_json::from_str::<Parameter>(buf).expect("Failed to deserialize");
+        assert_eq!(parameter.kind, ParameterType::String);
+        assert_eq!(parameter.default, Some(Value::from("1.0")));
+        assert!(parameter.choices.is_empty());
+    }
+
+    #[test]
+    fn resolve_parameters_defaults() {
+        let declared = vec![
+            parameter(ParameterType::String, Some(Value::from("1.0"))),
+            parameter(ParameterType::Boolean, Some(Value::Bool(false))),
+            parameter(ParameterType::Choice, None),
+        ];
+        let mut values = HashMap::new();
+        values.insert("CHOICE".to_string(), Value::from("production"));
+
+        let resolved = resolve_parameters(&declared, &values).expect("Failed to resolve");
+        assert_eq!(resolved.get("STRING"), Some(&"1.0".to_string()));
+        assert_eq!(resolved.get("BOOLEAN"), Some(&"false".to_string()));
+        assert_eq!(resolved.get("CHOICE"), Some(&"production".to_string()));
+    }
+
+    #[test]
+    fn resolve_parameters_errors() {
+        let declared = vec![
+            parameter(ParameterType::String, None),
+            parameter(ParameterType::Boolean, None),
+            parameter(ParameterType::Choice, None),
+        ];
+        let mut values = HashMap::new();
+        values.insert("BOOLEAN".to_string(), Value::from("yes"));
+        values.insert("CHOICE".to_string(), Value::from("qa"));
+        values.insert("UNKNOWN".to_string(), Value::from("?"));
+
+        let errors = resolve_parameters(&declared, &values).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "`BOOLEAN` must be a boolean",
+                "`STRING` must be given a value",
+                "`UNKNOWN` is not a parameter of the pipeline",
+                "`qa` is not one of the choices for `CHOICE`: staging, production",
+            ]
+        );
+    }
 }
@@ -1,4 +1,27 @@
+use crate::{Interpolation, SourceLocation, Step, StepParameters, Value};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/**
+ * Stable error codes for the problems found when validating steps against
+ * their manifests, these should never be re-used for a different kind of
+ * problem
+ */
+pub mod codes {
+    /// No manifest exists for the step's symbol
+    pub const UNKNOWN_STEP: &str = "E0100";
+    /// A required parameter of the step was not given
+    pub const MISSING_PARAMETER: &str = "E0101";
+    /// A keyword argument does not match any of the step's parameters
+    pub const UNKNOWN_PARAMETER: &str = "E0102";
+    /// More positional arguments were given than the step has parameters
+    pub const TOO_MANY_ARGUMENTS: &str = "E0103";
+    /// An argument's type does not match the type of the step's parameter
+    pub const TYPE_MISMATCH: &str = "E0104";
+    /// A block of steps was given to a step which has no block parameter
+    pub const UNEXPECTED_BLOCK: &str = "E0105";
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Manifest {
@@ -40,14 +63,344 @@ pub enum ParameterType {
     StringParameter,
     #[serde(rename = "boolean")]
     BoolParameter,
+    /// A number, which may also be given as a string such as an interpolation
+    #[serde(rename = "number")]
+    NumberParameter,
     #[serde(rename = "block")]
     BlockParameter,
 }
 
+/**
+ * Load the manifests for every step in the steps directory, keyed by their
+ * symbols
+ *
+ * Each step is expected to be in a directory of its own containing a
+ * manifest.yml, directories without a manifest are ignored
+ */
+pub fn load_manifests(dir: &Path) -> std::io::Result<HashMap<String, Manifest>> {
+    use std::io::{Error, ErrorKind};
+
+    let mut manifests = HashMap::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let manifest_file = entry?.path().join("manifest.yml");
+
+        if manifest_file.is_file() {
+            let file = std::fs::File::open(&manifest_file)?;
+            let manifest: Manifest = serde_yaml::from_reader(file).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to load {:?}: {}", manifest_file, e),
+                )
+            })?;
+            manifests.insert(manifest.symbol.clone(), manifest);
+        }
+    }
+    Ok(manifests)
+}
+
+/**
+ * A Problem is a mistake found when validating a step against its manifest,
+ * such as a misspelled step or a missing parameter
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Problem {
+    /// A stable error code from the `codes` module, e.g. E0100
+    pub code: &'static str,
+    pub message: String,
+    /// The symbol of the step the problem was found in
+    pub symbol: String,
+    /// Where the step was declared, if it was parsed from a pipeline
+    pub source: Option<SourceLocation>,
+    /// Descriptions of what was expected instead
+    pub expected: Vec<String>,
+    /// A suggestion for how the user might fix the problem
+    pub hint: Option<String>,
+}
+
+impl Problem {
+    fn new(step: &Step, code: &'static str, message: String) -> Self {
+        Self {
+            code,
+            message,
+            symbol: step.symbol.clone(),
+            source: step.source.clone(),
+            expected: vec![],
+            hint: None,
+        }
+    }
+
+    fn with_hint(mut self, hint: String) -> Self {
+        self.hint = Some(hint);
+        self
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "error[{}]: {}", self.code, self.message)?;
+        if let Some(source) = &self.source {
+            write!(f, " at {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Validate the steps, and any steps nested in their blocks, against the given
+ * manifests keyed by their symbols, returning every problem found
+ */
+pub fn validate_steps(steps: &[Step], manifests: &HashMap<String, Manifest>) -> Vec<Problem> {
+    let mut problems = vec![];
+
+    for step in steps.iter() {
+        match manifests.get(&step.symbol) {
+            Some(manifest) => validate_step(step, manifest, &mut problems),
+            None => {
+                let mut known: Vec<&String> = manifests.keys().collect();
+                known.sort();
+                let mut problem = Problem::new(
+                    step,
+                    codes::UNKNOWN_STEP,
+                    format!("unknown step `{}`", step.symbol),
+                );
+                problem.expected = known.iter().map(|s| format!("`{}`", s)).collect();
+                problems.push(problem);
+            }
+        }
+
+        if let Some(block) = &step.block {
+            problems.extend(validate_steps(block, manifests));
+        }
+    }
+    problems
+}
+
+fn validate_step(step: &Step, manifest: &Manifest, problems: &mut Vec<Problem>) {
+    let mut given: Vec<(&Parameter, &Value)> = vec![];
+
+    match &step.parameters {
+        StepParameters::Positional(args) => {
+            if args.len() > manifest.parameters.len() {
+                problems.push(
+                    Problem::new(
+                        step,
+                        codes::TOO_MANY_ARGUMENTS,
+                        format!(
+                            "`{}` takes at most {} arguments but {} were given",
+                            step.symbol,
+                            manifest.parameters.len(),
+                            args.len()
+                        ),
+                    )
+                    .with_hint(describe_parameters(manifest)),
+                );
+            }
+            given.extend(manifest.parameters.iter().zip(args.iter()));
+        }
+        StepParameters::Keyword(kwargs) => {
+            let mut names: Vec<&String> = kwargs.keys().collect();
+            names.sort();
+
+            for name in names {
+                match manifest.parameters.iter().find(|p| &p.name == name) {
+                    Some(parameter) => given.push((parameter, &kwargs[name])),
+                    None => problems.push(
+                        Problem::new(
+                            step,
+                            codes::UNKNOWN_PARAMETER,
+                            format!("`{}` has no parameter named `{}`", step.symbol, name),
+                        )
+                        .with_hint(describe_parameters(manifest)),
+                    ),
+                }
+            }
+        }
+    }
+
+    for (parameter, value) in given.iter() {
+        if let Some(expected) = mismatch(&parameter.p_type, value) {
+            problems.push(Problem::new(
+                step,
+                codes::TYPE_MISMATCH,
+                format!(
+                    "`{}` expects `{}` to be {}, found {}",
+                    step.symbol,
+                    parameter.name,
+                    expected,
+                    describe_value(value)
+                ),
+            ));
+        }
+    }
+
+    let block_parameter = manifest
+        .parameters
+        .iter()
+        .find(|p| matches!(p.p_type, ParameterType::BlockParameter));
+
+    if step.block.is_some() && block_parameter.is_none() {
+        problems.push(Problem::new(
+            step,
+            codes::UNEXPECTED_BLOCK,
+            format!("`{}` does not accept a block of steps", step.symbol),
+        ));
+    }
+
+    for parameter in manifest.parameters.iter().filter(|p| p.required) {
+        let provided = given.iter().any(|(p, _)| p.name == parameter.name)
+            || (matches!(parameter.p_type, ParameterType::BlockParameter) && step.block.is_some());
+
+        if !provided {
+            problems.push(
+                Problem::new(
+                    step,
+                    codes::MISSING_PARAMETER,
+                    format!(
+                        "`{}` is missing the required parameter `{}`",
+                        step.symbol, parameter.name
+                    ),
+                )
+                .with_hint(describe_parameters(manifest)),
+            );
+        }
+    }
+}
+
+/**
+ * Return a description of the expected type if the value does not match it
+ */
+fn mismatch(p_type: &ParameterType, value: &Value) -> Option<&'static str> {
+    // Interpolations will be resolved into strings when the step runs, which
+    // can only be checked as numbers once they have been
+    if Interpolation::from_value(value).is_some() {
+        return match p_type {
+            ParameterType::NumberParameter => None,
+            _ => mismatch(p_type, &Value::String(String::new())),
+        };
+    }
+
+    match (p_type, value) {
+        (ParameterType::StringParameter, Value::String(_)) => None,
+        (ParameterType::StringParameter, _) => Some("a string"),
+        (ParameterType::BoolParameter, Value::Bool(_)) => None,
+        (ParameterType::BoolParameter, _) => Some("a boolean"),
+        (ParameterType::NumberParameter, Value::Number(_)) => None,
+        (ParameterType::NumberParameter, Value::String(number))
+            if number.trim().parse::<f64>().is_ok() =>
+        {
+            None
+        }
+        (ParameterType::NumberParameter, _) => Some("a number"),
+        // Blocks are passed as a block of steps after the arguments, never as
+        // an argument itself
+        (ParameterType::BlockParameter, _) => Some("a block of steps"),
+    }
+}
+
+fn describe_value(value: &Value) -> &'static str {
+    if Interpolation::from_value(value).is_some() {
+        return "a string";
+    }
+
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "a list",
+        Value::Object(_) => "a map",
+    }
+}
+
+fn describe_parameters(manifest: &Manifest) -> String {
+    let names: Vec<String> = manifest
+        .parameters
+        .iter()
+        .map(|p| format!("`{}`", p.name))
+        .collect();
+
+    if names.is_empty() {
+        format!("`{}` does not take any parameters", manifest.symbol)
+    } else {
+        format!(
+            "`{}` takes the parameters {}",
+            manifest.symbol,
+            names.join(", ")
+        )
+    }
+}
+
 /** Simple function for serde defaults */
 fn default_false() -> bool {
     false
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_stdlib_manifests() {
+        let manifests =
+            load_manifests(Path::new("../../stdlib")).expect("Failed to load the stdlib");
+        let sh = manifests.get("sh").expect("Missing the sh manifest");
+        assert_eq!(sh.parameters[0].name, "script");
+        assert!(manifests.contains_key("dir"));
+    }
+
+    #[test]
+    fn load_manifests_invalid_dir() {
+        assert!(load_manifests(Path::new("Cargo.toml")).is_err());
+    }
+
+    #[test]
+    fn validate_number_parameters() {
+        let interpolation = crate::Interpolation {
+            segments: vec![crate::Segment::Reference(crate::Reference::Env(
+                "COUNT".to_string(),
+            ))],
+        };
+        assert_eq!(
+            mismatch(&ParameterType::NumberParameter, &Value::from(3)),
+            None
+        );
+        assert_eq!(
+            mismatch(&ParameterType::NumberParameter, &Value::from("2.5")),
+            None
+        );
+        assert_eq!(
+            mismatch(&ParameterType::NumberParameter, &interpolation.to_value()),
+            None
+        );
+        assert_eq!(
+            mismatch(&ParameterType::NumberParameter, &Value::from("three")),
+            Some("a number")
+        );
+        assert_eq!(
+            mismatch(&ParameterType::StringParameter, &Value::from(3)),
+            Some("a string")
+        );
+    }
+
+    #[test]
+    fn validate_steps_problems() {
+        let manifests =
+            load_manifests(Path::new("../../stdlib")).expect("Failed to load the stdlib");
+        let mut dir = Step::new(
+            crate::generate_uuid(),
+            "dir".to_string(),
+            StepParameters::Positional(vec![Value::String("a".into())]),
+        );
+        dir.block = Some(vec![Step::new(
+            crate::generate_uuid(),
+            "nope".to_string(),
+            StepParameters::Positional(vec![]),
+        )]);
+
+        let problems = validate_steps(&[dir], &manifests);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].code, codes::UNKNOWN_STEP);
+        assert_eq!(problems[0].to_string(), "error[E0100]: unknown step `nope`");
+    }
+}
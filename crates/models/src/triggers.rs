@@ -0,0 +1,345 @@
+/*
+ * The triggers module contains the declarations of when a pipeline should be
+ * run, without anybody asking for it: on a schedule, when its source is pushed
+ * to, or when the pipeline of another project succeeds
+ */
+
+use chrono::{Datelike, Timelike};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/**
+ * A Trigger declares something which should cause the pipeline to be run
+ *
+ * ```rust
+ * use otto_models::triggers::Trigger;
+ * let yaml = r#"
+ * - cron: '0 4 * * 1-5'
+ * - push: 'main'
+ * - upstream: 'otto-models'
+ *         "#;
+ * let triggers: Vec<Trigger> = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+ * assert_eq!(triggers.len(), 3);
+ * ```
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    /// Run whenever the schedule matches the current minute
+    Cron(Schedule),
+    /// Run whenever the source is pushed to a branch matching the glob pattern
+    Push(String),
+    /// Run whenever the pipeline of the named project succeeds
+    Upstream(String),
+}
+
+/**
+ * A cron schedule, e.g. `0 4 * * 1-5` for four in the morning on weekdays
+ *
+ * The five fields are the minute, hour, day of the month, month and day of
+ * the week. Each field may be `*`, a number, a range such as `1-5`, or a list
+ * of them separated by commas, and each of those may be followed by a step
+ * such as `/15`. Months and days of the week may also be named by their first
+ * three letters, e.g. `mon-fri`, and Sunday is both `0` and `7`.
+ *
+ * As with cron, when both the day of the month and the day of the week are
+ * restricted the schedule matches days which satisfy either of them.
+ *
+ * The macros `@yearly`, `@annually`, `@monthly`, `@weekly`, `@daily`,
+ * `@midnight` and `@hourly` stand in for their usual schedules.
+ *
+ * ```rust
+ * use chrono::{NaiveDate, Timelike};
+ * use otto_models::triggers::Schedule;
+ * let schedule = Schedule::parse("0-59/15 9-17 * * mon-fri").expect("Failed to parse");
+ * // A Friday
+ * let time = NaiveDate::from_ymd(2021, 3, 5).and_hms(9, 45, 0);
+ * assert!(schedule.matches(&time));
+ * assert!(!schedule.matches(&time.with_minute(50).unwrap()));
+ * ```
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    /// The text of the schedule as it was written
+    text: String,
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+/**
+ * The values a single field of a schedule matches, as a set of bits
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Field {
+    bits: u64,
+    /// False when the field starts with `*`, which matters for the days
+    restricted: bool,
+}
+
+impl Field {
+    fn contains(&self, value: u32) -> bool {
+        self.bits & (1 << value) != 0
+    }
+}
+
+/**
+ * The name and bounds of each field of a schedule, along with the names which
+ * the values of the field may be given
+ */
+struct Bounds {
+    name: &'static str,
+    min: u32,
+    max: u32,
+    names: &'static [&'static str],
+}
+
+const MINUTE: Bounds = Bounds {
+    name: "minute",
+    min: 0,
+    max: 59,
+    names: &[],
+};
+const HOUR: Bounds = Bounds {
+    name: "hour",
+    min: 0,
+    max: 23,
+    names: &[],
+};
+const DAY: Bounds = Bounds {
+    name: "day of the month",
+    min: 1,
+    max: 31,
+    names: &[],
+};
+const MONTH: Bounds = Bounds {
+    name: "month",
+    min: 1,
+    max: 12,
+    names: &[
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ],
+};
+const WEEKDAY: Bounds = Bounds {
+    name: "day of the week",
+    min: 0,
+    max: 7,
+    names: &["sun", "mon", "tue", "wed", "thu", "fri", "sat"],
+};
+
+impl Schedule {
+    /**
+     * Parse the text of a schedule, returning a description of the problem if
+     * it is not valid
+     */
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let expanded = match text.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other if other.starts_with('@') => {
+                return Err(format!("`{}` is not a known schedule", other));
+            }
+            other => other,
+        };
+
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "the schedule `{}` has {} fields but should have 5: minute, hour, day of the month, month and day of the week",
+                text.trim(),
+                fields.len()
+            ));
+        }
+
+        let mut weekdays = parse_field(fields[4], &WEEKDAY)?;
+        // Sunday is both 0 and 7
+        if weekdays.contains(7) {
+            weekdays.bits |= 1;
+        }
+
+        Ok(Self {
+            text: text.trim().to_string(),
+            minutes: parse_field(fields[0], &MINUTE)?,
+            hours: parse_field(fields[1], &HOUR)?,
+            days: parse_field(fields[2], &DAY)?,
+            months: parse_field(fields[3], &MONTH)?,
+            weekdays,
+        })
+    }
+
+    /**
+     * Returns true if the schedule matches the minute of the given time
+     */
+    pub fn matches<T: Datelike + Timelike>(&self, time: &T) -> bool {
+        let day = self.days.contains(time.day());
+        let weekday = self
+            .weekdays
+            .contains(time.weekday().num_days_from_sunday());
+        let date = match (self.days.restricted, self.weekdays.restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        };
+
+        date && self.minutes.contains(time.minute())
+            && self.hours.contains(time.hour())
+            && self.months.contains(time.month())
+    }
+}
+
+fn parse_field(text: &str, bounds: &Bounds) -> Result<Field, String> {
+    let mut field = Field {
+        bits: 0,
+        restricted: !text.starts_with('*'),
+    };
+
+    for item in text.split(',') {
+        let (range, step) = match item.find('/') {
+            Some(index) => {
+                let step = &item[index + 1..];
+                match step.parse::<u32>() {
+                    Ok(step) if step > 0 => (&item[..index], step),
+                    _ => {
+                        return Err(format!(
+                            "`{}` is not a valid step for the {}",
+                            step, bounds.name
+                        ))
+                    }
+                }
+            }
+            None => (item, 1),
+        };
+
+        let (first, last) = if range == "*" {
+            (bounds.min, bounds.max)
+        } else if let Some(index) = range.find('-') {
+            let first = parse_value(&range[..index], bounds)?;
+            let last = parse_value(&range[index + 1..], bounds)?;
+            if first > last {
+                return Err(format!(
+                    "the range `{}` of the {} is backwards",
+                    range, bounds.name
+                ));
+            }
+            (first, last)
+        } else {
+            let first = parse_value(range, bounds)?;
+            // A single value with a step runs from there to the end, as in `5/15`
+            if item.contains('/') {
+                (first, bounds.max)
+            } else {
+                (first, first)
+            }
+        };
+
+        for value in (first..=last).step_by(step as usize) {
+            field.bits |= 1 << value;
+        }
+    }
+    Ok(field)
+}
+
+fn parse_value(text: &str, bounds: &Bounds) -> Result<u32, String> {
+    let lower = text.to_lowercase();
+    if let Some(index) = bounds.names.iter().position(|name| *name == lower) {
+        return Ok(index as u32 + bounds.min);
+    }
+
+    match text.parse::<u32>() {
+        Ok(value) if value >= bounds.min && value <= bounds.max => Ok(value),
+        _ => Err(format!(
+            "`{}` is not a valid {}, it should be between {} and {}",
+            text, bounds.name, bounds.min, bounds.max
+        )),
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        Self::parse(&text)
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> String {
+        schedule.text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn parse_fields() {
+        let schedule = Schedule::parse("0,30 4-6/2 * jan-mar *").unwrap();
+        let time = NaiveDate::from_ymd(2021, 2, 14).and_hms(6, 30, 0);
+        assert!(schedule.matches(&time));
+        assert!(!schedule.matches(&time.with_hour(5).unwrap()));
+        assert!(!schedule.matches(&time.with_minute(15).unwrap()));
+        assert!(!schedule.matches(&time.with_month(4).unwrap()));
+    }
+
+    #[test]
+    fn parse_macros() {
+        let schedule = Schedule::parse("@weekly").unwrap();
+        assert_eq!(schedule.to_string(), "@weekly");
+        // A Sunday
+        assert!(schedule.matches(&NaiveDate::from_ymd(2021, 3, 7).and_hms(0, 0, 0)));
+        assert!(!schedule.matches(&NaiveDate::from_ymd(2021, 3, 8).and_hms(0, 0, 0)));
+        assert!(Schedule::parse("@fortnightly").is_err());
+    }
+
+    #[test]
+    fn match_either_day() {
+        // The first of the month, or any Sunday
+        let schedule = Schedule::parse("0 0 1 * 7").unwrap();
+        assert!(schedule.matches(&NaiveDate::from_ymd(2021, 3, 1).and_hms(0, 0, 0)));
+        assert!(schedule.matches(&NaiveDate::from_ymd(2021, 3, 7).and_hms(0, 0, 0)));
+        assert!(!schedule.matches(&NaiveDate::from_ymd(2021, 3, 8).and_hms(0, 0, 0)));
+
+        // Only Sundays, since the day of the month is not restricted
+        let schedule = Schedule::parse("0 0 */1 * sun").unwrap();
+        assert!(!schedule.matches(&NaiveDate::from_ymd(2021, 3, 1).and_hms(0, 0, 0)));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Schedule::parse("").is_err());
+        assert!(Schedule::parse("* * * *").is_err());
+        assert!(Schedule::parse("60 * * * *").is_err());
+        assert!(Schedule::parse("* * 0 * *").is_err());
+        assert!(Schedule::parse("*/0 * * * *").is_err());
+        assert!(Schedule::parse("5-1 * * * *").is_err());
+        assert_eq!(
+            Schedule::parse("* 24 * * *").unwrap_err(),
+            "`24` is not a valid hour, it should be between 0 and 23"
+        );
+    }
+
+    #[test]
+    fn deserialize_triggers() {
+        let triggers: Vec<Trigger> =
+            serde_json::from_str(r#"[{"cron": "@daily"}, {"upstream": "otto"}]"#).unwrap();
+        assert_eq!(triggers[1], Trigger::Upstream("otto".to_string()));
+        assert_eq!(
+            serde_json::to_string(&triggers).unwrap(),
+            r#"[{"cron":"@daily"},{"upstream":"otto"}]"#
+        );
+        assert!(serde_json::from_str::<Vec<Trigger>>(r#"[{"cron": "* *"}]"#).is_err());
+    }
+}
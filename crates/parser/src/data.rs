@@ -0,0 +1,814 @@
+/*
+ * The data module is the front-end for pipelines written as YAML or JSON, for
+ * tooling which would rather emit data than the pipeline syntax, e.g.
+ *
+ *  pipeline:
+ *    environment:
+ *      RUST_LOG: 'debug'
+ *    stages:
+ *      - name: 'Build'
+ *        steps:
+ *          - sh: 'cargo build'
+ *          - dir: 'docs'
+ *            block:
+ *              - sh: 'make'
+ *      - parallel:
+ *          - name: 'Test'
+ *            steps:
+ *              - sh: 'cargo test'
+ *          - name: 'Lint'
+ *            steps:
+ *              - sh: ['cargo', 'clippy']
+ *
+ * Each step is a map from its symbol to its arguments: a single value, a list
+ * of positional arguments, or a map of keyword arguments. The nested steps of
+ * a step go in its `block`. Any other string given to a stage is one of its
+ * properties, just as `name = 'Build'` would be.
+ *
+ * Strings are taken literally, as single quoted strings are in the pipeline
+ * syntax. The pipeline is parsed into the same model as the pipeline syntax,
+ * and problems are reported with the same diagnostics. YAML anchors and
+ * aliases are refused, since expanding them can multiply the size of a small
+ * file without bound.
+ */
+
+use crate::lower::{merge_environment, Source};
+use crate::{codes, Diagnostic, Parsed, Position};
+use otto_models::*;
+use std::collections::HashMap;
+use uuid::Uuid;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::{Marker, TScalarStyle};
+
+/**
+ * The syntaxes which pipelines can be written in
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syntax {
+    /// The pipeline syntax, typically in an Ottofile or a .otto file
+    Otto,
+    Yaml,
+    Json,
+}
+
+impl Syntax {
+    /**
+     * Choose the syntax by the extension of the file, defaulting to the
+     * pipeline syntax
+     */
+    pub fn from_path(path: &str) -> Self {
+        let extension = std::path::Path::new(path)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("yml") | Some("yaml") => Syntax::Yaml,
+            Some("json") => Syntax::Json,
+            _ => Syntax::Otto,
+        }
+    }
+
+    /**
+     * Choose the syntax by a content type such as `application/json`,
+     * returning None for content types which do not name one
+     */
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next().unwrap_or("").trim();
+        match essence.to_lowercase().as_str() {
+            "application/json" | "text/json" => Some(Syntax::Json),
+            "application/yaml" | "application/x-yaml" | "text/yaml" | "text/x-yaml" => {
+                Some(Syntax::Yaml)
+            }
+            _ => None,
+        }
+    }
+}
+
+/**
+ * A node of the YAML document along with the span of its source, which the
+ * YAML parsers for serde do not keep
+ */
+#[derive(Clone, Debug)]
+struct Node {
+    kind: Kind,
+    start: usize,
+    end: usize,
+}
+
+#[derive(Clone, Debug)]
+enum Kind {
+    Scalar(String, TScalarStyle),
+    Sequence(Vec<Node>),
+    Mapping(Vec<(Node, Node)>),
+}
+
+impl Node {
+    fn describe(&self) -> &'static str {
+        match &self.kind {
+            Kind::Scalar(_, TScalarStyle::Plain) if self.is_null() => "nothing",
+            Kind::Scalar(..) => "a string",
+            Kind::Sequence(_) => "a list",
+            Kind::Mapping(_) => "a map",
+        }
+    }
+
+    fn is_null(&self) -> bool {
+        matches!(&self.kind, Kind::Scalar(value, TScalarStyle::Plain) if value == "~" || value == "null")
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match &self.kind {
+            Kind::Scalar(value, _) if !self.is_null() => Some(value),
+            _ => None,
+        }
+    }
+
+    /**
+     * Convert the node into a value, plain scalars are typed as they would be
+     * in JSON
+     */
+    fn to_value(&self) -> Value {
+        match &self.kind {
+            Kind::Scalar(value, TScalarStyle::Plain) => match value.as_str() {
+                "~" | "null" => Value::Null,
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                other => serde_json::from_str::<serde_json::Number>(other)
+                    .map(Value::Number)
+                    .unwrap_or_else(|_| Value::String(other.to_string())),
+            },
+            Kind::Scalar(value, _) => Value::String(value.clone()),
+            Kind::Sequence(items) => Value::Array(items.iter().map(|n| n.to_value()).collect()),
+            Kind::Mapping(entries) => Value::Object(
+                entries
+                    .iter()
+                    .map(|(key, value)| {
+                        (
+                            escape_key(key.as_str().unwrap_or("").to_string()),
+                            value.to_value(),
+                        )
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/**
+ * A collection which has been started, but not yet finished
+ */
+struct Open {
+    node: Node,
+    /// The key of a mapping which is waiting for its value
+    key: Option<Node>,
+}
+
+/**
+ * Builds the tree of nodes from the events of the YAML parser
+ */
+struct Builder<'a> {
+    buffer: &'a str,
+    /// The byte offset of each character, since the YAML parser counts
+    /// characters
+    offsets: Vec<usize>,
+    /// The collections which have been started but not yet finished
+    open: Vec<Open>,
+    root: Option<Node>,
+    /// Where the first anchor or alias is, which are refused since every
+    /// alias would copy what it refers to
+    anchored: Option<usize>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(buffer: &'a str) -> Self {
+        Self {
+            buffer,
+            offsets: buffer.char_indices().map(|(i, _)| i).collect(),
+            open: vec![],
+            root: None,
+            anchored: None,
+        }
+    }
+
+    fn offset(&self, mark: &Marker) -> usize {
+        self.offsets
+            .get(mark.index())
+            .copied()
+            .unwrap_or(self.buffer.len())
+    }
+
+    /**
+     * The end of a scalar starting at the offset, which is only known exactly
+     * for strings on a single line
+     */
+    fn scalar_end(&self, start: usize, value: &str, style: TScalarStyle) -> usize {
+        let rest = &self.buffer[start..];
+        let end = match style {
+            TScalarStyle::Plain => value.len(),
+            TScalarStyle::SingleQuoted => {
+                let mut chars = rest.char_indices().skip(1).peekable();
+                let mut end = rest.len();
+                while let Some((i, c)) = chars.next() {
+                    if c == '\'' {
+                        if chars.peek().map(|(_, c)| *c) == Some('\'') {
+                            chars.next();
+                            continue;
+                        }
+                        end = i + 1;
+                        break;
+                    }
+                }
+                end
+            }
+            TScalarStyle::DoubleQuoted => {
+                let mut chars = rest.char_indices().skip(1);
+                let mut end = rest.len();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+                end
+            }
+            // Block scalars are pointed at by their indicator
+            _ => 1,
+        };
+        (start + end).min(self.buffer.len())
+    }
+
+    /**
+     * Collections are pointed at by the rest of the line they start on
+     */
+    fn line_end(&self, start: usize) -> usize {
+        self.buffer[start..]
+            .find('\n')
+            .map(|i| start + i)
+            .unwrap_or_else(|| self.buffer.len())
+    }
+
+    fn add(&mut self, node: Node) {
+        match self.open.last_mut() {
+            Some(open) => match (&mut open.node.kind, open.key.take()) {
+                (Kind::Sequence(items), _) => items.push(node),
+                (Kind::Mapping(entries), Some(key)) => entries.push((key, node)),
+                (Kind::Mapping(_), None) => open.key = Some(node),
+                (Kind::Scalar(..), _) => {}
+            },
+            None => {
+                if self.root.is_none() {
+                    self.root = Some(node);
+                }
+            }
+        }
+    }
+}
+
+impl<'a> MarkedEventReceiver for Builder<'a> {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let start = self.offset(&mark);
+
+        let anchored = match &event {
+            Event::Alias(_) => true,
+            Event::Scalar(_, _, anchor, _)
+            | Event::SequenceStart(anchor)
+            | Event::MappingStart(anchor) => *anchor > 0,
+            _ => false,
+        };
+        if anchored && self.anchored.is_none() {
+            self.anchored = Some(start);
+        }
+
+        match event {
+            Event::Scalar(value, style, _, _) => {
+                let end = self.scalar_end(start, &value, style);
+                self.add(Node {
+                    kind: Kind::Scalar(value, style),
+                    start,
+                    end,
+                });
+            }
+            Event::SequenceStart(_) | Event::MappingStart(_) => {
+                let kind = match event {
+                    Event::SequenceStart(_) => Kind::Sequence(vec![]),
+                    _ => Kind::Mapping(vec![]),
+                };
+                let end = self.line_end(start);
+                self.open.push(Open {
+                    node: Node { kind, start, end },
+                    key: None,
+                });
+            }
+            Event::SequenceEnd | Event::MappingEnd => {
+                if let Some(mut open) = self.open.pop() {
+                    // Block mappings are only marked once their first key has
+                    // been scanned, so they start with that key instead
+                    let first = match &open.node.kind {
+                        Kind::Sequence(items) => items.first(),
+                        Kind::Mapping(entries) => entries.first().map(|(key, _)| key),
+                        Kind::Scalar(..) => None,
+                    };
+                    if let Some(first) = first.filter(|first| first.start < open.node.start) {
+                        open.node.start = first.start;
+                        open.node.end = self.line_end(first.start);
+                    }
+                    self.add(open.node);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/**
+ * Parse the buffer as a pipeline written in YAML or JSON
+ */
+pub(crate) fn parse_pipeline(file: Option<&str>, buffer: &str, syntax: Syntax) -> Parsed {
+    let mut source = Source::new(file, buffer, crate::library::Imports::default());
+    let pipeline = match load(buffer, syntax) {
+        Ok(Some(root)) => parse_root(&root, &mut source),
+        Ok(None) => {
+            source
+                .error(
+                    codes::UNEXPECTED_EOF,
+                    "the pipeline is empty".to_string(),
+                    0,
+                    0,
+                )
+                .expected = vec!["`pipeline`".to_string()];
+            Pipeline::default()
+        }
+        Err(diagnostic) => {
+            source.diagnostics.push(*diagnostic);
+            Pipeline::default()
+        }
+    };
+
+    Parsed {
+        pipeline,
+        diagnostics: source.diagnostics,
+    }
+}
+
+/**
+ * Load the tree of nodes from the buffer, which is None if the buffer holds
+ * no document at all
+ */
+fn load(buffer: &str, syntax: Syntax) -> Result<Option<Node>, Box<Diagnostic>> {
+    let malformed = |message: String, offset: usize| {
+        let mut diagnostic =
+            Diagnostic::error(codes::MALFORMED_DATA, message, buffer, offset, offset);
+        diagnostic.hint = Some(match syntax {
+            Syntax::Json => "the pipeline must be valid JSON".to_string(),
+            _ => "the pipeline must be valid YAML".to_string(),
+        });
+        Box::new(diagnostic)
+    };
+
+    let yaml = if Syntax::Json == syntax {
+        // JSON is checked strictly, since it would otherwise be accepted
+        // leniently as YAML
+        if let Err(e) = serde_json::from_str::<serde_json::Value>(buffer) {
+            let offset = offset_of(buffer, e.line(), e.column().saturating_sub(1));
+            return Err(malformed(strip_location(&e.to_string()), offset));
+        }
+        // YAML does not allow tabs where JSON does, and they can only be
+        // whitespace in valid JSON. Replacing them keeps every offset the same
+        buffer.replace('\t', " ")
+    } else {
+        buffer.to_string()
+    };
+
+    let mut builder = Builder::new(buffer);
+    if let Err(e) = Parser::new(yaml.chars()).load(&mut builder, false) {
+        let offset = builder.offset(e.marker());
+        return Err(malformed(strip_location(&e.to_string()), offset));
+    }
+    if let Some(offset) = builder.anchored {
+        let mut diagnostic = malformed("anchors and aliases are not supported".to_string(), offset);
+        diagnostic.hint = Some("write out what the alias refers to in full".to_string());
+        return Err(diagnostic);
+    }
+    Ok(builder.root)
+}
+
+/**
+ * Remove the line and column from the end of an error message, since the
+ * diagnostic points at them
+ */
+fn strip_location(message: &str) -> String {
+    match message.rfind(" at line ") {
+        Some(index) => message[..index].to_string(),
+        None => message.to_string(),
+    }
+}
+
+/**
+ * The byte offset of the line, starting at 1, and the character within it
+ */
+fn offset_of(buffer: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = buffer
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(|line| line.len())
+        .sum();
+    buffer[line_start..]
+        .char_indices()
+        .nth(column)
+        .map(|(i, _)| line_start + i)
+        .unwrap_or_else(|| buffer.len())
+}
+
+fn location(node: &Node, source: &Source) -> SourceLocation {
+    let position = Position::from_offset(source.buffer, node.start);
+    SourceLocation {
+        file: source.file.map(|f| f.to_string()),
+        line: position.line,
+        column: position.column,
+        start: node.start,
+        end: node.end,
+    }
+}
+
+/**
+ * Report that the node is not what was expected
+ */
+fn mismatch(node: &Node, expected: &str, source: &mut Source) {
+    source.error(
+        codes::UNEXPECTED_TOKEN,
+        format!("expected {} but found {}", expected, node.describe()),
+        node.start,
+        node.end,
+    );
+}
+
+fn entries<'n>(node: &'n Node, source: &mut Source) -> Option<&'n [(Node, Node)]> {
+    match &node.kind {
+        Kind::Mapping(entries) => Some(entries),
+        _ => {
+            mismatch(node, "a map", source);
+            None
+        }
+    }
+}
+
+fn items<'n>(node: &'n Node, source: &mut Source) -> Option<&'n [Node]> {
+    match &node.kind {
+        Kind::Sequence(items) => Some(items),
+        _ => {
+            mismatch(node, "a list", source);
+            None
+        }
+    }
+}
+
+/**
+ * Report a key which is not one of the expected keys
+ */
+fn unexpected_key(key: &Node, expected: &[&str], source: &mut Source) {
+    source
+        .error(
+            codes::UNEXPECTED_TOKEN,
+            format!("unexpected `{}`", key.as_str().unwrap_or("")),
+            key.start,
+            key.end,
+        )
+        .expected = expected.iter().map(|key| format!("`{}`", key)).collect();
+}
+
+fn parse_root(root: &Node, source: &mut Source) -> Pipeline {
+    let mut pipeline = None;
+    for (key, value) in entries(root, source).unwrap_or_default() {
+        match key.as_str() {
+            Some("pipeline") => pipeline = Some(parse_body(value, source)),
+            _ => unexpected_key(key, &["pipeline"], source),
+        }
+    }
+
+    pipeline.unwrap_or_else(|| {
+        if source.diagnostics.is_empty() {
+            source
+                .error(
+                    codes::UNEXPECTED_EOF,
+                    "the document has no `pipeline`".to_string(),
+                    root.start,
+                    root.end,
+                )
+                .expected = vec!["`pipeline`".to_string()];
+        }
+        Pipeline::default()
+    })
+}
+
+fn parse_body(node: &Node, source: &mut Source) -> Pipeline {
+    let mut pipeline = Pipeline::default();
+    let mut environment = HashMap::new();
+
+    for (key, value) in entries(node, source).unwrap_or_default() {
+        match key.as_str() {
+            Some("environment") => environment = parse_environment(value, source),
+            Some("stages") => {
+                for item in items(value, source).unwrap_or_default() {
+                    if let Some(batch) = parse_batch(item, source) {
+                        pipeline.batches.push(batch);
+                    }
+                }
+            }
+            _ => unexpected_key(key, &["environment", "stages"], source),
+        }
+    }
+
+    merge_environment(&mut pipeline, &environment);
+    pipeline
+}
+
+fn parse_environment(node: &Node, source: &mut Source) -> HashMap<String, String> {
+    let mut environment = HashMap::new();
+    for (key, value) in entries(node, source).unwrap_or_default() {
+        match (key.as_str(), value.as_str()) {
+            (Some(key), Some(value)) => {
+                environment.insert(key.to_string(), value.to_string());
+            }
+            (_, None) => mismatch(value, "a string", source),
+            (None, _) => mismatch(key, "a string", source),
+        }
+    }
+    environment
+}
+
+/**
+ * Parse an item of the stages, which is either a stage or a block of stages
+ * which run in parallel
+ */
+fn parse_batch(node: &Node, source: &mut Source) -> Option<Batch> {
+    let entries = entries(node, source)?;
+    let mode = match entries.first().and_then(|(key, _)| key.as_str()) {
+        Some("parallel") => BatchMode::Parallel,
+        Some("fanout") => BatchMode::Fanout,
+        _ => {
+            return Some(Batch {
+                mode: BatchMode::Linear,
+                contexts: vec![parse_stage(node, entries, source)],
+            })
+        }
+    };
+
+    let contexts = items(&entries[0].1, source)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|item| {
+            let entries = self::entries(item, source)?;
+            Some(parse_stage(item, entries, source))
+        })
+        .collect();
+
+    for (key, _) in entries.iter().skip(1) {
+        source
+            .error(
+                codes::UNEXPECTED_TOKEN,
+                format!(
+                    "unexpected `{}` alongside `{}`",
+                    key.as_str().unwrap_or(""),
+                    entries[0].0.as_str().unwrap_or("")
+                ),
+                key.start,
+                key.end,
+            )
+            .hint = Some("the stages of a parallel block are in a list of their own".to_string());
+    }
+    Some(Batch { mode, contexts })
+}
+
+fn parse_stage(node: &Node, entries: &[(Node, Node)], source: &mut Source) -> Context {
+    let mut stage = Context {
+        source: Some(location(node, source)),
+        ..Default::default()
+    };
+    let mut has_steps = false;
+
+    for (key, value) in entries.iter() {
+        match key.as_str() {
+            Some("steps") => {
+                has_steps = true;
+                let steps = items(value, source).unwrap_or_default();
+                if steps.is_empty() && matches!(value.kind, Kind::Sequence(_)) {
+                    source
+                        .error(
+                            codes::EMPTY_STEPS,
+                            "`steps` has no steps".to_string(),
+                            key.start,
+                            key.end,
+                        )
+                        .hint = Some("add a step, e.g. `- sh: 'make'`".to_string());
+                }
+                stage.steps = parse_steps(steps, stage.uuid, source);
+            }
+            Some("environment") => {
+                stage.environment = Some(parse_environment(value, source));
+            }
+            Some("parallel") | Some("fanout") => {
+                source
+                    .error(
+                        codes::UNEXPECTED_TOKEN,
+                        format!("unexpected `{}` within a stage", key.as_str().unwrap_or("")),
+                        key.start,
+                        key.end,
+                    )
+                    .hint = Some("parallel blocks are items of the `stages`".to_string());
+            }
+            Some(property) => match value.as_str() {
+                Some(text) => {
+                    stage
+                        .properties
+                        .insert(property.to_string(), text.to_string());
+                }
+                None => {
+                    source
+                        .error(
+                            codes::UNEXPECTED_TOKEN,
+                            format!(
+                                "the property `{}` should be a string, not {}",
+                                property,
+                                value.describe()
+                            ),
+                            value.start,
+                            value.end,
+                        )
+                        .expected = vec!["`steps`".to_string(), "`environment`".to_string()];
+                }
+            },
+            None => mismatch(key, "a string", source),
+        }
+    }
+
+    if !has_steps {
+        source
+            .error(
+                codes::MISSING_STEPS,
+                "stage is missing `steps`".to_string(),
+                node.start,
+                node.end,
+            )
+            .hint = Some("every stage requires `steps`".to_string());
+    }
+    stage
+}
+
+fn is_identifier(symbol: &str) -> bool {
+    let mut chars = symbol.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_steps(nodes: &[Node], uuid: Uuid, source: &mut Source) -> Vec<Step> {
+    let mut steps = vec![];
+
+    for node in nodes.iter() {
+        let entries = match entries(node, source) {
+            Some(entries) => entries,
+            None => continue,
+        };
+
+        let mut step: Option<Step> = None;
+        let mut block = None;
+        for (key, value) in entries.iter() {
+            match key.as_str() {
+                Some("block") => {
+                    block = Some(parse_steps(
+                        items(value, source).unwrap_or_default(),
+                        uuid,
+                        source,
+                    ))
+                }
+                Some(symbol) if step.is_some() || !is_identifier(symbol) => {
+                    let message = if step.is_some() {
+                        format!("unexpected `{}`, a step only has one symbol", symbol)
+                    } else {
+                        format!("`{}` is not the symbol of a step", symbol)
+                    };
+                    source
+                        .error(codes::UNEXPECTED_TOKEN, message, key.start, key.end)
+                        .hint = Some(
+                        "each step is its own item of the list, e.g. `- sh: 'make'`".to_string(),
+                    );
+                }
+                Some(symbol) => {
+                    let parameters = match &value.kind {
+                        _ if value.is_null() => StepParameters::Positional(vec![]),
+                        Kind::Scalar(..) => StepParameters::Positional(vec![value.to_value()]),
+                        Kind::Sequence(items) => StepParameters::Positional(
+                            items.iter().map(|item| item.to_value()).collect(),
+                        ),
+                        Kind::Mapping(entries) => StepParameters::Keyword(
+                            entries
+                                .iter()
+                                .map(|(key, value)| {
+                                    (key.as_str().unwrap_or("").to_string(), value.to_value())
+                                })
+                                .collect(),
+                        ),
+                    };
+                    let mut created = Step::new(uuid, symbol.to_string(), parameters);
+                    created.source = Some(location(node, source));
+                    step = Some(created);
+                }
+                None => mismatch(key, "the symbol of a step", source),
+            }
+        }
+
+        match step {
+            Some(mut step) => {
+                step.block = block;
+                steps.push(step);
+            }
+            None => {
+                source
+                    .error(
+                        codes::UNEXPECTED_TOKEN,
+                        "the step has no symbol".to_string(),
+                        node.start,
+                        node.end,
+                    )
+                    .hint = Some("name the step to run, e.g. `- sh: 'make'`".to_string());
+            }
+        }
+    }
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syntax_from_path() {
+        assert_eq!(Syntax::from_path("pipeline.yml"), Syntax::Yaml);
+        assert_eq!(Syntax::from_path("ci/pipeline.YAML"), Syntax::Yaml);
+        assert_eq!(Syntax::from_path("pipeline.json"), Syntax::Json);
+        assert_eq!(Syntax::from_path("Ottofile"), Syntax::Otto);
+        assert_eq!(
+            Syntax::from_content_type("application/json; charset=utf-8"),
+            Some(Syntax::Json)
+        );
+        assert_eq!(Syntax::from_content_type("text/plain"), None);
+    }
+
+    #[test]
+    fn load_spans() {
+        let buffer = "pipeline:\n  stages:\n    - name: \"Build\"\n      steps: []\n";
+        let root = load(buffer, Syntax::Yaml).unwrap().unwrap();
+        let stages = match &root.kind {
+            Kind::Mapping(entries) => match &entries[0].1.kind {
+                Kind::Mapping(entries) => entries[0].1.clone(),
+                other => panic!("Unexpected {:?}", other),
+            },
+            other => panic!("Unexpected {:?}", other),
+        };
+        let stage = match &stages.kind {
+            Kind::Sequence(items) => items[0].clone(),
+            other => panic!("Unexpected {:?}", other),
+        };
+        let name = match &stage.kind {
+            Kind::Mapping(entries) => entries[0].1.clone(),
+            other => panic!("Unexpected {:?}", other),
+        };
+        assert_eq!(&buffer[name.start..name.end], "\"Build\"");
+        assert_eq!(&buffer[stage.start..stage.end], "name: \"Build\"");
+    }
+
+    #[test]
+    fn scalar_values() {
+        let root = load("[1, 1.5, true, ~, '1', text]", Syntax::Yaml)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            root.to_value(),
+            serde_json::json!([1, 1.5, true, null, "1", "text"])
+        );
+    }
+
+    #[test]
+    fn malformed_json() {
+        let diagnostic = load("{\n\t\"pipeline\": }", Syntax::Json).unwrap_err();
+        assert_eq!(diagnostic.code, codes::MALFORMED_DATA);
+        assert_eq!(diagnostic.start.line, 2);
+        assert_eq!(diagnostic.message, "expected value");
+    }
+
+    #[test]
+    fn anchors_refused() {
+        let buffer = "a: &a [x, x]\nb: [*a, *a]\n";
+        let diagnostic = load(buffer, Syntax::Yaml).unwrap_err();
+        assert_eq!(diagnostic.code, codes::MALFORMED_DATA);
+        assert_eq!(diagnostic.message, "anchors and aliases are not supported");
+        assert_eq!(diagnostic.start.line, 1);
+
+        let diagnostic = load("a: *missing\n", Syntax::Yaml).unwrap_err();
+        assert_eq!(diagnostic.code, codes::MALFORMED_DATA);
+    }
+}
@@ -0,0 +1,644 @@
+/*
+ * The diagnostic module contains the structures for reporting problems found
+ * while parsing a pipeline in a way that can be shown to users, either in a
+ * terminal or serialized as JSON by the parser service
+ */
+
+use crate::Rule;
+use pest::error::{Error as PestError, ErrorVariant, InputLocation};
+use serde::Serialize;
+use std::ops::Range;
+
+/**
+ * Stable error codes for the diagnostics, these should never be re-used for a
+ * different kind of problem
+ */
+pub mod codes {
+    /// The parser found something it did not expect
+    pub const UNEXPECTED_TOKEN: &str = "E0001";
+    /// The parser reached the end of the file while still expecting input
+    pub const UNEXPECTED_EOF: &str = "E0002";
+    /// A string was opened but never closed
+    pub const UNTERMINATED_STRING: &str = "E0003";
+    /// A double-quoted string contains an unsupported escape sequence
+    pub const INVALID_ESCAPE: &str = "E0004";
+    /// A stage was declared without a `steps` block
+    pub const MISSING_STEPS: &str = "E0005";
+    /// A `steps` block was declared without any steps in it
+    pub const EMPTY_STEPS: &str = "E0006";
+    /// A `matrix` block was declared without any axes
+    pub const MISSING_AXES: &str = "E0007";
+    /// A `matrix` block was declared without a stage to expand
+    pub const MISSING_STAGE: &str = "E0008";
+    /// An exclusion in a `matrix` refers to an axis, or an axis value, which
+    /// was not declared
+    pub const UNKNOWN_AXIS: &str = "E0009";
+    /// A `${...}` reference in a string is malformed or refers to nothing
+    pub const INVALID_INTERPOLATION: &str = "E0010";
+    /// A pipeline parameter is missing its name, has an unknown type or a
+    /// default which does not match its type, or is declared twice
+    pub const INVALID_PARAMETER: &str = "E0011";
+    /// A stage needs a stage which does not exist
+    pub const MISSING_DEPENDENCY: &str = "E0012";
+    /// The needs of the stages form a cycle, so none of them could ever start
+    pub const CYCLIC_DEPENDENCY: &str = "E0013";
+    /// An imported library could not be read, or is not allowed to be
+    pub const IMPORT_FAILED: &str = "E0014";
+    /// A library imports itself, directly or through other libraries
+    pub const CYCLIC_IMPORT: &str = "E0015";
+    /// A `use` names a template which no imported library defines
+    pub const UNKNOWN_TEMPLATE: &str = "E0016";
+    /// A macro was called with arguments or a block, neither of which macros
+    /// take
+    pub const INVALID_MACRO_CALL: &str = "E0017";
+    /// The label expression of an `agent` block is malformed, or the block
+    /// has no label
+    pub const INVALID_LABEL: &str = "E0018";
+    /// A trigger is malformed, such as a cron schedule without five fields
+    pub const INVALID_TRIGGER: &str = "E0019";
+    /// A pipeline written in YAML or JSON is not valid YAML or JSON
+    pub const MALFORMED_DATA: &str = "E0020";
+    /// Part of a Jenkinsfile has no equivalent in the pipeline syntax, so it
+    /// was left out when converting to an Ottofile
+    pub const UNSUPPORTED: &str = "E0021";
+    /// An axis of a `matrix` is declared more than once
+    pub const DUPLICATE_AXIS: &str = "E0022";
+    /// A `matrix` would expand into more stages than are allowed
+    pub const MATRIX_TOO_LARGE: &str = "E0023";
+    /// A number is too large to be represented, such as `1e400`
+    pub const NUMBER_OUT_OF_RANGE: &str = "E0024";
+
+    /*
+     * Codes from E0100 onwards are found by validating the pipeline against
+     * the manifests of the available steps, see `otto_models::osp::codes`
+     */
+    pub use otto_models::osp::codes::{
+        MISSING_PARAMETER, TOO_MANY_ARGUMENTS, TYPE_MISMATCH, UNEXPECTED_BLOCK, UNKNOWN_PARAMETER,
+        UNKNOWN_STEP,
+    };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/**
+ * A location within the parsed buffer
+ *
+ * Lines and columns start at 1, whereas the offset is the zero-indexed byte
+ * offset into the buffer. A line of zero means the location is unknown, such
+ * as for pipelines which were not parsed from a file.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+    pub offset: usize,
+}
+
+impl Position {
+    /**
+     * Compute the position of the given byte offset within the buffer
+     */
+    pub fn from_offset(buffer: &str, offset: usize) -> Self {
+        let offset = offset.min(buffer.len());
+        let before = &buffer[..offset];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+
+        Self {
+            line,
+            column: before[line_start..].chars().count() + 1,
+            offset,
+        }
+    }
+}
+
+/**
+ * A Diagnostic describes a single problem found in a pipeline
+ */
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// A stable error code from the `codes` module, e.g. E0001
+    pub code: &'static str,
+    pub message: String,
+    /// The imported library the problem was found in, the positions are
+    /// within that file rather than the pipeline
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// The line of the imported library which the problem starts on, kept
+    /// for rendering since the buffer being rendered is the pipeline's
+    #[serde(skip)]
+    pub library_line: Option<String>,
+    pub start: Position,
+    pub end: Position,
+    /// Human-readable descriptions of what the parser expected to find
+    pub expected: Vec<String>,
+    /// A suggestion for how the user might fix the problem
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    /**
+     * Create an error diagnostic covering the given byte offsets of the buffer
+     */
+    pub fn error(
+        code: &'static str,
+        message: impl Into<String>,
+        buffer: &str,
+        start: usize,
+        end: usize,
+    ) -> Self {
+        Self {
+            severity: Severity::Error,
+            code,
+            message: message.into(),
+            file: None,
+            library_line: None,
+            start: Position::from_offset(buffer, start),
+            end: Position::from_offset(buffer, end),
+            expected: vec![],
+            hint: None,
+        }
+    }
+
+    /**
+     * Create a warning diagnostic covering the given byte offsets of the buffer
+     */
+    pub fn warning(
+        code: &'static str,
+        message: impl Into<String>,
+        buffer: &str,
+        start: usize,
+        end: usize,
+    ) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(code, message, buffer, start, end)
+        }
+    }
+
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    /**
+     * Convert the error from pest into a diagnostic, using the buffer which was
+     * parsed to describe what was found
+     *
+     * The window is the range of the buffer which pest was given to parse,
+     * which is only a single statement when re-parsing statements which could
+     * not be parsed the first time around
+     */
+    pub(crate) fn from_pest(error: PestError<Rule>, buffer: &str, window: Range<usize>) -> Self {
+        let mut offset = window.start
+            + match error.location {
+                InputLocation::Pos(offset) => offset,
+                InputLocation::Span((start, _end)) => start,
+            };
+        // Keywords which are misspelled, e.g. `stagee`, fail part way through
+        // the word, in which case the whole word is what was unexpected
+        if buffer[offset..].starts_with(is_word) {
+            offset = buffer[window.start..offset]
+                .rfind(|c| !is_word(c))
+                .map(|i| window.start + i + 1)
+                .unwrap_or(window.start);
+        }
+        let statement = window.end < buffer.len();
+
+        if let Some(diagnostic) = string_diagnostic(buffer, offset) {
+            return diagnostic;
+        }
+
+        let rules = match error.variant {
+            ErrorVariant::ParsingError { positives, .. } => positives,
+            ErrorVariant::CustomError { message } => {
+                return Self::error(codes::UNEXPECTED_TOKEN, message, buffer, offset, offset);
+            }
+        };
+
+        let mut expected: Vec<String> = vec![];
+        for rule in rules.iter().flat_map(alternatives) {
+            if statement && rule == Rule::EOI {
+                continue;
+            }
+            if let Some(description) = describe(&rule) {
+                if !expected.contains(&description) {
+                    expected.push(description);
+                }
+            }
+        }
+        // The only literal which cannot be attributed to a rule, which may come
+        // wherever an import may in a pipeline, but not in a library
+        if (expected.is_empty() && buffer[..offset].trim().is_empty())
+            || (rules.contains(&Rule::import) && !rules.contains(&Rule::template))
+        {
+            expected.push("`pipeline`".to_string());
+        }
+
+        let token = found_token(&buffer[offset..]);
+        let end_of_line = statement && offset >= window.end && token.trim().is_empty();
+
+        let mut diagnostic = if end_of_line {
+            Self::error(
+                codes::UNEXPECTED_TOKEN,
+                "unexpected end of line",
+                buffer,
+                offset,
+                offset,
+            )
+        } else if buffer[offset..].trim().is_empty() {
+            let diagnostic = Self::error(
+                codes::UNEXPECTED_EOF,
+                "unexpected end of file",
+                buffer,
+                offset,
+                offset,
+            );
+            if buffer.trim().is_empty() {
+                diagnostic
+            } else {
+                diagnostic.with_hint("check for a missing closing `}`")
+            }
+        } else {
+            Self::error(
+                codes::UNEXPECTED_TOKEN,
+                format!("unexpected `{}`", token),
+                buffer,
+                offset,
+                offset + token.len(),
+            )
+        };
+
+        if diagnostic.hint.is_none() && rules == [Rule::STR] {
+            diagnostic.hint = Some("property values must be quoted strings".to_string());
+        }
+        diagnostic.expected = expected;
+        diagnostic
+    }
+
+    /**
+     * Create a diagnostic for whatever token is found at the offset, for when
+     * there is nothing more specific to say about it
+     */
+    pub(crate) fn unexpected(buffer: &str, offset: usize) -> Self {
+        let token = found_token(&buffer[offset..]);
+        Self::error(
+            codes::UNEXPECTED_TOKEN,
+            format!("unexpected `{}`", token),
+            buffer,
+            offset,
+            offset + token.len(),
+        )
+    }
+
+    /**
+     * Render the diagnostic for display in a terminal, showing the offending
+     * line of the buffer with a caret underneath the problem
+     *
+     * Problems found in imported libraries are shown with the line of the
+     * library they were found on, rather than a line of the buffer
+     */
+    pub fn render(&self, buffer: &str) -> String {
+        if self.start.line == 0 {
+            return format!("{}: {}\n", self.heading(), self.message);
+        }
+
+        let line = match (&self.file, &self.library_line) {
+            (_, Some(line)) => line.as_str(),
+            // Without the library's line, none of the buffer's lines would be
+            // the right one to show
+            (Some(_), None) => "",
+            (None, None) => buffer.lines().nth(self.start.line - 1).unwrap_or(""),
+        };
+        let gutter = " ".repeat(self.start.line.to_string().len());
+
+        let width = if self.end.line == self.start.line {
+            self.end.column.saturating_sub(self.start.column).max(1)
+        } else {
+            (line.chars().count() + 1)
+                .saturating_sub(self.start.column)
+                .max(1)
+        };
+
+        let mut rendered = format!(
+            "{}: {}\n{}--> {}{}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+            self.heading(),
+            self.message,
+            gutter,
+            self.file
+                .as_ref()
+                .map(|f| format!("{}:", f))
+                .unwrap_or_default(),
+            self.start.line,
+            self.start.column,
+            gutter,
+            self.start.line,
+            line,
+            gutter,
+            " ".repeat(self.start.column - 1),
+            "^".repeat(width),
+        );
+
+        if !self.expected.is_empty() {
+            rendered.push_str(&format!(
+                "{} = expected {}\n",
+                gutter,
+                join_expected(&self.expected)
+            ));
+        }
+        if let Some(hint) = &self.hint {
+            rendered.push_str(&format!("{} = hint: {}\n", gutter, hint));
+        }
+        rendered
+    }
+
+    fn heading(&self) -> String {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        format!("{}[{}]", severity, self.code)
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.start.line == 0 {
+            return write!(f, "{}: {}", self.heading(), self.message);
+        }
+        write!(
+            f,
+            "{}: {} at {}{}:{}",
+            self.heading(),
+            self.message,
+            self.file
+                .as_ref()
+                .map(|f| format!("{}:", f))
+                .unwrap_or_default(),
+            self.start.line,
+            self.start.column
+        )
+    }
+}
+
+/**
+ * Return the rules which the statement rule could have started with
+ *
+ * Pest reports the statement rule itself when none of its alternatives could
+ * be matched, which is meaningless to the user
+ */
+fn alternatives(rule: &Rule) -> Vec<Rule> {
+    match rule {
+        Rule::execStatement => vec![
+            Rule::stage,
+            Rule::steps,
+            Rule::parallel,
+            Rule::fanout,
+            Rule::matrix,
+            Rule::environment,
+            Rule::parameters,
+            Rule::triggers,
+            Rule::post,
+            Rule::useTemplate,
+        ],
+        Rule::parallelStatement => vec![Rule::stage, Rule::useTemplate],
+        Rule::libraryStatement => vec![Rule::template, Rule::stepMacro],
+        Rule::stageStatement => vec![
+            Rule::agent,
+            Rule::environment,
+            Rule::needs,
+            Rule::property,
+            Rule::when,
+            Rule::steps,
+            Rule::post,
+        ],
+        Rule::matrixStatement => vec![Rule::axes, Rule::excludes, Rule::stage],
+        Rule::triggersStatement => {
+            vec![Rule::cronTrigger, Rule::pushTrigger, Rule::upstreamTrigger]
+        }
+        Rule::whenStatement => vec![
+            Rule::whenBranch,
+            Rule::whenEnvironment,
+            Rule::whenChangeset,
+            Rule::whenNot,
+            Rule::whenAnyOf,
+            Rule::whenAllOf,
+        ],
+        other => vec![*other],
+    }
+}
+
+/**
+ * Return a human-readable description of the given grammar rule
+ */
+fn describe(rule: &Rule) -> Option<String> {
+    let description = match rule {
+        Rule::BLOCK_BEGIN => "`{`".to_string(),
+        Rule::BLOCK_END => "`}`".to_string(),
+        Rule::COMMA => "`,`".to_string(),
+        Rule::IDENT => "an identifier".to_string(),
+        Rule::STR => "a string".to_string(),
+        Rule::args => "arguments".to_string(),
+        Rule::kwarg => "a keyword argument".to_string(),
+        Rule::property => "a property".to_string(),
+        Rule::block => "a block".to_string(),
+        Rule::step => "a step".to_string(),
+        Rule::axis => "an axis".to_string(),
+        Rule::parameter | Rule::parameterType => "a parameter".to_string(),
+        Rule::useTemplate => "`use`".to_string(),
+        Rule::agentLabel => "`label`".to_string(),
+        Rule::cronTrigger => "`cron`".to_string(),
+        Rule::pushTrigger => "`push`".to_string(),
+        Rule::upstreamTrigger => "`upstream`".to_string(),
+        Rule::stepMacro => "`macro`".to_string(),
+        Rule::postCondition | Rule::postStatus => "a post condition".to_string(),
+        Rule::whenBranch => "`branch`".to_string(),
+        Rule::whenEnvironment => "`environment`".to_string(),
+        Rule::whenChangeset => "`changeset`".to_string(),
+        Rule::whenNot => "`not`".to_string(),
+        Rule::whenAnyOf => "`anyOf`".to_string(),
+        Rule::whenAllOf => "`allOf`".to_string(),
+        Rule::list => "a list".to_string(),
+        Rule::map => "a map".to_string(),
+        Rule::integer | Rule::float => "a number".to_string(),
+        Rule::boolean => "a boolean".to_string(),
+        Rule::EOI => "end of file".to_string(),
+        // Recovering from errors is never something the user should aim for
+        Rule::invalid => return None,
+        // The pipeline block is described along with the imports which may
+        // come before it
+        Rule::pipelineBlock => return None,
+        // The remaining rules are keyword blocks, e.g. `stage`
+        other => format!("`{:?}`", other),
+    };
+    Some(description)
+}
+
+fn join_expected(expected: &[String]) -> String {
+    match expected.len() {
+        0 => "".to_string(),
+        1 => expected[0].clone(),
+        len => format!(
+            "{}, or {}",
+            expected[..len - 1].join(", "),
+            expected[len - 1]
+        ),
+    }
+}
+
+/**
+ * Return the token at the start of the buffer, either a word or a single
+ * character, which is the most useful thing to show the user as having been
+ * unexpected
+ */
+fn found_token(buffer: &str) -> &str {
+    let word = buffer
+        .char_indices()
+        .find(|(_, c)| !is_word(*c))
+        .map(|(i, _)| i)
+        .unwrap_or_else(|| buffer.len());
+
+    if word > 0 {
+        &buffer[..word]
+    } else {
+        buffer
+            .chars()
+            .next()
+            .map(|c| &buffer[..c.len_utf8()])
+            .unwrap_or("")
+    }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/**
+ * Failures at the start of a string are almost always problems inside of the
+ * string itself, which pest only reports as the string not matching. This
+ * function looks into the string to report the actual problem.
+ */
+fn string_diagnostic(buffer: &str, offset: usize) -> Option<Diagnostic> {
+    let rest = &buffer[offset..];
+
+    let quote = ["'''", "\"\"\"", "'", "\""]
+        .iter()
+        .find(|quote| rest.starts_with(*quote))?;
+    let escapes = quote.starts_with('"');
+    let body = offset + quote.len();
+
+    let mut chars = buffer[body..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        if buffer[body + i..].starts_with(quote) {
+            // The string itself is fine, the problem must be elsewhere
+            return None;
+        }
+
+        if escapes && c == '\\' {
+            match chars.next() {
+                Some((_, 'n')) | Some((_, 't')) | Some((_, 'r')) | Some((_, '"'))
+                | Some((_, '\'')) | Some((_, '$')) | Some((_, '\\')) => {}
+                Some((_, other)) => {
+                    let start = body + i;
+                    return Some(
+                        Diagnostic::error(
+                            codes::INVALID_ESCAPE,
+                            format!("unknown escape sequence `\\{}`", other),
+                            buffer,
+                            start,
+                            start + 1 + other.len_utf8(),
+                        )
+                        .with_hint(
+                            "supported escapes are \\n, \\t, \\r, \\\", \\', \\$ and \\\\, \
+                            single-quoted strings are not escaped",
+                        ),
+                    );
+                }
+                None => break,
+            }
+        }
+    }
+
+    Some(
+        Diagnostic::error(
+            codes::UNTERMINATED_STRING,
+            "unterminated string",
+            buffer,
+            offset,
+            offset + quote.len(),
+        )
+        .with_hint(format!("add a closing {} to the string", quote)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn position_from_offset() {
+        let buffer = "pipeline {\n  steps\n}";
+        let position = Position::from_offset(buffer, 13);
+        assert_eq!(position.line, 2);
+        assert_eq!(position.column, 3);
+        assert_eq!(position.offset, 13);
+    }
+
+    #[test]
+    fn position_past_the_end() {
+        let buffer = "pipeline";
+        let position = Position::from_offset(buffer, 100);
+        assert_eq!(position.column, 9);
+        assert_eq!(position.offset, 8);
+    }
+
+    #[test]
+    fn found_token_word() {
+        assert_eq!(found_token("bad! }"), "bad");
+        assert_eq!(found_token("! }"), "!");
+        assert_eq!(found_token(""), "");
+    }
+
+    #[test]
+    fn join_expected_list() {
+        let expected = vec!["`{`".to_string(), "`}`".to_string(), "a step".to_string()];
+        assert_eq!(join_expected(&expected), "`{`, `}`, or a step");
+        assert_eq!(join_expected(&expected[..1]), "`{`");
+    }
+
+    #[test]
+    fn render_caret() {
+        let buffer = "pipeline {\n    bad\n}";
+        let diagnostic =
+            Diagnostic::error(codes::UNEXPECTED_TOKEN, "unexpected `bad`", buffer, 15, 18)
+                .with_hint("try harder");
+        let rendered = diagnostic.render(buffer);
+        assert_eq!(
+            rendered,
+            "error[E0001]: unexpected `bad`\n --> 2:5\n  |\n2 |     bad\n  |     ^^^\n  = hint: try harder\n"
+        );
+    }
+
+    #[test]
+    fn serialize_diagnostic() {
+        let buffer = "pipeline";
+        let diagnostic = Diagnostic::error(
+            codes::UNEXPECTED_EOF,
+            "unexpected end of file",
+            buffer,
+            8,
+            8,
+        );
+        let json = serde_json::to_value(&diagnostic).expect("Failed to serialize");
+        assert_eq!(json["severity"], "error");
+        assert_eq!(json["code"], "E0002");
+        assert_eq!(json["start"]["line"], 1);
+        assert_eq!(json["start"]["column"], 9);
+    }
+}
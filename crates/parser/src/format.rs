@@ -0,0 +1,714 @@
+/*
+ * The format module turns pipeline source into its canonical formatting
+ *
+ * Formatting works from the syntax tree of the pipeline rather than the parsed
+ * Pipeline model, so that the comments can be carried over, and so that the
+ * literals are written back exactly as the user wrote them.
+ */
+
+use crate::library::Imports;
+use crate::lower::{lower_file, Source};
+use crate::syntax::{SyntaxElement, SyntaxKind, SyntaxTree};
+use crate::{Diagnostic, Parsed};
+
+/// The indentation used for each level of nesting
+const INDENT: &str = "    ";
+
+/**
+ * Format the buffer as a canonical Ottofile
+ *
+ * The buffer must be a valid pipeline, otherwise the diagnostics describing
+ * why it is not are returned. Its imports are never read, so the libraries it
+ * imports are not checked.
+ */
+pub fn format_pipeline_string(buffer: &str) -> Result<String, Vec<Diagnostic>> {
+    let tree = SyntaxTree::parse(buffer);
+    if tree.has_errors() {
+        return Err(tree.diagnostics().cloned().collect());
+    }
+    let mut source = Source::from_tree(None, &tree, Imports::skipped());
+    let pipeline = lower_file(&tree, &mut source);
+    Parsed {
+        pipeline,
+        diagnostics: source.diagnostics,
+    }
+    .into_result()?;
+
+    let comments = tree
+        .root()
+        .tokens()
+        .into_iter()
+        .filter(|token| SyntaxKind::Comment == token.kind())
+        .map(|token| (token.start(), token.end()))
+        .collect();
+    let mut formatter = Formatter::new(buffer, comments);
+
+    for element in tree.root().children() {
+        match element.kind() {
+            SyntaxKind::Import => formatter.statement(element),
+            SyntaxKind::Pipeline => formatter.block(element, "pipeline".to_string()),
+            _ => {}
+        }
+    }
+    formatter.comments_before(buffer.len());
+    Ok(formatter.out)
+}
+
+/**
+ * The Formatter writes out the formatted pipeline, carrying along the comments
+ * from the source as it goes
+ */
+struct Formatter<'a> {
+    buffer: &'a str,
+    /// The byte ranges of the comments in the buffer, in order
+    comments: Vec<(usize, usize)>,
+    /// The index of the next comment which has not been written out
+    next_comment: usize,
+    /// The offset in the buffer of the last thing written out
+    last: usize,
+    /// Blank lines are never written right after opening a block
+    block_start: bool,
+    depth: usize,
+    out: String,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(buffer: &'a str, comments: Vec<(usize, usize)>) -> Self {
+        Self {
+            buffer,
+            comments,
+            next_comment: 0,
+            last: 0,
+            block_start: true,
+            depth: 0,
+            out: String::new(),
+        }
+    }
+
+    fn indent(&self) -> String {
+        INDENT.repeat(self.depth)
+    }
+
+    /**
+     * Write out every comment which starts before the given offset, each on a
+     * line of its own
+     */
+    fn comments_before(&mut self, offset: usize) {
+        while let Some(&(start, end)) = self.comments.get(self.next_comment) {
+            if start >= offset {
+                break;
+            }
+            self.begin_line(start);
+            self.out.push_str(&self.buffer[start..end]);
+            self.out.push('\n');
+            self.last = end;
+            self.next_comment += 1;
+        }
+    }
+
+    /**
+     * Write out a comment which follows on the same line as the given offset,
+     * with nothing else in between. Comments which follow another statement
+     * on the same line are left to that statement.
+     */
+    fn trailing_comment(&mut self, offset: usize) {
+        if let Some(&(start, end)) = self.comments.get(self.next_comment) {
+            if start >= offset
+                && self.buffer[offset..start]
+                    .trim_matches(&[' ', '\t'][..])
+                    .is_empty()
+            {
+                self.out.push(' ');
+                self.out.push_str(&self.buffer[start..end]);
+                self.last = end;
+                self.next_comment += 1;
+            }
+        }
+        self.out.push('\n');
+        self.last = self.last.max(offset);
+    }
+
+    /**
+     * Start a new line for something found at the offset, keeping a single
+     * blank line if the source had any before it
+     */
+    fn begin_line(&mut self, offset: usize) {
+        if !self.block_start
+            && offset > self.last
+            && self.buffer[self.last..offset].matches('\n').count() > 1
+        {
+            self.out.push('\n');
+        }
+        self.block_start = false;
+        self.out.push_str(&self.indent());
+    }
+
+    /**
+     * Open a block with the given header, the element being the opening brace
+     */
+    fn open(&mut self, brace: &SyntaxElement, header: String) {
+        self.comments_before(brace.start());
+        self.begin_line(brace.start());
+        self.out.push_str(&header);
+        self.out.push_str(" {");
+        self.trailing_comment(brace.end());
+        self.depth += 1;
+        self.block_start = true;
+    }
+
+    /**
+     * Close the block, the element being the closing brace
+     */
+    fn close(&mut self, brace: &SyntaxElement) {
+        self.comments_before(brace.start());
+        self.depth -= 1;
+        // Empty blocks are closed on the same line they were opened
+        if self.block_start && self.out.ends_with(" {\n") {
+            self.out.pop();
+            self.out.push_str(" }");
+        } else {
+            self.out.push_str(&self.indent());
+            self.out.push('}');
+        }
+        self.block_start = false;
+        self.trailing_comment(brace.end());
+    }
+
+    /**
+     * Write out a statement which fits on a single line, any comments inside
+     * of the statement are moved before it
+     */
+    fn line(&mut self, element: &SyntaxElement, text: &str) {
+        self.comments_before(element.end());
+        self.begin_line(element.start());
+        self.out.push_str(text);
+        self.trailing_comment(element.end());
+    }
+
+    /**
+     * Write out a block statement, e.g. `stage`, along with its contents
+     */
+    fn block(&mut self, element: &SyntaxElement, header: String) {
+        for child in element.children() {
+            match child.kind() {
+                SyntaxKind::LBrace => self.open(child, header.clone()),
+                SyntaxKind::RBrace => self.close(child),
+                _ => self.statement(child),
+            }
+        }
+    }
+
+    fn statement(&mut self, element: &SyntaxElement) {
+        match element.kind() {
+            SyntaxKind::Stage => self.block(element, "stage".to_string()),
+            SyntaxKind::Steps => self.block(element, "steps".to_string()),
+            SyntaxKind::Parallel => self.block(element, "parallel".to_string()),
+            SyntaxKind::Fanout => self.block(element, "fanout".to_string()),
+            SyntaxKind::Matrix => self.block(element, "matrix".to_string()),
+            SyntaxKind::Post => self.block(element, "post".to_string()),
+            SyntaxKind::When => self.block(element, "when".to_string()),
+            SyntaxKind::WhenNot => self.block(element, "not".to_string()),
+            SyntaxKind::WhenAnyOf => self.block(element, "anyOf".to_string()),
+            SyntaxKind::WhenAllOf => self.block(element, "allOf".to_string()),
+            SyntaxKind::Triggers => self.block(element, "triggers".to_string()),
+            SyntaxKind::WhenBranch
+            | SyntaxKind::WhenChangeset
+            | SyntaxKind::CronTrigger
+            | SyntaxKind::PushTrigger
+            | SyntaxKind::UpstreamTrigger => {
+                let keyword = element.text().split_whitespace().next().unwrap_or("");
+                let value = self.first(element, SyntaxKind::Str);
+                self.line(element, &format!("{} {}", keyword, value));
+            }
+            SyntaxKind::WhenEnvironment => {
+                let values: Vec<String> = element
+                    .inner()
+                    .filter(|p| SyntaxKind::Str == p.kind())
+                    .map(|p| self.value(p))
+                    .collect();
+                let text = match values.as_slice() {
+                    [name, value] => format!("environment name: {}, value: {}", name, value),
+                    [name] => format!("environment name: {}", name),
+                    _ => element.text().to_string(),
+                };
+                self.line(element, &text);
+            }
+            SyntaxKind::PostCondition => {
+                let condition = element.inner().next().map(|p| p.text()).unwrap_or("");
+                self.block(element, condition.to_string())
+            }
+            SyntaxKind::Axes => self.block(element, "axes".to_string()),
+            SyntaxKind::Excludes => self.block(element, "excludes".to_string()),
+            SyntaxKind::Exclude => self.block(element, "exclude".to_string()),
+            SyntaxKind::Environment => self.block(element, "environment".to_string()),
+            SyntaxKind::Agent => self.block(element, "agent".to_string()),
+            SyntaxKind::AgentLabel => {
+                let label = self.first(element, SyntaxKind::Str);
+                self.line(element, &format!("label {}", label));
+            }
+            SyntaxKind::Parameters => self.block(element, "parameters".to_string()),
+            SyntaxKind::Parameter => {
+                let mut kind = "";
+                let mut arguments = vec![];
+                for part in element.inner() {
+                    match part.kind() {
+                        SyntaxKind::Keyword => kind = part.text(),
+                        SyntaxKind::Kwarg => arguments.push(self.kwarg(part)),
+                        _ => {}
+                    }
+                }
+                self.line(element, &format!("{} {}", kind, arguments.join(", ")));
+            }
+            SyntaxKind::Property => {
+                let mut inner = element.inner();
+                let key = inner.next().map(|p| p.text()).unwrap_or("");
+                let value = inner.next().map(|p| self.value(p)).unwrap_or_default();
+                self.line(element, &format!("{} = {}", key, value));
+            }
+            SyntaxKind::Needs => {
+                let names: Vec<String> = element
+                    .inner()
+                    .filter(|p| SyntaxKind::Str == p.kind())
+                    .map(|p| self.value(p))
+                    .collect();
+                self.line(element, &format!("needs = [{}]", names.join(", ")));
+            }
+            SyntaxKind::Axis => {
+                let mut inner = element.inner();
+                let name = inner.next().map(|p| p.text()).unwrap_or("");
+                let values: Vec<String> = inner
+                    .filter(|p| SyntaxKind::Str == p.kind())
+                    .map(|p| self.value(p))
+                    .collect();
+                self.line(element, &format!("{} = [{}]", name, values.join(", ")));
+            }
+            SyntaxKind::Import => {
+                let path = self.first(element, SyntaxKind::Str);
+                self.line(element, &format!("import {}", path));
+            }
+            SyntaxKind::UseTemplate => {
+                let name = self.first(element, SyntaxKind::Ident);
+                self.line(element, &format!("use {}", name));
+            }
+            SyntaxKind::Step => self.step(element),
+            _ => {}
+        }
+    }
+
+    /**
+     * Format the first element of the given kind within the element, which
+     * follows the keyword of the statement
+     */
+    fn first(&self, element: &SyntaxElement, kind: SyntaxKind) -> String {
+        element
+            .inner()
+            .find(|p| kind == p.kind())
+            .map(|p| self.value(p))
+            .unwrap_or_default()
+    }
+
+    /**
+     * Write out a step, which has its arguments wrapped in parenthesis when it
+     * is followed by a block
+     */
+    fn step(&mut self, element: &SyntaxElement) {
+        let mut symbol = "";
+        let mut arguments = vec![];
+        let mut block = None;
+
+        for part in element.inner() {
+            match part.kind() {
+                SyntaxKind::Ident => symbol = part.text(),
+                SyntaxKind::Args => {
+                    arguments.extend(part.inner().map(|value| self.value(value)));
+                }
+                SyntaxKind::Kwarg => arguments.push(self.kwarg(part)),
+                SyntaxKind::Block => block = Some(part),
+                _ => {}
+            }
+        }
+
+        let arguments = arguments.join(", ");
+        match block {
+            Some(block) => {
+                let header = if arguments.is_empty() {
+                    symbol.to_string()
+                } else {
+                    format!("{}({})", symbol, arguments)
+                };
+                self.block(block, header);
+            }
+            None if arguments.is_empty() => self.line(element, &format!("{}()", symbol)),
+            None => self.line(element, &format!("{} {}", symbol, arguments)),
+        }
+    }
+
+    fn kwarg(&self, element: &SyntaxElement) -> String {
+        let mut inner = element.inner();
+        match (inner.next(), inner.next()) {
+            (Some(key), Some(value)) => format!("{}: {}", key.text(), self.value(value)),
+            _ => "".to_string(),
+        }
+    }
+
+    fn value(&self, element: &SyntaxElement) -> String {
+        match element.kind() {
+            SyntaxKind::Str => self.string(element),
+            SyntaxKind::List => {
+                let values: Vec<String> = element.inner().map(|p| self.value(p)).collect();
+                format!("[{}]", values.join(", "))
+            }
+            SyntaxKind::Map => {
+                let mut entries = vec![];
+                for entry in element.inner() {
+                    if SyntaxKind::MapEntry == entry.kind() {
+                        let mut inner = entry.inner();
+                        if let (Some(key), Some(value)) = (inner.next(), inner.next()) {
+                            let key = match key.kind() {
+                                SyntaxKind::Str => self.string(key),
+                                _ => key.text().to_string(),
+                            };
+                            entries.push(format!("{}: {}", key, self.value(value)));
+                        }
+                    }
+                }
+                if entries.is_empty() {
+                    "[:]".to_string()
+                } else {
+                    format!("[{}]", entries.join(", "))
+                }
+            }
+            // Numbers and booleans are kept exactly as they were written
+            _ => element.text().to_string(),
+        }
+    }
+
+    /**
+     * Strings are kept as they were written, except for multi-line strings
+     * which are re-indented to one level deeper than the current line. Since
+     * the common indentation of multi-line strings is stripped, this does not
+     * change their value.
+     */
+    fn string(&self, element: &SyntaxElement) -> String {
+        let text = element.text();
+        let quote = &text[..3.min(text.len())];
+
+        if quote != "'''" && quote != "\"\"\"" {
+            return text.to_string();
+        }
+
+        let content = &text[3..text.len() - 3];
+        let last_line = content.rsplit('\n').next().unwrap_or("");
+
+        // Only strings laid out as a block of lines between the quotes can be
+        // re-indented without changing their value
+        if !content.starts_with('\n') || !last_line.trim().is_empty() || content.contains('\r') {
+            return text.to_string();
+        }
+
+        let lines: Vec<&str> = content[1..content.len() - last_line.len()]
+            .split('\n')
+            .collect();
+        let common = lines
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.len() - line.trim_start_matches(&[' ', '\t'][..]).len())
+            .min()
+            .unwrap_or(0);
+        let indent = INDENT.repeat(self.depth + 1);
+
+        let mut formatted = format!("{}\n", quote);
+        for line in lines.iter().take(lines.len() - 1) {
+            if !line.trim().is_empty() {
+                formatted.push_str(&indent);
+                formatted.push_str(&line[common..]);
+            }
+            formatted.push('\n');
+        }
+        formatted.push_str(&self.indent());
+        formatted.push_str(quote);
+        formatted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_simple() {
+        let buf = "pipeline { stage { name = 'Build'\n steps { sh 'ls' } } }";
+        let formatted = format_pipeline_string(buf).expect("Failed to format");
+        assert_eq!(
+            formatted,
+            "pipeline {\n    stage {\n        name = 'Build'\n        steps {\n            sh 'ls'\n        }\n    }\n}\n"
+        );
+    }
+
+    #[test]
+    fn format_invalid() {
+        let diagnostics =
+            format_pipeline_string("pipeline { steps { } }").expect_err("Should fail");
+        assert_eq!(diagnostics.len(), 1);
+
+        let diagnostics =
+            format_pipeline_string("pipeline {\n  stages { }\n  steps { sh 'ls' !\n}\n}")
+                .expect_err("Should fail");
+        let lines: Vec<usize> = diagnostics.iter().map(|d| d.start.line).collect();
+        assert_eq!(lines, vec![2, 3]);
+    }
+
+    #[test]
+    fn format_arguments() {
+        let buf = r#"pipeline {
+  steps {
+    sh( 'ls' )
+    git url :'https://example.com',branch:  "main"
+    example [ 'a',1 ,[true] ], [ name:'otto' ,'key' :  2 ], [ : ], 1.5e3
+    dir 'a' { sh 'pwd' }
+    noargs()
+    parallelish {
+    }
+  }
+}"#;
+        let formatted = format_pipeline_string(buf).expect("Failed to format");
+        assert_eq!(
+            formatted,
+            r#"pipeline {
+    steps {
+        sh 'ls'
+        git url: 'https://example.com', branch: "main"
+        example ['a', 1, [true]], [name: 'otto', 'key': 2], [:], 1.5e3
+        dir('a') {
+            sh 'pwd'
+        }
+        noargs()
+        parallelish { }
+    }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn format_comments() {
+        let buf = r#"// The pipeline
+pipeline { // trailing
+  /* Before
+     the stage */
+  stage {
+    name = 'Build' // a name
+
+
+    steps {
+      sh 'ls' /* inside */, 'not a // comment'
+      // at the end
+    }
+  }
+}
+// Done"#;
+        let formatted = format_pipeline_string(buf).expect("Failed to format");
+        assert_eq!(
+            formatted,
+            r#"// The pipeline
+pipeline { // trailing
+    /* Before
+     the stage */
+    stage {
+        name = 'Build' // a name
+
+        steps {
+            /* inside */
+            sh 'ls', 'not a // comment'
+            // at the end
+        }
+    }
+}
+// Done
+"#
+        );
+    }
+
+    #[test]
+    fn format_trailing_comments() {
+        let buf = r#"pipeline {
+  stage { name = 'A' // why
+    steps { sh 'ls', /* mid */ 'la' }
+  }
+}"#;
+        let formatted = format_pipeline_string(buf).expect("Failed to format");
+        assert_eq!(
+            formatted,
+            r#"pipeline {
+    stage {
+        name = 'A' // why
+        steps {
+            /* mid */
+            sh 'ls', 'la'
+        }
+    }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn format_multiline_strings() {
+        let buf = "pipeline {\nsteps {\nsh '''\n  make\n\n    make install\n  '''\nsh '''inline\n  '''\n}\n}";
+        let formatted = format_pipeline_string(buf).expect("Failed to format");
+        assert_eq!(
+            formatted,
+            "pipeline {\n    steps {\n        sh '''\n            make\n\n              make install\n        '''\n        sh '''inline\n  '''\n    }\n}\n"
+        );
+    }
+
+    #[test]
+    fn format_matrix() {
+        let buf = "pipeline { matrix { axes { OS = [ 'linux','macos', ] }\n excludes { exclude { OS = 'macos' } }\n stage { steps { sh 'make' } } } }";
+        let formatted = format_pipeline_string(buf).expect("Failed to format");
+        assert_eq!(
+            formatted,
+            r#"pipeline {
+    matrix {
+        axes {
+            OS = ['linux', 'macos']
+        }
+        excludes {
+            exclude {
+                OS = 'macos'
+            }
+        }
+        stage {
+            steps {
+                sh 'make'
+            }
+        }
+    }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn format_parameters() {
+        let buf = "pipeline { parameters { boolean(name:'SKIP',default:false)\n choice name: 'TARGET',choices:['a','b'] }\n steps { sh \"v${params.SKIP}\" } }";
+        let formatted = format_pipeline_string(buf).expect("Failed to format");
+        assert_eq!(
+            formatted,
+            r#"pipeline {
+    parameters {
+        boolean name: 'SKIP', default: false
+        choice name: 'TARGET', choices: ['a', 'b']
+    }
+    steps {
+        sh "v${params.SKIP}"
+    }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn format_triggers() {
+        let buf = "pipeline { triggers { cron   '@daily'\n upstream \"Hello World\" }\n steps { sh 'make' } }";
+        let formatted = format_pipeline_string(buf).expect("Failed to format");
+        assert_eq!(
+            formatted,
+            r#"pipeline {
+    triggers {
+        cron '@daily'
+        upstream "Hello World"
+    }
+    steps {
+        sh 'make'
+    }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn format_needs() {
+        let buf = "pipeline { stage { name = 'Build'\n needs = [ ]\n steps { sh 'make' } }\n stage { name = 'Test'\n needs = [ 'Build' ,'Build', ]\n steps { sh 'make test' } } }";
+        let formatted = format_pipeline_string(buf).expect("Failed to format");
+        assert_eq!(
+            formatted,
+            r#"pipeline {
+    stage {
+        name = 'Build'
+        needs = []
+        steps {
+            sh 'make'
+        }
+    }
+    stage {
+        name = 'Test'
+        needs = ['Build', 'Build']
+        steps {
+            sh 'make test'
+        }
+    }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn format_agent() {
+        let buf =
+            "pipeline { stage { agent { label   \"linux && rust\" }\n steps { sh 'make' } } }";
+        let formatted = format_pipeline_string(buf).expect("Failed to format");
+        assert_eq!(
+            formatted,
+            r#"pipeline {
+    stage {
+        agent {
+            label "linux && rust"
+        }
+        steps {
+            sh 'make'
+        }
+    }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn format_imports() {
+        let buf = "import   'test_data/imports/lib/release.otto'\n\n\npipeline { stage { name = 'Build'\n steps { lint( ) } }\n use    release }";
+        let formatted = format_pipeline_string(buf).expect("Failed to format");
+        assert_eq!(
+            formatted,
+            r#"import 'test_data/imports/lib/release.otto'
+
+pipeline {
+    stage {
+        name = 'Build'
+        steps {
+            lint()
+        }
+    }
+    use release
+}
+"#
+        );
+    }
+
+    #[test]
+    fn format_never_reads_imports() {
+        let buf = "import 'Cargo.toml'\npipeline { steps { sh 'ls' } }";
+        let formatted = format_pipeline_string(buf).expect("Failed to format");
+        assert!(formatted.starts_with("import 'Cargo.toml'\n"));
+    }
+
+    #[test]
+    fn format_is_idempotent() {
+        let buf = "pipeline {\n  environment { FOO = 'bar' }\n  parallel {\n    stage { steps { sh 'a' } }\n\n    stage { steps { sh 'b' } }\n  }\n}";
+        let formatted = format_pipeline_string(buf).expect("Failed to format");
+        assert_eq!(
+            format_pipeline_string(&formatted).expect("Failed to format"),
+            formatted
+        );
+    }
+}
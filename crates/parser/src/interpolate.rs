@@ -0,0 +1,230 @@
+/*
+ * The interpolate module parses the `${...}` references in double-quoted
+ * strings into interpolations, which the agent resolves when the step runs
+ */
+
+use crate::diagnostic::{codes, Diagnostic};
+use otto_models::{Interpolation, Reference, Segment};
+
+/// The fields of the step's configuration which can be referenced
+const CONFIG_FIELDS: &[&str] = &["pipeline", "uuid", "cache", "ipc"];
+
+/**
+ * Parse the contents of a double-quoted string, which start at the offset in
+ * the buffer, into an interpolation
+ *
+ * Returns None when the string does not reference anything, in which case it
+ * is just a string
+ */
+pub(crate) fn parse(
+    text: &str,
+    buffer: &str,
+    offset: usize,
+) -> Result<Option<Interpolation>, Box<Diagnostic>> {
+    let mut segments = vec![];
+    // The literal text is kept escaped until the reference which ends it
+    let mut literal = String::new();
+    let mut chars = text.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            literal.push(c);
+            if let Some((_, escaped)) = chars.next() {
+                literal.push(escaped);
+            }
+            continue;
+        }
+
+        if c != '$' || !text[i + 1..].starts_with('{') {
+            literal.push(c);
+            continue;
+        }
+
+        let start = offset + i;
+        let body = i + 2;
+        let end = match text[body..].find('}') {
+            Some(end) => body + end,
+            None => {
+                return Err(Box::new(
+                    Diagnostic::error(
+                        codes::INVALID_INTERPOLATION,
+                        "unterminated `${`",
+                        buffer,
+                        start,
+                        start + 2,
+                    )
+                    .with_hint("add a closing `}` to the reference, or escape it as `\\${`"),
+                ));
+            }
+        };
+
+        let reference = parse_reference(&text[body..end])
+            .map_err(|d| Box::new(d.located(buffer, start, offset + end + 1)))?;
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(crate::syntax::unescape(&literal)));
+            literal.clear();
+        }
+        segments.push(Segment::Reference(reference));
+
+        // Skip over the rest of the reference
+        for (j, _) in chars.by_ref() {
+            if j >= end {
+                break;
+            }
+        }
+    }
+
+    if segments.is_empty() {
+        return Ok(None);
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(crate::syntax::unescape(&literal)));
+    }
+    Ok(Some(Interpolation { segments }))
+}
+
+/**
+ * Parse the text between the braces of a reference, e.g. `env.TARGET`
+ *
+ * The diagnostic returned on failure has no location yet
+ */
+fn parse_reference(text: &str) -> Result<Reference, Unlocated> {
+    let text = text.trim();
+    let (namespace, name) = match text.split_once('.') {
+        Some((namespace, name)) if is_name(name) => (namespace, name),
+        _ => {
+            return Err(Unlocated {
+                message: format!("invalid reference `{}`", text),
+                expected: vec![],
+                hint: Some("references look like `${env.NAME}`".to_string()),
+            })
+        }
+    };
+
+    match namespace {
+        "env" => Ok(Reference::Env(name.to_string())),
+        "properties" => Ok(Reference::Property(name.to_string())),
+        "params" => Ok(Reference::Parameter(name.to_string())),
+        "config" if CONFIG_FIELDS.contains(&name) => Ok(Reference::Config(name.to_string())),
+        "config" => Err(Unlocated {
+            message: format!("the configuration has no field `{}`", name),
+            expected: CONFIG_FIELDS.iter().map(|f| format!("`{}`", f)).collect(),
+            hint: None,
+        }),
+        other => Err(Unlocated {
+            message: format!("unknown reference `{}`", other),
+            expected: vec![
+                "`env`".to_string(),
+                "`properties`".to_string(),
+                "`params`".to_string(),
+                "`config`".to_string(),
+            ],
+            hint: None,
+        }),
+    }
+}
+
+pub(crate) fn is_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/**
+ * The parts of a diagnostic which are known before its location
+ */
+struct Unlocated {
+    message: String,
+    expected: Vec<String>,
+    hint: Option<String>,
+}
+
+impl Unlocated {
+    fn located(self, buffer: &str, start: usize, end: usize) -> Diagnostic {
+        let mut diagnostic = Diagnostic::error(
+            codes::INVALID_INTERPOLATION,
+            self.message,
+            buffer,
+            start,
+            end,
+        );
+        diagnostic.expected = self.expected;
+        diagnostic.hint = self.hint;
+        diagnostic
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(text: &str) -> Result<Option<Interpolation>, Box<Diagnostic>> {
+        parse(text, text, 0)
+    }
+
+    #[test]
+    fn parse_without_references() {
+        assert_eq!(parse_str("cargo build $HOME \\${env.X}").unwrap(), None);
+    }
+
+    #[test]
+    fn parse_references() {
+        let interpolation = parse_str("--target ${env.TARGET}\\t${ config.pipeline }")
+            .unwrap()
+            .expect("Should have found references");
+        assert_eq!(
+            interpolation.segments,
+            vec![
+                Segment::Literal("--target ".to_string()),
+                Segment::Reference(Reference::Env("TARGET".to_string())),
+                Segment::Literal("\t".to_string()),
+                Segment::Reference(Reference::Config("pipeline".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_parameter_reference() {
+        let interpolation = parse_str("v${params.VERSION}")
+            .unwrap()
+            .expect("Should have found references");
+        assert_eq!(
+            interpolation.segments[1],
+            Segment::Reference(Reference::Parameter("VERSION".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_escaped_reference() {
+        let interpolation = parse_str("\\${env.A} ${properties.name}")
+            .unwrap()
+            .expect("Should have found references");
+        assert_eq!(
+            interpolation.segments,
+            vec![
+                Segment::Literal("${env.A} ".to_string()),
+                Segment::Reference(Reference::Property("name".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_unterminated() {
+        let diagnostic = parse_str("echo ${env.A").unwrap_err();
+        assert_eq!(diagnostic.message, "unterminated `${`");
+        assert_eq!(diagnostic.start.offset, 5);
+    }
+
+    #[test]
+    fn parse_invalid_references() {
+        let diagnostic = parse_str("a ${secrets.TOKEN}").unwrap_err();
+        assert_eq!(diagnostic.message, "unknown reference `secrets`");
+        assert_eq!((diagnostic.start.offset, diagnostic.end.offset), (2, 18));
+
+        let diagnostic = parse_str("${config.nope}").unwrap_err();
+        assert!(diagnostic.expected.contains(&"`pipeline`".to_string()));
+
+        let diagnostic = parse_str("${env}").unwrap_err();
+        assert_eq!(diagnostic.message, "invalid reference `env`");
+    }
+}
@@ -0,0 +1,1802 @@
+/*
+ * The jenkins module converts declarative Jenkinsfiles into Ottofiles, e.g.
+ *
+ *  pipeline {
+ *      agent { label 'linux' }
+ *      stages {
+ *          stage('Build') {
+ *              steps { sh "make ${TARGET}" }
+ *          }
+ *      }
+ *  }
+ *
+ * becomes
+ *
+ *  pipeline {
+ *      stage {
+ *          name = 'Build'
+ *          agent {
+ *              label 'linux'
+ *          }
+ *          steps {
+ *              sh "make ${env.TARGET}"
+ *          }
+ *      }
+ *  }
+ *
+ * Only the declarative subset is converted: the `agent`, `environment`,
+ * `stages`, `steps`, `post`, `when` and `parallel` directives. Anything else,
+ * such as `script` blocks or `options`, is left out of the Ottofile and
+ * reported with a warning so that it can be converted by hand.
+ */
+
+use crate::interpolate::is_name;
+use crate::{codes, parse_pipeline_with_syntax, Diagnostic, Parsed, Severity, Syntax};
+use pest::error::InputLocation;
+use pest::iterators::{Pair, Pairs};
+use pest::Parser;
+
+#[derive(Parser)]
+#[grammar = "jenkinsfile.pest"]
+struct JenkinsParser;
+
+/// The indentation used for each level of nesting
+const INDENT: &str = "    ";
+
+/// The conditions of a `post` block which the pipeline syntax also has
+const POST_CONDITIONS: &[&str] = &["always", "success", "failure", "unstable", "aborted"];
+
+/**
+ * The result of converting a Jenkinsfile
+ *
+ * The diagnostics describe everything in the Jenkinsfile which could not be
+ * converted, and are located within the Jenkinsfile
+ */
+#[derive(Clone, Debug)]
+pub struct Converted {
+    /// The pipeline in the pipeline syntax, which is empty if the Jenkinsfile
+    /// has no declarative `pipeline` block
+    pub ottofile: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Converted {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+    }
+
+    /**
+     * Parse the converted Ottofile into a pipeline
+     *
+     * The diagnostics of the parsed pipeline are located within the Ottofile
+     * rather than the Jenkinsfile
+     */
+    pub fn to_pipeline(&self) -> Parsed {
+        parse_pipeline_with_syntax(None, &self.ottofile, Syntax::Otto, None)
+    }
+}
+
+/**
+ * Convert the buffer, a declarative Jenkinsfile, into an Ottofile
+ *
+ * ```rust
+ * let converted = otto_parser::convert_jenkinsfile(r#"
+ *     pipeline {
+ *         agent any
+ *         stages {
+ *             stage('Build') {
+ *                 steps {
+ *                     sh 'make'
+ *                     script { currentBuild.description = 'Built' }
+ *                 }
+ *             }
+ *         }
+ *     }"#);
+ * // The script block is reported since it could not be converted
+ * assert_eq!(converted.diagnostics.len(), 1);
+ * let pipeline = converted.to_pipeline().into_result().expect("Failed to parse");
+ * assert_eq!(pipeline.batches[0].contexts[0].steps.len(), 1);
+ * ```
+ */
+pub fn convert_jenkinsfile(buffer: &str) -> Converted {
+    let mut converter = Converter {
+        buffer,
+        diagnostics: vec![],
+        fail_fast: false,
+    };
+
+    let ottofile = match JenkinsParser::parse(Rule::jenkinsfile, buffer) {
+        Ok(pairs) => converter.convert(pairs),
+        Err(error) => {
+            let (start, end) = match error.location {
+                InputLocation::Pos(pos) => (pos, pos),
+                InputLocation::Span(span) => span,
+            };
+            let (code, message) = if start >= buffer.trim_end().len() {
+                (codes::UNEXPECTED_EOF, "unexpected end of file")
+            } else {
+                (
+                    codes::UNEXPECTED_TOKEN,
+                    "the Jenkinsfile could not be parsed",
+                )
+            };
+            converter.diagnostics.push(
+                Diagnostic::error(code, message, buffer, start, end)
+                    .with_hint("check that every `{` has a matching `}`"),
+            );
+            String::new()
+        }
+    };
+
+    Converted {
+        ottofile,
+        diagnostics: converter.diagnostics,
+    }
+}
+
+/**
+ * A node of the Jenkinsfile, such as `stage('Build') { ... }`, split into its
+ * parts
+ */
+struct Node<'a> {
+    pair: Pair<'a, Rule>,
+    name: &'a str,
+    /// Whether the arguments were wrapped in parenthesis
+    parens: bool,
+    args: Vec<Pair<'a, Rule>>,
+    kwargs: Vec<(String, Pair<'a, Rule>)>,
+    block: Option<Pair<'a, Rule>>,
+    /// The value of an assignment, e.g. `CC = 'clang'`
+    value: Option<Pair<'a, Rule>>,
+}
+
+impl<'a> Node<'a> {
+    fn new(pair: Pair<'a, Rule>) -> Self {
+        let mut inner = pair.clone().into_inner();
+        let name = inner.next().map(|ident| ident.as_str()).unwrap_or("");
+        let parens = pair.as_str()[name.len()..].trim_start().starts_with('(');
+        let mut node = Self {
+            pair,
+            name,
+            parens,
+            args: vec![],
+            kwargs: vec![],
+            block: None,
+            value: None,
+        };
+
+        for part in inner {
+            match part.as_rule() {
+                Rule::args => node.args = part.into_inner().collect(),
+                Rule::kwargs => node.kwargs = kwargs(part.into_inner()),
+                Rule::block => node.block = Some(part),
+                _ => node.value = Some(part),
+            }
+        }
+        node
+    }
+
+    /**
+     * Split a call value, such as `string(credentialsId: 'token')`, into its
+     * parts as if it were a node
+     */
+    fn new_call(pair: Pair<'a, Rule>) -> Self {
+        let mut node = Self {
+            name: "",
+            parens: true,
+            args: vec![],
+            kwargs: vec![],
+            block: None,
+            value: None,
+            pair: pair.clone(),
+        };
+        if pair.as_rule() != Rule::call {
+            node.name = pair.as_str();
+            return node;
+        }
+
+        for part in pair.into_inner() {
+            match part.as_rule() {
+                Rule::reference => node.name = part.as_str(),
+                Rule::args => node.args = part.into_inner().collect(),
+                Rule::kwargs => node.kwargs = kwargs(part.into_inner()),
+                _ => {}
+            }
+        }
+        node
+    }
+
+    /**
+     * The nodes, and the statements which could not be parsed, in the block
+     */
+    fn children(&self) -> Vec<Pair<'a, Rule>> {
+        match &self.block {
+            Some(block) => block
+                .clone()
+                .into_inner()
+                .filter(|pair| matches!(pair.as_rule(), Rule::node | Rule::invalid))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    fn kwarg(&self, key: &str) -> Option<&Pair<'a, Rule>> {
+        self.kwargs
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value)
+    }
+
+    /**
+     * The first positional argument, or the keyword argument with the key
+     */
+    fn argument(&self, key: &str) -> Option<&Pair<'a, Rule>> {
+        self.args.first().or_else(|| self.kwarg(key))
+    }
+}
+
+fn kwargs(pairs: Pairs<Rule>) -> Vec<(String, Pair<Rule>)> {
+    pairs
+        .filter_map(|kwarg| {
+            let mut inner = kwarg.into_inner();
+            let key = inner.next()?;
+            let key = match key.as_rule() {
+                Rule::STR => key.into_inner().as_str().to_string(),
+                _ => key.as_str().to_string(),
+            };
+            Some((key, inner.next()?))
+        })
+        .collect()
+}
+
+/**
+ * The part of a string which is literal text, or which references a value
+ * written as it would be in the pipeline syntax, e.g. `env.NAME`
+ */
+enum Part {
+    Text(String),
+    Reference(String),
+}
+
+#[derive(Clone)]
+enum Condition {
+    /// A condition which is written the same in both syntaxes
+    Test(String),
+    Not(Box<Condition>),
+    AnyOf(Vec<Condition>),
+    AllOf(Vec<Condition>),
+}
+
+/**
+ * A step written in the pipeline syntax, with its nested steps
+ */
+struct Step {
+    call: String,
+    block: Option<Vec<Step>>,
+}
+
+type Post = Vec<(&'static str, Vec<Step>)>;
+
+#[derive(Default)]
+struct Stage {
+    name: Option<String>,
+    label: Option<String>,
+    environment: Vec<(String, String)>,
+    conditions: Vec<Condition>,
+    steps: Vec<Step>,
+    post: Post,
+}
+
+impl Stage {
+    /**
+     * Give the stage the agent, environment and conditions of the stage it is
+     * nested within, its own taking precedence
+     */
+    fn inherit(&mut self, parent: &Stage) {
+        if self.label.is_none() {
+            self.label = parent.label.clone();
+        }
+
+        let mut environment: Vec<(String, String)> = parent
+            .environment
+            .iter()
+            .filter(|(name, _)| !self.environment.iter().any(|(own, _)| own == name))
+            .cloned()
+            .collect();
+        environment.append(&mut self.environment);
+        self.environment = environment;
+
+        let mut conditions = parent.conditions.clone();
+        conditions.append(&mut self.conditions);
+        self.conditions = conditions;
+    }
+}
+
+enum Item {
+    /// Stages which were nested within another stage are preceded by a
+    /// comment with its name
+    Comment(String),
+    Stage(Stage),
+    Parallel {
+        fanout: bool,
+        stages: Vec<Stage>,
+    },
+}
+
+impl Item {
+    fn stages(&mut self) -> &mut [Stage] {
+        match self {
+            Item::Comment(_) => &mut [],
+            Item::Stage(stage) => std::slice::from_mut(stage),
+            Item::Parallel { stages, .. } => stages,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Declarative {
+    environment: Vec<(String, String)>,
+    items: Vec<Item>,
+    post: Post,
+}
+
+struct Converter<'a> {
+    buffer: &'a str,
+    diagnostics: Vec<Diagnostic>,
+    /// Whether parallel stages should be cancelled as soon as one fails,
+    /// unless they say otherwise
+    fail_fast: bool,
+}
+
+impl<'a> Converter<'a> {
+    fn warn(&mut self, start: usize, end: usize, message: String, hint: Option<&str>) {
+        let mut diagnostic =
+            Diagnostic::warning(codes::UNSUPPORTED, message, self.buffer, start, end);
+        diagnostic.hint = hint.map(str::to_string);
+        self.diagnostics.push(diagnostic);
+    }
+
+    /**
+     * Warn about the first line of the pair
+     */
+    fn warn_pair(&mut self, pair: &Pair<Rule>, message: String, hint: Option<&str>) {
+        let start = pair.as_span().start();
+        let text = pair.as_str();
+        let end = start + text.find('\n').unwrap_or(text.len());
+        self.warn(start, end, message, hint);
+    }
+
+    /**
+     * Warn about the name of the node
+     */
+    fn warn_node(&mut self, node: &Node, message: String, hint: Option<&str>) {
+        let start = node.pair.as_span().start();
+        self.warn(start, start + node.name.len(), message, hint);
+    }
+
+    fn warn_invalid(&mut self, pair: &Pair<Rule>) {
+        self.warn_pair(
+            pair,
+            "the statement could not be converted".to_string(),
+            Some("only the declarative subset of Jenkinsfiles can be converted"),
+        );
+    }
+
+    fn convert(&mut self, pairs: Pairs<'a, Rule>) -> String {
+        let mut pipeline = None;
+
+        for pair in pairs {
+            match pair.as_rule() {
+                Rule::node => {
+                    let node = Node::new(pair);
+                    if node.name != "pipeline" || node.block.is_none() {
+                        self.warn_node(
+                            &node,
+                            format!(
+                                "`{}` is outside of the `pipeline` block and cannot be converted",
+                                node.name
+                            ),
+                            None,
+                        );
+                    } else if pipeline.is_some() {
+                        self.warn_node(
+                            &node,
+                            "unexpected second `pipeline` block".to_string(),
+                            None,
+                        );
+                    } else {
+                        pipeline = Some(self.pipeline(&node));
+                    }
+                }
+                Rule::invalid => self.warn_invalid(&pair),
+                _ => {}
+            }
+        }
+
+        match pipeline {
+            Some(pipeline) => render(&pipeline),
+            None => {
+                let end = self.buffer.len();
+                self.diagnostics.push(
+                    Diagnostic::error(
+                        codes::UNSUPPORTED,
+                        "no `pipeline` block was found",
+                        self.buffer,
+                        end,
+                        end,
+                    )
+                    .with_hint("only declarative pipelines can be converted, scripted pipelines must be converted by hand"),
+                );
+                String::new()
+            }
+        }
+    }
+
+    fn pipeline(&mut self, node: &Node<'a>) -> Declarative {
+        let children = node.children();
+        let mut pipeline = Declarative::default();
+        let mut label = None;
+
+        // The options change how the stages are converted, so are looked at
+        // before anything else
+        for child in children.iter().filter(|c| c.as_rule() == Rule::node) {
+            let child = Node::new(child.clone());
+            if child.name == "options" {
+                self.options(&child);
+            }
+        }
+
+        for child in children {
+            if child.as_rule() == Rule::invalid {
+                self.warn_invalid(&child);
+                continue;
+            }
+
+            let child = Node::new(child);
+            match child.name {
+                "agent" => label = self.agent(&child),
+                "environment" => pipeline.environment = self.environment(&child),
+                "stages" => pipeline.items = self.stages(&child),
+                "post" => pipeline.post = self.post(&child),
+                "options" => {}
+                other => {
+                    let hint = match other {
+                        "parameters" => Some("declare the parameters in a `parameters` block"),
+                        "triggers" => Some("declare the triggers in a `triggers` block"),
+                        "tools" => Some("install the tools on the agents instead"),
+                        _ => None,
+                    };
+                    self.warn_node(
+                        &child,
+                        format!("the `{}` directive cannot be converted", other),
+                        hint,
+                    );
+                }
+            }
+        }
+
+        if label.is_some() {
+            for stage in pipeline.items.iter_mut().flat_map(Item::stages) {
+                if stage.label.is_none() {
+                    stage.label = label.clone();
+                }
+            }
+        }
+        pipeline
+    }
+
+    fn options(&mut self, node: &Node<'a>) {
+        for child in node.children() {
+            if child.as_rule() == Rule::invalid {
+                self.warn_invalid(&child);
+                continue;
+            }
+
+            let child = Node::new(child);
+            if child.name == "parallelsAlwaysFailFast" {
+                self.fail_fast = true;
+            } else {
+                self.warn_node(
+                    &child,
+                    format!("the `{}` option cannot be converted", child.name),
+                    None,
+                );
+            }
+        }
+    }
+
+    /**
+     * Convert an agent into the label expression it selects, if any
+     */
+    fn agent(&mut self, node: &Node<'a>) -> Option<String> {
+        if node.block.is_none() {
+            // Stages may run on any agent unless they have a label anyway
+            if let [value] = node.args.as_slice() {
+                if matches!(value.as_str(), "any" | "none") {
+                    return None;
+                }
+            }
+            if node.kwarg("label").is_some() {
+                return self.label(node);
+            }
+            self.warn_node(node, "the agent cannot be converted".to_string(), None);
+            return None;
+        }
+
+        let mut label = None;
+        for child in node.children() {
+            if child.as_rule() == Rule::invalid {
+                self.warn_invalid(&child);
+                continue;
+            }
+
+            let child = Node::new(child);
+            match child.name {
+                "label" => label = self.label(&child),
+                "node" => {
+                    for child in child.children() {
+                        if child.as_rule() == Rule::invalid {
+                            self.warn_invalid(&child);
+                            continue;
+                        }
+                        let child = Node::new(child);
+                        match child.name {
+                            "label" => label = self.label(&child),
+                            other => self.warn_node(
+                                &child,
+                                format!("the `{}` of an agent cannot be converted", other),
+                                None,
+                            ),
+                        }
+                    }
+                }
+                other => self.warn_node(
+                    &child,
+                    format!("`{}` agents cannot be converted", other),
+                    Some("the stages will run on any agent which matches their labels"),
+                ),
+            }
+        }
+        label
+    }
+
+    /**
+     * Convert the label expression of a `label`, or of an agent given the
+     * label as a keyword argument
+     */
+    fn label(&mut self, node: &Node<'a>) -> Option<String> {
+        for (key, value) in node.kwargs.iter() {
+            if key != "label" {
+                self.warn_pair(
+                    value,
+                    format!("the `{}` of an agent cannot be converted", key),
+                    None,
+                );
+            }
+        }
+
+        let label = node.argument("label").cloned();
+        match label {
+            Some(label) => self.text(&label).filter(|label| !label.trim().is_empty()),
+            None => {
+                self.warn_node(node, "the label cannot be converted".to_string(), None);
+                None
+            }
+        }
+    }
+
+    fn environment(&mut self, node: &Node<'a>) -> Vec<(String, String)> {
+        let mut environment = vec![];
+
+        for child in node.children() {
+            if child.as_rule() == Rule::invalid {
+                self.warn_invalid(&child);
+                continue;
+            }
+
+            let child = Node::new(child);
+            let value = match &child.value {
+                Some(value) => value.clone(),
+                None => {
+                    self.warn_pair(
+                        &child.pair,
+                        "the statement could not be converted".to_string(),
+                        Some("environment variables are declared as `NAME = 'value'`"),
+                    );
+                    continue;
+                }
+            };
+
+            let text = match value.as_rule() {
+                Rule::STR => self.text(&value),
+                Rule::integer | Rule::float | Rule::boolean => Some(value.as_str().to_string()),
+                Rule::call if value.as_str().starts_with("credentials") => {
+                    self.warn_pair(
+                        &value,
+                        format!("the credentials of `{}` cannot be converted", child.name),
+                        Some(
+                            "use the `withCredentials` step around the steps which need the secret",
+                        ),
+                    );
+                    None
+                }
+                _ => {
+                    self.warn_pair(
+                        &value,
+                        format!("the value of `{}` cannot be converted", child.name),
+                        None,
+                    );
+                    None
+                }
+            };
+
+            if let Some(text) = text {
+                environment.retain(|(name, _)| name != child.name);
+                environment.push((child.name.to_string(), text));
+            }
+        }
+        environment
+    }
+
+    fn stages(&mut self, node: &Node<'a>) -> Vec<Item> {
+        let mut items = vec![];
+
+        for child in node.children() {
+            if child.as_rule() == Rule::invalid {
+                self.warn_invalid(&child);
+                continue;
+            }
+
+            let child = Node::new(child);
+            if child.name == "stage" {
+                items.append(&mut self.stage(&child));
+            } else {
+                self.warn_node(&child, format!("unexpected `{}`", child.name), None);
+            }
+        }
+        items
+    }
+
+    /**
+     * Convert a stage, which becomes multiple items when it contains parallel
+     * or sequential stages
+     */
+    fn stage(&mut self, node: &Node<'a>) -> Vec<Item> {
+        let mut stage = Stage::default();
+        if let Some(name) = node.argument("name").cloned() {
+            stage.name = self.text(&name);
+        }
+
+        let mut has_steps = false;
+        let mut fail_fast = self.fail_fast;
+        let mut parallel = None;
+        let mut sequential = None;
+
+        for child in node.children() {
+            if child.as_rule() == Rule::invalid {
+                self.warn_invalid(&child);
+                continue;
+            }
+
+            let child = Node::new(child);
+            match child.name {
+                "agent" => stage.label = self.agent(&child),
+                "environment" => stage.environment = self.environment(&child),
+                "when" => stage.conditions = self.when(&child),
+                "steps" => {
+                    has_steps = true;
+                    stage.steps = self.steps(&child);
+                }
+                "post" => stage.post = self.post(&child),
+                "parallel" => parallel = Some(child),
+                "stages" => sequential = Some(child),
+                "failFast" => fail_fast = child.args.iter().any(|arg| arg.as_str() == "true"),
+                other => self.warn_node(
+                    &child,
+                    format!("the `{}` directive of a stage cannot be converted", other),
+                    None,
+                ),
+            }
+        }
+
+        let nested = parallel.is_some() || sequential.is_some();
+        if nested && !stage.post.is_empty() {
+            self.warn_node(
+                node,
+                "the `post` block of a stage with nested stages cannot be converted".to_string(),
+                None,
+            );
+        }
+        let comment = stage.name.clone().map(Item::Comment);
+
+        if let Some(parallel) = parallel {
+            let mut stages = vec![];
+            for child in parallel.children() {
+                if child.as_rule() == Rule::invalid {
+                    self.warn_invalid(&child);
+                    continue;
+                }
+
+                let child = Node::new(child);
+                let mut items = self.stage(&child);
+                match items.as_mut_slice() {
+                    [] => {}
+                    [Item::Stage(branch)] => {
+                        branch.inherit(&stage);
+                        stages.push(std::mem::take(branch));
+                    }
+                    _ => self.warn_node(
+                        &child,
+                        "nested stages within parallel stages cannot be converted".to_string(),
+                        None,
+                    ),
+                }
+            }
+
+            if stages.is_empty() {
+                return vec![];
+            }
+            return comment
+                .into_iter()
+                .chain(std::iter::once(Item::Parallel {
+                    fanout: fail_fast,
+                    stages,
+                }))
+                .collect();
+        }
+
+        if let Some(sequential) = sequential {
+            let mut items = self.stages(&sequential);
+            for nested in items.iter_mut().flat_map(Item::stages) {
+                nested.inherit(&stage);
+            }
+            if items.is_empty() {
+                return vec![];
+            }
+            return comment.into_iter().chain(items).collect();
+        }
+
+        if stage.steps.is_empty() {
+            let message = if has_steps {
+                "none of the steps of the stage could be converted, so it was left out"
+            } else {
+                "the stage has no steps, so it was left out"
+            };
+            self.warn_node(node, message.to_string(), None);
+            return vec![];
+        }
+        vec![Item::Stage(stage)]
+    }
+
+    fn when(&mut self, node: &Node<'a>) -> Vec<Condition> {
+        node.children()
+            .into_iter()
+            .filter_map(|child| self.condition(child))
+            .collect()
+    }
+
+    fn condition(&mut self, pair: Pair<'a, Rule>) -> Option<Condition> {
+        if pair.as_rule() == Rule::invalid {
+            self.warn_invalid(&pair);
+            return None;
+        }
+
+        let node = Node::new(pair);
+        match node.name {
+            "branch" | "changeset" => {
+                for (key, value) in node.kwargs.iter() {
+                    match key.as_str() {
+                        "pattern" => {}
+                        "comparator" if value.as_str().contains("GLOB") => {}
+                        _ => {
+                            self.warn_pair(
+                                value,
+                                format!("the `{}` of a condition cannot be converted", key),
+                                Some("patterns are always matched as globs"),
+                            );
+                            return None;
+                        }
+                    }
+                }
+                let pattern = match node.argument("pattern") {
+                    Some(pattern) => self.text(&pattern.clone())?,
+                    None => {
+                        self.warn_node(
+                            &node,
+                            "the condition cannot be converted".to_string(),
+                            Some("the condition needs a pattern"),
+                        );
+                        return None;
+                    }
+                };
+                Some(Condition::Test(format!(
+                    "{} {}",
+                    node.name,
+                    quote(&pattern)
+                )))
+            }
+            "environment" => {
+                let name = node.kwarg("name").cloned();
+                let value = node.kwarg("value").cloned();
+                match (name, value) {
+                    (Some(name), Some(value)) => {
+                        let name = self.text(&name)?;
+                        let value = self.text(&value)?;
+                        Some(Condition::Test(format!(
+                            "environment name: {}, value: {}",
+                            quote(&name),
+                            quote(&value)
+                        )))
+                    }
+                    _ => {
+                        self.warn_node(
+                            &node,
+                            "the condition cannot be converted".to_string(),
+                            Some("environment conditions need a `name` and a `value`"),
+                        );
+                        None
+                    }
+                }
+            }
+            "not" => {
+                let mut children = node.children().into_iter();
+                match (children.next(), children.next()) {
+                    (Some(child), None) => Some(Condition::Not(Box::new(self.condition(child)?))),
+                    _ => {
+                        self.warn_node(
+                            &node,
+                            "the condition cannot be converted".to_string(),
+                            Some("`not` must contain exactly one condition"),
+                        );
+                        None
+                    }
+                }
+            }
+            "anyOf" | "allOf" => {
+                let conditions = self.when(&node);
+                if conditions.is_empty() {
+                    None
+                } else if node.name == "anyOf" {
+                    Some(Condition::AnyOf(conditions))
+                } else {
+                    Some(Condition::AllOf(conditions))
+                }
+            }
+            other => {
+                self.warn_node(
+                    &node,
+                    format!("the `{}` condition cannot be converted", other),
+                    Some("the stage will run whenever its other conditions are met"),
+                );
+                None
+            }
+        }
+    }
+
+    fn post(&mut self, node: &Node<'a>) -> Post {
+        let mut post = vec![];
+
+        for child in node.children() {
+            if child.as_rule() == Rule::invalid {
+                self.warn_invalid(&child);
+                continue;
+            }
+
+            let child = Node::new(child);
+            match POST_CONDITIONS.iter().find(|c| **c == child.name) {
+                Some(condition) => {
+                    let steps = self.steps(&child);
+                    if !steps.is_empty() {
+                        post.push((*condition, steps));
+                    }
+                }
+                None => {
+                    let hint = match child.name {
+                        "cleanup" => Some("steps which must always run could go in `always`"),
+                        _ => None,
+                    };
+                    self.warn_node(
+                        &child,
+                        format!(
+                            "the `{}` condition of a `post` block cannot be converted",
+                            child.name
+                        ),
+                        hint,
+                    );
+                }
+            }
+        }
+        post
+    }
+
+    /**
+     * Convert the steps in the block of the node
+     */
+    fn steps(&mut self, node: &Node<'a>) -> Vec<Step> {
+        let mut steps = vec![];
+        for child in node.children() {
+            steps.append(&mut self.step(child));
+        }
+        steps
+    }
+
+    fn step(&mut self, pair: Pair<'a, Rule>) -> Vec<Step> {
+        if pair.as_rule() == Rule::invalid {
+            self.warn_invalid(&pair);
+            return vec![];
+        }
+
+        let node = Node::new(pair);
+        if node.value.is_some() {
+            self.warn_pair(
+                &node.pair,
+                "the statement could not be converted".to_string(),
+                Some("only the declarative subset of Jenkinsfiles can be converted"),
+            );
+            return vec![];
+        }
+
+        match node.name {
+            "script" => {
+                self.warn_node(
+                    &node,
+                    "`script` blocks cannot be converted".to_string(),
+                    Some("rewrite the Groovy as steps, such as `sh` scripts"),
+                );
+                vec![]
+            }
+            "timeout" => self.timeout(&node),
+            "retry" => self.retry(&node),
+            "withCredentials" => self.with_credentials(&node),
+            _ => self.call(&node),
+        }
+    }
+
+    /**
+     * Convert a step which is written the same in both syntaxes
+     */
+    fn call(&mut self, node: &Node<'a>) -> Vec<Step> {
+        let mut arguments = vec![];
+        for arg in node.args.iter() {
+            arguments.push(self.value(arg));
+        }
+        for (key, value) in node.kwargs.iter() {
+            let value = self.value(value);
+            arguments.push(value.map(|value| format!("{}: {}", key, value)));
+        }
+
+        let arguments = match arguments.into_iter().collect::<Option<Vec<String>>>() {
+            Some(arguments) => arguments.join(", "),
+            None => {
+                self.warn_node(
+                    node,
+                    format!("the arguments of `{}` cannot be converted", node.name),
+                    Some("only strings, numbers, booleans, lists and maps can be given to steps"),
+                );
+                return vec![];
+            }
+        };
+
+        let call = if node.parens || node.block.is_some() || arguments.is_empty() {
+            format!("{}({})", node.name, arguments)
+        } else {
+            format!("{} {}", node.name, arguments)
+        };
+        let block = node.block.as_ref().map(|_| self.steps(node));
+        vec![Step { call, block }]
+    }
+
+    fn timeout(&mut self, node: &Node<'a>) -> Vec<Step> {
+        let mut unit = "MINUTES".to_string();
+        for (key, value) in node.kwargs.iter() {
+            match key.as_str() {
+                "time" => {}
+                "unit" => unit = value.as_str().trim_matches(&['\'', '"'][..]).to_string(),
+                other => self.warn_pair(
+                    value,
+                    format!("the `{}` of a timeout cannot be converted", other),
+                    None,
+                ),
+            }
+        }
+
+        let time = node
+            .argument("time")
+            .and_then(|time| time.as_str().parse::<u64>().ok());
+        // The number of the unit in a minute, or the minutes in the unit
+        let (per_minute, minutes_per) = match unit.as_str() {
+            "NANOSECONDS" => (60_000_000_000, 1),
+            "MICROSECONDS" => (60_000_000, 1),
+            "MILLISECONDS" => (60_000, 1),
+            "SECONDS" => (60, 1),
+            "MINUTES" => (1, 1),
+            "HOURS" => (1, 60),
+            "DAYS" => (1, 60 * 24),
+            _ => (0, 0),
+        };
+        let minutes = match time {
+            Some(time) if per_minute > 0 => {
+                let minutes = time.div_ceil(per_minute) * minutes_per;
+                if time % per_minute != 0 {
+                    self.warn_node(
+                        node,
+                        format!("the timeout was rounded up to {} minutes", minutes),
+                        Some("timeouts are given in whole minutes"),
+                    );
+                }
+                minutes
+            }
+            _ => {
+                self.warn_node(node, "the timeout cannot be converted".to_string(), None);
+                return self.steps(node);
+            }
+        };
+
+        vec![Step {
+            call: format!("timeout(minutes: '{}')", minutes),
+            block: Some(self.steps(node)),
+        }]
+    }
+
+    fn retry(&mut self, node: &Node<'a>) -> Vec<Step> {
+        for (key, value) in node.kwargs.iter() {
+            if key != "count" {
+                self.warn_pair(
+                    value,
+                    format!("the `{}` of a retry cannot be converted", key),
+                    None,
+                );
+            }
+        }
+
+        match node
+            .argument("count")
+            .and_then(|count| count.as_str().parse::<u64>().ok())
+        {
+            Some(count) => vec![Step {
+                call: format!("retry(count: '{}')", count),
+                block: Some(self.steps(node)),
+            }],
+            None => {
+                self.warn_node(node, "the retry cannot be converted".to_string(), None);
+                self.steps(node)
+            }
+        }
+    }
+
+    /**
+     * Convert the `string` and `file` bindings of credentials into nested
+     * `withCredentials` steps, since each of those binds a single secret
+     */
+    fn with_credentials(&mut self, node: &Node<'a>) -> Vec<Step> {
+        let mut bindings = vec![];
+        let list = node
+            .args
+            .first()
+            .filter(|list| list.as_rule() == Rule::list)
+            .cloned();
+
+        let calls = match list {
+            Some(list) => list.into_inner().collect(),
+            None => node.args.clone(),
+        };
+        for call in calls {
+            let binding = Node::new_call(call.clone());
+            let id = binding.kwarg("credentialsId").cloned();
+            let variable = binding.kwarg("variable").cloned();
+            match (binding.name, id, variable) {
+                ("string", Some(id), Some(variable)) | ("file", Some(id), Some(variable)) => {
+                    if let (Some(id), Some(variable)) = (self.text(&id), self.text(&variable)) {
+                        let kind = if binding.name == "file" { "file" } else { "variable" };
+                        bindings.push(format!(
+                            "withCredentials(id: {}, {}: {})",
+                            quote(&id),
+                            kind,
+                            quote(&variable)
+                        ));
+                    }
+                }
+                (name, _, _) => self.warn_pair(
+                    &call,
+                    format!("`{}` credentials cannot be converted", name),
+                    Some("only `string` and `file` credentials which bind a `variable` can be converted"),
+                ),
+            }
+        }
+
+        let mut steps = self.steps(node);
+        for call in bindings.into_iter().rev() {
+            steps = vec![Step {
+                call,
+                block: Some(steps),
+            }];
+        }
+        steps
+    }
+
+    /**
+     * Convert a value into the pipeline syntax, returning None if it has no
+     * equivalent there
+     */
+    fn value(&mut self, pair: &Pair<'a, Rule>) -> Option<String> {
+        match pair.as_rule() {
+            Rule::STR => Some(self.interpolated(pair)),
+            Rule::float | Rule::integer | Rule::boolean => Some(pair.as_str().to_string()),
+            Rule::map => {
+                let entries = kwargs(pair.clone().into_inner());
+                if entries.is_empty() {
+                    return Some("[:]".to_string());
+                }
+                let mut converted = vec![];
+                for (key, value) in entries.iter() {
+                    let key = if is_name(key) {
+                        key.to_string()
+                    } else {
+                        quote(key)
+                    };
+                    converted.push(format!("{}: {}", key, self.value(value)?));
+                }
+                Some(format!("[{}]", converted.join(", ")))
+            }
+            Rule::list => {
+                let mut converted = vec![];
+                for value in pair.clone().into_inner() {
+                    converted.push(self.value(&value)?);
+                }
+                Some(format!("[{}]", converted.join(", ")))
+            }
+            _ => None,
+        }
+    }
+
+    /**
+     * Convert a string which is only taken literally in the pipeline syntax,
+     * such as the name of a stage, returning None if it references any values
+     */
+    fn text(&mut self, pair: &Pair<'a, Rule>) -> Option<String> {
+        if pair.as_rule() != Rule::STR {
+            self.warn_pair(pair, "expected a string".to_string(), None);
+            return None;
+        }
+
+        let mut text = String::new();
+        for part in self.parts(pair) {
+            match part {
+                Part::Text(part) => text.push_str(&part),
+                Part::Reference(_) => {
+                    self.warn_pair(
+                        pair,
+                        "the string cannot reference other values here".to_string(),
+                        Some("only the arguments of steps can reference other values"),
+                    );
+                    return None;
+                }
+            }
+        }
+        Some(text)
+    }
+
+    /**
+     * Convert a string given to a step, which may reference other values
+     */
+    fn interpolated(&mut self, pair: &Pair<'a, Rule>) -> String {
+        let multiline = pair.as_str().starts_with("'''") || pair.as_str().starts_with("\"\"\"");
+        let parts = self.parts(pair);
+        write_string(&parts, multiline)
+    }
+
+    /**
+     * Split the Groovy string into literal text and the values it references
+     */
+    fn parts(&mut self, pair: &Pair<'a, Rule>) -> Vec<Part> {
+        let inner = match pair.clone().into_inner().next() {
+            Some(inner) => inner,
+            None => return vec![],
+        };
+        let interpolates = matches!(inner.as_rule(), Rule::DQSTRV | Rule::MLDQSTRV);
+        let raw = inner.as_str();
+        let offset = inner.as_span().start();
+
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut i = 0;
+
+        while let Some(c) = raw[i..].chars().next() {
+            i += c.len_utf8();
+
+            if c == '\\' {
+                if let Some(escaped) = raw[i..].chars().next() {
+                    i += escaped.len_utf8();
+                    match escaped {
+                        'n' => text.push('\n'),
+                        't' => text.push('\t'),
+                        'r' => text.push('\r'),
+                        'b' => text.push('\u{8}'),
+                        'f' => text.push('\u{c}'),
+                        's' => text.push(' '),
+                        'u' => {
+                            let code = raw
+                                .get(i..i + 4)
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .and_then(std::char::from_u32);
+                            match code {
+                                Some(code) => {
+                                    text.push(code);
+                                    i += 4;
+                                }
+                                None => text.push('u'),
+                            }
+                        }
+                        // A backslash at the end of a line continues it
+                        '\n' => {}
+                        other => text.push(other),
+                    }
+                }
+                continue;
+            }
+
+            if c != '$' || !interpolates {
+                text.push(c);
+                continue;
+            }
+
+            let start = i - 1;
+            let (expression, end) = if raw[i..].starts_with('{') {
+                match raw[i..].find('}') {
+                    Some(close) => (raw[i + 1..i + close].trim(), i + close + 1),
+                    None => {
+                        text.push(c);
+                        continue;
+                    }
+                }
+            } else {
+                let length = raw[i..]
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(raw.len() - i);
+                let mut end = i + length;
+                // Only the properties of `env` and `params` are referenced
+                if matches!(&raw[i..end], "env" | "params") && raw[end..].starts_with('.') {
+                    end += 1 + raw[end + 1..]
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(raw.len() - end - 1);
+                }
+                (&raw[i..end], end)
+            };
+
+            if expression.is_empty() {
+                text.push(c);
+                continue;
+            }
+            i = end;
+
+            match self.reference(expression, offset + start, offset + end) {
+                Some(reference) => {
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Reference(reference));
+                }
+                None => text.push_str(&raw[start..end]),
+            }
+        }
+
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        parts
+    }
+
+    /**
+     * Convert the expression referenced by a Groovy string, Jenkins makes the
+     * environment variables available by their names alone
+     */
+    fn reference(&mut self, expression: &str, start: usize, end: usize) -> Option<String> {
+        match expression.split_once('.') {
+            Some((namespace, name)) if matches!(namespace, "env" | "params") && is_name(name) => {
+                return Some(expression.to_string());
+            }
+            None if is_name(expression) => return Some(format!("env.{}", expression)),
+            _ => {}
+        }
+
+        self.warn(
+            start,
+            end,
+            format!("`{}` cannot be converted", expression),
+            Some("only environment variables and parameters can be referenced, it will be left in the string as it is"),
+        );
+        None
+    }
+}
+
+/**
+ * Write the text as a string in the pipeline syntax
+ */
+fn quote(text: &str) -> String {
+    write_string(&[Part::Text(text.to_string())], text.contains('\n'))
+}
+
+/**
+ * Write the parts as a string in the pipeline syntax, preferring single quotes
+ * when nothing is referenced and nothing needs escaping
+ */
+fn write_string(parts: &[Part], multiline: bool) -> String {
+    let references = parts.iter().any(|p| matches!(p, Part::Reference(_)));
+    let text: String = parts
+        .iter()
+        .filter_map(|p| match p {
+            Part::Text(text) => Some(text.as_str()),
+            Part::Reference(_) => None,
+        })
+        .collect();
+
+    if !references {
+        if !text.contains(&['\'', '\n', '\r'][..]) {
+            return format!("'{}'", text);
+        }
+        if multiline && !text.contains('\'') {
+            return format!("'''{}'''", text);
+        }
+    }
+
+    let multiline = multiline && text.contains('\n');
+    let mut out = String::new();
+    for part in parts {
+        match part {
+            Part::Reference(reference) => {
+                out.push_str("${");
+                out.push_str(reference);
+                out.push('}');
+            }
+            Part::Text(text) => {
+                let mut chars = text.chars().peekable();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => out.push_str("\\\\"),
+                        '"' => out.push_str("\\\""),
+                        '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+                        '\n' if !multiline => out.push_str("\\n"),
+                        '\r' => out.push_str("\\r"),
+                        '\t' if !multiline => out.push_str("\\t"),
+                        c => out.push(c),
+                    }
+                }
+            }
+        }
+    }
+
+    if multiline {
+        format!("\"\"\"{}\"\"\"", out)
+    } else {
+        format!("\"{}\"", out)
+    }
+}
+
+/**
+ * Write out the converted pipeline as an Ottofile
+ */
+fn render(pipeline: &Declarative) -> String {
+    let mut writer = Writer::default();
+    writer.open("pipeline");
+
+    if !pipeline.environment.is_empty() {
+        writer.environment(&pipeline.environment);
+    }
+
+    let mut after_comment = false;
+    for (index, item) in pipeline.items.iter().enumerate() {
+        if (index > 0 || !pipeline.environment.is_empty()) && !after_comment {
+            writer.blank();
+        }
+        after_comment = false;
+
+        match item {
+            Item::Comment(name) => {
+                writer.line(&format!("// {}", name.replace('\n', " ")));
+                after_comment = true;
+            }
+            Item::Stage(stage) => writer.stage(stage),
+            Item::Parallel { fanout, stages } => {
+                writer.open(if *fanout { "fanout" } else { "parallel" });
+                for stage in stages.iter() {
+                    writer.stage(stage);
+                }
+                writer.close();
+            }
+        }
+    }
+
+    if !pipeline.post.is_empty() {
+        writer.blank();
+        writer.post(&pipeline.post);
+    }
+    writer.close();
+    writer.out
+}
+
+#[derive(Default)]
+struct Writer {
+    out: String,
+    depth: usize,
+}
+
+impl Writer {
+    fn line(&mut self, text: &str) {
+        self.out.push_str(&INDENT.repeat(self.depth));
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn blank(&mut self) {
+        self.out.push('\n');
+    }
+
+    fn open(&mut self, header: &str) {
+        self.line(&format!("{} {{", header));
+        self.depth += 1;
+    }
+
+    fn close(&mut self) {
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    fn environment(&mut self, environment: &[(String, String)]) {
+        self.open("environment");
+        for (name, value) in environment.iter() {
+            self.line(&format!("{} = {}", name, quote(value)));
+        }
+        self.close();
+    }
+
+    fn stage(&mut self, stage: &Stage) {
+        self.open("stage");
+        if let Some(name) = &stage.name {
+            self.line(&format!("name = {}", quote(name)));
+        }
+        if let Some(label) = &stage.label {
+            self.open("agent");
+            self.line(&format!("label {}", quote(label)));
+            self.close();
+        }
+        if !stage.environment.is_empty() {
+            self.environment(&stage.environment);
+        }
+        if !stage.conditions.is_empty() {
+            self.open("when");
+            for condition in stage.conditions.iter() {
+                self.condition(condition);
+            }
+            self.close();
+        }
+        self.open("steps");
+        self.steps(&stage.steps);
+        self.close();
+        if !stage.post.is_empty() {
+            self.post(&stage.post);
+        }
+        self.close();
+    }
+
+    fn condition(&mut self, condition: &Condition) {
+        match condition {
+            Condition::Test(test) => self.line(test),
+            Condition::Not(condition) => {
+                self.open("not");
+                self.condition(condition);
+                self.close();
+            }
+            Condition::AnyOf(conditions) | Condition::AllOf(conditions) => {
+                let header = match condition {
+                    Condition::AnyOf(_) => "anyOf",
+                    _ => "allOf",
+                };
+                self.open(header);
+                for condition in conditions.iter() {
+                    self.condition(condition);
+                }
+                self.close();
+            }
+        }
+    }
+
+    fn steps(&mut self, steps: &[Step]) {
+        for step in steps.iter() {
+            match &step.block {
+                Some(block) => {
+                    self.open(&step.call);
+                    self.steps(block);
+                    self.close();
+                }
+                None => self.line(&step.call),
+            }
+        }
+    }
+
+    fn post(&mut self, post: &[(&'static str, Vec<Step>)]) {
+        self.open("post");
+        for (condition, steps) in post.iter() {
+            self.open(condition);
+            self.steps(steps);
+            self.close();
+        }
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(buffer: &str) -> Converted {
+        let converted = convert_jenkinsfile(buffer);
+        let parsed = converted.to_pipeline();
+        assert!(
+            !parsed.has_errors(),
+            "The Ottofile could not be parsed: {:?}\n{}",
+            parsed.diagnostics,
+            converted.ottofile
+        );
+        converted
+    }
+
+    fn messages(converted: &Converted) -> Vec<&str> {
+        converted
+            .diagnostics
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn convert_stages() {
+        let converted = convert(
+            r#"
+pipeline {
+    agent { label 'linux' }
+    environment { CC = 'clang' }
+    stages {
+        stage('Build') {
+            steps {
+                sh 'make'
+                dir('docs') { sh(script: 'make', returnStatus: true) }
+            }
+        }
+        stage('Deploy') {
+            agent { node { label 'deploy' } }
+            steps { sh "make deploy VERSION=${VERSION} TARGET=$env.TARGET" }
+        }
+    }
+}"#,
+        );
+        assert!(converted.diagnostics.is_empty());
+        assert_eq!(
+            converted.ottofile,
+            r#"pipeline {
+    environment {
+        CC = 'clang'
+    }
+
+    stage {
+        name = 'Build'
+        agent {
+            label 'linux'
+        }
+        steps {
+            sh 'make'
+            dir('docs') {
+                sh(script: 'make', returnStatus: true)
+            }
+        }
+    }
+
+    stage {
+        name = 'Deploy'
+        agent {
+            label 'deploy'
+        }
+        steps {
+            sh "make deploy VERSION=${env.VERSION} TARGET=${env.TARGET}"
+        }
+    }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn convert_when_and_post() {
+        let converted = convert(
+            r#"
+pipeline {
+    agent any
+    stages {
+        stage('Release') {
+            when {
+                branch 'main'
+                not { environment name: 'DRY_RUN', value: 'true' }
+                anyOf { changeset 'src/**'; expression { return true } }
+            }
+            steps { sh 'make release' }
+            post {
+                failure { echo 'Failed' }
+                fixed { echo 'Fixed' }
+            }
+        }
+    }
+    post { always { echo 'Done' } }
+}"#,
+        );
+        assert_eq!(
+            messages(&converted),
+            vec![
+                "the `expression` condition cannot be converted",
+                "the `fixed` condition of a `post` block cannot be converted"
+            ]
+        );
+        assert!(converted.ottofile.contains("        when {\n            branch 'main'\n            not {\n                environment name: 'DRY_RUN', value: 'true'\n            }\n            anyOf {\n                changeset 'src/**'\n            }\n        }\n"));
+        assert!(converted.ottofile.contains(
+            "    post {\n        always {\n            echo 'Done'\n        }\n    }\n}\n"
+        ));
+    }
+
+    #[test]
+    fn convert_parallel() {
+        let converted = convert(
+            r#"
+pipeline {
+    agent none
+    stages {
+        stage('Test') {
+            agent { label 'linux' }
+            environment { RUST_LOG = 'debug' }
+            failFast true
+            parallel {
+                stage('Unit') { steps { sh 'make check' } }
+                stage('Lint') {
+                    agent { label 'lint' }
+                    steps { sh 'make lint' }
+                }
+            }
+        }
+    }
+}"#,
+        );
+        assert!(converted.diagnostics.is_empty());
+        let pipeline = converted.to_pipeline().pipeline;
+        assert_eq!(pipeline.batches.len(), 1);
+        let contexts = &pipeline.batches[0].contexts;
+        assert_eq!(contexts.len(), 2);
+        assert_eq!(contexts[0].properties["name"], "Unit");
+        let labels = |context: &otto_models::Context| {
+            context.agent.as_ref().map(|agent| agent.label.to_string())
+        };
+        assert_eq!(labels(&contexts[0]).as_deref(), Some("linux"));
+        assert_eq!(labels(&contexts[1]).as_deref(), Some("lint"));
+        assert!(converted.ottofile.contains("    // Test\n    fanout {\n"));
+        assert_eq!(
+            contexts[1].environment.as_ref().unwrap()["RUST_LOG"],
+            "debug"
+        );
+    }
+
+    #[test]
+    fn convert_steps() {
+        let converted = convert(
+            r#"
+pipeline {
+    agent any
+    stages {
+        stage('Build') {
+            steps {
+                timeout(time: 2, unit: 'HOURS') {
+                    retry(3) { sh 'make' }
+                }
+                timeout(time: 90, unit: 'SECONDS') { sh 'make check' }
+                withCredentials([string(credentialsId: 'token', variable: 'TOKEN'), usernamePassword(credentialsId: 'login')]) {
+                    sh 'make publish'
+                }
+                checkout scm
+                sh """
+                    echo "${currentBuild.number}"
+                """
+            }
+        }
+    }
+}"#,
+        );
+        assert_eq!(
+            messages(&converted),
+            vec![
+                "the timeout was rounded up to 2 minutes",
+                "`usernamePassword` credentials cannot be converted",
+                "the arguments of `checkout` cannot be converted",
+                "`currentBuild.number` cannot be converted",
+            ]
+        );
+        let ottofile = &converted.ottofile;
+        assert!(
+            ottofile.contains("timeout(minutes: '120') {\n                retry(count: '3') {\n")
+        );
+        assert!(ottofile.contains("timeout(minutes: '2') {"));
+        assert!(ottofile.contains("withCredentials(id: 'token', variable: 'TOKEN') {\n                sh 'make publish'\n"));
+        // The expression which could not be converted is left as it is
+        assert!(ottofile.contains("sh '''\n                    echo \"${currentBuild.number}\"\n"));
+    }
+
+    #[test]
+    fn convert_unsupported() {
+        let converted = convert(
+            r#"
+@Library('shared') _
+pipeline {
+    agent { docker 'rust:latest' }
+    options { timestamps() }
+    environment {
+        TOKEN = credentials('token')
+        PATH = "${env.HOME}/bin"
+    }
+    stages {
+        stage('Build') {
+            steps {
+                script { def x = 1 }
+            }
+        }
+        stage('Test') { steps { sh 'make test' } }
+    }
+}"#,
+        );
+        assert_eq!(
+            messages(&converted),
+            vec![
+                "the statement could not be converted",
+                "the `timestamps` option cannot be converted",
+                "`docker` agents cannot be converted",
+                "the credentials of `TOKEN` cannot be converted",
+                "the string cannot reference other values here",
+                "`script` blocks cannot be converted",
+                "none of the steps of the stage could be converted, so it was left out",
+            ]
+        );
+        assert!(converted
+            .diagnostics
+            .iter()
+            .all(|d| d.severity == Severity::Warning && d.code == codes::UNSUPPORTED));
+        // The first diagnostic points at the library import
+        assert_eq!(converted.diagnostics[0].start.line, 2);
+        assert_eq!(converted.diagnostics[0].end.column, 21);
+    }
+
+    #[test]
+    fn convert_scripted() {
+        let converted = convert_jenkinsfile("node { sh 'make' }");
+        assert!(converted.has_errors());
+        assert!(converted.ottofile.is_empty());
+
+        let converted = convert_jenkinsfile("pipeline {\n    stages {\n");
+        assert!(converted.has_errors());
+        assert_eq!(converted.diagnostics[0].code, codes::UNEXPECTED_EOF);
+    }
+
+    #[test]
+    fn write_strings() {
+        let text = |s: &str| Part::Text(s.to_string());
+        assert_eq!(write_string(&[text("make")], false), "'make'");
+        assert_eq!(write_string(&[text("it's")], false), r#""it's""#);
+        assert_eq!(write_string(&[text("a\nb")], false), r#""a\nb""#);
+        assert_eq!(write_string(&[text("\n  a\n")], true), "'''\n  a\n'''");
+        assert_eq!(
+            write_string(
+                &[
+                    text("echo \"${"),
+                    Part::Reference("env.HOME".to_string()),
+                    text("\\")
+                ],
+                false
+            ),
+            r#""echo \"\${${env.HOME}\\""#
+        );
+    }
+}
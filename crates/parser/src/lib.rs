@@ -2,221 +2,148 @@ extern crate pest;
 #[macro_use]
 extern crate pest_derive;
 
-use log::*;
 use otto_models::*;
-use pest::error::Error as PestError;
-use pest::iterators::{Pair, Pairs};
-use pest::Parser;
-use uuid::Uuid;
+use std::path::Path;
 
-pub use pest::error::ErrorVariant;
-pub use pest::error::LineColLocation;
+mod data;
+mod diagnostic;
+mod format;
+mod interpolate;
+mod jenkins;
+mod library;
+mod lower;
+pub mod syntax;
+mod validate;
+
+pub use data::Syntax;
+pub use diagnostic::{codes, Diagnostic, Position, Severity};
+pub use format::format_pipeline_string;
+pub use jenkins::{convert_jenkinsfile, Converted};
+pub use syntax::{SyntaxElement, SyntaxKind, SyntaxNode, SyntaxToken, SyntaxTree};
+pub use validate::{validate, validate_steps};
 
 #[derive(Parser)]
 #[grammar = "pipeline.pest"]
 struct PipelineParser;
 
 /**
- * This function will attempt to fully parse the buffer as if it were a complete
- * pipeline file.
+ * The result of parsing a pipeline while recovering from errors
+ *
+ * The pipeline contains everything which could be parsed, and the diagnostics
+ * describe every problem which was found along the way
  */
-pub fn parse_pipeline_string(buffer: &str) -> Result<Pipeline, PestError<Rule>> {
-    let mut parser = PipelineParser::parse(Rule::pipeline, buffer)?;
-    let mut pipeline = Pipeline::default();
-
-    while let Some(parsed) = parser.next() {
-        match parsed.as_rule() {
-            Rule::execBlocks => {
-                let mut parsed = parsed.into_inner();
-                while let Some(parsed) = parsed.next() {
-                    match parsed.as_rule() {
-                        Rule::steps => {
-                            let mut ctx = Context::default();
-                            ctx.steps
-                                .extend(parse_steps(&mut parsed.into_inner(), pipeline.uuid));
-
-                            pipeline.batches.push(Batch {
-                                mode: BatchMode::Linear,
-                                contexts: vec![ctx],
-                            });
-                        }
-                        Rule::stage => {
-                            let ctx = parse_stage(&mut parsed.into_inner());
-                            pipeline.batches.push(Batch {
-                                mode: BatchMode::Linear,
-                                contexts: vec![ctx],
-                            });
-                        }
-                        Rule::parallel => {
-                            pipeline.batches.push(Batch {
-                                mode: BatchMode::Parallel,
-                                contexts: parse_parallel(&mut parsed.into_inner()),
-                            });
-                        }
-                        _ => {}
-                    }
-                }
-            }
-            _ => {}
-        }
-    }
-
-    Ok(pipeline)
+#[derive(Clone, Debug)]
+pub struct Parsed {
+    pub pipeline: Pipeline,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
-fn parse_parallel(parser: &mut Pairs<Rule>) -> Vec<Context> {
-    let mut contexts = vec![];
-    while let Some(parsed) = parser.next() {
-        match parsed.as_rule() {
-            Rule::stage => {
-                let ctx = parse_stage(&mut parsed.into_inner());
-                contexts.push(ctx);
-            }
-            _ => {}
-        }
+impl Parsed {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
     }
-    contexts
-}
 
-fn parse_str(parser: &mut Pair<Rule>) -> String {
-    // TODO: There's got to be a better way than cloning
-    let mut parser = parser.clone().into_inner();
-    while let Some(parsed) = parser.next() {
-        match parsed.as_rule() {
-            Rule::STRV => {
-                return parsed.as_str().to_string();
-            }
-            _ => {}
+    /**
+     * Convert into a Result which is only Ok if no errors were found, in which
+     * case the pipeline is complete
+     */
+    pub fn into_result(self) -> Result<Pipeline, Vec<Diagnostic>> {
+        if self.has_errors() {
+            Err(self.diagnostics)
+        } else {
+            Ok(self.pipeline)
         }
     }
-    "".to_string()
 }
 
 /**
- * Parse a pair of keyword arguments (kwarg rule) into a tuple
- * of the string and value
+ * This function will attempt to fully parse the buffer as if it were a complete
+ * pipeline file.
  */
-fn parse_kwarg(parser: &mut Pairs<Rule>) -> Option<(String, Value)> {
-    let mut key: Option<String> = None;
-    let mut value: Option<Value> = None;
-
-    while let Some(mut parsed) = parser.next() {
-        match parsed.as_rule() {
-            Rule::IDENT => {
-                key = Some(parsed.as_str().to_string());
-            }
-            Rule::STR => {
-                value = Some(Value::String(parse_str(&mut parsed)));
-            }
-            _ => {}
-        }
-    }
+pub fn parse_pipeline_string(buffer: &str) -> Result<Pipeline, Vec<Diagnostic>> {
+    parse_pipeline(None, buffer).into_result()
+}
 
-    if key.is_some() && value.is_some() {
-        return Some((key.unwrap(), value.unwrap()));
-    }
-    None
+/**
+ * Parse the buffer as a complete pipeline file, recording the given file name
+ * in the source locations of the parsed steps and contexts
+ */
+pub fn parse_named_pipeline_string(file: &str, buffer: &str) -> Result<Pipeline, Vec<Diagnostic>> {
+    parse_pipeline(Some(file), buffer).into_result()
 }
 
 /**
- * Parse the steps
+ * Parse the buffer as a pipeline file, recovering from errors at each
+ * statement so that every problem in the file is reported at once
+ *
+ * The returned pipeline is partial when there are errors, containing only the
+ * statements which could be parsed.
  *
- * In the case of orphan steps, the uuid should be the pipeline's uuid
+ * Imports are refused, since the pipeline may have come from anywhere, see
+ * `parse_pipeline_with_imports()` for allowing them. Files ending in .yml,
+ * .yaml or .json are parsed as pipelines written in YAML or JSON.
  */
-fn parse_steps(parser: &mut Pairs<Rule>, uuid: Uuid) -> Vec<Step> {
-    use std::collections::HashMap;
-
-    let mut steps = vec![];
-
-    while let Some(parsed) = parser.next() {
-        if Rule::step == parsed.as_rule() {
-            let mut symbol: Option<String> = None;
-            let mut kwargs: HashMap<String, Value> = HashMap::new();
-            let mut args: Vec<Value> = vec![];
-
-            // Grab the step components
-            for part in parsed.into_inner() {
-                match part.as_rule() {
-                    Rule::IDENT => {
-                        symbol = Some(part.as_str().to_string());
-                    }
-                    Rule::kwarg => {
-                        if let Some((key, value)) = parse_kwarg(&mut part.into_inner()) {
-                            kwargs.insert(key, value);
-                        }
-                    }
-                    Rule::args => {
-                        let mut pairs: Vec<Pair<Rule>> = part.into_inner().collect();
-                        for mut pair in pairs.iter_mut() {
-                            if Rule::STR == pair.as_rule() {
-                                args.push(Value::String(parse_str(&mut pair)));
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-            }
+pub fn parse_pipeline(file: Option<&str>, buffer: &str) -> Parsed {
+    let syntax = file.map(Syntax::from_path).unwrap_or(Syntax::Otto);
+    parse_pipeline_with_syntax(file, buffer, syntax, None)
+}
 
-            if let Some(symbol) = symbol {
-                if kwargs.len() > 0 {
-                    if args.len() > 0 {
-                        error!(
-                            "Parsed keyword and positional arguments out, discarding positionals"
-                        );
-                    }
-                    let parameters = StepParameters::Keyword(kwargs);
-                    let step = Step::new(uuid, symbol, parameters);
-                    steps.push(step);
-                } else {
-                    let parameters = StepParameters::Positional(args);
-                    let step = Step::new(uuid, symbol, parameters);
-                    steps.push(step);
-                }
-            }
-        }
+/**
+ * Parse the buffer as a pipeline written in the given syntax
+ *
+ * Only the pipeline syntax can import libraries, from within the root
+ * directory
+ */
+pub fn parse_pipeline_with_syntax(
+    file: Option<&str>,
+    buffer: &str,
+    syntax: Syntax,
+    root: Option<&Path>,
+) -> Parsed {
+    match syntax {
+        Syntax::Otto => parse_pipeline_with_imports(file, buffer, root),
+        data => data::parse_pipeline(file, buffer, data),
     }
-    steps
 }
 
-fn parse_stage(parser: &mut Pairs<Rule>) -> Context {
-    let mut stage = Context::default();
-
-    debug!("stage: {:?}", parser);
-
-    while let Some(parsed) = parser.next() {
-        match parsed.as_rule() {
-            Rule::property => {
-                let mut inner = parsed.into_inner();
+/**
+ * Parse the buffer as a pipeline file which may only import libraries from
+ * within the root directory, or nothing at all when there is no root
+ *
+ * Imports are relative to the directory of the file, or to the root when
+ * there is no file.
+ */
+pub fn parse_pipeline_with_imports(
+    file: Option<&str>,
+    buffer: &str,
+    root: Option<&Path>,
+) -> Parsed {
+    lower_pipeline(&SyntaxTree::parse(buffer), file, root)
+}
 
-                while let Some(parsed) = inner.next() {
-                    match parsed.as_rule() {
-                        Rule::IDENT => {
-                            let key = parsed.as_str().to_string();
+/**
+ * Lower the syntax tree of a pipeline file into the pipeline, which may only
+ * import libraries from within the root directory
+ *
+ * The diagnostics include the syntax errors of the tree, in the order they
+ * appear among the problems found while lowering it.
+ */
+pub fn lower_pipeline(tree: &SyntaxTree, file: Option<&str>, root: Option<&Path>) -> Parsed {
+    let mut source = lower::Source::from_tree(file, tree, library::Imports::new(file, root));
+    let pipeline = lower::lower_file(tree, &mut source);
 
-                            // This pair should be a STR
-                            if let Some(pair) = inner.next() {
-                                let value = pair.into_inner().as_str().to_string();
-                                debug!("Adding to context key: {}, value: {}", key, value);
-                                stage.properties.insert(key, value);
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-            }
-            Rule::steps => {
-                let mut inner = parsed.into_inner();
-                stage.steps.extend(parse_steps(&mut inner, stage.uuid));
-            }
-            _ => {}
-        }
+    Parsed {
+        pipeline,
+        diagnostics: source.diagnostics,
     }
-    stage
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use pest::Parser;
 
     #[test]
     fn parse_steps() {
@@ -437,4 +364,1167 @@ mod tests {
         let batch = &pipeline.batches[0];
         assert_eq!(batch.contexts.len(), 2);
     }
+
+    #[test]
+    fn parse_fanout() {
+        let buf = r#"
+            pipeline {
+                fanout {
+                    stage {
+                        name = 'Linux'
+                        steps { sh 'make' }
+                    }
+                    stage {
+                        name = 'macOS'
+                        steps { sh 'make' }
+                    }
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        assert_eq!(pipeline.batches.len(), 1);
+
+        let batch = &pipeline.batches[0];
+        assert!(matches!(batch.mode, BatchMode::Fanout));
+        assert_eq!(batch.contexts.len(), 2);
+    }
+
+    #[test]
+    fn parse_matrix() {
+        let buf = r#"
+            pipeline {
+                matrix {
+                    axes {
+                        TOOLCHAIN = ['stable', 'nightly']
+                        FEATURES = ['default', 'all',]
+                    }
+                    excludes {
+                        exclude { TOOLCHAIN = 'nightly'  FEATURES = 'all' }
+                    }
+                    stage {
+                        name = 'Build'
+                        steps { dir('src') { sh 'cargo build' } }
+                    }
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        assert_eq!(pipeline.batches.len(), 1);
+
+        let batch = &pipeline.batches[0];
+        assert!(matches!(batch.mode, BatchMode::Parallel));
+
+        let names: Vec<&str> = batch
+            .contexts
+            .iter()
+            .map(|ctx| ctx.properties["name"].as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "Build (stable, default)",
+                "Build (stable, all)",
+                "Build (nightly, default)"
+            ]
+        );
+
+        let ctx = &batch.contexts[2];
+        assert_eq!(ctx.properties["TOOLCHAIN"], "nightly");
+        let environment = ctx.environment.as_ref().expect("No environment");
+        assert_eq!(environment["FEATURES"], "default");
+
+        // Every expanded context has its own steps
+        assert_ne!(batch.contexts[0].uuid, ctx.uuid);
+        assert_eq!(ctx.steps[0].context, ctx.uuid);
+        assert_eq!(ctx.steps[0].block.as_ref().unwrap()[0].context, ctx.uuid);
+        assert_ne!(batch.contexts[0].steps[0].uuid, ctx.steps[0].uuid);
+    }
+
+    #[test]
+    fn parse_matrix_errors() {
+        let buf = r#"pipeline {
+    matrix {
+        axes {
+            OS = ['linux']
+            OS = ['macos']
+        }
+        excludes {
+            exclude { ARCH = 'arm' }
+            exclude { OS = 'windows' }
+        }
+    }
+}"#;
+        let diagnostics = parse_pipeline_string(buf).expect_err("Should not parse");
+        let codes: Vec<&str> = diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(
+            codes,
+            vec![
+                codes::DUPLICATE_AXIS,
+                codes::UNKNOWN_AXIS,
+                codes::UNKNOWN_AXIS,
+                codes::MISSING_STAGE
+            ]
+        );
+        assert_eq!(diagnostics[1].message, "unknown axis `ARCH`");
+        assert_eq!(
+            diagnostics[2].message,
+            "`windows` is not a value of the axis `OS`"
+        );
+        assert_eq!(diagnostics[3].start.line, 2);
+    }
+
+    #[test]
+    fn parse_matrix_too_large() {
+        let values = (0..10)
+            .map(|value| format!("'{}'", value))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let buf = format!(
+            "pipeline {{ matrix {{ axes {{ A = [{0}]\n B = [{0}]\n C = [{0}] }} stage {{ steps {{ sh 'ls' }} }} }} }}",
+            values
+        );
+        let diagnostics = parse_pipeline_string(&buf).expect_err("Should not parse");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, codes::MATRIX_TOO_LARGE);
+        assert_eq!(
+            diagnostics[0].message,
+            "matrix would expand into 1000 stages, more than the 256 allowed"
+        );
+    }
+
+    #[test]
+    fn parse_when() {
+        let buf = r#"
+            pipeline {
+                stage {
+                    name = 'Deploy'
+                    when {
+                        branch 'release/*'
+                        environment name: 'DEPLOY', value: 'true'
+                        not { changeset 'docs/**' }
+                        anyOf {
+                            environment name: 'FORCE'
+                            allOf { }
+                        }
+                    }
+                    steps { sh 'make deploy' }
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        let ctx = &pipeline.batches[0].contexts[0];
+        assert_eq!(
+            ctx.when,
+            vec![
+                Condition::Branch("release/*".to_string()),
+                Condition::Environment {
+                    name: "DEPLOY".to_string(),
+                    value: Some("true".to_string()),
+                },
+                Condition::Not(Box::new(Condition::Changeset("docs/**".to_string()))),
+                Condition::AnyOf(vec![
+                    Condition::Environment {
+                        name: "FORCE".to_string(),
+                        value: None,
+                    },
+                    Condition::AllOf(vec![]),
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_when_errors() {
+        let buf = "pipeline {\n  stage {\n    when {\n      tag 'v1'\n    }\n    steps { sh 'make' }\n  }\n}";
+        let diagnostic = parse_error(buf);
+        assert_eq!(diagnostic.message, "unexpected `tag`");
+        assert_eq!(diagnostic.start.line, 4);
+        assert!(diagnostic.expected.contains(&"`branch`".to_string()));
+
+        // A second `when` is refused even if the first had no conditions
+        let buf = "pipeline { stage { when { } when { branch 'main' } steps { sh 'make' } } }";
+        let diagnostic = parse_error(buf);
+        assert_eq!(diagnostic.message, "unexpected second `when` block");
+    }
+
+    #[test]
+    fn parse_post() {
+        let buf = r#"
+            pipeline {
+                stage {
+                    steps { sh 'make' }
+                    post {
+                        always { echo 'done' }
+                        failure { sh 'make clean' }
+                    }
+                }
+                post {
+                    success { echo 'shipped' }
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+
+        let ctx = &pipeline.batches[0].contexts[0];
+        let post = ctx.post.as_ref().expect("Stage should have a post block");
+        assert_eq!(post.always[0].symbol, "echo");
+        assert_eq!(post.failure[0].symbol, "sh");
+        assert_eq!(post.failure[0].context, ctx.uuid);
+        assert!(post.success.is_empty());
+
+        let post = pipeline
+            .post
+            .as_ref()
+            .expect("Pipeline should have a post block");
+        assert_eq!(post.success[0].symbol, "echo");
+        assert_eq!(post.success[0].context, pipeline.uuid);
+    }
+
+    #[test]
+    fn parse_post_errors() {
+        let buf = r#"pipeline {
+    steps { sh 'make' }
+    post {
+        always { echo 'one' }
+        always { echo 'two' }
+        sometimes { echo 'three' }
+    }
+}"#;
+        let diagnostics = parse_pipeline_string(buf).expect_err("Should not parse");
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "unexpected second `always` condition",
+                "unexpected `sometimes`"
+            ]
+        );
+        assert_eq!(diagnostics[1].expected, vec!["a post condition"]);
+    }
+
+    #[test]
+    fn parse_numbers_out_of_range() {
+        let buf = r#"pipeline {
+    steps {
+        echo big: 18446744073709551615, small: -9223372036854775808, tiny: 1e-400
+        echo huge: 1e400, larger: 18446744073709551616
+    }
+}"#;
+        let diagnostics = parse_pipeline_string(buf).expect_err("Should not parse");
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "the number `1e400` is out of range",
+                "the number `18446744073709551616` is out of range"
+            ]
+        );
+        assert_eq!(diagnostics[0].code, codes::NUMBER_OUT_OF_RANGE);
+        assert_eq!(diagnostics[0].start.line, 4);
+    }
+
+    #[test]
+    fn parse_interpolation() {
+        let buf = r#"
+            pipeline {
+                steps {
+                    sh "cargo build --target ${env.TARGET}"
+                    sh script: """
+                        echo ${properties.name}
+                    """, label: '${env.LITERAL}'
+                    echo "\${env.ESCAPED}"
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        let steps = &pipeline.batches[0].contexts[0].steps;
+
+        match &steps[0].parameters {
+            StepParameters::Positional(args) => {
+                let interpolation =
+                    Interpolation::from_value(&args[0]).expect("Should be interpolated");
+                assert_eq!(
+                    interpolation.segments[1],
+                    Segment::Reference(Reference::Env("TARGET".to_string()))
+                );
+            }
+            _ => panic!("Expected positional arguments"),
+        }
+
+        match &steps[1].parameters {
+            StepParameters::Keyword(kwargs) => {
+                let interpolation =
+                    Interpolation::from_value(&kwargs["script"]).expect("Should be interpolated");
+                assert_eq!(
+                    interpolation.segments[0],
+                    Segment::Literal("echo ".to_string())
+                );
+                // Single quoted strings are taken literally
+                assert_eq!(kwargs["label"], Value::String("${env.LITERAL}".to_string()));
+            }
+            _ => panic!("Expected keyword arguments"),
+        }
+
+        match &steps[2].parameters {
+            StepParameters::Positional(args) => {
+                assert_eq!(args[0], Value::String("${env.ESCAPED}".to_string()));
+            }
+            _ => panic!("Expected positional arguments"),
+        }
+    }
+
+    #[test]
+    fn parse_map_like_interpolation() {
+        let buf = r#"pipeline { steps { echo data: ['$interpolate': []] } }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+
+        match &pipeline.batches[0].contexts[0].steps[0].parameters {
+            StepParameters::Keyword(kwargs) => {
+                assert_eq!(Interpolation::from_value(&kwargs["data"]), None);
+                assert!(kwargs["data"].get("$$interpolate").is_some());
+            }
+            _ => panic!("Expected keyword arguments"),
+        }
+    }
+
+    #[test]
+    fn parse_invalid_interpolation() {
+        let buf = "pipeline {\n  steps {\n    sh \"echo ${secret.TOKEN}\"\n  }\n}";
+        let diagnostic = parse_error(buf);
+        assert_eq!(diagnostic.code, codes::INVALID_INTERPOLATION);
+        assert_eq!(diagnostic.message, "unknown reference `secret`");
+        assert_eq!((diagnostic.start.line, diagnostic.start.column), (3, 14));
+    }
+
+    #[test]
+    fn parse_parameters() {
+        let buf = r#"
+            pipeline {
+                parameters {
+                    string name: 'VERSION', default: '1.0.0', description: 'The version to release'
+                    boolean(name: 'SKIP_TESTS', default: false)
+                    choice name: 'TARGET', choices: ['staging', 'production']
+                }
+                steps {
+                    sh "make release VERSION=${params.VERSION}"
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        assert_eq!(pipeline.parameters.len(), 3);
+
+        let version = &pipeline.parameters[0];
+        assert_eq!(version.name, "VERSION");
+        assert_eq!(version.kind, ParameterType::String);
+        assert_eq!(version.default, Some(Value::from("1.0.0")));
+        assert_eq!(
+            version.description.as_deref(),
+            Some("The version to release")
+        );
+
+        assert_eq!(pipeline.parameters[1].kind, ParameterType::Boolean);
+        assert_eq!(pipeline.parameters[1].default, Some(Value::Bool(false)));
+        assert_eq!(
+            pipeline.parameters[2].choices,
+            vec!["staging", "production"]
+        );
+        assert_eq!(pipeline.parameters[2].default, None);
+    }
+
+    #[test]
+    fn parse_parameters_errors() {
+        let buf = r#"pipeline {
+  parameters {
+    string default: 'a'
+    boolean name: 'SKIP', default: 'no'
+    choice name: 'TARGET'
+    string name: 'SKIP'
+    number name: 'COUNT'
+  }
+  steps {
+    sh "echo ${params.MISSING}"
+  }
+}"#;
+        let diagnostics = parse_pipeline_string(buf).expect_err("Should not parse");
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "the parameter has no name",
+                "invalid default, `SKIP` must be a boolean",
+                "the choice parameter `TARGET` has no choices",
+                "the parameter `SKIP` is declared twice",
+                "unexpected `number`",
+                "`${params.MISSING}` is not a parameter of the pipeline",
+            ]
+        );
+        assert!(diagnostics[4].expected.contains(&"a parameter".to_string()));
+        assert_eq!(diagnostics[5].start.line, 10);
+        assert!(diagnostics[..4]
+            .iter()
+            .all(|d| d.code == codes::INVALID_PARAMETER));
+    }
+
+    #[test]
+    fn parse_needs() {
+        let buf = r#"
+            pipeline {
+                stage {
+                    name = 'Build'
+                    steps { sh 'make' }
+                }
+                stage {
+                    name = 'Lint'
+                    needs = []
+                    steps { sh 'make lint' }
+                }
+                matrix {
+                    axes { OS = ['linux', 'macos'] }
+                    stage {
+                        name = 'Test'
+                        needs = ['Build']
+                        steps { sh 'make test' }
+                    }
+                }
+                stage {
+                    name = 'Release'
+                    needs = ['Test', 'Lint']
+                    steps { sh 'make release' }
+                }
+                stage {
+                    name = 'Publish'
+                    steps { sh 'make publish' }
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        assert!(pipeline.is_graph());
+
+        let uuid = |batch: usize, ctx: usize| pipeline.batches[batch].contexts[ctx].uuid;
+        let needs = |batch: usize, ctx: usize| {
+            pipeline.batches[batch].contexts[ctx]
+                .needs
+                .clone()
+                .expect("Every context should have needs")
+        };
+
+        assert_eq!(needs(0, 0), vec![]);
+        assert_eq!(needs(1, 0), vec![]);
+        assert_eq!(needs(2, 0), vec![uuid(0, 0)]);
+        assert_eq!(needs(2, 1), vec![uuid(0, 0)]);
+        assert_eq!(needs(3, 0), vec![uuid(2, 0), uuid(2, 1), uuid(1, 0)]);
+        // Stages without needs wait for the batch before them
+        assert_eq!(needs(4, 0), vec![uuid(3, 0)]);
+    }
+
+    #[test]
+    fn parse_without_needs() {
+        let buf = "pipeline { stage { steps { sh 'a' } } stage { steps { sh 'b' } } }";
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        assert!(!pipeline.is_graph());
+    }
+
+    #[test]
+    fn parse_needs_errors() {
+        let buf = r#"pipeline {
+  stage {
+    name = 'A'
+    needs = ['C']
+    steps { sh 'a' }
+  }
+  stage {
+    name = 'B'
+    needs = ['A', 'Missing']
+    steps { sh 'b' }
+  }
+  stage {
+    name = 'C'
+    needs = ['B']
+    steps { sh 'c' }
+  }
+}"#;
+        let diagnostics = parse_pipeline_string(buf).expect_err("Should not parse");
+        assert_eq!(diagnostics.len(), 2, "{:?}", diagnostics);
+
+        assert_eq!(diagnostics[0].code, codes::MISSING_DEPENDENCY);
+        assert_eq!(diagnostics[0].message, "there is no stage named `Missing`");
+        assert_eq!(
+            (diagnostics[0].start.line, diagnostics[0].start.column),
+            (9, 19)
+        );
+        assert!(diagnostics[0].expected.contains(&"`C`".to_string()));
+
+        assert_eq!(diagnostics[1].code, codes::CYCLIC_DEPENDENCY);
+        assert_eq!(
+            diagnostics[1].message,
+            "the stages need each other: `A` -> `C` -> `B` -> `A`"
+        );
+        assert_eq!(diagnostics[1].start.line, 4);
+    }
+
+    #[test]
+    fn parse_agent() {
+        let buf = "pipeline { stage { agent { label 'linux && rust' } steps { sh 'ls' } } }";
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        let agent = pipeline.batches[0].contexts[0]
+            .agent
+            .as_ref()
+            .expect("The stage should require an agent");
+        assert_eq!(agent.label.to_string(), "linux && rust");
+        assert!(agent.matches(&["rust".to_string(), "linux".to_string()]));
+        assert!(!agent.matches(&["linux".to_string()]));
+    }
+
+    #[test]
+    fn parse_agent_errors() {
+        let buf = r#"pipeline {
+  stage {
+    agent { label 'linux &' }
+    steps { sh 'a' }
+  }
+  stage {
+    agent { }
+    steps { sh 'b' }
+  }
+  stage {
+    agent { label 'linux'
+      label 'rust' }
+    steps { sh 'c' }
+  }
+}"#;
+        let diagnostics = parse_pipeline_string(buf).expect_err("Should not parse");
+        assert_eq!(diagnostics.len(), 3, "{:?}", diagnostics);
+
+        assert_eq!(diagnostics[0].code, codes::INVALID_LABEL);
+        assert_eq!(diagnostics[0].message, "`&` should be `&&`");
+        assert_eq!(
+            (diagnostics[0].start.line, diagnostics[0].start.column),
+            (3, 19)
+        );
+        assert_eq!(diagnostics[1].message, "`agent` block has no label");
+        assert_eq!(diagnostics[2].message, "unexpected second `label`");
+    }
+
+    #[test]
+    fn parse_triggers() {
+        use otto_models::triggers::Trigger;
+        let buf = "pipeline { triggers { cron '@hourly' push '*' upstream 'Hello World' } steps { sh 'ls' } }";
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        assert_eq!(pipeline.triggers.len(), 3);
+        match &pipeline.triggers[0] {
+            Trigger::Cron(schedule) => assert_eq!(schedule.to_string(), "@hourly"),
+            other => panic!("Expected a cron trigger, got {:?}", other),
+        }
+        assert_eq!(pipeline.triggers[1], Trigger::Push("*".to_string()));
+        assert_eq!(
+            pipeline.triggers[2],
+            Trigger::Upstream("Hello World".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_triggers_errors() {
+        let buf = r#"pipeline {
+  triggers {
+    cron '0 4 * *'
+    upstream ''
+    push 'main'
+    push 'main'
+    pull 'main'
+  }
+  triggers { cron '@daily' }
+  steps { sh 'ls' }
+}"#;
+        let diagnostics = parse_pipeline_string(buf).expect_err("Should not parse");
+        assert_eq!(diagnostics.len(), 5, "{:?}", diagnostics);
+
+        assert_eq!(diagnostics[0].code, codes::INVALID_TRIGGER);
+        assert_eq!(
+            (diagnostics[0].start.line, diagnostics[0].start.column),
+            (3, 10)
+        );
+        assert_eq!(
+            diagnostics[1].message,
+            "`upstream` needs the name of a project"
+        );
+        assert_eq!(diagnostics[2].message, "the trigger is declared twice");
+        assert_eq!(diagnostics[3].code, codes::UNEXPECTED_TOKEN);
+        assert_eq!(
+            diagnostics[3].expected,
+            vec!["`cron`", "`push`", "`upstream`"]
+        );
+        assert_eq!(diagnostics[4].message, "unexpected second `triggers` block");
+    }
+
+    #[test]
+    fn parse_import_url() {
+        let parsed = parse_pipeline_with_imports(
+            None,
+            "import 'https://example.com/lib.otto'\npipeline { steps { sh 'ls' } }",
+            Some(Path::new(".")),
+        );
+        assert_eq!(parsed.diagnostics.len(), 1);
+        assert_eq!(parsed.diagnostics[0].code, codes::IMPORT_FAILED);
+        assert_eq!(parsed.diagnostics[0].start.offset, 7);
+        assert!(parsed.diagnostics[0].hint.is_some());
+    }
+
+    #[test]
+    fn parse_imports_refused_by_default() {
+        let parsed = parse_pipeline(
+            Some("test_data/imports/pipeline.otto"),
+            "import 'lib/common.otto'\npipeline { steps { sh 'ls' } }",
+        );
+        assert_eq!(parsed.diagnostics.len(), 1);
+        assert_eq!(
+            parsed.diagnostics[0].message,
+            "cannot import `lib/common.otto`, imports are not allowed here"
+        );
+    }
+
+    #[test]
+    fn parse_macro_with_arguments() {
+        let parsed = parse_pipeline_with_imports(
+            Some("test_data/imports/pipeline.otto"),
+            "import 'lib/common.otto'\npipeline { steps { lint('src') } }",
+            Some(Path::new("test_data/imports")),
+        );
+        assert_eq!(parsed.diagnostics.len(), 1);
+        assert_eq!(parsed.diagnostics[0].code, codes::INVALID_MACRO_CALL);
+        assert_eq!(
+            parsed.diagnostics[0].message,
+            "the macro `lint` does not take arguments"
+        );
+    }
+
+    #[test]
+    fn parse_needs_itself() {
+        let buf = "pipeline {\n  stage {\n    name = 'A'\n    needs = ['A']\n    steps { sh 'a' }\n  }\n}";
+        let diagnostic = parse_error(buf);
+        assert_eq!(diagnostic.message, "the stages need each other: `A` -> `A`");
+    }
+
+    #[test]
+    fn parse_typed_kwargs() {
+        let buf = r#"
+            pipeline {
+                steps {
+                    archive artifacts: 'target/*.tar.gz', followSymlinks: true, retries: 3, ratio: 0.5
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        let step = &pipeline.batches[0].contexts[0].steps[0];
+
+        match &step.parameters {
+            StepParameters::Positional(_args) => {
+                panic!("Shouldn't have positional arguments")
+            }
+            StepParameters::Keyword(kwargs) => {
+                assert_eq!(kwargs.get("artifacts").unwrap(), "target/*.tar.gz");
+                assert_eq!(kwargs.get("followSymlinks").unwrap(), &Value::Bool(true));
+                assert_eq!(kwargs.get("retries").unwrap(), &Value::from(3));
+                assert_eq!(kwargs.get("ratio").unwrap(), &Value::from(0.5));
+            }
+        }
+    }
+
+    #[test]
+    fn parse_typed_pos_args() {
+        let buf = r#"
+            pipeline {
+                steps {
+                    example false, -12, 1.5e3, 'yes'
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        let step = &pipeline.batches[0].contexts[0].steps[0];
+
+        match &step.parameters {
+            StepParameters::Positional(args) => {
+                assert_eq!(
+                    args,
+                    &vec![
+                        Value::Bool(false),
+                        Value::from(-12),
+                        Value::from(1500.0),
+                        Value::from("yes"),
+                    ]
+                );
+            }
+            StepParameters::Keyword(_kwargs) => {
+                panic!("Not expecting keyword arguments for this step");
+            }
+        }
+    }
+
+    #[test]
+    fn parse_lists_and_maps() {
+        let buf = r#"
+            pipeline {
+                steps {
+                    example list: ['a', 1, [true]], map: [name: 'otto', 'the count': 2], empty: [:], none: []
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        let step = &pipeline.batches[0].contexts[0].steps[0];
+
+        match &step.parameters {
+            StepParameters::Positional(_args) => {
+                panic!("Shouldn't have positional arguments")
+            }
+            StepParameters::Keyword(kwargs) => {
+                assert_eq!(
+                    kwargs.get("list").unwrap(),
+                    &serde_json::json!(["a", 1, [true]])
+                );
+                assert_eq!(
+                    kwargs.get("map").unwrap(),
+                    &serde_json::json!({"name" : "otto", "the count" : 2})
+                );
+                assert_eq!(kwargs.get("empty").unwrap(), &serde_json::json!({}));
+                assert_eq!(kwargs.get("none").unwrap(), &serde_json::json!([]));
+            }
+        }
+    }
+
+    #[test]
+    fn parse_boolean_prefixed_step() {
+        let buf = r#"
+            pipeline {
+                steps {
+                    sh 'ls'
+                    trueStep 'hi'
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        let steps = &pipeline.batches[0].contexts[0].steps;
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[1].symbol, "trueStep");
+    }
+
+    #[test]
+    fn parse_double_quoted_escapes() {
+        let buf = r#"
+            pipeline {
+                steps {
+                    sh "echo \"it's\"\tdone\\n\nls"
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        let step = &pipeline.batches[0].contexts[0].steps[0];
+
+        match &step.parameters {
+            StepParameters::Positional(args) => {
+                assert_eq!(args[0], "echo \"it's\"\tdone\\n\nls");
+            }
+            StepParameters::Keyword(_kwargs) => {
+                panic!("Not expecting keyword arguments for this step");
+            }
+        }
+    }
+
+    #[test]
+    fn parse_invalid_escape() {
+        let buf = r#"
+            pipeline {
+                steps {
+                    sh "grep \d"
+                }
+            }"#;
+        assert!(parse_pipeline_string(buf).is_err());
+    }
+
+    #[test]
+    fn parse_single_quoted_is_literal() {
+        let buf = r#"
+            pipeline {
+                steps {
+                    sh ' echo \n'
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        let step = &pipeline.batches[0].contexts[0].steps[0];
+
+        match &step.parameters {
+            StepParameters::Positional(args) => {
+                assert_eq!(args[0], " echo \\n");
+            }
+            StepParameters::Keyword(_kwargs) => {
+                panic!("Not expecting keyword arguments for this step");
+            }
+        }
+    }
+
+    #[test]
+    fn parse_multiline_strings() {
+        let buf = r#"
+            pipeline {
+                stage {
+                    name = "Build"
+                    steps {
+                        sh '''
+                            echo 'hello'
+                              make
+                        '''
+                        sh script: """
+                            printf "%s\n" "world"
+                        """
+                    }
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        let context = &pipeline.batches[0].contexts[0];
+        assert_eq!(context.properties.get("name").unwrap(), "Build");
+
+        match &context.steps[0].parameters {
+            StepParameters::Positional(args) => {
+                assert_eq!(args[0], "echo 'hello'\n  make\n");
+            }
+            StepParameters::Keyword(_kwargs) => {
+                panic!("Not expecting keyword arguments for this step");
+            }
+        }
+
+        match &context.steps[1].parameters {
+            StepParameters::Positional(_args) => {
+                panic!("Shouldn't have positional arguments")
+            }
+            StepParameters::Keyword(kwargs) => {
+                assert_eq!(kwargs.get("script").unwrap(), "printf \"%s\n\" \"world\"\n");
+            }
+        }
+    }
+
+    #[test]
+    fn parse_step_with_block() {
+        let buf = r#"
+            pipeline {
+                stage {
+                    name = 'Build'
+                    steps {
+                        dir('subproject') {
+                            sh 'make'
+                            dir 'nested' {
+                                sh 'make install'
+                            }
+                        }
+                        sh('ls')
+                    }
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        let context = &pipeline.batches[0].contexts[0];
+        assert_eq!(context.steps.len(), 2);
+
+        let dir = &context.steps[0];
+        assert_eq!(dir.symbol, "dir");
+        match &dir.parameters {
+            StepParameters::Positional(args) => {
+                assert_eq!(args, &vec![Value::from("subproject")]);
+            }
+            StepParameters::Keyword(_kwargs) => {
+                panic!("Not expecting keyword arguments for this step");
+            }
+        }
+
+        let block = dir.block.as_ref().expect("Missing the block");
+        assert_eq!(block.len(), 2);
+        assert_eq!(block[0].symbol, "sh");
+        assert_eq!(block[0].context, context.uuid);
+
+        let nested = block[1].block.as_ref().expect("Missing the nested block");
+        assert_eq!(nested.len(), 1);
+        assert_eq!(nested[0].context, context.uuid);
+
+        assert!(context.steps[1].block.is_none());
+    }
+
+    #[test]
+    fn parse_orphan_step_with_block() {
+        let buf = r#"
+            pipeline {
+                steps {
+                    dir(directory: 'subproject') { }
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        let step = &pipeline.batches[0].contexts[0].steps[0];
+        assert_eq!(step.context, pipeline.uuid);
+        assert_eq!(step.block.as_ref().map(|b| b.len()), Some(0));
+    }
+
+    #[test]
+    fn parse_environment_blocks() {
+        let buf = r#"
+            pipeline {
+                environment {
+                    FOO = 'bar'
+                    SHARED = 'pipeline'
+                }
+                steps {
+                    sh 'env'
+                }
+                stage {
+                    name = 'Build'
+                    environment {
+                        SHARED = 'stage'
+                    }
+                    steps {
+                        sh 'env'
+                    }
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        assert_eq!(pipeline.batches.len(), 2);
+
+        let orphan = pipeline.batches[0].contexts[0]
+            .environment
+            .as_ref()
+            .expect("Missing environment");
+        assert_eq!(orphan.get("FOO").unwrap(), "bar");
+        assert_eq!(orphan.get("SHARED").unwrap(), "pipeline");
+
+        let stage = &pipeline.batches[1].contexts[0];
+        assert_eq!(stage.properties.len(), 1);
+        let env = stage.environment.as_ref().expect("Missing environment");
+        assert_eq!(env.get("FOO").unwrap(), "bar");
+        assert_eq!(env.get("SHARED").unwrap(), "stage");
+    }
+
+    #[test]
+    fn parse_environment_for_post() {
+        let buf = r#"
+            pipeline {
+                environment {
+                    FOO = 'bar'
+                }
+                steps {
+                    sh 'env'
+                }
+                post {
+                    always {
+                        sh "echo ${env.FOO}"
+                    }
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        let post = pipeline.post.as_ref().expect("Missing post");
+        let env = post.environment.as_ref().expect("Missing environment");
+        assert_eq!(env.get("FOO").unwrap(), "bar");
+    }
+
+    #[test]
+    fn parse_without_environment() {
+        let buf = r#"
+            pipeline {
+                steps {
+                    sh 'env'
+                }
+            }"#;
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        assert!(pipeline.batches[0].contexts[0].environment.is_none());
+    }
+
+    /**
+     * Parse a buffer which is expected to have exactly one error
+     */
+    fn parse_error(buf: &str) -> Diagnostic {
+        let mut diagnostics = parse_pipeline_string(buf).expect_err("Should not parse");
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        diagnostics.remove(0)
+    }
+
+    #[test]
+    fn diagnostic_unexpected_token() {
+        let buf = "pipeline {\n    steps {\n        sh 'ls'\n        bad!\n    }\n}";
+        let diagnostic = parse_error(buf);
+        assert_eq!(diagnostic.code, codes::UNEXPECTED_TOKEN);
+        assert_eq!(diagnostic.message, "unexpected `!`");
+        assert_eq!(diagnostic.start.line, 4);
+        assert_eq!(diagnostic.start.column, 12);
+        assert!(diagnostic.expected.contains(&"`{`".to_string()));
+    }
+
+    #[test]
+    fn diagnostic_missing_steps() {
+        let buf = "pipeline { stage { name = 'Build' } }";
+        let diagnostic = parse_error(buf);
+        assert_eq!(diagnostic.code, codes::MISSING_STEPS);
+        assert_eq!(diagnostic.start.column, 12);
+        assert_eq!(diagnostic.end.column, 17);
+        assert_eq!(
+            diagnostic.hint,
+            Some("every stage requires a `steps` block".to_string())
+        );
+    }
+
+    #[test]
+    fn diagnostic_empty_steps() {
+        let diagnostic = parse_error("pipeline { steps { } }");
+        assert_eq!(diagnostic.code, codes::EMPTY_STEPS);
+    }
+
+    #[test]
+    fn diagnostic_property_after_steps() {
+        let diagnostic = parse_error("pipeline { stage { steps { sh 'ls' } name = 'Build' } }");
+        assert_eq!(diagnostic.code, codes::UNEXPECTED_TOKEN);
+        assert_eq!(
+            diagnostic.message,
+            "unexpected `name` after the `steps` block"
+        );
+    }
+
+    #[test]
+    fn diagnostic_empty() {
+        let diagnostic = parse_error("");
+        assert_eq!(diagnostic.code, codes::UNEXPECTED_EOF);
+        assert_eq!(
+            diagnostic.expected,
+            vec!["`import`".to_string(), "`pipeline`".to_string()]
+        );
+    }
+
+    #[test]
+    fn diagnostic_unexpected_eof() {
+        let diagnostic = parse_error("pipeline {");
+        assert_eq!(diagnostic.code, codes::UNEXPECTED_EOF);
+        assert!(diagnostic.expected.contains(&"`stage`".to_string()));
+        assert!(diagnostic.expected.contains(&"`}`".to_string()));
+    }
+
+    #[test]
+    fn diagnostic_invalid_escape() {
+        let buf = r#"pipeline { steps { sh "grep \d" } }"#;
+        let diagnostic = parse_error(buf);
+        assert_eq!(diagnostic.code, codes::INVALID_ESCAPE);
+        assert_eq!(diagnostic.start.column, 29);
+        assert_eq!(diagnostic.end.column, 31);
+    }
+
+    #[test]
+    fn diagnostic_unterminated_string() {
+        let buf = "pipeline { steps { sh 'ls } }";
+        let diagnostic = parse_error(buf);
+        assert_eq!(diagnostic.code, codes::UNTERMINATED_STRING);
+        assert_eq!(diagnostic.start.column, 23);
+    }
+
+    #[test]
+    fn diagnostic_end_of_line() {
+        let buf = "pipeline {\n  steps {\n    sh\n    sh 'ls'\n  }\n}";
+        let diagnostic = parse_error(buf);
+        assert_eq!(diagnostic.message, "unexpected end of line");
+        assert_eq!((diagnostic.start.line, diagnostic.start.column), (3, 7));
+        assert!(diagnostic.expected.contains(&"arguments".to_string()));
+    }
+
+    #[test]
+    fn lower_pipeline_from_tree() {
+        let buf = "pipeline {\n    // Build it\n    steps {\n        sh 'make'\n    }\n}\n";
+        let tree = SyntaxTree::parse(buf);
+        assert!(!tree.has_errors());
+        assert!(tree
+            .root()
+            .tokens()
+            .iter()
+            .any(|t| SyntaxKind::Comment == t.kind() && t.text() == "// Build it"));
+
+        let parsed = lower_pipeline(&tree, Some("Ottofile"), None);
+        assert!(!parsed.has_errors());
+        let ctx = &parsed.pipeline.batches[0].contexts[0];
+        assert_eq!(ctx.steps[0].symbol, "sh");
+        let location = ctx.steps[0].source.as_ref().unwrap();
+        assert_eq!((location.line, location.column), (4, 9));
+    }
+
+    #[test]
+    fn recover_multiple_errors() {
+        let buf = r#"pipeline {
+    stages { }
+    stage {
+        name = 'Build'
+        steps {
+            sh 'make'
+            bad!
+            sh "grep \d"
+            sh 'make install'
+        }
+    }
+    stage {
+        name = 'Deploy'
+        label 'deploy'
+    }
+}"#;
+        let parsed = parse_pipeline(None, buf);
+        assert!(parsed.has_errors());
+
+        let lines: Vec<usize> = parsed.diagnostics.iter().map(|d| d.start.line).collect();
+        assert_eq!(lines, vec![2, 7, 8, 14, 12]);
+        assert_eq!(parsed.diagnostics[2].code, codes::INVALID_ESCAPE);
+        assert_eq!(parsed.diagnostics[4].code, codes::MISSING_STEPS);
+
+        // Everything which could be parsed is still in the pipeline
+        let pipeline = parsed.pipeline;
+        assert_eq!(pipeline.batches.len(), 2);
+        let steps = &pipeline.batches[0].contexts[0].steps;
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[1].source.as_ref().unwrap().line, 9);
+        assert_eq!(
+            pipeline.batches[1].contexts[0].properties.get("name"),
+            Some(&"Deploy".to_string())
+        );
+    }
+
+    #[test]
+    fn recover_invalid_block() {
+        let buf = "pipeline {\n  steps {\n    dir('a') {\n      sh 'ls' !\n    }\n  }\n  parallel {\n    stagee { steps { sh 'ls' } }\n  }\n}";
+        let parsed = parse_pipeline(None, buf);
+        let lines: Vec<usize> = parsed.diagnostics.iter().map(|d| d.start.line).collect();
+        assert_eq!(lines, vec![4, 8]);
+        assert_eq!(parsed.diagnostics[1].message, "unexpected `stagee`");
+
+        let dir = &parsed.pipeline.batches[0].contexts[0].steps[0];
+        assert_eq!(dir.block.as_ref().map(|b| b.len()), Some(1));
+    }
+
+    #[test]
+    fn parse_source_locations() {
+        let buf = "pipeline {\n  stage {\n    name = 'Build'\n    steps {\n      sh 'ls'\n      dir('a') {\n        sh 'pwd'\n      }\n    }\n  }\n}";
+        let pipeline = parse_named_pipeline_string("Ottofile", buf).expect("Failed to parse");
+        let context = &pipeline.batches[0].contexts[0];
+
+        let location = context.source.as_ref().expect("Missing context source");
+        assert_eq!(location.file, Some("Ottofile".to_string()));
+        assert_eq!((location.line, location.column), (2, 3));
+        assert!(buf[location.start..location.end].starts_with("stage {"));
+
+        let location = context.steps[0]
+            .source
+            .as_ref()
+            .expect("Missing step source");
+        assert_eq!(location.to_string(), "Ottofile:5:7");
+        assert_eq!(&buf[location.start..location.end], "sh 'ls'");
+
+        let nested = &context.steps[1].block.as_ref().unwrap()[0];
+        let location = nested.source.as_ref().expect("Missing nested step source");
+        assert_eq!((location.line, location.column), (7, 9));
+    }
+
+    #[test]
+    fn parse_trailing_commas() {
+        let buf = "pipeline { steps { sh 'ls', \n sh script: 'pwd', returnStdout: true, } }";
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        assert_eq!(pipeline.batches[0].contexts[0].steps.len(), 2);
+    }
+
+    #[test]
+    fn parse_long_argument_list() {
+        let args = vec!["'a'"; 10_000].join(", ");
+        let buf = format!("pipeline {{ steps {{ sh {} }} }}", args);
+        let pipeline = parse_pipeline_string(&buf).expect("Failed to parse");
+        assert_eq!(pipeline.batches[0].contexts[0].steps.len(), 1);
+    }
+
+    #[test]
+    fn parse_orphan_source_location() {
+        let pipeline =
+            parse_pipeline_string("pipeline { steps { sh 'ls' } }").expect("Failed to parse");
+        let context = &pipeline.batches[0].contexts[0];
+        let location = context.source.as_ref().expect("Missing context source");
+        assert_eq!(location.file, None);
+        assert_eq!(location.column, 12);
+    }
 }
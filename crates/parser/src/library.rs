@@ -0,0 +1,340 @@
+/*
+ * The library module loads the libraries imported by a pipeline, and expands
+ * the stage templates and step macros they define into plain contexts and
+ * steps
+ */
+
+use crate::codes;
+use crate::lower::{assign_context, lower_stage, lower_steps, renew, Needs, Source};
+use crate::syntax::{string_value, SyntaxElement, SyntaxKind, SyntaxTree};
+use otto_models::{Context, Step, StepParameters};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/**
+ * Where the libraries imported by the file being parsed may be read from
+ */
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Imports {
+    /// The directory which every library must be within, imports are refused
+    /// entirely when there is none
+    pub(crate) root: Option<PathBuf>,
+    /// The directory which imports are relative to
+    pub(crate) dir: PathBuf,
+    /// The libraries being imported, from the pipeline down to the file being
+    /// parsed, used to detect libraries which import themselves
+    pub(crate) chain: Vec<PathBuf>,
+    /// Whether imports are passed over rather than refused, along with the
+    /// templates they would have defined
+    pub(crate) skip: bool,
+}
+
+impl Imports {
+    /**
+     * Imports for a pipeline, relative to the directory of its file when it
+     * has one, otherwise relative to the root
+     */
+    pub(crate) fn new(file: Option<&str>, root: Option<&Path>) -> Self {
+        let root = root.and_then(|root| root.canonicalize().ok());
+        let file = file.map(PathBuf::from);
+
+        let dir = file
+            .as_ref()
+            .and_then(|file| file.parent())
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(|dir| dir.to_path_buf())
+            .or_else(|| root.clone())
+            .unwrap_or_else(|| PathBuf::from("."));
+
+        Self {
+            root,
+            dir,
+            chain: file
+                .and_then(|file| file.canonicalize().ok())
+                .into_iter()
+                .collect(),
+            skip: false,
+        }
+    }
+
+    /**
+     * Imports which are never read, for when only the file's own statements
+     * are being checked
+     */
+    pub(crate) fn skipped() -> Self {
+        Self {
+            skip: true,
+            ..Default::default()
+        }
+    }
+}
+
+/**
+ * The templates and macros defined by the imported libraries
+ */
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Library {
+    templates: HashMap<String, Template>,
+    macros: HashMap<String, Vec<Step>>,
+}
+
+#[derive(Clone, Debug)]
+struct Template {
+    context: Context,
+    /// The names of the stages the template's stage needs
+    needs: Option<Vec<String>>,
+}
+
+impl Library {
+    fn extend(&mut self, other: Library) {
+        self.templates.extend(other.templates);
+        self.macros.extend(other.macros);
+    }
+
+    pub(crate) fn has_macro(&self, symbol: &str) -> bool {
+        self.macros.contains_key(symbol)
+    }
+}
+
+/**
+ * Import the library named by the import statement, adding its templates and
+ * macros, along with those of the libraries it imports, to the source
+ *
+ * Problems found within the library are reported with its file name, and
+ * positions within it
+ */
+pub(crate) fn lower_import(parsed: &SyntaxElement, source: &mut Source) {
+    if source.imports.skip {
+        return;
+    }
+    let literal = match parsed.inner().find(|p| SyntaxKind::Str == p.kind()) {
+        Some(literal) => literal,
+        None => return,
+    };
+    let (start, end) = (literal.start(), literal.end());
+    let name = string_value(literal.text());
+
+    let root = match &source.imports.root {
+        Some(root) => root.clone(),
+        None => {
+            source.error(
+                codes::IMPORT_FAILED,
+                format!("cannot import `{}`, imports are not allowed here", name),
+                start,
+                end,
+            );
+            return;
+        }
+    };
+
+    if name.contains("://") {
+        source
+            .error(
+                codes::IMPORT_FAILED,
+                format!(
+                    "cannot import `{}`, libraries must be imported by path",
+                    name
+                ),
+                start,
+                end,
+            )
+            .hint = Some("copy the library alongside the pipeline and import its path".to_string());
+        return;
+    }
+
+    let path = source.imports.dir.join(&name);
+    let canonical = match path.canonicalize() {
+        Ok(canonical) if canonical.starts_with(&root) => canonical,
+        Ok(_) => {
+            source.error(
+                codes::IMPORT_FAILED,
+                format!(
+                    "cannot import `{}`, it is outside of {}",
+                    name,
+                    root.display()
+                ),
+                start,
+                end,
+            );
+            return;
+        }
+        Err(e) => {
+            source.error(
+                codes::IMPORT_FAILED,
+                format!("cannot import `{}`: {}", name, e),
+                start,
+                end,
+            );
+            return;
+        }
+    };
+
+    if let Some(index) = source.imports.chain.iter().position(|p| p == &canonical) {
+        let mut cycle: Vec<String> = source.imports.chain[index..]
+            .iter()
+            .map(|p| format!("`{}`", p.file_name().unwrap_or_default().to_string_lossy()))
+            .collect();
+        cycle.push(cycle[0].clone());
+        source
+            .error(
+                codes::CYCLIC_IMPORT,
+                format!("the library imports itself: {}", cycle.join(" -> ")),
+                start,
+                end,
+            )
+            .hint = Some("move what both libraries need into a third library".to_string());
+        return;
+    }
+
+    let buffer = match std::fs::read_to_string(&canonical) {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            source.error(
+                codes::IMPORT_FAILED,
+                format!("cannot import `{}`: {}", name, e),
+                start,
+                end,
+            );
+            return;
+        }
+    };
+
+    let file = path.to_string_lossy().to_string();
+    let mut chain = source.imports.chain.clone();
+    chain.push(canonical.clone());
+    let imports = Imports {
+        root: Some(root),
+        dir: path.parent().map(|p| p.to_path_buf()).unwrap_or_default(),
+        chain,
+        skip: false,
+    };
+    let tree = SyntaxTree::parse_library(&buffer);
+    let mut library = Source::from_tree(Some(&file), &tree, imports);
+    lower_library(&tree, &mut library);
+
+    for mut diagnostic in library.diagnostics.into_iter() {
+        if diagnostic.file.is_none() {
+            diagnostic.file = Some(file.clone());
+            diagnostic.library_line = buffer
+                .lines()
+                .nth(diagnostic.start.line.saturating_sub(1))
+                .map(|line| line.to_string());
+        }
+        source.diagnostics.push(diagnostic);
+    }
+    source.library.extend(library.library);
+}
+
+/**
+ * Lower the imports, templates and macros of a library
+ */
+fn lower_library(tree: &SyntaxTree, source: &mut Source) {
+    for parsed in tree.root().inner() {
+        match parsed.kind() {
+            SyntaxKind::Import => lower_import(parsed, source),
+            SyntaxKind::Template => {
+                let name = definition_name(parsed);
+                let context = lower_stage(parsed, source);
+                let needs = source
+                    .needs
+                    .remove(&context.uuid)
+                    .map(|needs| needs.names.into_iter().map(|(name, _, _)| name).collect());
+                source
+                    .library
+                    .templates
+                    .insert(name, Template { context, needs });
+            }
+            SyntaxKind::Macro => {
+                let name = definition_name(parsed);
+                // The steps are given their context when the macro is called
+                let steps = lower_steps(parsed, Uuid::nil(), source);
+                source.library.macros.insert(name, steps);
+            }
+            SyntaxKind::Invalid => source.invalid(parsed),
+            _ => {}
+        }
+    }
+}
+
+fn definition_name(parsed: &SyntaxElement) -> String {
+    parsed
+        .inner()
+        .find(|p| SyntaxKind::Ident == p.kind())
+        .map(|p| p.text().to_string())
+        .unwrap_or_default()
+}
+
+/**
+ * Create a context from the template named by the `use` statement, returning
+ * None if no imported library defines it
+ *
+ * The template's needs are resolved along with those of the pipeline's stages,
+ * with any problems reported at the `use` statement
+ */
+pub(crate) fn use_template(parsed: &SyntaxElement, source: &mut Source) -> Option<Context> {
+    let (start, end) = (parsed.start(), parsed.end());
+    let name = definition_name(parsed);
+
+    let template = match source.library.templates.get(&name) {
+        Some(template) => template.clone(),
+        // The template may well be defined by one of the skipped imports
+        None if source.imports.skip => return None,
+        None => {
+            let mut known: Vec<String> = source
+                .library
+                .templates
+                .keys()
+                .map(|name| format!("`{}`", name))
+                .collect();
+            known.sort();
+            source
+                .error(
+                    codes::UNKNOWN_TEMPLATE,
+                    format!("there is no template named `{}`", name),
+                    start,
+                    end,
+                )
+                .expected = known;
+            return None;
+        }
+    };
+
+    let context = renew(&template.context);
+    if let Some(needs) = template.needs {
+        source.needs.insert(
+            context.uuid,
+            Needs {
+                names: needs.into_iter().map(|name| (name, start, end)).collect(),
+                start,
+                end,
+            },
+        );
+    }
+    Some(context)
+}
+
+/**
+ * Expand the call of a macro into the macro's steps, which belong to the
+ * context of the call
+ */
+pub(crate) fn call_macro(step: &Step, start: usize, end: usize, source: &mut Source) -> Vec<Step> {
+    let takes_arguments = match &step.parameters {
+        StepParameters::Positional(args) => !args.is_empty(),
+        StepParameters::Keyword(kwargs) => !kwargs.is_empty(),
+    };
+    if takes_arguments || step.block.is_some() {
+        source
+            .error(
+                codes::INVALID_MACRO_CALL,
+                format!("the macro `{}` does not take arguments", step.symbol),
+                start,
+                end,
+            )
+            .hint = Some(format!("call the macro as `{}()`", step.symbol));
+    }
+
+    let mut steps = source.library.macros[&step.symbol].clone();
+    assign_context(&mut steps, step.context);
+    steps
+}
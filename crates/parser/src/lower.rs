@@ -0,0 +1,1528 @@
+/*
+ * The lower module turns the syntax tree of a pipeline into the Pipeline
+ * model, which is the second of the two phases of parsing
+ *
+ * This is where everything which the grammar cannot check is reported, such
+ * as stages without steps or needs which name no stage, along with the syntax
+ * errors of the invalid statements in the order they are found. Templates and
+ * macros from the imported libraries are expanded along the way.
+ */
+
+use crate::syntax::{
+    string_content, string_value, strip_indent, SyntaxElement, SyntaxKind, SyntaxTree,
+};
+use crate::{codes, interpolate, library, Diagnostic, Position};
+use log::*;
+use otto_models::*;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/**
+ * The most stages a single matrix may expand into, since every combination of
+ * the axis values becomes a stage of its own
+ */
+const MAX_MATRIX_STAGES: usize = 256;
+
+/**
+ * The Source carries information about where the tree being lowered came from
+ * through the lower functions, and collects the diagnostics for the problems
+ * found while lowering
+ */
+pub(crate) struct Source<'a> {
+    pub(crate) file: Option<&'a str>,
+    pub(crate) buffer: &'a str,
+    /// The tree being lowered, which has the syntax errors of its invalid
+    /// statements
+    tree: Option<&'a SyntaxTree<'a>>,
+    pub(crate) diagnostics: Vec<Diagnostic>,
+    /// The `${params.NAME}` references found in strings along with the span
+    /// of the string, which are checked once every parameter is known
+    parameter_references: Vec<(String, usize, usize)>,
+    /// The needs declared by each context, which are resolved into the uuids
+    /// of the contexts they name once every stage is known
+    pub(crate) needs: HashMap<Uuid, Needs>,
+    /// Names which refer to more than one stage, i.e. the name of a matrix's
+    /// stage refers to every stage expanded from it
+    stage_aliases: Vec<(String, Uuid)>,
+    pub(crate) imports: library::Imports,
+    /// The templates and macros of every library imported so far
+    pub(crate) library: library::Library,
+}
+
+/**
+ * The names of the stages from a `needs` statement, along with their spans
+ * and the span of the statement itself
+ */
+#[derive(Clone, Debug)]
+pub(crate) struct Needs {
+    pub(crate) names: Vec<(String, usize, usize)>,
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+impl<'a> Source<'a> {
+    pub(crate) fn new(file: Option<&'a str>, buffer: &'a str, imports: library::Imports) -> Self {
+        Self {
+            file,
+            buffer,
+            tree: None,
+            diagnostics: vec![],
+            parameter_references: vec![],
+            needs: HashMap::new(),
+            stage_aliases: vec![],
+            imports,
+            library: library::Library::default(),
+        }
+    }
+
+    /**
+     * A source for lowering the tree, whose text is the buffer
+     */
+    pub(crate) fn from_tree(
+        file: Option<&'a str>,
+        tree: &'a SyntaxTree<'a>,
+        imports: library::Imports,
+    ) -> Self {
+        Self {
+            tree: Some(tree),
+            ..Self::new(file, tree.root().text(), imports)
+        }
+    }
+
+    fn location(&self, parsed: &SyntaxElement) -> SourceLocation {
+        let position = Position::from_offset(self.buffer, parsed.start());
+        SourceLocation {
+            file: self.file.map(|f| f.to_string()),
+            line: position.line,
+            column: position.column,
+            start: parsed.start(),
+            end: parsed.end(),
+        }
+    }
+
+    pub(crate) fn error(
+        &mut self,
+        code: &'static str,
+        message: String,
+        start: usize,
+        end: usize,
+    ) -> &mut Diagnostic {
+        self.diagnostics
+            .push(Diagnostic::error(code, message, self.buffer, start, end));
+        self.diagnostics.last_mut().unwrap()
+    }
+
+    /**
+     * Record the syntax error of an invalid statement, at the point it is
+     * found so that the diagnostics stay in the order of the source
+     */
+    pub(crate) fn invalid(&mut self, parsed: &SyntaxElement) {
+        if let Some(diagnostic) = self.tree.and_then(|tree| tree.error_at(parsed.start())) {
+            self.diagnostics.push(diagnostic.clone());
+        }
+    }
+}
+
+/**
+ * Lower the tree of a pipeline file into the pipeline, its imports having been
+ * expanded
+ */
+pub(crate) fn lower_file(tree: &SyntaxTree, source: &mut Source) -> Pipeline {
+    let mut pipeline = Pipeline::default();
+    let mut environment = HashMap::new();
+    let mut parameters_declared = false;
+    let mut triggers_declared = false;
+
+    for parsed in tree.root().inner() {
+        match parsed.kind() {
+            SyntaxKind::Import => library::lower_import(parsed, source),
+            SyntaxKind::Invalid => source.invalid(parsed),
+            _ => {}
+        }
+        if SyntaxKind::Pipeline != parsed.kind() {
+            continue;
+        }
+
+        for parsed in parsed.inner() {
+            match parsed.kind() {
+                SyntaxKind::Environment => {
+                    environment.extend(lower_environment(parsed, source));
+                }
+                SyntaxKind::Steps => {
+                    let mut ctx = Context {
+                        source: Some(source.location(parsed)),
+                        ..Default::default()
+                    };
+                    ctx.steps
+                        .extend(lower_steps_block(parsed, pipeline.uuid, source));
+
+                    pipeline.batches.push(Batch {
+                        mode: BatchMode::Linear,
+                        contexts: vec![ctx],
+                    });
+                }
+                SyntaxKind::Stage => {
+                    let ctx = lower_stage(parsed, source);
+                    pipeline.batches.push(Batch {
+                        mode: BatchMode::Linear,
+                        contexts: vec![ctx],
+                    });
+                }
+                SyntaxKind::UseTemplate => {
+                    if let Some(ctx) = library::use_template(parsed, source) {
+                        pipeline.batches.push(Batch {
+                            mode: BatchMode::Linear,
+                            contexts: vec![ctx],
+                        });
+                    }
+                }
+                SyntaxKind::Parallel => {
+                    pipeline.batches.push(Batch {
+                        mode: BatchMode::Parallel,
+                        contexts: lower_parallel(parsed, source),
+                    });
+                }
+                SyntaxKind::Fanout => {
+                    pipeline.batches.push(Batch {
+                        mode: BatchMode::Fanout,
+                        contexts: lower_parallel(parsed, source),
+                    });
+                }
+                SyntaxKind::Matrix => {
+                    pipeline.batches.push(Batch {
+                        mode: BatchMode::Parallel,
+                        contexts: lower_matrix(parsed, source),
+                    });
+                }
+                SyntaxKind::Post => {
+                    if pipeline.post.is_some() {
+                        duplicate_post(parsed, source);
+                        continue;
+                    }
+                    pipeline.post = Some(lower_post(parsed, pipeline.uuid, source));
+                }
+                SyntaxKind::Parameters => {
+                    if parameters_declared {
+                        source
+                            .error(
+                                codes::UNEXPECTED_TOKEN,
+                                "unexpected second `parameters` block".to_string(),
+                                parsed.start(),
+                                parsed.start() + "parameters".len(),
+                            )
+                            .hint = Some(
+                            "the parameters should all be in one `parameters` block".to_string(),
+                        );
+                        continue;
+                    }
+                    parameters_declared = true;
+                    pipeline.parameters = lower_parameters(parsed, source);
+                }
+                SyntaxKind::Triggers => {
+                    if triggers_declared {
+                        source
+                            .error(
+                                codes::UNEXPECTED_TOKEN,
+                                "unexpected second `triggers` block".to_string(),
+                                parsed.start(),
+                                parsed.start() + "triggers".len(),
+                            )
+                            .hint =
+                            Some("the triggers should all be in one `triggers` block".to_string());
+                        continue;
+                    }
+                    triggers_declared = true;
+                    pipeline.triggers = lower_triggers(parsed, source);
+                }
+                SyntaxKind::Invalid => source.invalid(parsed),
+                _ => {}
+            }
+        }
+    }
+
+    merge_environment(&mut pipeline, &environment);
+    check_parameter_references(&pipeline.parameters, source);
+    resolve_needs(&mut pipeline, source);
+    pipeline
+}
+
+/**
+ * Resolve the needs of the stages into the uuids of the contexts they name,
+ * reporting any names which match no stage and any cycles
+ *
+ * Once any stage declares its needs, the pipeline becomes a graph and the
+ * contexts which did not declare theirs need every context of the batch
+ * before them, just as they would have waited for it otherwise
+ */
+fn resolve_needs(pipeline: &mut Pipeline, source: &mut Source) {
+    if source.needs.is_empty() {
+        return;
+    }
+
+    let mut names: HashMap<String, Vec<Uuid>> = HashMap::new();
+    for ctx in pipeline.contexts() {
+        if let Some(name) = ctx.properties.get("name") {
+            names.entry(name.clone()).or_default().push(ctx.uuid);
+        }
+    }
+    for (name, uuid) in source.stage_aliases.iter() {
+        let uuids = names.entry(name.clone()).or_default();
+        if !uuids.contains(uuid) {
+            uuids.push(*uuid);
+        }
+    }
+
+    let mut previous: Vec<Uuid> = vec![];
+    for batch in pipeline.batches.iter_mut() {
+        let uuids: Vec<Uuid> = batch.contexts.iter().map(|ctx| ctx.uuid).collect();
+
+        for ctx in batch.contexts.iter_mut() {
+            let needs = match source.needs.get(&ctx.uuid) {
+                Some(needs) => needs.clone(),
+                None => {
+                    ctx.needs = Some(previous.clone());
+                    continue;
+                }
+            };
+
+            let mut resolved = vec![];
+            for (name, start, end) in needs.names.iter() {
+                match names.get(name) {
+                    Some(uuids) => resolved.extend(uuids.iter().cloned()),
+                    None => {
+                        let mut known: Vec<String> =
+                            names.keys().map(|n| format!("`{}`", n)).collect();
+                        known.sort();
+                        source
+                            .error(
+                                codes::MISSING_DEPENDENCY,
+                                format!("there is no stage named `{}`", name),
+                                *start,
+                                *end,
+                            )
+                            .expected = known;
+                    }
+                }
+            }
+            ctx.needs = Some(resolved);
+        }
+        previous = uuids;
+    }
+
+    check_cycles(pipeline, source);
+}
+
+/**
+ * Report every cycle in the needs of the contexts, at the `needs` statement of
+ * one of the stages in the cycle
+ */
+fn check_cycles(pipeline: &Pipeline, source: &mut Source) {
+    let contexts: HashMap<Uuid, &Context> = pipeline.contexts().map(|c| (c.uuid, c)).collect();
+    // Contexts which have been completely explored, and are not part of a cycle
+    // which has yet to be reported
+    let mut finished: Vec<Uuid> = vec![];
+
+    for ctx in pipeline.contexts() {
+        let mut path = vec![];
+        if let Some(cycle) = find_cycle(ctx.uuid, &contexts, &mut path, &mut finished) {
+            let describe = |uuid: &Uuid| match contexts[uuid].properties.get("name") {
+                Some(name) => format!("`{}`", name),
+                None => "an unnamed stage".to_string(),
+            };
+            let mut description: Vec<String> = cycle.iter().map(describe).collect();
+            description.push(describe(&cycle[0]));
+
+            // Needs which are not declared only refer back to earlier batches,
+            // so at least one stage in the cycle must have declared its needs
+            if let Some(needs) = cycle.iter().find_map(|uuid| source.needs.get(uuid)) {
+                let (start, end) = (needs.start, needs.end);
+                source
+                    .error(
+                        codes::CYCLIC_DEPENDENCY,
+                        format!("the stages need each other: {}", description.join(" -> ")),
+                        start,
+                        end,
+                    )
+                    .hint = Some("remove one of the needs to break the cycle".to_string());
+            }
+            finished.extend(cycle);
+        }
+    }
+}
+
+/**
+ * Search the needs of the context for a cycle, returning the contexts which
+ * form it
+ */
+fn find_cycle(
+    uuid: Uuid,
+    contexts: &HashMap<Uuid, &Context>,
+    path: &mut Vec<Uuid>,
+    finished: &mut Vec<Uuid>,
+) -> Option<Vec<Uuid>> {
+    if finished.contains(&uuid) {
+        return None;
+    }
+    if let Some(index) = path.iter().position(|u| u == &uuid) {
+        return Some(path[index..].to_vec());
+    }
+
+    path.push(uuid);
+    if let Some(needs) = contexts.get(&uuid).and_then(|ctx| ctx.needs.as_ref()) {
+        for need in needs.iter() {
+            if let Some(cycle) = find_cycle(*need, contexts, path, finished) {
+                return Some(cycle);
+            }
+        }
+    }
+    path.pop();
+    finished.push(uuid);
+    None
+}
+
+/**
+ * Lower the parameters block, reporting any parameter which is declared
+ * incorrectly
+ */
+fn lower_parameters(parsed: &SyntaxElement, source: &mut Source) -> Vec<Parameter> {
+    let mut parameters: Vec<Parameter> = vec![];
+
+    for parsed in parsed.inner() {
+        match parsed.kind() {
+            SyntaxKind::Parameter => {
+                if let Some(parameter) = lower_parameter(parsed, source) {
+                    if parameters.iter().any(|p| p.name == parameter.name) {
+                        source.error(
+                            codes::INVALID_PARAMETER,
+                            format!("the parameter `{}` is declared twice", parameter.name),
+                            parsed.start(),
+                            parsed.end(),
+                        );
+                        continue;
+                    }
+                    parameters.push(parameter);
+                }
+            }
+            SyntaxKind::Invalid => source.invalid(parsed),
+            _ => {}
+        }
+    }
+    parameters
+}
+
+/**
+ * Lower the triggers block, reporting any trigger which is malformed
+ */
+fn lower_triggers(parsed: &SyntaxElement, source: &mut Source) -> Vec<triggers::Trigger> {
+    use otto_models::triggers::{Schedule, Trigger};
+    let mut triggers = vec![];
+
+    for parsed in parsed.inner() {
+        let kind = parsed.kind();
+        if SyntaxKind::Invalid == kind {
+            source.invalid(parsed);
+            continue;
+        }
+
+        let literal = match parsed.inner().find(|p| SyntaxKind::Str == p.kind()) {
+            Some(literal) => literal,
+            None => continue,
+        };
+        let (start, end) = (literal.start(), literal.end());
+        let value = string_value(literal.text());
+
+        let trigger = match kind {
+            SyntaxKind::CronTrigger => match Schedule::parse(&value) {
+                Ok(schedule) => Trigger::Cron(schedule),
+                Err(message) => {
+                    source
+                        .error(codes::INVALID_TRIGGER, message, start, end)
+                        .hint = Some(
+                        "schedules have five fields, e.g. `cron '0 4 * * 1-5'` for four in the morning on weekdays"
+                            .to_string(),
+                    );
+                    continue;
+                }
+            },
+            SyntaxKind::PushTrigger | SyntaxKind::UpstreamTrigger if value.trim().is_empty() => {
+                let (message, hint) = if SyntaxKind::PushTrigger == kind {
+                    (
+                        "`push` needs the branches to run for",
+                        "use `push '*'` to run for every branch",
+                    )
+                } else {
+                    (
+                        "`upstream` needs the name of a project",
+                        "name the project as it is titled, e.g. `upstream 'Hello World'`",
+                    )
+                };
+                source
+                    .error(codes::INVALID_TRIGGER, message.to_string(), start, end)
+                    .hint = Some(hint.to_string());
+                continue;
+            }
+            SyntaxKind::PushTrigger => Trigger::Push(value),
+            SyntaxKind::UpstreamTrigger => Trigger::Upstream(value),
+            _ => continue,
+        };
+
+        if triggers.contains(&trigger) {
+            source.error(
+                codes::INVALID_TRIGGER,
+                "the trigger is declared twice".to_string(),
+                start,
+                end,
+            );
+            continue;
+        }
+        triggers.push(trigger);
+    }
+    triggers
+}
+
+/**
+ * Lower a single parameter, e.g. `string name: 'VERSION', default: '1.0.0'`
+ *
+ * Returns None if the parameter has no name
+ */
+fn lower_parameter(parsed: &SyntaxElement, source: &mut Source) -> Option<Parameter> {
+    let mut name: Option<String> = None;
+    let mut kind = ParameterType::String;
+    let mut default: Option<(Value, usize, usize)> = None;
+    let mut choices = vec![];
+    let mut description = None;
+
+    for part in parsed.inner() {
+        match part.kind() {
+            SyntaxKind::Keyword => {
+                kind = match part.text() {
+                    "boolean" => ParameterType::Boolean,
+                    "choice" => ParameterType::Choice,
+                    _ => ParameterType::String,
+                };
+            }
+            SyntaxKind::Kwarg => {
+                let (start, end) = (part.start(), part.end());
+                let (key, value) = match lower_kwarg(part, source) {
+                    Some(kwarg) => kwarg,
+                    None => continue,
+                };
+
+                match (key.as_str(), value) {
+                    ("name", Value::String(value)) => name = Some(value),
+                    ("description", Value::String(value)) => description = Some(value),
+                    ("default", value) => default = Some((value, start, end)),
+                    ("choices", Value::Array(values)) if values.iter().all(|v| v.is_string()) => {
+                        choices = values
+                            .iter()
+                            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                            .collect();
+                    }
+                    ("choices", _) => {
+                        source.error(
+                            codes::INVALID_PARAMETER,
+                            "`choices` must be a list of strings".to_string(),
+                            start,
+                            end,
+                        );
+                    }
+                    ("name", _) | ("description", _) => {
+                        source.error(
+                            codes::INVALID_PARAMETER,
+                            format!("`{}` must be a string", key),
+                            start,
+                            end,
+                        );
+                    }
+                    (other, _) => {
+                        source
+                            .error(
+                                codes::INVALID_PARAMETER,
+                                format!("unknown keyword argument `{}` for a parameter", other),
+                                start,
+                                end,
+                            )
+                            .expected = ["`name`", "`default`", "`choices`", "`description`"]
+                            .iter()
+                            .map(|k| k.to_string())
+                            .collect();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let name = match name {
+        Some(name) => name,
+        None => {
+            source
+                .error(
+                    codes::INVALID_PARAMETER,
+                    "the parameter has no name".to_string(),
+                    parsed.start(),
+                    parsed.end(),
+                )
+                .hint = Some("add a `name: 'NAME'` keyword argument".to_string());
+            return None;
+        }
+    };
+
+    if kind == ParameterType::Choice && choices.is_empty() {
+        source
+            .error(
+                codes::INVALID_PARAMETER,
+                format!("the choice parameter `{}` has no choices", name),
+                parsed.start(),
+                parsed.end(),
+            )
+            .hint = Some("add a `choices: ['a', 'b']` keyword argument".to_string());
+    }
+
+    let mut parameter = Parameter {
+        name,
+        kind,
+        default: None,
+        choices,
+        description,
+    };
+
+    if let Some((value, start, end)) = default {
+        match parameter.check(&value) {
+            Ok(()) => parameter.default = Some(value),
+            Err(e) => {
+                source.error(
+                    codes::INVALID_PARAMETER,
+                    format!("invalid default, {}", e),
+                    start,
+                    end,
+                );
+            }
+        }
+    }
+    Some(parameter)
+}
+
+/**
+ * Report every `${params.NAME}` reference to a parameter which the pipeline
+ * does not declare
+ */
+fn check_parameter_references(parameters: &[Parameter], source: &mut Source) {
+    let references = std::mem::take(&mut source.parameter_references);
+
+    for (name, start, end) in references.into_iter() {
+        if parameters.iter().any(|p| p.name == name) {
+            continue;
+        }
+        source
+            .error(
+                codes::INVALID_INTERPOLATION,
+                format!("`${{params.{}}}` is not a parameter of the pipeline", name),
+                start,
+                end,
+            )
+            .hint = Some("declare it in the `parameters` block".to_string());
+    }
+}
+
+/**
+ * Merge the pipeline-level environment into every context, with the variables
+ * declared by the context itself taking precedence, and give it to the
+ * pipeline's post steps
+ */
+pub(crate) fn merge_environment(pipeline: &mut Pipeline, environment: &HashMap<String, String>) {
+    if environment.is_empty() {
+        return;
+    }
+
+    for batch in pipeline.batches.iter_mut() {
+        for ctx in batch.contexts.iter_mut() {
+            let mut merged = environment.clone();
+            if let Some(env) = ctx.environment.take() {
+                merged.extend(env);
+            }
+            ctx.environment = Some(merged);
+        }
+    }
+
+    if let Some(post) = pipeline.post.as_mut() {
+        post.environment = Some(environment.clone());
+    }
+}
+
+fn lower_parallel(parsed: &SyntaxElement, source: &mut Source) -> Vec<Context> {
+    let mut contexts = vec![];
+    for parsed in parsed.inner() {
+        match parsed.kind() {
+            SyntaxKind::Stage => {
+                let ctx = lower_stage(parsed, source);
+                contexts.push(ctx);
+            }
+            SyntaxKind::UseTemplate => {
+                contexts.extend(library::use_template(parsed, source));
+            }
+            SyntaxKind::Invalid => source.invalid(parsed),
+            _ => {}
+        }
+    }
+    contexts
+}
+
+/**
+ * Lower the conditions of a `when`, `anyOf` or `allOf` block
+ */
+fn lower_conditions(parsed: &SyntaxElement, source: &mut Source) -> Vec<Condition> {
+    let mut conditions = vec![];
+    for parsed in parsed.inner() {
+        match parsed.kind() {
+            SyntaxKind::Invalid => source.invalid(parsed),
+            _ => conditions.extend(lower_condition(parsed, source)),
+        }
+    }
+    conditions
+}
+
+/**
+ * Lower a single condition, returning None if the element is not a condition
+ */
+fn lower_condition(parsed: &SyntaxElement, source: &mut Source) -> Option<Condition> {
+    let mut literals = parsed
+        .inner()
+        .filter(|p| SyntaxKind::Str == p.kind())
+        .map(|p| string_value(p.text()));
+
+    match parsed.kind() {
+        SyntaxKind::WhenBranch => literals.next().map(Condition::Branch),
+        SyntaxKind::WhenChangeset => literals.next().map(Condition::Changeset),
+        SyntaxKind::WhenEnvironment => literals.next().map(|name| Condition::Environment {
+            name,
+            value: literals.next(),
+        }),
+        SyntaxKind::WhenNot => parsed
+            .inner()
+            .find_map(|p| lower_condition(p, source))
+            .map(|condition| Condition::Not(Box::new(condition))),
+        SyntaxKind::WhenAnyOf => Some(Condition::AnyOf(lower_conditions(parsed, source))),
+        SyntaxKind::WhenAllOf => Some(Condition::AllOf(lower_conditions(parsed, source))),
+        _ => None,
+    }
+}
+
+/**
+ * Lower a post block, the steps of which belong to the context with the given
+ * uuid
+ */
+fn lower_post(parsed: &SyntaxElement, uuid: Uuid, source: &mut Source) -> Post {
+    let mut post = Post::default();
+    let mut declared = vec![];
+
+    for parsed in parsed.inner() {
+        match parsed.kind() {
+            SyntaxKind::PostCondition => {
+                let condition = first_word(parsed);
+
+                if declared.contains(&condition) {
+                    source
+                        .error(
+                            codes::UNEXPECTED_TOKEN,
+                            format!("unexpected second `{}` condition", condition),
+                            parsed.start(),
+                            parsed.start() + condition.len(),
+                        )
+                        .hint = Some("each condition may only be declared once".to_string());
+                    continue;
+                }
+                declared.push(condition);
+
+                let steps = lower_steps(parsed, uuid, source);
+                match condition {
+                    "always" => post.always = steps,
+                    "success" => post.success = steps,
+                    "failure" => post.failure = steps,
+                    "unstable" => post.unstable = steps,
+                    "aborted" => post.aborted = steps,
+                    _ => {}
+                }
+            }
+            SyntaxKind::Invalid => source.invalid(parsed),
+            _ => {}
+        }
+    }
+    post
+}
+
+fn duplicate_post(parsed: &SyntaxElement, source: &mut Source) {
+    source
+        .error(
+            codes::UNEXPECTED_TOKEN,
+            "unexpected second `post` block".to_string(),
+            parsed.start(),
+            parsed.start() + "post".len(),
+        )
+        .hint = Some("the conditions should all be in one `post` block".to_string());
+}
+
+/**
+ * Lower a matrix block, expanding its stage into a context for every
+ * combination of the axis values which has not been excluded
+ */
+fn lower_matrix(parsed: &SyntaxElement, source: &mut Source) -> Vec<Context> {
+    let mut axes: Vec<(String, Vec<String>)> = vec![];
+    let mut excludes = vec![];
+    let mut stage: Option<Context> = None;
+
+    for block in parsed.inner() {
+        match block.kind() {
+            SyntaxKind::Axes => {
+                for axis in block.inner() {
+                    match axis.kind() {
+                        SyntaxKind::Axis => {
+                            let mut inner = axis.inner();
+                            let name = inner.next().map(|p| p.text()).unwrap_or_default();
+
+                            if axes.iter().any(|(n, _)| n == name) {
+                                source
+                                    .error(
+                                        codes::DUPLICATE_AXIS,
+                                        format!("axis `{}` is declared more than once", name),
+                                        axis.start(),
+                                        axis.start() + name.len(),
+                                    )
+                                    .hint = Some("each axis may only be declared once".to_string());
+                                continue;
+                            }
+
+                            let values = inner
+                                .filter(|p| SyntaxKind::Str == p.kind())
+                                .map(|p| string_value(p.text()))
+                                .collect();
+                            axes.push((name.to_string(), values));
+                        }
+                        SyntaxKind::Invalid => source.invalid(axis),
+                        _ => {}
+                    }
+                }
+            }
+            SyntaxKind::Excludes => {
+                for exclude in block.inner() {
+                    match exclude.kind() {
+                        SyntaxKind::Exclude => excludes.push(exclude),
+                        SyntaxKind::Invalid => source.invalid(exclude),
+                        _ => {}
+                    }
+                }
+            }
+            SyntaxKind::Stage => {
+                if stage.is_some() {
+                    source
+                        .error(
+                            codes::UNEXPECTED_TOKEN,
+                            "unexpected second `stage` in the matrix".to_string(),
+                            block.start(),
+                            block.start() + "stage".len(),
+                        )
+                        .hint = Some("a matrix expands exactly one `stage`".to_string());
+                    continue;
+                }
+                stage = Some(lower_stage(block, source));
+            }
+            SyntaxKind::Invalid => source.invalid(block),
+            _ => {}
+        }
+    }
+
+    // The exclusions are checked once all of the axes are known, since they
+    // may be declared before the axes
+    let excludes: Vec<HashMap<String, String>> = excludes
+        .into_iter()
+        .map(|exclude| lower_exclude(exclude, &axes, source))
+        .collect();
+
+    if axes.is_empty() {
+        source
+            .error(
+                codes::MISSING_AXES,
+                "matrix is missing its axes".to_string(),
+                parsed.start(),
+                parsed.start() + "matrix".len(),
+            )
+            .hint = Some("declare the axes, e.g. `axes { OS = ['linux', 'macos'] }`".to_string());
+    }
+
+    let stage = match stage {
+        Some(stage) => stage,
+        None => {
+            source
+                .error(
+                    codes::MISSING_STAGE,
+                    "matrix is missing a `stage`".to_string(),
+                    parsed.start(),
+                    parsed.start() + "matrix".len(),
+                )
+                .hint =
+                Some("a matrix requires a `stage` to expand for each combination".to_string());
+            return vec![];
+        }
+    };
+
+    // The size is checked before anything is expanded, since a handful of
+    // axes with a handful of values each can make a great many combinations
+    let size = axes.iter().fold(1usize, |size, (_, values)| {
+        size.saturating_mul(values.len())
+    });
+    if size > MAX_MATRIX_STAGES {
+        source
+            .error(
+                codes::MATRIX_TOO_LARGE,
+                format!(
+                    "matrix would expand into {} stages, more than the {} allowed",
+                    size, MAX_MATRIX_STAGES
+                ),
+                parsed.start(),
+                parsed.start() + "matrix".len(),
+            )
+            .hint = Some("use fewer axes, or fewer values for them".to_string());
+        return vec![];
+    }
+
+    let mut combinations: Vec<Vec<(&String, &String)>> = vec![vec![]];
+    for (name, values) in axes.iter() {
+        combinations = combinations
+            .into_iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.push((name, value));
+                    combination
+                })
+            })
+            .collect();
+    }
+
+    let expanded: Vec<Context> = combinations
+        .iter()
+        .filter(|combination| {
+            !excludes.iter().any(|exclude| {
+                !exclude.is_empty()
+                    && exclude
+                        .iter()
+                        .all(|(axis, value)| combination.contains(&(axis, value)))
+            })
+        })
+        .map(|combination| expand_stage(&stage, combination))
+        .collect();
+
+    // The expanded stages need what the matrix's stage needed, and can all be
+    // needed by the name of the matrix's stage
+    if let Some(needs) = source.needs.remove(&stage.uuid) {
+        for ctx in expanded.iter() {
+            source.needs.insert(ctx.uuid, needs.clone());
+        }
+    }
+    if let Some(name) = stage.properties.get("name") {
+        for ctx in expanded.iter() {
+            source.stage_aliases.push((name.clone(), ctx.uuid));
+        }
+    }
+    expanded
+}
+
+/**
+ * Lower an exclude block into the axis values it matches, reporting any axes
+ * or values which are not part of the matrix
+ */
+fn lower_exclude(
+    parsed: &SyntaxElement,
+    axes: &[(String, Vec<String>)],
+    source: &mut Source,
+) -> HashMap<String, String> {
+    let mut exclude = HashMap::new();
+
+    for parsed in parsed.inner() {
+        match parsed.kind() {
+            SyntaxKind::Property => {
+                if let Some((key, value)) = lower_property(parsed) {
+                    match axes.iter().find(|(name, _)| name == &key) {
+                        Some((_, values)) if !values.contains(&value) => {
+                            source
+                                .error(
+                                    codes::UNKNOWN_AXIS,
+                                    format!("`{}` is not a value of the axis `{}`", value, key),
+                                    parsed.start(),
+                                    parsed.end(),
+                                )
+                                .expected = values.iter().map(|v| format!("`{}`", v)).collect();
+                        }
+                        Some(_) => {}
+                        None => {
+                            source
+                                .error(
+                                    codes::UNKNOWN_AXIS,
+                                    format!("unknown axis `{}`", key),
+                                    parsed.start(),
+                                    parsed.start() + key.len(),
+                                )
+                                .expected = axes.iter().map(|(n, _)| format!("`{}`", n)).collect();
+                        }
+                    }
+                    exclude.insert(key, value);
+                }
+            }
+            SyntaxKind::Invalid => source.invalid(parsed),
+            _ => {}
+        }
+    }
+    exclude
+}
+
+/**
+ * Create a copy of the matrix's stage for the combination of axis values,
+ * which are added to both its properties and its environment
+ */
+fn expand_stage(stage: &Context, combination: &[(&String, &String)]) -> Context {
+    let mut ctx = renew(stage);
+
+    // Every expanded stage would otherwise share the same name
+    if let Some(name) = ctx.properties.get_mut("name") {
+        let values: Vec<&str> = combination.iter().map(|(_, v)| v.as_str()).collect();
+        *name = format!("{} ({})", name, values.join(", "));
+    }
+
+    let environment = ctx.environment.get_or_insert_with(HashMap::new);
+    for (axis, value) in combination.iter() {
+        ctx.properties.insert(axis.to_string(), value.to_string());
+        environment.insert(axis.to_string(), value.to_string());
+    }
+    ctx
+}
+
+/**
+ * Create a copy of the context with a new uuid, whose steps are copied into it
+ */
+pub(crate) fn renew(context: &Context) -> Context {
+    let mut ctx = context.clone();
+    ctx.uuid = generate_uuid();
+    assign_context(&mut ctx.steps, ctx.uuid);
+    if let Some(post) = ctx.post.as_mut() {
+        for steps in [
+            &mut post.always,
+            &mut post.success,
+            &mut post.failure,
+            &mut post.unstable,
+            &mut post.aborted,
+        ] {
+            assign_context(steps, ctx.uuid);
+        }
+    }
+    ctx
+}
+
+/**
+ * Give the steps, and any steps nested in their blocks, new uuids belonging to
+ * the given context
+ */
+pub(crate) fn assign_context(steps: &mut [Step], context: Uuid) {
+    for step in steps.iter_mut() {
+        step.uuid = generate_uuid();
+        step.context = context;
+        if let Some(block) = step.block.as_mut() {
+            assign_context(block, context);
+        }
+    }
+}
+
+/**
+ * Lower a string literal into either a string, or an interpolation if it is
+ * double quoted and references other values
+ */
+fn lower_interpolated_str(parsed: &SyntaxElement, source: &mut Source) -> Value {
+    let (quote, content) = string_content(parsed.text());
+    let text = match quote {
+        "\"" => content.to_string(),
+        "\"\"\"" => strip_indent(content),
+        _ => return Value::String(string_value(parsed.text())),
+    };
+
+    // The references are checked in the string as it was written, so that the
+    // diagnostics point at the right place
+    let offset = parsed.start() + quote.len();
+    match interpolate::parse(content, source.buffer, offset) {
+        Err(diagnostic) => {
+            source.diagnostics.push(*diagnostic);
+            return Value::String(string_value(parsed.text()));
+        }
+        Ok(Some(interpolation)) => {
+            for segment in interpolation.segments {
+                if let Segment::Reference(Reference::Parameter(name)) = segment {
+                    source
+                        .parameter_references
+                        .push((name, parsed.start(), parsed.end()));
+                }
+            }
+        }
+        Ok(None) => {}
+    }
+
+    if let Ok(Some(interpolation)) = interpolate::parse(&text, &text, 0) {
+        return interpolation.to_value();
+    }
+    Value::String(string_value(parsed.text()))
+}
+
+/**
+ * Lower a keyword argument into a tuple of its key and value
+ */
+fn lower_kwarg(parsed: &SyntaxElement, source: &mut Source) -> Option<(String, Value)> {
+    let mut key: Option<String> = None;
+    let mut value: Option<Value> = None;
+
+    for parsed in parsed.inner() {
+        match parsed.kind() {
+            SyntaxKind::Ident => {
+                key = Some(parsed.text().to_string());
+            }
+            _ => {
+                value = lower_value(parsed, source);
+            }
+        }
+    }
+
+    match (key, value) {
+        (Some(key), Some(value)) => Some((key, value)),
+        _ => None,
+    }
+}
+
+/**
+ * Lower a single value (a string, number, boolean, list or map) into the
+ * appropriate serde_json::Value
+ *
+ * Double-quoted strings which reference other values, e.g. `"${env.HOME}"`,
+ * are lowered into an Interpolation for the agent to resolve
+ *
+ * Returns None if the element is not a value at all
+ */
+fn lower_value(parsed: &SyntaxElement, source: &mut Source) -> Option<Value> {
+    match parsed.kind() {
+        SyntaxKind::Str => Some(lower_interpolated_str(parsed, source)),
+        SyntaxKind::Boolean => Some(Value::Bool(parsed.text() == "true")),
+        SyntaxKind::Integer | SyntaxKind::Float => lower_number(parsed, source),
+        SyntaxKind::List => Some(Value::Array(
+            parsed
+                .inner()
+                .filter_map(|p| lower_value(p, source))
+                .collect(),
+        )),
+        SyntaxKind::Map => {
+            let mut map = serde_json::Map::new();
+
+            for entry in parsed.inner() {
+                if SyntaxKind::MapEntry == entry.kind() {
+                    let mut inner = entry.inner();
+
+                    if let (Some(key), Some(value)) = (inner.next(), inner.next()) {
+                        let key = escape_key(match key.kind() {
+                            SyntaxKind::Str => string_value(key.text()),
+                            _ => key.text().to_string(),
+                        });
+
+                        if let Some(value) = lower_value(value, source) {
+                            map.insert(key, value);
+                        }
+                    }
+                }
+            }
+            Some(Value::Object(map))
+        }
+        _ => None,
+    }
+}
+
+/**
+ * Lower a number, reporting those which are out of range rather than letting
+ * them become null or lose their precision
+ *
+ * Whole numbers must fit into 64 bits, and other numbers must be finite
+ */
+fn lower_number(parsed: &SyntaxElement, source: &mut Source) -> Option<Value> {
+    let text = parsed.text();
+    let number = match parsed.kind() {
+        SyntaxKind::Integer => text
+            .parse::<i64>()
+            .map(Value::from)
+            .or_else(|_| text.parse::<u64>().map(Value::from))
+            .ok(),
+        _ => text
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number),
+    };
+
+    if number.is_none() {
+        source
+            .error(
+                codes::NUMBER_OUT_OF_RANGE,
+                format!("the number `{}` is out of range", text),
+                parsed.start(),
+                parsed.end(),
+            )
+            .hint = Some(format!(
+            "quote it as a string, e.g. `'{}'`, if it need not be a number",
+            text
+        ));
+    }
+    number
+}
+
+/**
+ * Lower the steps of a block of steps, such as `steps`, a post condition, a
+ * macro or the block of a step
+ *
+ * In the case of orphan steps, the uuid should be the pipeline's uuid
+ */
+pub(crate) fn lower_steps(parsed: &SyntaxElement, uuid: Uuid, source: &mut Source) -> Vec<Step> {
+    let mut steps = vec![];
+
+    for parsed in parsed.inner() {
+        if SyntaxKind::Invalid == parsed.kind() {
+            source.invalid(parsed);
+        } else if SyntaxKind::Step == parsed.kind() {
+            let location = source.location(parsed);
+            let mut symbol: Option<String> = None;
+            let mut kwargs: HashMap<String, Value> = HashMap::new();
+            let mut args: Vec<Value> = vec![];
+            let mut block: Option<Vec<Step>> = None;
+
+            // Grab the step components
+            for part in parsed.inner() {
+                match part.kind() {
+                    SyntaxKind::Ident => {
+                        symbol = Some(part.text().to_string());
+                    }
+                    SyntaxKind::Kwarg => {
+                        if let Some((key, value)) = lower_kwarg(part, source) {
+                            kwargs.insert(key, value);
+                        }
+                    }
+                    SyntaxKind::Args => {
+                        args.extend(part.inner().filter_map(|p| lower_value(p, source)));
+                    }
+                    SyntaxKind::Block => {
+                        // Nested steps belong to the same context as their parent
+                        block = Some(lower_steps(part, uuid, source));
+                    }
+                    _ => {}
+                }
+            }
+
+            if let Some(symbol) = symbol {
+                let parameters = if !kwargs.is_empty() {
+                    if !args.is_empty() {
+                        error!(
+                            "Parsed keyword and positional arguments out, discarding positionals"
+                        );
+                    }
+                    StepParameters::Keyword(kwargs)
+                } else {
+                    StepParameters::Positional(args)
+                };
+                let mut step = Step::new(uuid, symbol, parameters);
+                step.block = block;
+                step.source = Some(location);
+
+                if source.library.has_macro(&step.symbol) {
+                    steps.extend(library::call_macro(
+                        &step,
+                        parsed.start(),
+                        parsed.end(),
+                        source,
+                    ));
+                } else {
+                    steps.push(step);
+                }
+            }
+        }
+    }
+    steps
+}
+
+/**
+ * Lower a `steps` block, which unlike the blocks passed to steps must contain
+ * at least one step
+ */
+fn lower_steps_block(parsed: &SyntaxElement, uuid: Uuid, source: &mut Source) -> Vec<Step> {
+    if !parsed
+        .inner()
+        .any(|p| matches!(p.kind(), SyntaxKind::Step | SyntaxKind::Invalid))
+    {
+        source
+            .error(
+                codes::EMPTY_STEPS,
+                "`steps` block has no steps".to_string(),
+                parsed.start(),
+                parsed.start() + "steps".len(),
+            )
+            .hint = Some("add a step, e.g. `sh 'make'`".to_string());
+    }
+    lower_steps(parsed, uuid, source)
+}
+
+/**
+ * Lower a property (`key = 'value'`) into a tuple of the key and value
+ */
+fn lower_property(parsed: &SyntaxElement) -> Option<(String, String)> {
+    let mut inner = parsed.inner();
+    while let Some(part) = inner.next() {
+        if SyntaxKind::Ident == part.kind() {
+            let key = part.text().to_string();
+
+            // This should be a Str
+            if let Some(value) = inner.next() {
+                return Some((key, string_value(value.text())));
+            }
+        }
+    }
+    None
+}
+
+/**
+ * Lower the properties of an environment block into a map of variables
+ */
+fn lower_environment(parsed: &SyntaxElement, source: &mut Source) -> HashMap<String, String> {
+    let mut environment = HashMap::new();
+
+    for parsed in parsed.inner() {
+        match parsed.kind() {
+            SyntaxKind::Property => {
+                environment.extend(lower_property(parsed));
+            }
+            SyntaxKind::Invalid => source.invalid(parsed),
+            _ => {}
+        }
+    }
+    environment
+}
+
+/**
+ * Lower a stage, or the stage of a template
+ */
+pub(crate) fn lower_stage(parsed: &SyntaxElement, source: &mut Source) -> Context {
+    let keyword = first_word(parsed);
+    let mut stage = Context {
+        source: Some(source.location(parsed)),
+        ..Default::default()
+    };
+    let mut has_steps = false;
+    let mut has_agent = false;
+    // An empty `when` block has no conditions, but is still declared
+    let mut has_when = false;
+
+    debug!("stage: {}", parsed.text());
+
+    for parsed in parsed.inner() {
+        if has_steps
+            && matches!(
+                parsed.kind(),
+                SyntaxKind::Property
+                    | SyntaxKind::Needs
+                    | SyntaxKind::Agent
+                    | SyntaxKind::Environment
+            )
+        {
+            source
+                .error(
+                    codes::UNEXPECTED_TOKEN,
+                    format!(
+                        "unexpected `{}` after the `steps` block",
+                        first_word(parsed)
+                    ),
+                    parsed.start(),
+                    parsed.end(),
+                )
+                .hint = Some(
+                "properties and environment must be declared before the `steps` block".to_string(),
+            );
+            continue;
+        }
+
+        match parsed.kind() {
+            SyntaxKind::Property => {
+                if let Some((key, value)) = lower_property(parsed) {
+                    debug!("Adding to context key: {}, value: {}", key, value);
+                    stage.properties.insert(key, value);
+                }
+            }
+            SyntaxKind::Needs => {
+                if source.needs.contains_key(&stage.uuid) {
+                    source
+                        .error(
+                            codes::UNEXPECTED_TOKEN,
+                            "unexpected second `needs`".to_string(),
+                            parsed.start(),
+                            parsed.start() + "needs".len(),
+                        )
+                        .hint = Some("a stage may only declare its needs once".to_string());
+                    continue;
+                }
+                let names = parsed
+                    .inner()
+                    .filter(|p| SyntaxKind::Str == p.kind())
+                    .map(|p| (string_value(p.text()), p.start(), p.end()))
+                    .collect();
+                source.needs.insert(
+                    stage.uuid,
+                    Needs {
+                        names,
+                        start: parsed.start(),
+                        end: parsed.end(),
+                    },
+                );
+            }
+            SyntaxKind::Agent => {
+                if has_agent {
+                    source
+                        .error(
+                            codes::UNEXPECTED_TOKEN,
+                            "unexpected second `agent` block".to_string(),
+                            parsed.start(),
+                            parsed.start() + "agent".len(),
+                        )
+                        .hint = Some("a stage may only have one `agent` block".to_string());
+                    continue;
+                }
+                has_agent = true;
+                stage.agent = lower_agent(parsed, source);
+            }
+            SyntaxKind::Environment => {
+                let environment = lower_environment(parsed, source);
+                stage
+                    .environment
+                    .get_or_insert_with(HashMap::new)
+                    .extend(environment);
+            }
+            SyntaxKind::Steps => {
+                if has_steps {
+                    source
+                        .error(
+                            codes::UNEXPECTED_TOKEN,
+                            "unexpected second `steps` block".to_string(),
+                            parsed.start(),
+                            parsed.start() + "steps".len(),
+                        )
+                        .hint = Some("a stage may only have one `steps` block".to_string());
+                    continue;
+                }
+                has_steps = true;
+                let steps = lower_steps_block(parsed, stage.uuid, source);
+                stage.steps.extend(steps);
+            }
+            SyntaxKind::When => {
+                if has_when {
+                    source
+                        .error(
+                            codes::UNEXPECTED_TOKEN,
+                            "unexpected second `when` block".to_string(),
+                            parsed.start(),
+                            parsed.start() + "when".len(),
+                        )
+                        .hint =
+                        Some("the conditions should all be in one `when` block".to_string());
+                    continue;
+                }
+                has_when = true;
+                stage.when = lower_conditions(parsed, source);
+            }
+            SyntaxKind::Post => {
+                if stage.post.is_some() {
+                    duplicate_post(parsed, source);
+                    continue;
+                }
+                stage.post = Some(lower_post(parsed, stage.uuid, source));
+            }
+            SyntaxKind::Invalid => source.invalid(parsed),
+            _ => {}
+        }
+    }
+
+    if !has_steps {
+        source
+            .error(
+                codes::MISSING_STEPS,
+                format!("{} is missing a `steps` block", keyword),
+                parsed.start(),
+                parsed.start() + keyword.len(),
+            )
+            .hint = Some(format!("every {} requires a `steps` block", keyword));
+    }
+    stage
+}
+
+/**
+ * Lower an agent block into the requirements its stage has of the agent which
+ * runs it, returning None if the block has no valid label
+ */
+fn lower_agent(parsed: &SyntaxElement, source: &mut Source) -> Option<AgentRequirement> {
+    let mut labelled = false;
+    let mut label = None;
+
+    for part in parsed.inner() {
+        match part.kind() {
+            SyntaxKind::AgentLabel => {
+                if labelled {
+                    source
+                        .error(
+                            codes::UNEXPECTED_TOKEN,
+                            "unexpected second `label`".to_string(),
+                            part.start(),
+                            part.end(),
+                        )
+                        .hint = Some(
+                        "combine the labels into one expression, e.g. `label 'linux && rust'`"
+                            .to_string(),
+                    );
+                    continue;
+                }
+                labelled = true;
+
+                if let Some(literal) = part.inner().find(|p| SyntaxKind::Str == p.kind()) {
+                    let (start, end) = (literal.start(), literal.end());
+                    match labels::Expression::parse(&string_value(literal.text())) {
+                        Ok(expression) => label = Some(expression),
+                        Err(message) => {
+                            source.error(codes::INVALID_LABEL, message, start, end).hint = Some(
+                                "labels are combined with `&&`, `||` and `!`, e.g. `linux && !arm`"
+                                    .to_string(),
+                            );
+                        }
+                    }
+                }
+            }
+            SyntaxKind::Invalid => source.invalid(part),
+            _ => {}
+        }
+    }
+
+    if !labelled {
+        source
+            .error(
+                codes::INVALID_LABEL,
+                "`agent` block has no label".to_string(),
+                parsed.start(),
+                parsed.start() + "agent".len(),
+            )
+            .hint = Some("add the labels the agent must have, e.g. `label 'linux'`".to_string());
+    }
+    label.map(|label| AgentRequirement { label })
+}
+
+/**
+ * Return the first word of the element, typically the keyword or name which
+ * it starts with
+ */
+pub(crate) fn first_word<'a>(parsed: &SyntaxElement<'a>) -> &'a str {
+    let text = parsed.text();
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .next()
+        .unwrap_or(text)
+}
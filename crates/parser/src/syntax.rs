@@ -0,0 +1,998 @@
+/*
+ * The syntax module parses pipeline source into a lossless concrete syntax
+ * tree, which is the first of the two phases of parsing. The second phase
+ * lowers the tree into the Pipeline model.
+ *
+ * Every byte of the source belongs to exactly one token of the tree, including
+ * the whitespace and comments between the tokens, so that tools such as the
+ * formatter can work from the tree and still write back exactly what the user
+ * wrote.
+ */
+
+use crate::{Diagnostic, PipelineParser, Rule};
+use pest::iterators::{Pair, Pairs};
+use pest::Parser;
+
+/**
+ * The kinds of the nodes and tokens of the syntax tree
+ *
+ * Nodes are named after the statements they hold, whereas tokens are the
+ * leaves of the tree
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SyntaxKind {
+    /// The root of every tree, holding the imports and either the pipeline or
+    /// the definitions of a library
+    File,
+    Import,
+    /// The `pipeline` block
+    Pipeline,
+    Template,
+    Macro,
+    /// A `use` statement naming a template
+    UseTemplate,
+    Stage,
+    Steps,
+    Step,
+    /// The block of nested steps following a step
+    Block,
+    /// The positional arguments of a step
+    Args,
+    /// A keyword argument of a step or parameter
+    Kwarg,
+    /// A `key = 'value'` property
+    Property,
+    Needs,
+    Agent,
+    AgentLabel,
+    When,
+    WhenBranch,
+    WhenEnvironment,
+    WhenChangeset,
+    WhenNot,
+    WhenAnyOf,
+    WhenAllOf,
+    Environment,
+    Parameters,
+    Parameter,
+    Triggers,
+    CronTrigger,
+    PushTrigger,
+    UpstreamTrigger,
+    Post,
+    PostCondition,
+    Parallel,
+    Fanout,
+    Matrix,
+    Axes,
+    Axis,
+    Excludes,
+    Exclude,
+    List,
+    Map,
+    MapEntry,
+
+    /// Words with a meaning in the syntax, e.g. `stage`, or the type of a
+    /// parameter
+    Keyword,
+    /// Names chosen by the user, e.g. the symbol of a step
+    Ident,
+    /// A string literal, along with its quotes
+    Str,
+    Integer,
+    Float,
+    Boolean,
+    LBrace,
+    RBrace,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Colon,
+    Equals,
+    Comma,
+    Whitespace,
+    Comment,
+    /// A statement which could not be parsed, up to the end of its line and
+    /// along with any block it opens
+    Invalid,
+}
+
+impl SyntaxKind {
+    /**
+     * Returns true for the tokens which have no meaning to the pipeline,
+     * whitespace and comments
+     */
+    pub fn is_trivia(self) -> bool {
+        matches!(self, SyntaxKind::Whitespace | SyntaxKind::Comment)
+    }
+
+    /**
+     * Returns true for the tokens which only separate or enclose the other
+     * parts of a statement
+     */
+    pub fn is_punctuation(self) -> bool {
+        matches!(
+            self,
+            SyntaxKind::LBrace
+                | SyntaxKind::RBrace
+                | SyntaxKind::LParen
+                | SyntaxKind::RParen
+                | SyntaxKind::LBracket
+                | SyntaxKind::RBracket
+                | SyntaxKind::Colon
+                | SyntaxKind::Equals
+                | SyntaxKind::Comma
+        )
+    }
+}
+
+/**
+ * A node of the syntax tree, which covers the text of all of its children
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxNode<'a> {
+    kind: SyntaxKind,
+    text: &'a str,
+    start: usize,
+    children: Vec<SyntaxElement<'a>>,
+}
+
+/**
+ * A leaf of the syntax tree
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SyntaxToken<'a> {
+    kind: SyntaxKind,
+    text: &'a str,
+    start: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SyntaxElement<'a> {
+    Node(SyntaxNode<'a>),
+    Token(SyntaxToken<'a>),
+}
+
+impl<'a> SyntaxNode<'a> {
+    pub fn kind(&self) -> SyntaxKind {
+        self.kind
+    }
+
+    pub fn text(&self) -> &'a str {
+        self.text
+    }
+
+    /// The byte offset of the start of the node in the source
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.start + self.text.len()
+    }
+
+    /**
+     * Every child of the node, including the trivia and punctuation
+     */
+    pub fn children(&self) -> &[SyntaxElement<'a>] {
+        &self.children
+    }
+
+    /**
+     * The children of the node which carry meaning, skipping the trivia and
+     * punctuation
+     */
+    pub fn inner(&self) -> impl Iterator<Item = &SyntaxElement<'a>> + Clone {
+        self.children
+            .iter()
+            .filter(|child| !(child.kind().is_trivia() || child.kind().is_punctuation()))
+    }
+
+    /**
+     * Every token under the node in the order they appear in the source, the
+     * text of which adds up to the text of the node
+     */
+    pub fn tokens(&self) -> Vec<SyntaxToken<'a>> {
+        let mut tokens = vec![];
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens(&self, tokens: &mut Vec<SyntaxToken<'a>>) {
+        for child in self.children.iter() {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(token) => tokens.push(*token),
+            }
+        }
+    }
+}
+
+impl<'a> SyntaxToken<'a> {
+    pub fn kind(&self) -> SyntaxKind {
+        self.kind
+    }
+
+    pub fn text(&self) -> &'a str {
+        self.text
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.start + self.text.len()
+    }
+}
+
+impl<'a> SyntaxElement<'a> {
+    pub fn kind(&self) -> SyntaxKind {
+        match self {
+            SyntaxElement::Node(node) => node.kind,
+            SyntaxElement::Token(token) => token.kind,
+        }
+    }
+
+    pub fn text(&self) -> &'a str {
+        match self {
+            SyntaxElement::Node(node) => node.text,
+            SyntaxElement::Token(token) => token.text,
+        }
+    }
+
+    pub fn start(&self) -> usize {
+        match self {
+            SyntaxElement::Node(node) => node.start,
+            SyntaxElement::Token(token) => token.start,
+        }
+    }
+
+    pub fn end(&self) -> usize {
+        self.start() + self.text().len()
+    }
+
+    pub fn as_node(&self) -> Option<&SyntaxNode<'a>> {
+        match self {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        }
+    }
+
+    pub fn as_token(&self) -> Option<&SyntaxToken<'a>> {
+        match self {
+            SyntaxElement::Node(_) => None,
+            SyntaxElement::Token(token) => Some(token),
+        }
+    }
+
+    /**
+     * Every child of the element, tokens have none
+     */
+    pub fn children(&self) -> &[SyntaxElement<'a>] {
+        match self {
+            SyntaxElement::Node(node) => &node.children,
+            SyntaxElement::Token(_) => &[],
+        }
+    }
+
+    /**
+     * The children of the element which carry meaning, tokens have none
+     */
+    pub fn inner(&self) -> impl Iterator<Item = &SyntaxElement<'a>> + Clone {
+        self.children()
+            .iter()
+            .filter(|child| !(child.kind().is_trivia() || child.kind().is_punctuation()))
+    }
+}
+
+/**
+ * The syntax tree of a pipeline or library, along with the syntax errors found
+ * while parsing it
+ */
+#[derive(Clone, Debug)]
+pub struct SyntaxTree<'a> {
+    root: SyntaxNode<'a>,
+    /// The diagnostics for the invalid statements, by the offset at which the
+    /// statement starts
+    errors: Vec<(usize, Diagnostic)>,
+}
+
+impl<'a> SyntaxTree<'a> {
+    /**
+     * Parse the buffer as a pipeline file
+     *
+     * Statements which cannot be parsed become `Invalid` tokens, so that the
+     * rest of the file can still be parsed. When the file cannot be parsed
+     * at all, the whole of it is a single `Invalid` token.
+     */
+    pub fn parse(buffer: &'a str) -> Self {
+        Self::parse_rule(buffer, Rule::pipeline)
+    }
+
+    /**
+     * Parse the buffer as a library of templates and macros
+     */
+    pub fn parse_library(buffer: &'a str) -> Self {
+        Self::parse_rule(buffer, Rule::library)
+    }
+
+    fn parse_rule(buffer: &'a str, rule: Rule) -> Self {
+        let mut builder = Builder {
+            buffer,
+            errors: vec![],
+        };
+
+        let root = match PipelineParser::parse(rule, buffer) {
+            Ok(pairs) => builder.root(pairs),
+            Err(e) => {
+                builder
+                    .errors
+                    .push((0, Diagnostic::from_pest(e, buffer, 0..buffer.len())));
+                SyntaxNode {
+                    kind: SyntaxKind::File,
+                    text: buffer,
+                    start: 0,
+                    children: vec![SyntaxElement::Token(SyntaxToken {
+                        kind: SyntaxKind::Invalid,
+                        text: buffer,
+                        start: 0,
+                    })],
+                }
+            }
+        };
+
+        Self {
+            root,
+            errors: builder.errors,
+        }
+    }
+
+    pub fn root(&self) -> &SyntaxNode<'a> {
+        &self.root
+    }
+
+    /**
+     * The syntax errors in the tree, in the order they appear in the source
+     */
+    pub fn diagnostics(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.errors.iter().map(|(_, diagnostic)| diagnostic)
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    /**
+     * The diagnostic for the `Invalid` token starting at the offset
+     */
+    pub(crate) fn error_at(&self, offset: usize) -> Option<&Diagnostic> {
+        self.errors
+            .iter()
+            .find(|(start, _)| *start == offset)
+            .map(|(_, diagnostic)| diagnostic)
+    }
+}
+
+/**
+ * Parse a string literal, such as the text of a `Str` token, into its actual
+ * value, processing the escape sequences and indentation for the quoting style
+ * that was used
+ */
+pub fn string_value(literal: &str) -> String {
+    let (quote, content) = string_content(literal);
+    match quote {
+        "'" => content.to_string(),
+        "\"" => unescape(content),
+        "'''" => strip_indent(content),
+        "\"\"\"" => unescape(&strip_indent(content)),
+        _ => "".to_string(),
+    }
+}
+
+/**
+ * Split a string literal into its quote and its content as it was written
+ */
+pub(crate) fn string_content(literal: &str) -> (&str, &str) {
+    for quote in ["'''", "\"\"\"", "'", "\""].iter() {
+        if literal.len() >= 2 * quote.len()
+            && literal.starts_with(quote)
+            && literal.ends_with(quote)
+        {
+            return (quote, &literal[quote.len()..literal.len() - quote.len()]);
+        }
+    }
+    ("", literal)
+}
+
+/**
+ * Replace the escape sequences allowed by the grammar with the characters they
+ * represent
+ */
+pub(crate) fn unescape(buffer: &str) -> String {
+    let mut unescaped = String::with_capacity(buffer.len());
+    let mut chars = buffer.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            // The grammar only otherwise allows \", \', \$ and \\
+            Some(other) => unescaped.push(other),
+            None => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/**
+ * Strip the common leading indentation from a multi-line string
+ *
+ * Much like Groovy's stripIndent(), a newline immediately following the
+ * opening quotes is dropped, and lines containing only whitespace do not count
+ * towards the common indentation. This allows multi-line scripts to be
+ * indented along with the rest of the pipeline:
+ *
+ * ```text
+ * sh '''
+ *     make
+ *     make install
+ * '''
+ * ```
+ */
+pub(crate) fn strip_indent(buffer: &str) -> String {
+    let buffer = buffer
+        .strip_prefix("\r\n")
+        .or_else(|| buffer.strip_prefix('\n'))
+        .unwrap_or(buffer);
+
+    let indent = buffer
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches(&[' ', '\t'][..]).len())
+        .min()
+        .unwrap_or(0);
+
+    buffer
+        .split('\n')
+        .map(|line| {
+            if line.trim().is_empty() {
+                ""
+            } else {
+                &line[indent..]
+            }
+        })
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/**
+ * What becomes of a pair from the grammar in the syntax tree
+ */
+enum Shape {
+    Node(SyntaxKind),
+    Token(SyntaxKind),
+    /// The children of the pair are added to its parent, for the rules which
+    /// only group statements
+    Flatten,
+    Skip,
+}
+
+fn shape(rule: Rule) -> Shape {
+    let kind = match rule {
+        Rule::IDENT => return Shape::Token(SyntaxKind::Ident),
+        Rule::STR => return Shape::Token(SyntaxKind::Str),
+        Rule::integer => return Shape::Token(SyntaxKind::Integer),
+        Rule::float => return Shape::Token(SyntaxKind::Float),
+        Rule::boolean => return Shape::Token(SyntaxKind::Boolean),
+        Rule::BLOCK_BEGIN => return Shape::Token(SyntaxKind::LBrace),
+        Rule::BLOCK_END => return Shape::Token(SyntaxKind::RBrace),
+        Rule::COMMA => return Shape::Token(SyntaxKind::Comma),
+        Rule::parameterType | Rule::postStatus => return Shape::Token(SyntaxKind::Keyword),
+        Rule::invalid => return Shape::Token(SyntaxKind::Invalid),
+        Rule::execBlocks | Rule::definitions => return Shape::Flatten,
+
+        Rule::import => SyntaxKind::Import,
+        Rule::pipelineBlock => SyntaxKind::Pipeline,
+        Rule::template => SyntaxKind::Template,
+        Rule::stepMacro => SyntaxKind::Macro,
+        Rule::useTemplate => SyntaxKind::UseTemplate,
+        Rule::stage => SyntaxKind::Stage,
+        Rule::steps => SyntaxKind::Steps,
+        Rule::step => SyntaxKind::Step,
+        Rule::block => SyntaxKind::Block,
+        Rule::args => SyntaxKind::Args,
+        Rule::kwarg => SyntaxKind::Kwarg,
+        Rule::property => SyntaxKind::Property,
+        Rule::needs => SyntaxKind::Needs,
+        Rule::agent => SyntaxKind::Agent,
+        Rule::agentLabel => SyntaxKind::AgentLabel,
+        Rule::when => SyntaxKind::When,
+        Rule::whenBranch => SyntaxKind::WhenBranch,
+        Rule::whenEnvironment => SyntaxKind::WhenEnvironment,
+        Rule::whenChangeset => SyntaxKind::WhenChangeset,
+        Rule::whenNot => SyntaxKind::WhenNot,
+        Rule::whenAnyOf => SyntaxKind::WhenAnyOf,
+        Rule::whenAllOf => SyntaxKind::WhenAllOf,
+        Rule::environment => SyntaxKind::Environment,
+        Rule::parameters => SyntaxKind::Parameters,
+        Rule::parameter => SyntaxKind::Parameter,
+        Rule::triggers => SyntaxKind::Triggers,
+        Rule::cronTrigger => SyntaxKind::CronTrigger,
+        Rule::pushTrigger => SyntaxKind::PushTrigger,
+        Rule::upstreamTrigger => SyntaxKind::UpstreamTrigger,
+        Rule::post => SyntaxKind::Post,
+        Rule::postCondition => SyntaxKind::PostCondition,
+        Rule::parallel => SyntaxKind::Parallel,
+        Rule::fanout => SyntaxKind::Fanout,
+        Rule::matrix => SyntaxKind::Matrix,
+        Rule::axes => SyntaxKind::Axes,
+        Rule::axis => SyntaxKind::Axis,
+        Rule::excludes => SyntaxKind::Excludes,
+        Rule::exclude => SyntaxKind::Exclude,
+        Rule::list => SyntaxKind::List,
+        Rule::map => SyntaxKind::Map,
+        Rule::mapEntry => SyntaxKind::MapEntry,
+        // The end of input, and the rules which only exist for re-parsing
+        // invalid statements
+        _ => return Shape::Skip,
+    };
+    Shape::Node(kind)
+}
+
+/**
+ * The rule for re-parsing the invalid statements found in the node, which
+ * will fail in the same way that they did the first time, but without
+ * anything else around them
+ */
+fn statement_rule(parent: SyntaxKind) -> Rule {
+    match parent {
+        SyntaxKind::File => Rule::libraryStatement,
+        SyntaxKind::Pipeline => Rule::execStatement,
+        SyntaxKind::Stage | SyntaxKind::Template => Rule::stageStatement,
+        SyntaxKind::Agent => Rule::agentStatement,
+        SyntaxKind::When | SyntaxKind::WhenAnyOf | SyntaxKind::WhenAllOf => Rule::whenStatement,
+        SyntaxKind::Post => Rule::postStatement,
+        SyntaxKind::Environment | SyntaxKind::Exclude => Rule::environmentStatement,
+        SyntaxKind::Parameters => Rule::parametersStatement,
+        SyntaxKind::Triggers => Rule::triggersStatement,
+        SyntaxKind::Parallel | SyntaxKind::Fanout => Rule::parallelStatement,
+        SyntaxKind::Matrix => Rule::matrixStatement,
+        SyntaxKind::Axes => Rule::axesStatement,
+        SyntaxKind::Excludes => Rule::excludesStatement,
+        _ => Rule::stepStatement,
+    }
+}
+
+/**
+ * The Builder turns the pairs from the grammar into the syntax tree, filling
+ * in the tokens which the grammar does not produce pairs for, such as the
+ * keywords, punctuation and trivia
+ */
+struct Builder<'a> {
+    buffer: &'a str,
+    errors: Vec<(usize, Diagnostic)>,
+}
+
+impl<'a> Builder<'a> {
+    fn root(&mut self, pairs: Pairs<'a, Rule>) -> SyntaxNode<'a> {
+        let mut children = vec![];
+        let mut cursor = 0;
+        self.pairs(pairs, SyntaxKind::File, &mut children, &mut cursor);
+        self.lex(cursor, self.buffer.len(), &mut children);
+
+        SyntaxNode {
+            kind: SyntaxKind::File,
+            text: self.buffer,
+            start: 0,
+            children,
+        }
+    }
+
+    fn node(&mut self, kind: SyntaxKind, pair: Pair<'a, Rule>) -> SyntaxNode<'a> {
+        let span = pair.as_span();
+        let mut children = vec![];
+        let mut cursor = span.start();
+        self.pairs(pair.into_inner(), kind, &mut children, &mut cursor);
+        self.lex(cursor, span.end(), &mut children);
+
+        SyntaxNode {
+            kind,
+            text: span.as_str(),
+            start: span.start(),
+            children,
+        }
+    }
+
+    /**
+     * Add the pairs to the children of the parent, along with the tokens
+     * between them, the cursor being the end of the last child
+     */
+    fn pairs(
+        &mut self,
+        pairs: Pairs<'a, Rule>,
+        parent: SyntaxKind,
+        children: &mut Vec<SyntaxElement<'a>>,
+        cursor: &mut usize,
+    ) {
+        for pair in pairs {
+            let span = pair.as_span();
+            let element = match shape(pair.as_rule()) {
+                Shape::Skip => continue,
+                Shape::Flatten => {
+                    self.pairs(pair.into_inner(), parent, children, cursor);
+                    continue;
+                }
+                Shape::Token(kind) => {
+                    if SyntaxKind::Invalid == kind {
+                        self.invalid(&pair, parent);
+                    }
+                    SyntaxElement::Token(SyntaxToken {
+                        kind,
+                        text: span.as_str(),
+                        start: span.start(),
+                    })
+                }
+                Shape::Node(kind) => SyntaxElement::Node(self.node(kind, pair)),
+            };
+
+            self.lex(*cursor, span.start(), children);
+            children.push(element);
+            *cursor = span.end();
+        }
+    }
+
+    /**
+     * Record the diagnostic for an invalid statement by re-parsing it with the
+     * statement rule of its parent
+     */
+    fn invalid(&mut self, pair: &Pair<'a, Rule>, parent: SyntaxKind) {
+        let span = pair.as_span();
+        let window = span.start()..span.end();
+
+        let diagnostic = match PipelineParser::parse(statement_rule(parent), span.as_str()) {
+            Err(e) => Diagnostic::from_pest(e, self.buffer, window),
+            Ok(_) => Diagnostic::unexpected(self.buffer, window.start),
+        };
+        self.errors.push((span.start(), diagnostic));
+    }
+
+    /**
+     * Add the tokens of the text between two pairs, which can only be the
+     * literals of the grammar, whitespace or comments
+     */
+    fn lex(&self, start: usize, end: usize, children: &mut Vec<SyntaxElement<'a>>) {
+        children.extend(
+            lex(&self.buffer[start..end], start)
+                .into_iter()
+                .map(SyntaxElement::Token),
+        );
+    }
+}
+
+/**
+ * Split the text into tokens, the first of which starts at the given offset of
+ * the source
+ *
+ * This works without the grammar, so that the text of an `Invalid` token can
+ * still be made sense of, such as a statement which is still being typed.
+ * Every word is a `Keyword`, and strings or block comments which are never
+ * closed are `Invalid` tokens running to the end of the text.
+ */
+pub fn lex(text: &str, start: usize) -> Vec<SyntaxToken<'_>> {
+    let mut tokens = vec![];
+    let mut offset = 0;
+
+    while offset < text.len() {
+        let rest = &text[offset..];
+        let first = rest.chars().next().unwrap_or_default();
+
+        let (kind, length) = if rest.starts_with("//") {
+            (SyntaxKind::Comment, rest.find('\n').unwrap_or(rest.len()))
+        } else if rest.starts_with("/*") {
+            match block_comment_length(rest) {
+                Some(length) => (SyntaxKind::Comment, length),
+                None => (SyntaxKind::Invalid, rest.len()),
+            }
+        } else if first == '\'' || first == '"' {
+            match string_length(rest) {
+                Some(length) => (SyntaxKind::Str, length),
+                None => (SyntaxKind::Invalid, rest.len()),
+            }
+        } else if first.is_whitespace() {
+            (
+                SyntaxKind::Whitespace,
+                rest.find(|c: char| !c.is_whitespace())
+                    .unwrap_or(rest.len()),
+            )
+        } else if first.is_ascii_alphanumeric() || first == '_' {
+            (
+                SyntaxKind::Keyword,
+                rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len()),
+            )
+        } else {
+            let kind = match first {
+                '{' => SyntaxKind::LBrace,
+                '}' => SyntaxKind::RBrace,
+                '(' => SyntaxKind::LParen,
+                ')' => SyntaxKind::RParen,
+                '[' => SyntaxKind::LBracket,
+                ']' => SyntaxKind::RBracket,
+                ':' => SyntaxKind::Colon,
+                '=' => SyntaxKind::Equals,
+                ',' => SyntaxKind::Comma,
+                _ => SyntaxKind::Invalid,
+            };
+            (kind, first.len_utf8())
+        };
+
+        tokens.push(SyntaxToken {
+            kind,
+            text: &text[offset..offset + length],
+            start: start + offset,
+        });
+        offset += length;
+    }
+    tokens
+}
+
+/**
+ * Return the length of the string literal at the start of the buffer, or None
+ * if it is never closed
+ */
+fn string_length(buffer: &str) -> Option<usize> {
+    for quote in ["'''", "\"\"\"", "'", "\""].iter() {
+        if !buffer.starts_with(quote) {
+            continue;
+        }
+        if quote.len() == 3 || *quote == "'" {
+            return buffer[quote.len()..]
+                .find(quote)
+                .map(|end| 2 * quote.len() + end);
+        }
+
+        // Only double quoted strings have escapes
+        let mut escaped = false;
+        for (i, c) in buffer.char_indices().skip(1) {
+            match c {
+                '\\' if !escaped => escaped = true,
+                '"' if !escaped => return Some(i + 1),
+                _ => escaped = false,
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/**
+ * Return the length of the block comment at the start of the buffer, which
+ * like the grammar allows for nested block comments, or None if it is never
+ * closed
+ */
+fn block_comment_length(buffer: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut offset = 0;
+
+    while offset < buffer.len() {
+        let rest = &buffer[offset..];
+        if rest.starts_with("/*") {
+            depth += 1;
+            offset += 2;
+        } else if rest.starts_with("*/") {
+            depth -= 1;
+            offset += 2;
+            if depth == 0 {
+                return Some(offset);
+            }
+        } else {
+            offset += rest.chars().next().map(|c| c.len_utf8()).unwrap_or(1);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(node: &SyntaxNode) -> Vec<SyntaxKind> {
+        node.children().iter().map(|child| child.kind()).collect()
+    }
+
+    fn find<'t, 'a>(node: &'t SyntaxNode<'a>, kind: SyntaxKind) -> &'t SyntaxNode<'a> {
+        node.children()
+            .iter()
+            .find_map(|child| child.as_node().filter(|n| n.kind() == kind))
+            .expect("Missing the node")
+    }
+
+    /**
+     * Every byte of the buffer must be in exactly one token, in order
+     */
+    fn assert_lossless(buffer: &str, tree: &SyntaxTree) {
+        let mut offset = 0;
+        for token in tree.root().tokens() {
+            assert_eq!(token.start(), offset, "{:?}", token);
+            offset = token.end();
+        }
+        assert_eq!(offset, buffer.len());
+        let text: String = tree.root().tokens().iter().map(|t| t.text()).collect();
+        assert_eq!(text, buffer);
+    }
+
+    #[test]
+    fn tree_is_lossless() {
+        let buffer = r#"// The pipeline
+import 'lib/release.otto'
+
+pipeline { /* inline */
+    environment { CC = 'clang' }
+    stage {
+        name = "Build ${env.CC}"
+        needs = ['Lint', ]
+        when { not { branch 'main' } }
+        steps {
+            sh script: '''
+                make
+            ''', returnStdout: true
+            dir('a') { example [1, 2.5e3, [key: false], [:]] }
+        }
+    }
+    use release
+}
+"#;
+        let tree = SyntaxTree::parse(buffer);
+        assert!(!tree.has_errors());
+        assert_lossless(buffer, &tree);
+
+        let root = tree.root();
+        assert_eq!(root.kind(), SyntaxKind::File);
+        assert_eq!(
+            kinds(root),
+            vec![
+                SyntaxKind::Comment,
+                SyntaxKind::Whitespace,
+                SyntaxKind::Import,
+                SyntaxKind::Whitespace,
+                SyntaxKind::Pipeline,
+                SyntaxKind::Whitespace,
+            ]
+        );
+
+        let pipeline = find(root, SyntaxKind::Pipeline);
+        assert!(pipeline.text().starts_with("pipeline {"));
+        assert_eq!(
+            pipeline.inner().map(|c| c.kind()).collect::<Vec<_>>(),
+            vec![
+                SyntaxKind::Keyword,
+                SyntaxKind::Environment,
+                SyntaxKind::Stage,
+                SyntaxKind::UseTemplate,
+            ]
+        );
+        assert!(pipeline
+            .children()
+            .iter()
+            .any(|c| c.kind() == SyntaxKind::Comment && c.text() == "/* inline */"));
+
+        let stage = find(pipeline, SyntaxKind::Stage);
+        let steps = find(stage, SyntaxKind::Steps);
+        let sh = find(steps, SyntaxKind::Step);
+        assert_eq!(
+            sh.inner().map(|c| c.kind()).collect::<Vec<_>>(),
+            vec![SyntaxKind::Ident, SyntaxKind::Kwarg, SyntaxKind::Kwarg]
+        );
+        assert_eq!(
+            kinds(find(sh, SyntaxKind::Kwarg)),
+            vec![
+                SyntaxKind::Ident,
+                SyntaxKind::Colon,
+                SyntaxKind::Whitespace,
+                SyntaxKind::Str,
+            ]
+        );
+    }
+
+    #[test]
+    fn tree_of_invalid_statements() {
+        let buffer = "pipeline {\n  stages { }\n  steps {\n    sh 'ls' !\n  }\n}";
+        let tree = SyntaxTree::parse(buffer);
+        assert_lossless(buffer, &tree);
+
+        let lines: Vec<usize> = tree.diagnostics().map(|d| d.start.line).collect();
+        assert_eq!(lines, vec![2, 4]);
+
+        let pipeline = find(tree.root(), SyntaxKind::Pipeline);
+        let invalid = pipeline
+            .inner()
+            .find(|c| c.kind() == SyntaxKind::Invalid)
+            .expect("Missing the invalid statement");
+        assert_eq!(invalid.text(), "stages { }");
+        assert_eq!(
+            tree.error_at(invalid.start()).map(|d| d.message.as_str()),
+            Some("unexpected `stages`")
+        );
+    }
+
+    #[test]
+    fn tree_of_unparseable_file() {
+        let buffer = "pipeline {\n  steps { sh 'ls' }\n";
+        let tree = SyntaxTree::parse(buffer);
+        assert_lossless(buffer, &tree);
+        assert_eq!(kinds(tree.root()), vec![SyntaxKind::Invalid]);
+        assert_eq!(tree.diagnostics().count(), 1);
+    }
+
+    #[test]
+    fn tree_of_library() {
+        let buffer =
+            "macro lint {\n  sh 'make lint'\n}\n\ntemplate release {\n  steps { lint() }\n}\n";
+        let tree = SyntaxTree::parse_library(buffer);
+        assert!(!tree.has_errors());
+        assert_lossless(buffer, &tree);
+        assert_eq!(
+            tree.root().inner().map(|c| c.kind()).collect::<Vec<_>>(),
+            vec![SyntaxKind::Macro, SyntaxKind::Template]
+        );
+    }
+
+    #[test]
+    fn lex_unparseable_text() {
+        let tokens = lex("dir(\"a\\\"\") { sh 'make", 10);
+        assert_eq!(
+            tokens.iter().map(|t| t.kind()).collect::<Vec<_>>(),
+            vec![
+                SyntaxKind::Keyword,
+                SyntaxKind::LParen,
+                SyntaxKind::Str,
+                SyntaxKind::RParen,
+                SyntaxKind::Whitespace,
+                SyntaxKind::LBrace,
+                SyntaxKind::Whitespace,
+                SyntaxKind::Keyword,
+                SyntaxKind::Whitespace,
+                SyntaxKind::Invalid,
+            ]
+        );
+        assert_eq!(tokens[0].start(), 10);
+        assert_eq!(tokens[2].text(), "\"a\\\"\"");
+        assert_eq!(tokens[9].text(), "'make");
+
+        let tokens = lex("/* a /* b */", 0);
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].kind(), SyntaxKind::Invalid);
+    }
+
+    #[test]
+    fn string_values() {
+        assert_eq!(string_value("'a\\n'"), "a\\n");
+        assert_eq!(string_value("\"a\\n\""), "a\n");
+        assert_eq!(string_value("'''\n  a\n    b\n'''"), "a\n  b\n");
+        assert_eq!(string_value("''"), "");
+        assert_eq!(string_content("'''"), ("'", "'"));
+    }
+
+    #[test]
+    fn strip_indent_blank_lines() {
+        assert_eq!(strip_indent("\n    a\n\n      b\n    "), "a\n\n  b\n");
+    }
+
+    #[test]
+    fn strip_indent_single_line() {
+        assert_eq!(strip_indent("hello"), "hello");
+    }
+
+    #[test]
+    fn unescape_sequences() {
+        assert_eq!(unescape(r#"a\tb\nc\"d\'e\\f\rg"#), "a\tb\nc\"d'e\\f\rg");
+    }
+}
@@ -0,0 +1,257 @@
+/*
+ * The validate module checks a parsed pipeline against the manifests of the
+ * steps which are available, catching mistakes such as misspelled steps or
+ * missing parameters before anything is executed
+ *
+ * The steps themselves are validated by `otto_models::osp`, so that the agent
+ * can validate them without the parser, this module turns the problems found
+ * into diagnostics
+ */
+
+use crate::diagnostic::{Diagnostic, Position, Severity};
+use otto_models::osp::{Manifest, Problem};
+use otto_models::*;
+use std::collections::HashMap;
+
+/**
+ * Validate every step in the pipeline against the given manifests, keyed by
+ * their symbols, returning a diagnostic for every problem found
+ */
+pub fn validate(pipeline: &Pipeline, manifests: &HashMap<String, Manifest>) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+
+    for ctx in pipeline
+        .batches
+        .iter()
+        .flat_map(|batch| batch.contexts.iter())
+    {
+        diagnostics.extend(validate_steps(&ctx.steps, manifests));
+        if let Some(post) = &ctx.post {
+            diagnostics.extend(validate_post(post, manifests));
+        }
+    }
+
+    if let Some(post) = &pipeline.post {
+        diagnostics.extend(validate_post(post, manifests));
+    }
+    diagnostics
+}
+
+fn validate_post(post: &Post, manifests: &HashMap<String, Manifest>) -> Vec<Diagnostic> {
+    post.steps()
+        .flat_map(|step| validate_steps(std::slice::from_ref(step), manifests))
+        .collect()
+}
+
+/**
+ * Validate the steps, and any steps nested in their blocks, against the given
+ * manifests
+ */
+pub fn validate_steps(steps: &[Step], manifests: &HashMap<String, Manifest>) -> Vec<Diagnostic> {
+    osp::validate_steps(steps, manifests)
+        .into_iter()
+        .map(diagnose)
+        .collect()
+}
+
+/**
+ * Create an error diagnostic covering the symbol of the step the problem was
+ * found in
+ *
+ * Steps which were not parsed from a file, and therefore have no source
+ * location, will have their diagnostic at line zero. Steps which were parsed
+ * from a named file, such as an imported library, have their diagnostic in
+ * that file
+ */
+fn diagnose(problem: Problem) -> Diagnostic {
+    let (start, end) = match &problem.source {
+        Some(source) => (
+            Position {
+                line: source.line,
+                column: source.column,
+                offset: source.start,
+            },
+            Position {
+                line: source.line,
+                column: source.column + problem.symbol.chars().count(),
+                offset: source.start + problem.symbol.len(),
+            },
+        ),
+        None => {
+            let unknown = Position {
+                line: 0,
+                column: 0,
+                offset: 0,
+            };
+            (unknown, unknown)
+        }
+    };
+
+    Diagnostic {
+        severity: Severity::Error,
+        code: problem.code,
+        message: problem.message,
+        file: problem.source.and_then(|source| source.file),
+        library_line: None,
+        start,
+        end,
+        expected: problem.expected,
+        hint: problem.hint,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::codes;
+    use crate::parse_pipeline_string;
+
+    fn manifests() -> HashMap<String, Manifest> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../../stdlib");
+        osp::load_manifests(&path).expect("Failed to load the stdlib manifests")
+    }
+
+    fn validate_str(buf: &str) -> Vec<Diagnostic> {
+        let pipeline = parse_pipeline_string(buf).expect("Failed to parse");
+        validate(&pipeline, &manifests())
+    }
+
+    #[test]
+    fn validate_valid() {
+        let diagnostics = validate_str(
+            r#"pipeline {
+                steps {
+                    sh 'ls'
+                    sh script: 'ls', returnStatus: true
+                    dir('subproject') {
+                        echo 'hi'
+                    }
+                }
+            }"#,
+        );
+        assert_eq!(diagnostics, vec![]);
+    }
+
+    #[test]
+    fn validate_unknown_step() {
+        let diagnostics = validate_str("pipeline {\n  steps {\n    shh 'ls'\n  }\n}");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, codes::UNKNOWN_STEP);
+        assert_eq!(diagnostics[0].message, "unknown step `shh`");
+        assert_eq!(
+            (diagnostics[0].start.line, diagnostics[0].start.column),
+            (3, 5)
+        );
+        assert_eq!(diagnostics[0].end.column, 8);
+        assert!(diagnostics[0].expected.contains(&"`sh`".to_string()));
+    }
+
+    #[test]
+    fn validate_nested_unknown_step() {
+        let diagnostics = validate_str("pipeline { steps { dir('a') { nope 'x' } } }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, codes::UNKNOWN_STEP);
+    }
+
+    #[test]
+    fn validate_missing_parameter() {
+        let diagnostics = validate_str("pipeline { steps { sh label: 'build' } }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, codes::MISSING_PARAMETER);
+        assert_eq!(
+            diagnostics[0].message,
+            "`sh` is missing the required parameter `script`"
+        );
+    }
+
+    #[test]
+    fn validate_missing_block() {
+        let diagnostics = validate_str("pipeline { steps { dir 'a' } }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, codes::MISSING_PARAMETER);
+    }
+
+    #[test]
+    fn validate_unexpected_block() {
+        let diagnostics = validate_str("pipeline { steps { sh 'ls' { echo 'hi' } } }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, codes::UNEXPECTED_BLOCK);
+    }
+
+    #[test]
+    fn validate_unknown_parameter() {
+        let diagnostics = validate_str("pipeline { steps { sh script: 'ls', scirpt: 'ls' } }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, codes::UNKNOWN_PARAMETER);
+        assert!(diagnostics[0].hint.as_ref().unwrap().contains("`script`"));
+    }
+
+    #[test]
+    fn validate_too_many_arguments() {
+        let diagnostics = validate_str("pipeline { steps { echo 'a', 'b' } }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, codes::TOO_MANY_ARGUMENTS);
+    }
+
+    #[test]
+    fn validate_type_mismatch() {
+        let diagnostics = validate_str("pipeline { steps { sh script: 3, returnStdout: 'yes' } }");
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "`sh` expects `returnStdout` to be a boolean, found a string",
+                "`sh` expects `script` to be a string, found a number",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_interpolation() {
+        let diagnostics = validate_str(
+            r#"pipeline { steps { sh script: "make ${env.TARGET}", returnStatus: "${env.X}" } }"#,
+        );
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec!["`sh` expects `returnStatus` to be a boolean, found a string"]
+        );
+    }
+
+    #[test]
+    fn validate_numbers() {
+        let diagnostics = validate_str(
+            r#"pipeline { steps { retry(count: 3) { timeout(minutes: '1.5') { sh 'make' } } timeout(minutes: "${env.MINUTES}") { sh 'make' } retry(count: 'twice') { sh 'make' } } }"#,
+        );
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec!["`retry` expects `count` to be a number, found a string"]
+        );
+    }
+
+    #[test]
+    fn validate_post() {
+        let diagnostics = validate_str(
+            "pipeline { stage { steps { sh 'ls' } post { failure { shh 'ls' } } } post { always { ech 'hi' } } }",
+        );
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec!["unknown step `shh`", "unknown step `ech`"]);
+    }
+
+    #[test]
+    fn validate_without_source() {
+        let step = Step::new(
+            generate_uuid(),
+            "nope".to_string(),
+            StepParameters::Positional(vec![]),
+        );
+        let diagnostics = validate_steps(&[step], &manifests());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].start.line, 0);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "error[E0100]: unknown step `nope`"
+        );
+    }
+}
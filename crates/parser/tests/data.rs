@@ -0,0 +1,135 @@
+/*
+ * This test module will parse the pipelines in test_data/data, which are
+ * written in YAML and JSON
+ */
+use otto_models::{Pipeline, Step, StepParameters};
+use otto_parser::*;
+
+fn parse(name: &str) -> Parsed {
+    let path = format!("./test_data/data/{}", name);
+    let buffer = std::fs::read_to_string(&path).expect("Failed to read file into string");
+    parse_pipeline(Some(&path), &buffer)
+}
+
+/**
+ * Describe the steps without their uuids or where they came from, which
+ * differ between the syntaxes
+ */
+fn describe_steps(steps: &[Step]) -> Vec<String> {
+    steps
+        .iter()
+        .map(|step| {
+            let parameters = match &step.parameters {
+                StepParameters::Positional(args) => serde_json::to_string(args),
+                StepParameters::Keyword(kwargs) => {
+                    let sorted: std::collections::BTreeMap<_, _> = kwargs.iter().collect();
+                    serde_json::to_string(&sorted)
+                }
+            };
+            let block = step
+                .block
+                .as_deref()
+                .map(describe_steps)
+                .unwrap_or_default();
+            format!("{} {} {:?}", step.symbol, parameters.unwrap(), block)
+        })
+        .collect()
+}
+
+fn describe(pipeline: &Pipeline) -> Vec<String> {
+    pipeline
+        .batches
+        .iter()
+        .flat_map(|batch| {
+            batch.contexts.iter().map(move |ctx| {
+                let mut environment: Vec<_> = ctx.environment.iter().flatten().collect();
+                environment.sort();
+                assert!(ctx.steps.iter().all(|step| step.context == ctx.uuid));
+                format!(
+                    "{:?} {:?} {:?} {:?}",
+                    batch.mode,
+                    ctx.properties,
+                    environment,
+                    describe_steps(&ctx.steps)
+                )
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_same_as_pipeline_syntax() {
+    let expected = parse("pipeline.otto")
+        .into_result()
+        .expect("Failed to parse");
+
+    for name in ["pipeline.yml", "pipeline.json"].iter() {
+        let parsed = parse(name);
+        assert!(parsed.diagnostics.is_empty(), "{:?}", parsed.diagnostics);
+        assert_eq!(describe(&parsed.pipeline), describe(&expected), "{}", name);
+    }
+}
+
+#[test]
+fn test_source_locations() {
+    let pipeline = parse("pipeline.yml").into_result().unwrap();
+    let build = &pipeline.batches[0].contexts[0];
+    let location = build.source.as_ref().unwrap();
+    assert_eq!((location.line, location.column), (6, 7));
+    assert_eq!(
+        location.file.as_deref(),
+        Some("./test_data/data/pipeline.yml")
+    );
+
+    let step = build.steps[1].source.as_ref().unwrap();
+    assert_eq!((step.line, step.column), (11, 11));
+}
+
+#[test]
+fn test_invalid() {
+    let diagnostics = parse("invalid.yml").diagnostics;
+    let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "unexpected `echo`, a step only has one symbol",
+            "the property `name` should be a string, not a list",
+            "stage is missing `steps`",
+            "`steps` has no steps",
+            "unexpected `name` alongside `parallel`",
+            "unexpected `agents`",
+        ]
+    );
+    assert_eq!(diagnostics[0].start.line, 6);
+    assert_eq!(diagnostics[3].code, codes::EMPTY_STEPS);
+    assert_eq!(diagnostics[5].expected, vec!["`environment`", "`stages`"]);
+}
+
+#[test]
+fn test_malformed() {
+    let parsed = parse_pipeline_with_syntax(None, "pipeline:\n  stages: [\n", Syntax::Yaml, None);
+    assert_eq!(parsed.diagnostics.len(), 1);
+    assert_eq!(parsed.diagnostics[0].code, codes::MALFORMED_DATA);
+
+    let parsed = parse_pipeline_with_syntax(None, "", Syntax::Json, None);
+    assert_eq!(parsed.diagnostics[0].code, codes::MALFORMED_DATA);
+
+    let parsed = parse_pipeline_with_syntax(None, "", Syntax::Yaml, None);
+    assert_eq!(parsed.diagnostics[0].message, "the pipeline is empty");
+}
+
+#[test]
+fn test_validated_like_pipeline_syntax() {
+    let manifests = otto_models::osp::load_manifests(std::path::Path::new("../../stdlib"))
+        .expect("Failed to load the stdlib manifests");
+    let buffer = "pipeline:\n  stages:\n    - steps:\n        - sh: 'ls'\n        - shh: 'ls'\n";
+    let pipeline = parse_pipeline_with_syntax(None, buffer, Syntax::Yaml, None)
+        .into_result()
+        .expect("Failed to parse");
+
+    let diagnostics = validate(&pipeline, &manifests);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code, codes::UNKNOWN_STEP);
+    assert_eq!(diagnostics[0].start.line, 5);
+    assert_eq!(diagnostics[0].start.column, 11);
+}
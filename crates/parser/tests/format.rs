@@ -0,0 +1,77 @@
+/*
+ * This test module will format everything in test_data/valid and ensure that
+ * the formatted pipelines are equivalent to the originals
+ */
+use otto_parser::*;
+use serde_json::Value;
+
+/**
+ * Convert the pipeline into JSON without the fields which are expected to
+ * differ between two parses of equivalent pipelines
+ *
+ * The needs of the contexts are replaced with the positions of the contexts
+ * they need, since their uuids differ too
+ */
+fn comparable(pipeline: &otto_models::Pipeline) -> Value {
+    fn strip(value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for key in ["uuid", "context", "source"].iter() {
+                    map.remove(*key);
+                }
+                map.values_mut().for_each(strip);
+            }
+            Value::Array(values) => values.iter_mut().for_each(strip),
+            _ => {}
+        }
+    }
+
+    let positions: Vec<uuid::Uuid> = pipeline.contexts().map(|ctx| ctx.uuid).collect();
+    let mut pipeline = pipeline.clone();
+    for batch in pipeline.batches.iter_mut() {
+        for ctx in batch.contexts.iter_mut() {
+            if let Some(needs) = ctx.needs.as_mut() {
+                for need in needs.iter_mut() {
+                    let position = positions.iter().position(|uuid| uuid == need);
+                    *need = uuid::Uuid::from_u128(position.expect("Unknown need") as u128);
+                }
+            }
+        }
+    }
+
+    let mut value = serde_json::to_value(&pipeline).expect("Failed to serialize");
+    strip(&mut value);
+    value
+}
+
+#[test]
+fn test_format_valid_pipelines() {
+    let dir = std::fs::read_dir("./test_data/valid").expect("Failed to read directory");
+
+    for entry in dir.flatten() {
+        let path = entry.path();
+        if path.extension().map(|ext| ext == "otto") != Some(true) {
+            continue;
+        }
+
+        let contents = std::fs::read_to_string(&path).expect("Failed to read file");
+        let original = parse_pipeline_string(&contents).expect("Failed to parse");
+        let formatted = format_pipeline_string(&contents)
+            .unwrap_or_else(|_| panic!("Failed to format {:?}", path));
+        let reparsed = parse_pipeline_string(&formatted)
+            .unwrap_or_else(|_| panic!("Failed to parse the formatted {:?}", path));
+
+        assert_eq!(
+            comparable(&original),
+            comparable(&reparsed),
+            "Formatting changed the meaning of {:?}",
+            path
+        );
+        assert_eq!(
+            format_pipeline_string(&formatted).expect("Failed to format"),
+            formatted,
+            "Formatting {:?} is not idempotent",
+            path
+        );
+    }
+}
@@ -0,0 +1,91 @@
+/*
+ * This test module will parse the pipelines in test_data/imports along with
+ * the libraries they import
+ */
+use otto_parser::*;
+
+fn parse(name: &str) -> Result<otto_models::Pipeline, Vec<Diagnostic>> {
+    let path = format!("./test_data/imports/{}", name);
+    let buffer = std::fs::read_to_string(&path).expect("Failed to read file into string");
+    parse_pipeline_with_imports(
+        Some(&path),
+        &buffer,
+        Some(std::path::Path::new("./test_data/imports")),
+    )
+    .into_result()
+}
+
+#[test]
+fn test_templates_and_macros() {
+    let pipeline = parse("pipeline.otto").expect("Failed to parse");
+    assert_eq!(pipeline.batches.len(), 2);
+
+    let build = &pipeline.batches[0].contexts[0];
+    let symbols: Vec<&str> = build.steps.iter().map(|s| s.symbol.as_str()).collect();
+    assert_eq!(symbols, vec!["sh", "sh", "sh"]);
+    assert!(build.steps.iter().all(|s| s.context == build.uuid));
+
+    // The macro's steps point into the library which defined it
+    let location = build.steps[1].source.as_ref().unwrap();
+    assert_eq!(
+        location.file.as_deref(),
+        Some("./test_data/imports/lib/common.otto")
+    );
+    assert_eq!(location.line, 2);
+
+    let release = &pipeline.batches[1].contexts[0];
+    assert_eq!(release.properties.get("name").unwrap(), "Release");
+    assert_eq!(release.needs, Some(vec![build.uuid]));
+    assert_eq!(release.steps.len(), 3);
+    assert!(release.steps.iter().all(|s| s.context == release.uuid));
+    assert_eq!(
+        release.steps[2].source.as_ref().unwrap().file.as_deref(),
+        Some("./test_data/imports/lib/release.otto")
+    );
+}
+
+#[test]
+fn test_cyclic_imports() {
+    let diagnostics = parse("cyclic.otto").expect_err("Should have failed to parse");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code, codes::CYCLIC_IMPORT);
+    assert_eq!(
+        diagnostics[0].message,
+        "the library imports itself: `ping.otto` -> `pong.otto` -> `ping.otto`"
+    );
+    assert_eq!(
+        diagnostics[0].file.as_deref(),
+        Some("./test_data/imports/lib/pong.otto")
+    );
+    assert_eq!(diagnostics[0].start.line, 1);
+
+    // The library's line is shown, not the line of the pipeline being rendered
+    let rendered = diagnostics[0].render("import 'lib/ping.otto'");
+    assert!(rendered.contains("1 | import 'ping.otto'\n"));
+}
+
+#[test]
+fn test_missing_imports() {
+    let diagnostics = parse("missing.otto").expect_err("Should have failed to parse");
+    let codes: Vec<&str> = diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(codes, vec![codes::IMPORT_FAILED, codes::UNKNOWN_TEMPLATE]);
+    assert!(diagnostics.iter().all(|d| d.file.is_none()));
+}
+
+#[test]
+fn test_imports_outside_of_root() {
+    let buffer = "import '../valid/00_simple.otto'\npipeline { steps { sh 'make' } }";
+    let parsed = parse_pipeline_with_imports(
+        None,
+        buffer,
+        Some(std::path::Path::new("./test_data/imports")),
+    );
+    assert_eq!(parsed.diagnostics.len(), 1);
+    assert!(parsed.diagnostics[0].message.contains("it is outside of"));
+
+    let parsed = parse_pipeline_with_imports(None, buffer, None);
+    assert_eq!(
+        parsed.diagnostics[0].message,
+        "cannot import `../valid/00_simple.otto`, imports are not allowed here"
+    );
+}
@@ -0,0 +1,60 @@
+/*
+ * This test module will convert the Jenkinsfile in test_data/jenkins and
+ * compare it with the Ottofile it is expected to become
+ */
+use otto_parser::*;
+
+fn convert() -> Converted {
+    let buffer = std::fs::read_to_string("./test_data/jenkins/Jenkinsfile")
+        .expect("Failed to read file into string");
+    convert_jenkinsfile(&buffer)
+}
+
+#[test]
+fn convert_to_ottofile() {
+    let expected = std::fs::read_to_string("./test_data/jenkins/Ottofile")
+        .expect("Failed to read file into string");
+    assert_eq!(convert().ottofile, expected);
+}
+
+#[test]
+fn convert_reports_unsupported() {
+    let converted = convert();
+    assert!(!converted.has_errors());
+
+    let reported: Vec<(usize, &str)> = converted
+        .diagnostics
+        .iter()
+        .map(|d| (d.start.line, d.message.as_str()))
+        .collect();
+    assert_eq!(
+        reported,
+        vec![
+            (2, "the statement could not be converted"),
+            (8, "the `timestamps` option cannot be converted"),
+            (20, "the arguments of `checkout` cannot be converted"),
+            (56, "the `changeRequest` condition cannot be converted"),
+            (62, "`script` blocks cannot be converted"),
+            (
+                76,
+                "the `cleanup` condition of a `post` block cannot be converted"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn convert_to_pipeline() {
+    let pipeline = convert()
+        .to_pipeline()
+        .into_result()
+        .expect("Failed to parse the converted Ottofile");
+
+    assert_eq!(pipeline.batches.len(), 4);
+    assert_eq!(pipeline.batches[2].contexts.len(), 2);
+    assert!(pipeline.post.is_some());
+    for ctx in pipeline.contexts() {
+        let environment = ctx.environment.as_ref().expect("Missing environment");
+        assert_eq!(environment["RUST_BACKTRACE"], "1");
+    }
+}
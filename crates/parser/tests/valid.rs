@@ -5,7 +5,7 @@ use otto_parser::*;
 use std::fs::ReadDir;
 use std::path::PathBuf;
 
-fn parse_file(path: &PathBuf) -> Result<otto_models::Pipeline, pest::error::Error<Rule>> {
+fn parse_file(path: &PathBuf) -> Result<otto_models::Pipeline, Vec<Diagnostic>> {
     use std::fs::File;
     use std::io::Read;
 
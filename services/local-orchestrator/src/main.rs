@@ -3,14 +3,143 @@
  */
 use async_std::task;
 use log::*;
-use serde::Deserialize;
+use otto_models::{Batch, BatchMode, Post, RunWorkload, Status};
+use std::collections::HashMap;
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
 use tide::Request;
 use uuid::Uuid;
 
-#[derive(Clone, Debug, Deserialize)]
-struct RunWorkload {
-    pipeline: Uuid,
-    contexts: Vec<otto_models::Context>,
+mod scheduler;
+
+/// The values of the pipeline's parameters, as the steps will see them
+type Parameters = HashMap<String, String>;
+
+/**
+ * The agent started for a context
+ */
+enum Agent {
+    Running(u32),
+    /// The context was terminated before its agent was started
+    Cancelled,
+}
+
+/**
+ * The agents running the contexts of a batch, by their contexts, so that the
+ * agents can be terminated when a sibling fails
+ */
+#[derive(Clone, Default)]
+struct Agents {
+    agents: Arc<Mutex<HashMap<Uuid, Agent>>>,
+}
+
+impl Agents {
+    /**
+     * Spawn the agent for the context, unless the context was terminated
+     * before it could be started
+     */
+    fn spawn(&self, context: &Uuid, cmd: &mut Command) -> std::io::Result<Option<Child>> {
+        let mut agents = self.agents.lock().expect("Failed to lock the agents");
+        if let Some(Agent::Cancelled) = agents.get(context) {
+            return Ok(None);
+        }
+        let child = cmd.spawn()?;
+        agents.insert(*context, Agent::Running(child.id()));
+        Ok(Some(child))
+    }
+
+    /**
+     * Forget the agent for the context once it has exited
+     */
+    fn finish(&self, context: &Uuid) {
+        self.agents
+            .lock()
+            .expect("Failed to lock the agents")
+            .remove(context);
+    }
+
+    /**
+     * Terminate the agent for the context, or stop it from being started if it
+     * has not been yet
+     *
+     * Agents are asked to terminate over their control socket, falling back to
+     * a signal for agents which have not bound their socket yet
+     */
+    fn terminate(&self, context: &Uuid) {
+        let pid = {
+            let mut agents = self.agents.lock().expect("Failed to lock the agents");
+            match agents.get(context) {
+                Some(Agent::Running(pid)) => *pid,
+                Some(Agent::Cancelled) => return,
+                None => {
+                    agents.insert(*context, Agent::Cancelled);
+                    return;
+                }
+            }
+        };
+
+        let sock = otto_agent::control::agent_socket(context);
+        if let Err(e) = otto_agent::control::terminate(&sock) {
+            debug!(
+                "Failed to terminate context {} over its socket, signalling it instead: {}",
+                context, e
+            );
+            if let Err(e) = otto_agent::control::signal_terminate(pid) {
+                error!("Failed to terminate context {}: {}", context, e);
+            }
+        }
+    }
+}
+
+/**
+ * The labels advertised by the agents of this orchestrator, which all run on
+ * the local machine: its operating system and architecture, along with any
+ * listed in AGENT_LABELS separated by commas or whitespace
+ */
+fn agent_labels() -> Vec<String> {
+    let mut labels = vec![
+        std::env::consts::OS.to_string(),
+        std::env::consts::ARCH.to_string(),
+    ];
+    if let Ok(extra) = std::env::var("AGENT_LABELS") {
+        labels.extend(
+            extra
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|label| !label.is_empty())
+                .map(|label| label.to_string()),
+        );
+    }
+    labels
+}
+
+/**
+ * Describe every context which cannot run on an agent with the given labels
+ */
+fn unmatched_contexts(contexts: &[otto_models::Context], labels: &[String]) -> Vec<String> {
+    contexts
+        .iter()
+        .filter_map(|ctx| {
+            let agent = ctx.agent.as_ref()?;
+            if agent.matches(labels) {
+                return None;
+            }
+            let name = ctx
+                .properties
+                .get("name")
+                .map(|name| format!("`{}`", name))
+                .unwrap_or_else(|| ctx.uuid.to_string());
+            Some(format!(
+                "the context {} needs an agent labelled `{}`, but the agents only have {}",
+                name,
+                agent.label,
+                labels
+                    .iter()
+                    .map(|label| format!("`{}`", label))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ))
+        })
+        .collect()
 }
 
 /**
@@ -18,17 +147,32 @@ struct RunWorkload {
  * context and will spawn an agent to run it.
  *
  */
-fn run_context(pipeline: &Uuid, ctx: &otto_models::Context) -> std::io::Result<bool> {
+fn run_context(
+    pipeline: &Uuid,
+    ctx: &otto_models::Context,
+    parameters: &Parameters,
+    agents: &Agents,
+) -> std::io::Result<Status> {
     use os_pipe::pipe;
     use std::io::{BufRead, BufReader};
     use std::io::{Error, ErrorKind};
-    use std::process::Command;
     use tempfile::NamedTempFile;
 
+    match &ctx.source {
+        Some(source) => info!("Running context {} declared at {}", ctx.uuid, source),
+        None => info!("Running context {}", ctx.uuid),
+    }
+
     let mut file = NamedTempFile::new()?;
     let invocation = otto_agent::Invocation {
         pipeline: *pipeline,
         steps: ctx.steps.clone(),
+        environment: ctx.environment.clone(),
+        properties: ctx.properties.clone(),
+        parameters: parameters.clone(),
+        when: ctx.when.clone(),
+        post: ctx.post.clone(),
+        context: Some(ctx.uuid),
     };
 
     println!("{}", serde_json::to_string(&invocation).unwrap());
@@ -49,7 +193,13 @@ fn run_context(pipeline: &Uuid, ctx: &otto_models::Context) -> std::io::Result<b
     cmd.stdout(writer);
     cmd.stderr(writer_clone);
 
-    let mut handle = cmd.spawn()?;
+    let mut handle = match agents.spawn(&ctx.uuid, &mut cmd)? {
+        Some(handle) => handle,
+        None => {
+            info!("Not running context {}, it was terminated", ctx.uuid);
+            return Ok(Status::Aborted);
+        }
+    };
     drop(cmd);
 
     let bufr = BufReader::new(reader);
@@ -60,7 +210,190 @@ fn run_context(pipeline: &Uuid, ctx: &otto_models::Context) -> std::io::Result<b
     }
 
     let status = handle.wait()?;
-    return Ok(status.success());
+    agents.finish(&ctx.uuid);
+    // The agent exits with the status of the context
+    Ok(status
+        .code()
+        .map(Status::from_code)
+        .unwrap_or(Status::Failed))
+}
+
+/**
+ * Run the contexts according to the mode of the batch, returning the status
+ * of the batch as a whole
+ *
+ * Linear batches stop at the first failed context, parallel batches run every
+ * context to completion, and fanout batches terminate the agents of every
+ * context still running once any one of them fails. Unstable contexts never
+ * stop the batch.
+ *
+ * Contexts which declare what they need are scheduled as a graph instead, see
+ * `run_graph()`
+ */
+fn run_batch<R>(contexts: &[otto_models::Context], mode: &BatchMode, run: R) -> Status
+where
+    R: Fn(&otto_models::Context, &Agents) -> std::io::Result<Status> + Clone + Send + 'static,
+{
+    let graph = contexts.iter().any(|ctx| ctx.needs.is_some());
+
+    if let (BatchMode::Linear, false) = (mode, graph) {
+        let mut status = Status::Successful;
+        for ctx in contexts.iter() {
+            match run(ctx, &Agents::default()) {
+                Ok(Status::Successful) => debug!("Context succeeded, continuing"),
+                Ok(Status::Skipped) => info!("Context {} was skipped, continuing", ctx.uuid),
+                Ok(Status::Unstable) => status = Status::Unstable,
+                Ok(failed) => return failed,
+                Err(e) => {
+                    error!("Failed to run context {}: {}", ctx.uuid, e);
+                    return Status::Failed;
+                }
+            }
+        }
+        return status;
+    }
+
+    run_graph(contexts, mode, run)
+}
+
+/**
+ * Run the batches one after another, stopping at the first which fails.
+ * Unstable batches do not stop the ones after them.
+ *
+ * The needs of a context may name a context of any batch, so once any
+ * context declares what it needs the contexts of every batch are scheduled
+ * as a single graph instead, and only their needs order them.
+ */
+fn run_batches<R>(batches: &[Batch], run: R) -> Status
+where
+    R: Fn(&otto_models::Context, &Agents) -> std::io::Result<Status> + Clone + Send + 'static,
+{
+    let contexts: Vec<otto_models::Context> = batches
+        .iter()
+        .flat_map(|batch| batch.contexts.iter().cloned())
+        .collect();
+    if contexts.iter().any(|ctx| ctx.needs.is_some()) {
+        return run_graph(&contexts, &BatchMode::Parallel, run);
+    }
+
+    let mut status = Status::Successful;
+    for batch in batches.iter() {
+        match run_batch(&batch.contexts, &batch.mode, run.clone()) {
+            Status::Successful | Status::Skipped => {}
+            Status::Unstable => status = Status::Unstable,
+            failed => return failed,
+        }
+    }
+    status
+}
+
+/**
+ * Run the contexts concurrently, starting each one as soon as every context
+ * it needs has succeeded. Contexts which need nothing start straight away, so
+ * a batch without any needs runs entirely in parallel.
+ *
+ * Contexts which need a context that failed are never started, but the
+ * others still run to completion unless the batch is a fanout, in which case
+ * the first failure terminates everything still running.
+ */
+fn run_graph<R>(contexts: &[otto_models::Context], mode: &BatchMode, run: R) -> Status
+where
+    R: Fn(&otto_models::Context, &Agents) -> std::io::Result<Status> + Clone + Send + 'static,
+{
+    use std::sync::mpsc::channel;
+
+    let mut status = Status::Successful;
+    let mut scheduler = scheduler::Scheduler::new(contexts);
+    let agents = Agents::default();
+    let (sender, receiver) = channel();
+
+    loop {
+        for ctx in scheduler.ready() {
+            let sender = sender.clone();
+            let agents = agents.clone();
+            let run = run.clone();
+            std::thread::spawn(move || {
+                let finished = run(&ctx, &agents).unwrap_or_else(|e| {
+                    error!("Failed to run context {}: {}", ctx.uuid, e);
+                    Status::Failed
+                });
+                // The receiver only goes away once the batch is finished
+                let _ = sender.send((ctx.uuid, finished));
+            });
+        }
+
+        for ctx in scheduler.blocked() {
+            info!(
+                "Not running context {}, a context it needs failed or was skipped",
+                ctx.uuid
+            );
+        }
+
+        if scheduler.running().is_empty() {
+            // Anything left can only be waiting on itself
+            for ctx in scheduler.cancel() {
+                error!(
+                    "Context {} can never start, its needs form a cycle",
+                    ctx.uuid
+                );
+                status = Status::Failed;
+            }
+            return status;
+        }
+
+        let (uuid, finished) = match receiver.recv() {
+            Ok(finished) => finished,
+            Err(_) => return Status::Failed,
+        };
+        scheduler.finish(uuid, finished.clone());
+
+        match finished {
+            Status::Successful => debug!("Context {} succeeded", uuid),
+            Status::Skipped => info!("Context {} was skipped", uuid),
+            Status::Unstable if status == Status::Successful => status = Status::Unstable,
+            Status::Unstable => {}
+            // Only the first failure counts, the siblings terminated because
+            // of it will have been aborted
+            failed if matches!(status, Status::Successful | Status::Unstable) => {
+                if let BatchMode::Fanout = mode {
+                    info!("Context {} failed, terminating its siblings", uuid);
+                    scheduler.cancel();
+                    for sibling in scheduler.running() {
+                        agents.terminate(sibling);
+                    }
+                }
+                status = failed;
+            }
+            _ => {}
+        }
+    }
+}
+
+/**
+ * Run the pipeline's post steps for the status it finished with, in a
+ * context of their own with the pipeline's environment
+ */
+fn run_post<R>(post: &Post, status: &Status, run: R) -> Status
+where
+    R: Fn(&otto_models::Context, &Agents) -> std::io::Result<Status>,
+{
+    let steps = post.steps_for(status);
+    if steps.is_empty() {
+        return status.clone();
+    }
+
+    let ctx = otto_models::Context {
+        steps,
+        environment: post.environment.clone(),
+        ..Default::default()
+    };
+    match run(&ctx, &Agents::default()) {
+        Ok(post_status) => post_status,
+        Err(e) => {
+            error!("Failed to run the post steps: {}", e);
+            Status::Failed
+        }
+    }
 }
 
 async fn healthcheck(_req: Request<()>) -> tide::Result {
@@ -74,18 +407,51 @@ async fn run_workload(mut req: Request<()>) -> tide::Result {
     let run: RunWorkload = req.body_json().await?;
     debug!("Received RunWorkload: {:?}", run);
 
-    task::spawn(async move {
-        for ctx in run.contexts.iter() {
-            match run_context(&run.pipeline, ctx) {
-                Ok(success) => {
-                    if !success {
-                        return;
-                    }
-                    debug!("Context succeeded, continuing");
-                }
-                Err(_) => {
-                    return;
-                }
+    let parameters = match otto_models::resolve_parameters(&run.parameters, &run.values) {
+        Ok(parameters) => parameters,
+        Err(errors) => {
+            error!("Refusing to run {}: {}", run.pipeline, errors.join(", "));
+            return Ok(tide::Response::builder(422)
+                .body(serde_json::json!({ "errors": errors }))
+                .content_type("application/json")
+                .build());
+        }
+    };
+
+    // Every context is run on the local machine, so a context which needs an
+    // agent it does not match could never run
+    let batches = run.batches();
+    let contexts: Vec<otto_models::Context> = batches
+        .iter()
+        .flat_map(|batch| batch.contexts.iter().cloned())
+        .collect();
+    let unmatched = unmatched_contexts(&contexts, &agent_labels());
+    if !unmatched.is_empty() {
+        error!("Refusing to run {}: {}", run.pipeline, unmatched.join(", "));
+        return Ok(tide::Response::builder(422)
+            .body(serde_json::json!({ "errors": unmatched }))
+            .content_type("application/json")
+            .build());
+    }
+
+    task::spawn_blocking(move || {
+        let pipeline = run.pipeline;
+        let run_in_agent = move |ctx: &otto_models::Context, agents: &Agents| {
+            run_context(&pipeline, ctx, &parameters, agents)
+        };
+        let status = run_batches(&batches, run_in_agent.clone());
+        if status != Status::Successful {
+            error!("Pipeline {} finished as {:?}", run.pipeline, status);
+        }
+
+        // The post steps run no matter how the contexts finished
+        if let Some(post) = &run.post {
+            let post_status = run_post(post, &status, run_in_agent);
+            if post_status != Status::Successful {
+                error!(
+                    "The post steps for {} finished as {:?}",
+                    run.pipeline, post_status
+                );
             }
         }
     });
@@ -114,4 +480,145 @@ async fn main() -> std::io::Result<()> {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use otto_models::labels::Expression;
+    use otto_models::{AgentRequirement, Context};
+
+    #[test]
+    fn agents_terminate_without_socket() {
+        let agents = Agents::default();
+        let context = otto_models::generate_uuid();
+        let mut child = agents
+            .spawn(&context, Command::new("sleep").arg("30"))
+            .expect("Failed to spawn")
+            .expect("The agent was not started");
+
+        // The agent has no control socket, so it is signalled instead
+        agents.terminate(&context);
+        assert_eq!(child.wait().expect("Failed to wait").code(), None);
+    }
+
+    #[test]
+    fn agents_terminate_before_spawn() {
+        let agents = Agents::default();
+        let context = otto_models::generate_uuid();
+        agents.terminate(&context);
+        assert!(agents
+            .spawn(&context, &mut Command::new("true"))
+            .expect("Failed to spawn")
+            .is_none());
+    }
+
+    /**
+     * Run the batches with a runner which records when each context starts
+     * and finishes, instead of starting agents
+     */
+    fn run_recorded(
+        batches: &[Batch],
+        status: impl Fn(&Context) -> Status + Clone + Send + 'static,
+    ) -> (Status, Vec<(Uuid, bool)>) {
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        let finished = run_batches(batches, move |ctx: &Context, _: &Agents| {
+            recorded.lock().unwrap().push((ctx.uuid, true));
+            let finished = status(ctx);
+            recorded.lock().unwrap().push((ctx.uuid, false));
+            Ok(finished)
+        });
+        let events = events.lock().unwrap().clone();
+        (finished, events)
+    }
+
+    fn batch(ctx: &Context) -> Batch {
+        Batch {
+            mode: BatchMode::Linear,
+            contexts: vec![ctx.clone()],
+        }
+    }
+
+    #[test]
+    fn run_needs_across_batches() {
+        let slow = Context {
+            needs: Some(vec![]),
+            ..Default::default()
+        };
+        let independent = Context {
+            needs: Some(vec![]),
+            ..Default::default()
+        };
+
+        let slow_uuid = slow.uuid;
+        let (status, events) = run_recorded(&[batch(&slow), batch(&independent)], move |ctx| {
+            if ctx.uuid == slow_uuid {
+                std::thread::sleep(std::time::Duration::from_millis(500));
+            }
+            Status::Successful
+        });
+
+        assert_eq!(status, Status::Successful);
+        let position = |event| events.iter().position(|e| e == &event).unwrap();
+        assert!(position((independent.uuid, true)) < position((slow.uuid, false)));
+    }
+
+    #[test]
+    fn run_needs_blocked_by_skipped_batch() {
+        let skipped = Context {
+            needs: Some(vec![]),
+            ..Default::default()
+        };
+        let needing = Context {
+            needs: Some(vec![skipped.uuid]),
+            ..Default::default()
+        };
+
+        let (status, events) =
+            run_recorded(&[batch(&skipped), batch(&needing)], |_| Status::Skipped);
+
+        assert_eq!(status, Status::Successful);
+        assert_eq!(events, vec![(skipped.uuid, true), (skipped.uuid, false)]);
+    }
+
+    #[test]
+    fn run_post_with_environment() {
+        let mut environment = HashMap::new();
+        environment.insert("FOO".to_string(), "bar".to_string());
+        let post = Post {
+            always: vec![otto_models::Step::new(
+                otto_models::generate_uuid(),
+                "sh".to_string(),
+                otto_models::StepParameters::Positional(vec![]),
+            )],
+            environment: Some(environment.clone()),
+            ..Default::default()
+        };
+
+        let status = run_post(&post, &Status::Failed, |ctx: &Context, _: &Agents| {
+            assert_eq!(ctx.steps.len(), 1);
+            assert_eq!(ctx.environment.as_ref(), Some(&environment));
+            Ok(Status::Successful)
+        });
+        assert_eq!(status, Status::Successful);
+    }
+
+    #[test]
+    fn unmatched_contexts_by_label() {
+        let requiring = |label: &str| Context {
+            agent: Some(AgentRequirement {
+                label: Expression::parse(label).unwrap(),
+            }),
+            ..Default::default()
+        };
+        let mut windows = requiring("windows");
+        windows
+            .properties
+            .insert("name".to_string(), "Installer".to_string());
+        let contexts = vec![Context::default(), requiring("linux && rust"), windows];
+
+        let labels = vec!["linux".to_string(), "rust".to_string()];
+        assert_eq!(
+            unmatched_contexts(&contexts, &labels),
+            vec!["the context `Installer` needs an agent labelled `windows`, but the agents only have `linux`, `rust`"]
+        );
+    }
+}
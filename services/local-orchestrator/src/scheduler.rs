@@ -0,0 +1,214 @@
+/*
+ * The scheduler decides when each context of a workload may start, which is as
+ * soon as every context it needs has succeeded
+ */
+use otto_models::{Context, Status};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+pub struct Scheduler {
+    pending: Vec<Context>,
+    running: Vec<Uuid>,
+    finished: HashMap<Uuid, Status>,
+    /// Contexts which will never start, because something they need did not
+    /// succeed or the workload was cancelled
+    blocked: Vec<Uuid>,
+}
+
+impl Scheduler {
+    pub fn new(contexts: &[Context]) -> Self {
+        Self {
+            pending: contexts.to_vec(),
+            running: vec![],
+            finished: HashMap::new(),
+            blocked: vec![],
+        }
+    }
+
+    /**
+     * Take the contexts which may start now, which are considered running
+     * until they are finished
+     *
+     * Contexts which need nothing may always start, as may those which only
+     * need contexts outside of the workload, since those must have already
+     * succeeded for the workload to have been submitted
+     */
+    pub fn ready(&mut self) -> Vec<Context> {
+        let ready: Vec<Uuid> = self
+            .pending
+            .iter()
+            .filter(|ctx| match &ctx.needs {
+                Some(needs) => needs.iter().all(|need| self.satisfied(need)),
+                None => true,
+            })
+            .map(|ctx| ctx.uuid)
+            .collect();
+
+        let (ready, pending): (Vec<Context>, Vec<Context>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|ctx| ready.contains(&ctx.uuid));
+        self.pending = pending;
+        self.running.extend(ready.iter().map(|ctx| ctx.uuid));
+        ready
+    }
+
+    /**
+     * Take the contexts which can never start since something they need did
+     * not succeed
+     *
+     * Contexts which need a skipped context are blocked too, since what they
+     * need was never done
+     */
+    pub fn blocked(&mut self) -> Vec<Context> {
+        let mut blocked = vec![];
+
+        // Blocking a context may block those which need it in turn
+        loop {
+            let (finished, blocked_uuids) = (&self.finished, &self.blocked);
+            let (newly, pending): (Vec<Context>, Vec<Context>) = std::mem::take(&mut self.pending)
+                .into_iter()
+                .partition(|ctx| {
+                    ctx.needs.iter().flatten().any(|need| {
+                        blocked_uuids.contains(need)
+                            || matches!(
+                                finished.get(need),
+                                Some(Status::Failed)
+                                    | Some(Status::Aborted)
+                                    | Some(Status::Skipped)
+                            )
+                    })
+                });
+            self.pending = pending;
+
+            if newly.is_empty() {
+                return blocked;
+            }
+            self.blocked.extend(newly.iter().map(|ctx| ctx.uuid));
+            blocked.extend(newly);
+        }
+    }
+
+    /**
+     * Take every context which has not started, none of which will now
+     */
+    pub fn cancel(&mut self) -> Vec<Context> {
+        let cancelled: Vec<Context> = self.pending.drain(..).collect();
+        self.blocked.extend(cancelled.iter().map(|ctx| ctx.uuid));
+        cancelled
+    }
+
+    pub fn finish(&mut self, uuid: Uuid, status: Status) {
+        self.running.retain(|r| r != &uuid);
+        self.finished.insert(uuid, status);
+    }
+
+    pub fn running(&self) -> &[Uuid] {
+        &self.running
+    }
+
+    /**
+     * Returns true if the context is finished in a way which lets the
+     * contexts needing it start
+     *
+     * Unstable contexts did what they were meant to, even if something about
+     * it was not quite right, whereas skipped contexts did nothing at all
+     */
+    fn satisfied(&self, uuid: &Uuid) -> bool {
+        match self.finished.get(uuid) {
+            Some(status) => matches!(status, Status::Successful | Status::Unstable),
+            None => {
+                !self.pending.iter().any(|ctx| &ctx.uuid == uuid)
+                    && !self.running.contains(uuid)
+                    && !self.blocked.contains(uuid)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(needs: Option<Vec<&Context>>) -> Context {
+        Context {
+            needs: needs.map(|needs| needs.iter().map(|ctx| ctx.uuid).collect()),
+            ..Default::default()
+        }
+    }
+
+    fn uuids(contexts: &[Context]) -> Vec<Uuid> {
+        contexts.iter().map(|ctx| ctx.uuid).collect()
+    }
+
+    #[test]
+    fn schedule_as_soon_as_needs_succeed() {
+        let build = context(Some(vec![]));
+        let lint = context(Some(vec![]));
+        let test = context(Some(vec![&build]));
+        let release = context(Some(vec![&test, &lint]));
+        let mut scheduler =
+            Scheduler::new(&[build.clone(), lint.clone(), test.clone(), release.clone()]);
+
+        assert_eq!(uuids(&scheduler.ready()), vec![build.uuid, lint.uuid]);
+        assert!(scheduler.ready().is_empty());
+
+        // The tests start without waiting for the slow lint
+        scheduler.finish(build.uuid, Status::Successful);
+        assert_eq!(uuids(&scheduler.ready()), vec![test.uuid]);
+
+        scheduler.finish(test.uuid, Status::Unstable);
+        assert!(scheduler.ready().is_empty());
+        scheduler.finish(lint.uuid, Status::Successful);
+        assert_eq!(uuids(&scheduler.ready()), vec![release.uuid]);
+        assert_eq!(scheduler.running(), &[release.uuid]);
+    }
+
+    #[test]
+    fn schedule_blocks_after_failure() {
+        let build = context(None);
+        let test = context(Some(vec![&build]));
+        let release = context(Some(vec![&test]));
+        let docs = context(None);
+        let mut scheduler =
+            Scheduler::new(&[build.clone(), test.clone(), release.clone(), docs.clone()]);
+
+        assert_eq!(uuids(&scheduler.ready()), vec![build.uuid, docs.uuid]);
+        scheduler.finish(build.uuid, Status::Failed);
+        assert!(scheduler.ready().is_empty());
+        assert_eq!(uuids(&scheduler.blocked()), vec![test.uuid, release.uuid]);
+        assert!(scheduler.blocked().is_empty());
+        assert_eq!(scheduler.running(), &[docs.uuid]);
+    }
+
+    #[test]
+    fn schedule_blocks_after_skip() {
+        let deploy = context(None);
+        let smoke = context(Some(vec![&deploy]));
+        let mut scheduler = Scheduler::new(&[deploy.clone(), smoke.clone()]);
+
+        assert_eq!(uuids(&scheduler.ready()), vec![deploy.uuid]);
+        scheduler.finish(deploy.uuid, Status::Skipped);
+        assert!(scheduler.ready().is_empty());
+        assert_eq!(uuids(&scheduler.blocked()), vec![smoke.uuid]);
+    }
+
+    #[test]
+    fn schedule_needs_outside_workload() {
+        let earlier = context(None);
+        let ctx = context(Some(vec![&earlier]));
+        let mut scheduler = Scheduler::new(std::slice::from_ref(&ctx));
+        assert_eq!(uuids(&scheduler.ready()), vec![ctx.uuid]);
+    }
+
+    #[test]
+    fn schedule_cycle_never_starts() {
+        let mut a = context(None);
+        let b = context(Some(vec![&a]));
+        a.needs = Some(vec![b.uuid]);
+        let mut scheduler = Scheduler::new(&[a.clone(), b.clone()]);
+
+        assert!(scheduler.ready().is_empty());
+        assert!(scheduler.running().is_empty());
+        assert_eq!(uuids(&scheduler.cancel()), vec![a.uuid, b.uuid]);
+    }
+}
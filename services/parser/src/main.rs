@@ -7,36 +7,72 @@
 extern crate serde_json;
 
 use log::*;
+use otto_models::osp::Manifest;
 use otto_parser::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
 use tide::{Request, Response};
 
-async fn parse(mut req: Request<()>) -> tide::Result {
+#[derive(Clone, Debug)]
+struct State {
+    /// The manifests of the available steps, when the service has been
+    /// configured with a STEPS_DIR to validate pipelines against
+    manifests: Option<Arc<HashMap<String, Manifest>>>,
+    /// The directory which pipelines may import libraries from, when the
+    /// service has been configured with a LIBRARY_DIR
+    libraries: Option<PathBuf>,
+}
+
+/**
+ * Choose the syntax of the pipeline by the content type of the request, or
+ * otherwise by the extension of the `file` it names in its query, e.g.
+ * `/v1/parse?file=pipeline.yml`
+ */
+fn syntax_of(req: &Request<State>) -> Syntax {
+    if let Some(syntax) = req
+        .content_type()
+        .and_then(|mime| Syntax::from_content_type(mime.essence()))
+    {
+        return syntax;
+    }
+
+    req.url()
+        .query_pairs()
+        .find(|(key, _)| key == "file")
+        .map(|(_, file)| Syntax::from_path(&file))
+        .unwrap_or(Syntax::Otto)
+}
+
+async fn parse(mut req: Request<State>) -> tide::Result {
+    let syntax = syntax_of(&req);
+
     if let Ok(body) = req.body_string().await {
-        let parsed = parse_pipeline_string(&body);
+        // Every problem in the pipeline is reported at once, rather than making
+        // the user fix them one at a time
+        let libraries = req.state().libraries.as_deref();
+        let parsed = parse_pipeline_with_syntax(None, &body, syntax, libraries)
+            .into_result()
+            .and_then(|pipeline| match &req.state().manifests {
+                Some(manifests) => {
+                    let diagnostics = validate(&pipeline, manifests);
+                    if diagnostics.is_empty() {
+                        Ok(pipeline)
+                    } else {
+                        Err(diagnostics)
+                    }
+                }
+                None => Ok(pipeline),
+            });
 
         match parsed {
-            Err(e) => {
-                error!("Failed to parse: {:?}", e);
-
-                let variant = match e.variant {
-                    ErrorVariant::CustomError { message } => message,
-                    ErrorVariant::ParsingError {
-                        positives,
-                        negatives,
-                    } => format!("{:?}, {:?}", positives, negatives),
-                };
-
-                let (line, column) = match e.line_col {
-                    LineColLocation::Pos(line_col) => line_col,
-                    LineColLocation::Span(line_col_start, _line_col_end) => line_col_start,
-                };
+            Err(diagnostics) => {
+                for diagnostic in diagnostics.iter() {
+                    error!("Failed to parse:\n{}", diagnostic.render(&body));
+                }
 
                 return Ok(Response::builder(400)
-                    .body(json!({
-                        "variant" : variant,
-                        "line" : line,
-                        "column" : column
-                    }))
+                    .body(json!({ "diagnostics": diagnostics }))
                     .content_type("application/json")
                     .build());
             }
@@ -57,7 +93,30 @@ async fn parse(mut req: Request<()>) -> tide::Result {
         .build())
 }
 
-async fn healthcheck(_req: Request<()>) -> tide::Result {
+async fn format(mut req: Request<State>) -> tide::Result {
+    if let Ok(body) = req.body_string().await {
+        match format_pipeline_string(&body) {
+            Err(diagnostics) => {
+                return Ok(Response::builder(400)
+                    .body(json!({ "diagnostics": diagnostics }))
+                    .content_type("application/json")
+                    .build());
+            }
+            Ok(formatted) => {
+                return Ok(Response::builder(200)
+                    .body(formatted)
+                    .content_type("text/plain")
+                    .build());
+            }
+        }
+    }
+
+    Ok(Response::builder(422)
+        .content_type("application/json")
+        .build())
+}
+
+async fn healthcheck(_req: Request<State>) -> tide::Result {
     Ok(Response::builder(200)
         .body("{}")
         .content_type("application/json")
@@ -68,9 +127,31 @@ async fn healthcheck(_req: Request<()>) -> tide::Result {
 async fn main() -> Result<(), std::io::Error> {
     use std::{env, net::TcpListener, os::unix::io::FromRawFd};
     tide::log::start();
-    let mut app = tide::new();
+
+    let manifests = match env::var("STEPS_DIR") {
+        Ok(dir) => {
+            let manifests = otto_models::osp::load_manifests(std::path::Path::new(&dir))?;
+            info!("Validating pipelines against {} steps", manifests.len());
+            Some(Arc::new(manifests))
+        }
+        Err(_) => {
+            warn!("STEPS_DIR is not defined, pipelines will not be validated");
+            None
+        }
+    };
+
+    let libraries = env::var("LIBRARY_DIR").ok().map(PathBuf::from);
+    if libraries.is_none() {
+        warn!("LIBRARY_DIR is not defined, pipelines will not be able to import libraries");
+    }
+
+    let mut app = tide::with_state(State {
+        manifests,
+        libraries,
+    });
     app.at("/health").get(healthcheck);
     app.at("/v1/parse").post(parse);
+    app.at("/v1/format").post(format);
 
     if let Some(fd) = env::var("LISTEN_FD").ok().and_then(|fd| fd.parse().ok()) {
         app.listen(unsafe { TcpListener::from_raw_fd(fd) }).await?;
@@ -0,0 +1,143 @@
+/*
+ * The scheduler runs the pipelines of projects whose cron triggers are due,
+ * by submitting them to the orchestrator as each minute passes
+ */
+use async_std::task;
+use chrono::Timelike;
+use log::*;
+use otto_models::config::Project;
+use otto_models::{Pipeline, RunWorkload};
+use otto_parser::{parse_pipeline_with_syntax, Syntax};
+use std::path::{Path, PathBuf};
+
+mod scheduler;
+
+use scheduler::{Clock, LocalClock, Scheduled, Scheduler};
+
+/**
+ * Load the project's pipeline, from its inline pipeline or from the path
+ * within its source
+ *
+ * Sources can only be read when they are a local directory, since nothing can
+ * check out a repository yet
+ */
+fn load_pipeline(project: &Project) -> Option<Pipeline> {
+    let (file, buffer, root) = if let Some(inline) = &project.pipeline.inline {
+        (None, inline.clone(), None)
+    } else {
+        let source = Path::new(
+            project
+                .source
+                .url
+                .strip_prefix("file://")
+                .unwrap_or(&project.source.url),
+        );
+        let path = project.pipeline.path.as_ref()?;
+        if !source.is_dir() {
+            warn!(
+                "Cannot schedule `{}`, its source {} is not a local directory",
+                project.title, project.source.url
+            );
+            return None;
+        }
+
+        let file: PathBuf = source.join(path);
+        match std::fs::read_to_string(&file) {
+            // The pipeline may import libraries from anywhere in its source
+            Ok(buffer) => (
+                Some(file.to_string_lossy().to_string()),
+                buffer,
+                Some(source),
+            ),
+            Err(e) => {
+                error!("Failed to read the pipeline {:?}: {}", file, e);
+                return None;
+            }
+        }
+    };
+
+    let syntax = file
+        .as_deref()
+        .map(Syntax::from_path)
+        .unwrap_or(Syntax::Otto);
+    match parse_pipeline_with_syntax(file.as_deref(), &buffer, syntax, root).into_result() {
+        Ok(pipeline) => Some(pipeline),
+        Err(diagnostics) => {
+            for diagnostic in diagnostics.iter() {
+                error!(
+                    "Failed to parse the pipeline of `{}`:\n{}",
+                    project.title,
+                    diagnostic.render(&buffer)
+                );
+            }
+            None
+        }
+    }
+}
+
+/**
+ * Submit the workload to the orchestrator, logging rather than returning any
+ * failure since the next project should still be submitted
+ */
+async fn submit(orchestrator: &str, title: &str, workload: &RunWorkload) {
+    info!("Running `{}` as {}", title, workload.pipeline);
+
+    let body = match surf::Body::from_json(workload) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to serialize the workload for `{}`: {}", title, e);
+            return;
+        }
+    };
+
+    match surf::post(format!("{}/v1/run", orchestrator))
+        .body(body)
+        .await
+    {
+        Ok(mut response) if !response.status().is_success() => {
+            error!(
+                "The orchestrator refused to run `{}` ({}): {}",
+                title,
+                response.status(),
+                response.body_string().await.unwrap_or_default()
+            );
+        }
+        Ok(_) => debug!("Submitted `{}`", title),
+        Err(e) => error!("Failed to submit `{}` to the orchestrator: {}", title, e),
+    }
+}
+
+#[async_std::main]
+async fn main() -> std::io::Result<()> {
+    use std::env;
+    pretty_env_logger::init();
+
+    let projects_dir = env::var("PROJECTS_DIR").unwrap_or_else(|_| "projects.d".to_string());
+    let orchestrator =
+        env::var("ORCHESTRATOR_URL").unwrap_or_else(|_| "http://localhost:7673".to_string());
+
+    let mut scheduled = vec![];
+    for project in otto_models::config::load_projects(Path::new(&projects_dir))? {
+        if let Some(pipeline) = load_pipeline(&project) {
+            if let Some(project) = Scheduled::new(&project.title, pipeline, &project.triggers) {
+                scheduled.push(project);
+            }
+        }
+    }
+    info!(
+        "Scheduling {} projects from {}",
+        scheduled.len(),
+        projects_dir
+    );
+
+    let mut scheduler = Scheduler::new(LocalClock, scheduled);
+    loop {
+        // Wake up just after the start of each minute
+        let second = LocalClock.now().second() as u64;
+        task::sleep(std::time::Duration::from_secs(60 - second.min(59))).await;
+
+        for (title, workload) in scheduler.tick() {
+            submit(&orchestrator, &title, &workload).await;
+        }
+    }
+}
@@ -0,0 +1,237 @@
+/*
+ * The scheduler module decides which projects are due to run as time passes,
+ * by evaluating the cron triggers of each project against a clock which can
+ * be replaced in tests
+ */
+
+use chrono::{Duration, Local, NaiveDateTime, Timelike};
+use otto_models::triggers::{Schedule, Trigger};
+use otto_models::{Pipeline, RunWorkload};
+
+/// How far the scheduler will catch up on the minutes it missed, such as when
+/// the machine was asleep, so that waking up does not run everything at once
+const MAX_CATCH_UP: i64 = 60;
+
+/**
+ * A Clock tells the scheduler what time it is
+ */
+pub trait Clock {
+    fn now(&self) -> NaiveDateTime;
+}
+
+/**
+ * The clock of the local machine, cron schedules are evaluated in its timezone
+ */
+pub struct LocalClock;
+
+impl Clock for LocalClock {
+    fn now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+}
+
+impl<T: Clock + ?Sized> Clock for &T {
+    fn now(&self) -> NaiveDateTime {
+        (**self).now()
+    }
+}
+
+/**
+ * A project which has at least one cron trigger
+ */
+#[derive(Clone, Debug)]
+pub struct Scheduled {
+    pub title: String,
+    schedules: Vec<Schedule>,
+    pipeline: Pipeline,
+}
+
+impl Scheduled {
+    /**
+     * Combine the triggers of the project with those declared by its pipeline,
+     * returning None if none of them are cron triggers
+     */
+    pub fn new(title: &str, pipeline: Pipeline, triggers: &[Trigger]) -> Option<Self> {
+        let mut schedules: Vec<Schedule> = vec![];
+        for trigger in triggers.iter().chain(pipeline.triggers.iter()) {
+            if let Trigger::Cron(schedule) = trigger {
+                if !schedules.contains(schedule) {
+                    schedules.push(schedule.clone());
+                }
+            }
+        }
+
+        if schedules.is_empty() {
+            return None;
+        }
+        Some(Self {
+            title: title.to_string(),
+            schedules,
+            pipeline,
+        })
+    }
+
+    fn is_due(&self, minute: &NaiveDateTime) -> bool {
+        self.schedules.iter().any(|s| s.matches(minute))
+    }
+}
+
+/**
+ * The Scheduler finds the projects which are due each time it ticks
+ */
+pub struct Scheduler<C: Clock> {
+    clock: C,
+    projects: Vec<Scheduled>,
+    /// The last minute which has been evaluated
+    last: NaiveDateTime,
+}
+
+impl<C: Clock> Scheduler<C> {
+    /**
+     * The minute which has already started when the scheduler is created is
+     * never evaluated, since it may have been evaluated before a restart
+     */
+    pub fn new(clock: C, projects: Vec<Scheduled>) -> Self {
+        let last = minute_of(&clock.now());
+        Self {
+            clock,
+            projects,
+            last,
+        }
+    }
+
+    /**
+     * Evaluate every minute since the last tick, returning the workloads of
+     * the projects which were due along with their titles
+     *
+     * Each project is run at most once per tick, however many of the minutes
+     * it was due in
+     */
+    pub fn tick(&mut self) -> Vec<(String, RunWorkload)> {
+        let now = minute_of(&self.clock.now());
+        if now <= self.last {
+            return vec![];
+        }
+
+        let earliest = now - Duration::minutes(MAX_CATCH_UP - 1);
+        let mut minute = std::cmp::max(self.last + Duration::minutes(1), earliest);
+        let mut due = vec![false; self.projects.len()];
+
+        while minute <= now {
+            for (index, project) in self.projects.iter().enumerate() {
+                due[index] = due[index] || project.is_due(&minute);
+            }
+            minute += Duration::minutes(1);
+        }
+        self.last = now;
+
+        self.projects
+            .iter()
+            .zip(due)
+            .filter(|(_, due)| *due)
+            .map(|(project, _)| (project.title.clone(), RunWorkload::new(&project.pipeline)))
+            .collect()
+    }
+}
+
+/**
+ * Truncate the time to the start of its minute
+ */
+fn minute_of(time: &NaiveDateTime) -> NaiveDateTime {
+    time.date().and_hms(time.hour(), time.minute(), 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use std::cell::Cell;
+
+    struct FakeClock(Cell<NaiveDateTime>);
+
+    impl FakeClock {
+        fn advance(&self, minutes: i64) {
+            self.0.set(self.0.get() + Duration::minutes(minutes));
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> NaiveDateTime {
+            self.0.get()
+        }
+    }
+
+    fn scheduled(title: &str, cron: &str) -> Scheduled {
+        let pipeline = otto_parser::parse_pipeline_string(
+            "pipeline { stage { steps { sh 'make' } } stage { steps { sh 'make test' } } }",
+        )
+        .expect("Failed to parse");
+        let triggers = vec![Trigger::Cron(Schedule::parse(cron).unwrap())];
+        Scheduled::new(title, pipeline, &triggers).expect("Should be scheduled")
+    }
+
+    fn titles(due: &[(String, RunWorkload)]) -> Vec<&str> {
+        due.iter().map(|(title, _)| title.as_str()).collect()
+    }
+
+    #[test]
+    fn tick_runs_due_projects() {
+        let clock = FakeClock(Cell::new(
+            NaiveDate::from_ymd(2021, 3, 5).and_hms(3, 59, 30),
+        ));
+        let mut scheduler = Scheduler::new(
+            &clock,
+            vec![
+                scheduled("Nightly", "0 4 * * *"),
+                scheduled("Often", "*/5 * * * *"),
+            ],
+        );
+        assert!(scheduler.tick().is_empty());
+
+        clock.advance(1);
+        let due = scheduler.tick();
+        assert_eq!(titles(&due), vec!["Nightly", "Often"]);
+        let (_, workload) = &due[0];
+        assert_eq!(workload.batches().len(), 2);
+
+        // The same minute is never evaluated twice
+        assert!(scheduler.tick().is_empty());
+
+        clock.advance(5);
+        assert_eq!(titles(&scheduler.tick()), vec!["Often"]);
+    }
+
+    #[test]
+    fn tick_catches_up_once() {
+        let clock = FakeClock(Cell::new(NaiveDate::from_ymd(2021, 3, 5).and_hms(12, 1, 0)));
+        let mut scheduler = Scheduler::new(&clock, vec![scheduled("Often", "*/5 * * * *")]);
+
+        // Many minutes were due while the scheduler was not ticking, but the
+        // project only runs once
+        clock.advance(30);
+        assert_eq!(titles(&scheduler.tick()), vec!["Often"]);
+
+        // Minutes too long ago are never caught up on
+        let mut scheduler = Scheduler::new(&clock, vec![scheduled("Nightly", "0 4 * * *")]);
+        clock.advance(24 * 60 - 60);
+        assert!(scheduler.tick().is_empty());
+    }
+
+    #[test]
+    fn scheduled_needs_cron() {
+        let pipeline = Pipeline::default();
+        let triggers = vec![Trigger::Upstream("Hello World".to_string())];
+        assert!(Scheduled::new("Downstream", pipeline, &triggers).is_none());
+    }
+
+    #[test]
+    fn workloads_serialize_like_run_requests() {
+        let workload = RunWorkload::new(&scheduled("Nightly", "@daily").pipeline);
+        let json = serde_json::to_value(&workload).unwrap();
+        assert!(json.get("pipeline").is_some());
+        // Each stage is a batch of its own, which the orchestrator runs in order
+        assert_eq!(json["batches"].as_array().map(Vec::len), Some(2));
+        assert!(json.get("contexts").is_none());
+        assert!(json.get("post").is_none());
+    }
+}
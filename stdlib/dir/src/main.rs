@@ -23,7 +23,13 @@ fn main() -> std::io::Result<()> {
         &steps_dir,
         &invoke.parameters.block,
         invoke.configuration.pipeline,
-        None,
+        // The references in the block are resolved by the agent for it, with the
+        // values of the context
+        otto_agent::RunOptions {
+            environment: Some(&invoke.environment),
+            values: Some(&invoke.values),
+            ..Default::default()
+        },
     )
     .unwrap();
     // Pass our block-scoped status back up to the caller
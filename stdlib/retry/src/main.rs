@@ -0,0 +1,60 @@
+/*
+ * A step which will invoke steps again until they succeed
+ */
+
+use otto_agent::step::*;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+#[derive(Clone, Debug, Deserialize)]
+struct Parameters {
+    count: Number,
+    block: Vec<otto_models::Step>,
+}
+
+fn main() -> std::io::Result<()> {
+    let steps_dir = std::env::var("STEPS_DIR").expect("STEPS_DIR must be defined");
+    let args = std::env::args().collect();
+    let invoke: Invocation<Parameters> = invocation_from_args(&args).unwrap();
+
+    let count = match invoke
+        .parameters
+        .count
+        .as_u64()
+        .and_then(|count| u32::try_from(count).ok())
+    {
+        Some(count) if count > 0 => count,
+        _ => {
+            println!(
+                "The count must be a positive whole number, not `{}`",
+                invoke.parameters.count
+            );
+            std::process::exit(otto_models::Status::Failed as i32);
+        }
+    };
+
+    let status = otto_agent::retry(
+        "retry",
+        invoke.configuration.uuid,
+        count,
+        Duration::from_secs(1),
+        || {
+            otto_agent::run(
+                &steps_dir,
+                &invoke.parameters.block,
+                invoke.configuration.pipeline,
+                // The references in the block are resolved by the agent for
+                // it, with the values of the context
+                otto_agent::RunOptions {
+                    environment: Some(&invoke.environment),
+                    values: Some(&invoke.values),
+                    ..Default::default()
+                },
+            )
+        },
+    )
+    .unwrap();
+    // Pass our block-scoped status back up to the caller
+    std::process::exit(status as i32);
+}
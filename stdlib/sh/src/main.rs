@@ -25,8 +25,12 @@ fn main() -> std::io::Result<()> {
     let args = std::env::args().collect();
     let invoke: Invocation<Parameters> = invocation_from_args(&args).unwrap();
 
-    // Create a file inside of `std::env::temp_dir()`.
-    let mut file = NamedTempFile::new()?;
+    // The script may have secrets interpolated into it, so it is kept in the
+    // scratch directory which the agent removes even if the step is killed
+    let mut file = match std::env::var_os("SCRATCH_DIR") {
+        Some(dir) => NamedTempFile::new_in(dir)?,
+        None => NamedTempFile::new()?,
+    };
     writeln!(file, "{}", invoke.parameters.script)
         .expect("Failed to write temporary file for script");
 
@@ -0,0 +1,56 @@
+/*
+ * A step which will invoke steps, killing them if they run out of time
+ */
+
+use otto_agent::step::*;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Deserialize)]
+struct Parameters {
+    minutes: Number,
+    block: Vec<otto_models::Step>,
+}
+
+fn main() -> std::io::Result<()> {
+    let steps_dir = std::env::var("STEPS_DIR").expect("STEPS_DIR must be defined");
+    let args = std::env::args().collect();
+    let invoke: Invocation<Parameters> = invocation_from_args(&args).unwrap();
+
+    // Durations too long to represent are refused rather than overflowing,
+    // which includes anything parsed as infinity
+    let deadline = invoke
+        .parameters
+        .minutes
+        .as_f64()
+        .filter(|minutes| *minutes > 0.0)
+        .and_then(|minutes| Duration::try_from_secs_f64(minutes * 60.0).ok())
+        .and_then(|duration| Instant::now().checked_add(duration));
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => {
+            println!(
+                "The minutes must be a positive number of a reasonable size, not `{}`",
+                invoke.parameters.minutes
+            );
+            std::process::exit(otto_models::Status::Failed as i32);
+        }
+    };
+
+    let status = otto_agent::run(
+        &steps_dir,
+        &invoke.parameters.block,
+        invoke.configuration.pipeline,
+        // The references in the block are resolved by the agent for it, with the
+        // values of the context
+        otto_agent::RunOptions {
+            environment: Some(&invoke.environment),
+            values: Some(&invoke.values),
+            deadline: Some(deadline),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    // Pass our block-scoped status back up to the caller
+    std::process::exit(status as i32);
+}
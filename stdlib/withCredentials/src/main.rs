@@ -0,0 +1,94 @@
+/*
+ * A step which will invoke steps with a secret from the credentials store
+ */
+
+use otto_agent::credentials::{mark_secret, Store};
+use otto_agent::step::*;
+use otto_models::Status;
+use serde::Deserialize;
+use std::io::Write;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, Deserialize)]
+struct Parameters {
+    id: String,
+    variable: Option<String>,
+    file: Option<String>,
+    block: Vec<otto_models::Step>,
+}
+
+/**
+ * Print the reason the step cannot run, and fail it
+ */
+fn fail(message: &str) -> ! {
+    println!("{}", message);
+    std::process::exit(Status::Failed as i32);
+}
+
+fn main() -> std::io::Result<()> {
+    let steps_dir = std::env::var("STEPS_DIR").expect("STEPS_DIR must be defined");
+    let args = std::env::args().collect();
+    let invoke: Invocation<Parameters> = invocation_from_args(&args).unwrap();
+    let parameters = &invoke.parameters;
+
+    let dir = match std::env::var("CREDENTIALS_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => fail("CREDENTIALS_DIR must be defined to use credentials"),
+    };
+    let secret = match Store::open_existing(&dir).and_then(|store| match store {
+        Some(store) => store.get(&parameters.id),
+        None => Ok(None),
+    }) {
+        Ok(Some(secret)) => secret,
+        Ok(None) => fail(&format!("There is no credential `{}`", parameters.id)),
+        Err(e) => fail(&format!("Failed to read the credentials store: {}", e)),
+    };
+
+    // The secret is exported into this process, which the steps inherit, and
+    // given to the agent for the block so that the steps can refer to it
+    let mut environment = invoke.environment.clone();
+    // Kept until the steps have finished, when it is removed
+    let mut secret_file = None;
+    match (&parameters.variable, &parameters.file) {
+        (Some(variable), None) => {
+            std::env::set_var(variable, &secret);
+            mark_secret(variable, false);
+            environment.insert(variable.clone(), secret.clone());
+        }
+        (None, Some(variable)) => {
+            // The agent removes its scratch directory for this step even if the
+            // step is killed before it can remove the file itself
+            let mut file = match std::env::var_os("SCRATCH_DIR") {
+                Some(dir) => tempfile::NamedTempFile::new_in(dir)?,
+                None => tempfile::NamedTempFile::new()?,
+            };
+            file.write_all(secret.as_bytes())?;
+            std::env::set_var(variable, file.path());
+            mark_secret(variable, true);
+            environment.insert(variable.clone(), file.path().to_string_lossy().to_string());
+            secret_file = Some(file);
+        }
+        _ => fail("Exactly one of `variable` or `file` must be given"),
+    }
+
+    let status = otto_agent::run(
+        &steps_dir,
+        &parameters.block,
+        invoke.configuration.pipeline,
+        // The references in the block are resolved by the agent for it, with
+        // the values of the context
+        otto_agent::RunOptions {
+            environment: Some(&environment),
+            values: Some(&invoke.values),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    // Exiting will not run the destructor which removes the file
+    if let Some(file) = secret_file {
+        file.close()?;
+    }
+    // Pass our block-scoped status back up to the caller
+    std::process::exit(status as i32);
+}